@@ -19,7 +19,7 @@ if [ "$CONTAINER_CLI" == "docker" ]; then
 fi
 
 # Execute the build command
-CMD="${CONTAINER_CLI} run --rm -it -v \"$(pwd)\":/home/rust/src ghcr.io/rust-cross/rust-musl-cross:x86_64-musl cargo build --release"
+CMD="${CONTAINER_CLI} run --rm -it -v \"$(pwd)\":/home/rust/src ghcr.io/rust-cross/rust-musl-cross:x86_64-musl cargo build --release --features builtin-dns"
 
 echo "Executing the build command:"
 echo "$CMD"
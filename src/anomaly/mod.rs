@@ -0,0 +1,201 @@
+use std::{collections::HashMap, net::IpAddr};
+
+// EWMA 的平滑系数
+const ALPHA: f64 = 0.3;
+// 建立基线所需的样本数,在此之前不判断异常
+const WARMUP_SAMPLES: usize = 3;
+// 残差的最小标准差,避免延迟非常稳定时微小波动也被当作异常
+const MIN_DEVIATION_MS: f64 = 2.0;
+
+/// A change of the state of one monitored IP
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// The delay has stayed above the baseline for several runs.
+    /// `delay_ms` is `None` when the IP failed the last run.
+    Regression {
+        ip: IpAddr,
+        delay_ms: Option<f64>,
+        baseline_ms: f64,
+    },
+    /// The delay is back to the baseline after a regression
+    Recovered {
+        ip: IpAddr,
+        delay_ms: f64,
+        baseline_ms: f64,
+    },
+}
+
+/// Baseline and streaks of one IP
+#[derive(Debug, Default)]
+struct IpState {
+    samples: usize,
+    mean: f64,
+    // 残差平方的 EWMA
+    variance: f64,
+    // 连续异常或连续恢复的次数
+    streak: usize,
+    alerting: bool,
+}
+
+impl IpState {
+    fn threshold(&self, k: f64) -> f64 {
+        self.mean + k * self.variance.sqrt().max(MIN_DEVIATION_MS)
+    }
+
+    fn learn(&mut self, delay_ms: f64) {
+        if self.samples == 0 {
+            self.mean = delay_ms;
+        } else {
+            let residual = delay_ms - self.mean;
+            self.mean += ALPHA * residual;
+            self.variance = (1.0 - ALPHA) * (self.variance + ALPHA * residual * residual);
+        }
+        self.samples += 1;
+    }
+}
+
+/// Detects sustained latency regressions of monitored IPs across runs.
+///
+/// Every IP keeps an EWMA baseline of its delay. A sample is anomalous when its residual exceeds
+/// `k` deviations, or when the IP failed; an alert is raised only after `sustain` anomalous runs in
+/// a row, and a recovery after as many normal runs. Anomalous samples are kept out of the baseline.
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    k: f64,
+    sustain: usize,
+    states: HashMap<IpAddr, IpState>,
+}
+
+impl AnomalyDetector {
+    pub fn new(k: f64, sustain: usize) -> Self {
+        AnomalyDetector {
+            k,
+            sustain: sustain.max(1),
+            states: HashMap::new(),
+        }
+    }
+
+    /// Feed the delay of `ip` in one run, `None` if it failed
+    pub fn observe(&mut self, ip: IpAddr, delay_ms: Option<f64>) -> Option<Alert> {
+        let state = self.states.entry(ip).or_default();
+
+        if state.samples < WARMUP_SAMPLES {
+            if let Some(delay_ms) = delay_ms {
+                state.learn(delay_ms);
+            }
+            return None;
+        }
+
+        let anomalous = match delay_ms {
+            Some(delay_ms) => delay_ms > state.threshold(self.k),
+            None => true,
+        };
+
+        if state.alerting {
+            // 告警期间统计连续恢复的次数
+            if anomalous {
+                state.streak = 0;
+                return None;
+            }
+            state.streak += 1;
+            if state.streak < self.sustain {
+                return None;
+            }
+            state.alerting = false;
+            state.streak = 0;
+            let delay_ms = delay_ms.unwrap_or_default();
+            state.learn(delay_ms);
+            return Some(Alert::Recovered {
+                ip,
+                delay_ms,
+                baseline_ms: state.mean,
+            });
+        }
+
+        if !anomalous {
+            state.streak = 0;
+            state.learn(delay_ms.unwrap_or_default());
+            return None;
+        }
+        state.streak += 1;
+        if state.streak < self.sustain {
+            return None;
+        }
+        state.alerting = true;
+        state.streak = 0;
+        Some(Alert::Regression {
+            ip,
+            delay_ms,
+            baseline_ms: state.mean,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip() -> IpAddr {
+        "1.1.1.1".parse().unwrap()
+    }
+
+    fn feed(detector: &mut AnomalyDetector, samples: &[Option<f64>]) -> Vec<Alert> {
+        samples
+            .iter()
+            .filter_map(|s| detector.observe(ip(), *s))
+            .collect()
+    }
+
+    #[test]
+    fn test_single_spike_is_ignored() {
+        let mut detector = AnomalyDetector::new(3.0, 3);
+        let alerts = feed(
+            &mut detector,
+            &[
+                Some(10.0),
+                Some(11.0),
+                Some(10.0),
+                Some(200.0),
+                Some(10.0),
+                None,
+                Some(11.0),
+            ],
+        );
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn test_sustained_regression_and_recovery() {
+        let mut detector = AnomalyDetector::new(3.0, 3);
+        let alerts = feed(
+            &mut detector,
+            &[
+                Some(10.0),
+                Some(11.0),
+                Some(10.0),
+                Some(80.0),
+                None,
+                Some(90.0),
+            ],
+        );
+        assert_eq!(alerts.len(), 1);
+        match alerts[0] {
+            Alert::Regression {
+                delay_ms,
+                baseline_ms,
+                ..
+            } => {
+                assert_eq!(delay_ms, Some(90.0));
+                assert!(baseline_ms < 11.0);
+            }
+            _ => panic!("expected a regression"),
+        }
+
+        // 持续异常不会重复告警
+        assert!(feed(&mut detector, &[Some(95.0), Some(85.0)]).is_empty());
+
+        let alerts = feed(&mut detector, &[Some(10.0), Some(11.0), Some(10.0)]);
+        assert_eq!(alerts.len(), 1);
+        assert!(matches!(alerts[0], Alert::Recovered { .. }));
+    }
+}
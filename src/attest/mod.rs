@@ -0,0 +1,284 @@
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compare::RunConfig;
+use crate::history::Measurement;
+use crate::output::Redaction;
+
+/// The results of one run as signed by '--attest'
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Version of the binary that measured them
+    pub version: String,
+    /// Hash of the settings, see [`RunConfig::fingerprint`]
+    pub config: String,
+    /// The settings, as in the first line of the result CSV
+    pub settings: String,
+    /// Unix timestamps of the start and the end of the run
+    pub started: i64,
+    pub finished: i64,
+    pub results: Vec<AttestedResult>,
+}
+
+/// One IP of a [`Summary`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttestedResult {
+    /// The IP, masked if the run used '--redact'
+    pub ip: String,
+    pub colo: Option<String>,
+    pub delay_ms: Option<f64>,
+    pub loss: Option<f64>,
+    pub speed_mbps: Option<f64>,
+}
+
+/// A [`Summary`] with its Ed25519 signature, both hex encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub summary: Summary,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Summary {
+    pub fn new(
+        config: &RunConfig,
+        started: i64,
+        finished: i64,
+        measurements: &[Measurement],
+        redaction: Redaction,
+    ) -> Self {
+        Summary {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config: config.fingerprint(),
+            settings: config.to_comment(),
+            started,
+            finished,
+            results: measurements
+                .iter()
+                .map(|m| AttestedResult {
+                    ip: redaction.apply(&m.ip),
+                    colo: m.colo.clone(),
+                    delay_ms: m.delay_ms,
+                    loss: m.loss,
+                    speed_mbps: m.speed_mbps,
+                })
+                .collect(),
+        }
+    }
+
+    /// The signed bytes
+    fn payload(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("serializable summary")
+    }
+
+    /// Whether the settings are the ones `config` is the hash of, i.e. were not edited
+    pub fn config_matches(&self) -> bool {
+        RunConfig::parse_comment(&self.settings)
+            .is_some_and(|config| config.fingerprint() == self.config)
+    }
+}
+
+impl Attestation {
+    /// Sign `summary` with the key in `key_path`, created if there is none yet.
+    /// Also returns whether the key was created.
+    pub fn sign(summary: Summary, key_path: &Path) -> io::Result<(Attestation, bool)> {
+        let (pkcs8, created) = match fs::read(key_path) {
+            Ok(pkcs8) => (pkcs8, false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = keys::generate()?;
+                write_private(key_path, &pkcs8)?;
+                (pkcs8, true)
+            }
+            Err(e) => return Err(e),
+        };
+        let (public_key, signature) = keys::sign(&pkcs8, &summary.payload())?;
+        let attestation = Attestation {
+            summary,
+            public_key: to_hex(&public_key),
+            signature: to_hex(&signature),
+        };
+        Ok((attestation, created))
+    }
+
+    /// Check the signature and the settings hash, and that the summary was
+    /// signed with `public_key` if given
+    pub fn verify(&self, public_key: Option<&str>) -> Result<(), String> {
+        if public_key.is_some_and(|key| !key.eq_ignore_ascii_case(&self.public_key)) {
+            return Err(format!("signed with another key: {}", self.public_key));
+        }
+        if !self.summary.config_matches() {
+            return Err(format!(
+                "the settings do not match the config hash {}",
+                self.summary.config
+            ));
+        }
+        let key = from_hex(&self.public_key).ok_or("the public key is not hex")?;
+        let signature = from_hex(&self.signature).ok_or("the signature is not hex")?;
+        keys::verify(&key, &self.summary.payload(), &signature)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Attestation> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let text = serde_json::to_string_pretty(self).expect("serializable attestation");
+        fs::write(path, text + "\n")
+    }
+}
+
+/// Create `path` readable only by its owner and write `bytes` to it
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)
+}
+
+/// Create `path` and write `bytes` to it, with the default permissions where
+/// there is no owner-only file mode
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    fs::File::create(path)?.write_all(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Ed25519 from ring, which rustls brings along
+#[cfg(feature = "tls")]
+mod keys {
+    use std::io;
+
+    use ring::{
+        rand::SystemRandom,
+        signature::{self, Ed25519KeyPair, KeyPair},
+    };
+
+    /// A new key pair as a PKCS#8 document
+    pub fn generate() -> io::Result<Vec<u8>> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map(|pkcs8| pkcs8.as_ref().to_vec())
+            .map_err(|_| io::Error::other("cannot generate a key"))
+    }
+
+    /// The public key of `pkcs8` and its signature of `message`
+    pub fn sign(pkcs8: &[u8], message: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let signature = pair.sign(message);
+        Ok((
+            pair.public_key().as_ref().to_vec(),
+            signature.as_ref().to_vec(),
+        ))
+    }
+
+    pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+        signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, signature)
+            .map_err(|_| "the signature does not match the summary".to_string())
+    }
+}
+
+/// Stand-in for builds without the `tls` feature: nothing can be signed or verified
+#[cfg(not(feature = "tls"))]
+mod keys {
+    use std::io;
+
+    const DISABLED: &str = "built without the tls feature";
+
+    pub fn generate() -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, DISABLED))
+    }
+
+    pub fn sign(_pkcs8: &[u8], _message: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, DISABLED))
+    }
+
+    pub fn verify(_public_key: &[u8], _message: &[u8], _signature: &[u8]) -> Result<(), String> {
+        Err(DISABLED.to_string())
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+    use crate::input::Opts;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = std::env::temp_dir().join(format!("attest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("key");
+        let _ = fs::remove_file(&key);
+
+        let measurements = vec![Measurement {
+            ip: "1.1.1.1".parse().unwrap(),
+            colo: Some("SJC".to_string()),
+            delay_ms: Some(12.5),
+            loss: Some(0.0),
+            speed_mbps: None,
+        }];
+        let config = RunConfig::from_opts(&Opts::default());
+        let summary = Summary::new(&config, 100, 160, &measurements, Redaction::None);
+        assert!(summary.config_matches());
+        assert_eq!(summary.results[0].ip, "1.1.1.1");
+        let redacted = Summary::new(&config, 100, 160, &measurements, Redaction::LastOctet);
+        assert_eq!(redacted.results[0].ip, "1.1.1.x");
+
+        let (attestation, created) = Attestation::sign(summary.clone(), &key).unwrap();
+        assert!(created);
+        attestation.verify(None).unwrap();
+        attestation
+            .verify(Some(&attestation.public_key.to_uppercase()))
+            .unwrap();
+        assert!(attestation.verify(Some("00")).is_err());
+
+        // 同一个密钥,不再新建
+        let path = dir.join("attestation.json");
+        let (again, created) = Attestation::sign(summary, &key).unwrap();
+        assert!(!created);
+        assert_eq!(again.public_key, attestation.public_key);
+        again.write(&path).unwrap();
+        Attestation::load(&path).unwrap().verify(None).unwrap();
+
+        // 改过的结果或设置都通不过
+        let mut forged = attestation.clone();
+        forged.summary.results[0].delay_ms = Some(1.0);
+        assert!(forged.verify(None).is_err());
+        let mut forged = attestation;
+        forged.summary.settings.push_str(" udping=true");
+        assert!(forged.verify(None).unwrap_err().contains("settings"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x01, 0xab]), "01ab");
+        assert_eq!(from_hex("01AB"), Some(vec![0x01, 0xab]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
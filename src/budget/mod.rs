@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+use crate::utils::parse_fraction;
+
+/// The number of most recent targets the failure share is measured over
+pub const ERROR_BUDGET_WINDOW: usize = 100;
+
+/// Parse an error budget given as a percentage (`30%`) or a fraction (`0.3`)
+pub fn parse_error_budget(src: &str) -> Result<f64, String> {
+    parse_fraction(src)
+        .map_err(|_| format!("invalid error budget '{}', expected e.g. 30% or 0.3", src))
+}
+
+/// Tracks which of the last [`ERROR_BUDGET_WINDOW`] targets failed, to notice
+/// when most probes start failing at once, e.g. because the local network
+/// went down or a captive portal intercepts the connections
+#[derive(Debug, Clone)]
+pub struct ErrorBudget {
+    fraction: f64,
+    window: VecDeque<bool>,
+    failures: usize,
+}
+
+impl ErrorBudget {
+    pub fn new(fraction: f64) -> Self {
+        ErrorBudget {
+            fraction,
+            window: VecDeque::with_capacity(ERROR_BUDGET_WINDOW),
+            failures: 0,
+        }
+    }
+
+    /// Record whether the next target failed. True once the window is full
+    /// and more than the budget of it failed.
+    pub fn record(&mut self, failed: bool) -> bool {
+        if self.window.len() == ERROR_BUDGET_WINDOW {
+            self.failures -= self.window.pop_front().unwrap_or(false) as usize;
+        }
+        self.window.push_back(failed);
+        self.failures += failed as usize;
+        self.window.len() == ERROR_BUDGET_WINDOW && self.failed_share() > self.fraction
+    }
+
+    /// The share of the window that failed
+    pub fn failed_share(&self) -> f64 {
+        self.failures as f64 / self.window.len().max(1) as f64
+    }
+
+    /// Start over with an empty window
+    pub fn reset(&mut self) {
+        self.window.clear();
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_budget() {
+        assert_eq!(parse_error_budget("30%"), Ok(0.3));
+        assert_eq!(parse_error_budget("0.3"), Ok(0.3));
+        assert!(parse_error_budget("130%").is_err());
+        assert!(parse_error_budget("abc").is_err());
+    }
+
+    #[test]
+    fn test_error_budget() {
+        let mut budget = ErrorBudget::new(0.3);
+        // 窗口未满时不触发
+        for _ in 0..ERROR_BUDGET_WINDOW - 1 {
+            assert!(!budget.record(true));
+        }
+        assert!(budget.record(true));
+
+        budget.reset();
+        for n in 0..ERROR_BUDGET_WINDOW {
+            assert!(!budget.record(n % 4 == 0));
+        }
+        // 滑出窗口的成功被失败取代,失败比例升到 30% 以上
+        let tripped = (0..ERROR_BUDGET_WINDOW).find(|_| budget.record(true));
+        assert_eq!(tripped, Some(7));
+        assert!(budget.failed_share() > 0.3);
+    }
+}
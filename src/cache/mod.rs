@@ -0,0 +1,136 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::targets::Targets;
+
+/// Results of earlier stage runs stored on disk, reused while the same
+/// targets are tested again with the same stage options.
+#[derive(Debug, Clone)]
+pub struct StageCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    // 写入时间(unix 秒)
+    created: u64,
+    results: T,
+}
+
+impl StageCache {
+    pub fn new<P: AsRef<Path>>(dir: P, ttl: Duration) -> Self {
+        StageCache {
+            dir: dir.as_ref().to_path_buf(),
+            ttl,
+        }
+    }
+
+    /// Hash of a stage, its targets and the options that change its results.
+    /// The order of the targets does not matter.
+    pub fn key(stage: &str, targets: &Targets, options: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(stage.as_bytes());
+        hasher.update([0]);
+        hasher.update(options.as_bytes());
+        hasher.update([0]);
+        hasher.update(targets.fingerprint().as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn path(&self, stage: &str, key: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}.json", stage, key))
+    }
+
+    /// The cached results of `stage` for `key`, if stored less than the TTL ago
+    pub fn get<T: DeserializeOwned>(&self, stage: &str, key: &str) -> Option<T> {
+        let text = fs::read_to_string(self.path(stage, key)).ok()?;
+        let entry: Entry<T> = serde_json::from_str(&text).ok()?;
+        if now().saturating_sub(entry.created) >= self.ttl.as_secs() {
+            return None;
+        }
+        Some(entry.results)
+    }
+
+    pub fn put<T: Serialize>(&self, stage: &str, key: &str, results: &T) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry = Entry {
+            created: now(),
+            results,
+        };
+        let text = serde_json::to_string(&entry)?;
+        // 先写临时文件再改名,避免并发任务读到写了一半的缓存
+        let path = self.path(stage, key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(tmp, path)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rustspeedtest-{}-{}", name, rand::random::<u32>()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_key() {
+        let a: IpAddr = "1.1.1.1".parse().unwrap();
+        let b: IpAddr = "1.0.0.1".parse().unwrap();
+        let targets = |ips: &[IpAddr]| Targets::from(ips.to_vec());
+
+        assert_eq!(
+            StageCache::key("tcping", &targets(&[a, b]), "443"),
+            StageCache::key("tcping", &targets(&[b, a]), "443")
+        );
+        assert_ne!(
+            StageCache::key("tcping", &targets(&[a, b]), "443"),
+            StageCache::key("tcping", &targets(&[a, b]), "80")
+        );
+        assert_ne!(
+            StageCache::key("tcping", &targets(&[a]), "443"),
+            StageCache::key("httping", &targets(&[a]), "443")
+        );
+    }
+
+    #[test]
+    fn test_get_put() {
+        let dir = temp_dir("cache");
+        let cache = StageCache::new(&dir, Duration::from_secs(60));
+        let key = StageCache::key("tcping", &Targets::parse("1.1.1.1", 0), "");
+
+        assert_eq!(cache.get::<Vec<u32>>("tcping", &key), None);
+        cache.put("tcping", &key, &vec![1u32, 2]).unwrap();
+        assert_eq!(cache.get::<Vec<u32>>("tcping", &key), Some(vec![1, 2]));
+
+        // 过期的缓存不再使用
+        let expired = StageCache::new(&dir, Duration::ZERO);
+        assert_eq!(expired.get::<Vec<u32>>("tcping", &key), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
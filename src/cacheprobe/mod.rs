@@ -0,0 +1,547 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::{Position, Url};
+
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+use crate::tls::{self, TlsConnector};
+
+/// Bytes read of a response, enough for its headers
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// What the edge said about one request of the cached URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheResponse {
+    /// CF-Cache-Status, or the first word of X-Cache, uppercased
+    pub status: Option<String>,
+    /// Seconds the object has been in the cache, from the Age header
+    pub age: Option<u64>,
+    /// From sending the request to the first response byte
+    pub ttfb: Duration,
+}
+
+impl CacheResponse {
+    pub fn is_hit(&self) -> bool {
+        self.status.as_deref() == Some("HIT")
+    }
+}
+
+/// Two consecutive requests of the same URL to one IP, `None` if the request failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheResult {
+    pub ip: IpAddr,
+    pub first: Option<CacheResponse>,
+    pub second: Option<CacheResponse>,
+}
+
+impl CacheResult {
+    /// Whether the edge serves the URL from its cache, at the latest on the second request
+    pub fn cached(&self) -> bool {
+        self.second.as_ref().is_some_and(CacheResponse::is_hit)
+    }
+
+    /// How much slower the first request was when it missed the cache and the second hit it
+    pub fn miss_penalty(&self) -> Option<Duration> {
+        match (&self.first, &self.second) {
+            (Some(first), Some(second)) if !first.is_hit() && second.is_hit() => {
+                Some(first.ttfb.saturating_sub(second.ttfb))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Requests a cacheable URL twice from a few IPs to find out whether their
+/// edge serves it from its cache, and how much a miss costs
+pub struct CacheProbe {
+    url: Url,
+    timeout: Duration,
+    tls: TlsConnector,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+}
+
+impl CacheProbe {
+    pub fn new(url: Url, timeout: Duration) -> Self {
+        CacheProbe {
+            url,
+            timeout,
+            tls: tls::connector(),
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every request
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Probe all `ips` concurrently, keeping their order
+    pub async fn run(&self, ips: &[IpAddr]) -> Vec<CacheResult> {
+        self.events.stage_start("cache_probe", ips.len());
+
+        let results = join_all(ips.iter().map(|ip| self.probe(*ip))).await;
+
+        for result in results.iter() {
+            let response = |r: &Option<CacheResponse>| {
+                r.as_ref().map(|r| {
+                    json!({
+                        "status": r.status,
+                        "age": r.age,
+                        "ttfb_ms": r.ttfb.as_secs_f64() * 1000.0,
+                    })
+                })
+            };
+            self.events.result(
+                "cache_probe",
+                result.ip,
+                result.cached(),
+                json!({
+                    "first": response(&result.first),
+                    "second": response(&result.second),
+                    "miss_penalty_ms": result.miss_penalty().map(|d| d.as_secs_f64() * 1000.0),
+                }),
+            );
+        }
+        self.events
+            .stage_end("cache_probe", results.iter().filter(|r| r.cached()).count());
+
+        results
+    }
+
+    async fn probe(&self, ip: IpAddr) -> CacheResult {
+        // 第二次请求在第一次完成后发出,才能命中第一次写入的缓存
+        let first = self.request(ip).await;
+        let second = self.request(ip).await;
+        CacheResult { ip, first, second }
+    }
+
+    async fn request(&self, ip: IpAddr) -> Option<CacheResponse> {
+        let response = fetch(
+            &self.url,
+            ip,
+            self.timeout,
+            &self.tls,
+            &self.socket_options,
+            false,
+        )
+        .await;
+        match response {
+            Ok((ttfb, head)) => {
+                let (status, age) = cache_headers(&head);
+                self.events.trace("cache_probe", ip, || {
+                    format!("{:?} age {:?} after {:?}", status, age, ttfb)
+                });
+                Some(CacheResponse { status, age, ttfb })
+            }
+            Err(e) => {
+                self.events
+                    .trace("cache_probe", ip, || format!("request failed: {}", e));
+                None
+            }
+        }
+    }
+}
+
+/// The answer of one IP to one URL of a cache warming
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warmed {
+    /// HTTP status code, 0 if the response was not HTTP
+    pub code: u16,
+    /// CF-Cache-Status, or the first word of X-Cache, uppercased
+    pub status: Option<String>,
+}
+
+/// The answers of one IP to every URL of a cache warming, in the order of
+/// the URLs, `None` where the request failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmResult {
+    pub ip: IpAddr,
+    pub responses: Vec<Option<Warmed>>,
+}
+
+impl WarmResult {
+    /// How many URLs got a successful or redirect response
+    pub fn warmed(&self) -> usize {
+        self.responses
+            .iter()
+            .flatten()
+            .filter(|warmed| (200..400).contains(&warmed.code))
+            .count()
+    }
+}
+
+/// Requests a list of URLs through each chosen IP, with the Host and SNI of
+/// the URL, so that their edges have the objects cached before traffic is
+/// switched to them
+pub struct CacheWarmer {
+    urls: Vec<Url>,
+    timeout: Duration,
+    tls: TlsConnector,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+}
+
+impl CacheWarmer {
+    /// Warm `urls`, giving every request `timeout` to read the whole body
+    pub fn new(urls: Vec<Url>, timeout: Duration) -> Self {
+        CacheWarmer {
+            urls,
+            timeout,
+            tls: tls::connector(),
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every request
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Warm all `ips` concurrently, keeping their order
+    pub async fn run(&self, ips: &[IpAddr]) -> Vec<WarmResult> {
+        self.events.stage_start("cache_warm", ips.len());
+
+        let results = join_all(ips.iter().map(|ip| self.warm(*ip))).await;
+
+        for result in results.iter() {
+            self.events.result(
+                "cache_warm",
+                result.ip,
+                result.warmed() == self.urls.len(),
+                json!({"warmed": result.warmed(), "urls": self.urls.len()}),
+            );
+        }
+        self.events.stage_end(
+            "cache_warm",
+            results
+                .iter()
+                .filter(|r| r.warmed() == self.urls.len())
+                .count(),
+        );
+
+        results
+    }
+
+    async fn warm(&self, ip: IpAddr) -> WarmResult {
+        // 同一 IP 的 URL 依次请求,不占用太多连接
+        let mut responses = Vec::with_capacity(self.urls.len());
+        for url in self.urls.iter() {
+            let response =
+                fetch(url, ip, self.timeout, &self.tls, &self.socket_options, true).await;
+            let warmed = match response {
+                Ok((_, head)) => {
+                    let warmed = Warmed {
+                        code: status_code(&head),
+                        status: cache_headers(&head).0,
+                    };
+                    self.events.trace("cache_warm", ip, || {
+                        format!("{}: {} {:?}", url, warmed.code, warmed.status)
+                    });
+                    Some(warmed)
+                }
+                Err(e) => {
+                    self.events
+                        .trace("cache_warm", ip, || format!("{}: {}", url, e));
+                    None
+                }
+            };
+            responses.push(warmed);
+        }
+        WarmResult { ip, responses }
+    }
+}
+
+/// Read the URLs to warm from `path`, one per line. Empty lines and lines
+/// starting with '#' are skipped.
+pub fn load_urls<P: AsRef<Path>>(path: P) -> io::Result<Vec<Url>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let mut urls = Vec::new();
+    for (number, line) in file.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let url = Url::parse(line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, e),
+            )
+        })?;
+        urls.push(url);
+    }
+    Ok(urls)
+}
+
+/// Request `url` from `ip`, over TLS with the host of `url` as SNI if it is
+/// https, and return the time to the first byte and the response headers.
+/// The body is read to its end if `read_body`.
+async fn fetch(
+    url: &Url,
+    ip: IpAddr,
+    timeout: Duration,
+    connector: &TlsConnector,
+    socket_options: &SocketOptions,
+    read_body: bool,
+) -> io::Result<(Duration, String)> {
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addr = SocketAddr::new(ip, port);
+    let host = url.host_str().unwrap_or_default();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustspeedtest\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        &url[Position::BeforePath..Position::AfterQuery],
+        host
+    );
+
+    tokio::time::timeout(timeout, async {
+        let stream = socket_options.connect(addr, timeout).await?;
+        if url.scheme() == "https" {
+            let stream = tls::handshake(connector, host, stream).await?;
+            exchange(stream, &request, read_body).await
+        } else {
+            exchange(stream, &request, read_body).await
+        }
+    })
+    .await?
+}
+
+/// Send `request` and read the response headers, timing the first byte
+async fn exchange<S>(
+    mut stream: S,
+    request: &str,
+    read_body: bool,
+) -> io::Result<(Duration, String)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 握手不计入,只测量请求本身
+    let start = Instant::now();
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 4096];
+    let mut ttfb = None;
+    while head.len() < MAX_HEAD_BYTES {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        ttfb.get_or_insert_with(|| start.elapsed());
+        head.extend_from_slice(&buf[..n]);
+        if head.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    // 读完整个响应,边缘节点才会缓存完整的对象
+    if read_body {
+        while stream.read(&mut buf).await? > 0 {}
+    }
+    let _ = stream.shutdown().await;
+
+    match ttfb {
+        Some(ttfb) => Ok((ttfb, String::from_utf8_lossy(&head).into_owned())),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// The status code of `response`, 0 if it is not HTTP
+pub fn status_code(response: &str) -> u16 {
+    response
+        .lines()
+        .next()
+        .filter(|line| line.starts_with("HTTP/"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The cache status and age in the headers of `response`
+fn cache_headers(response: &str) -> (Option<String>, Option<u64>) {
+    let mut cf_status = None;
+    let mut x_cache = None;
+    let mut age = None;
+    for line in response.lines().skip(1) {
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "cf-cache-status" => cf_status = Some(value.to_ascii_uppercase()),
+            // 例如 'Hit from cloudfront' 或 'HIT, MISS'
+            "x-cache" => {
+                x_cache = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .next()
+                    .map(str::to_ascii_uppercase)
+            }
+            "age" => age = value.parse().ok(),
+            _ => {}
+        }
+    }
+    (cf_status.or(x_cache), age)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_headers() {
+        assert_eq!(
+            cache_headers("HTTP/1.1 200 OK\r\nCF-Cache-Status: hit\r\nAge: 42\r\n\r\n"),
+            (Some("HIT".to_string()), Some(42))
+        );
+        assert_eq!(
+            cache_headers("HTTP/1.1 200 OK\r\nX-Cache: Miss from cloudfront\r\n\r\n"),
+            (Some("MISS".to_string()), None)
+        );
+        assert_eq!(cache_headers("HTTP/1.1 200 OK\r\n\r\n"), (None, None));
+    }
+
+    #[test]
+    fn test_miss_penalty() {
+        let response = |status: &str, ms| {
+            Some(CacheResponse {
+                status: Some(status.to_string()),
+                age: None,
+                ttfb: Duration::from_millis(ms),
+            })
+        };
+        let mut result = CacheResult {
+            ip: "1.1.1.1".parse().unwrap(),
+            first: response("MISS", 120),
+            second: response("HIT", 20),
+        };
+        assert!(result.cached());
+        assert_eq!(result.miss_penalty(), Some(Duration::from_millis(100)));
+
+        result.first = response("HIT", 25);
+        assert_eq!(result.miss_penalty(), None);
+        result.second = response("DYNAMIC", 30);
+        assert!(!result.cached());
+    }
+
+    #[tokio::test]
+    async fn test_cache_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut status = "MISS";
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nCF-Cache-Status: {}\r\nAge: 0\r\n\r\nbody",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                status = "HIT";
+            }
+        });
+
+        let url = Url::parse(&format!("http://example.com:{}/app.js?v=1", port)).unwrap();
+        let probe = CacheProbe::new(url, Duration::from_secs(1));
+        let results = probe.run(&["127.0.0.1".parse().unwrap()]).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].first.as_ref().unwrap().status.as_deref(),
+            Some("MISS")
+        );
+        assert!(results[0].cached());
+        assert!(results[0].miss_penalty().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_warmer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let response = if request.starts_with("GET /missing ") {
+                    "HTTP/1.1 404 Not Found\r\n\r\n".to_string()
+                } else {
+                    // 请求带有 URL 的 Host
+                    assert!(request.contains("Host: example.com\r\n"));
+                    format!(
+                        "HTTP/1.1 200 OK\r\nCF-Cache-Status: MISS\r\n\r\n{}",
+                        "x".repeat(100_000)
+                    )
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let url = |path: &str| Url::parse(&format!("http://example.com:{}{}", port, path)).unwrap();
+        let warmer = CacheWarmer::new(
+            vec![url("/app.js"), url("/missing")],
+            Duration::from_secs(1),
+        );
+        let results = warmer.run(&["127.0.0.1".parse().unwrap()]).await;
+        assert_eq!(results[0].warmed(), 1);
+        assert_eq!(
+            results[0].responses,
+            vec![
+                Some(Warmed {
+                    code: 200,
+                    status: Some("MISS".to_string())
+                }),
+                Some(Warmed {
+                    code: 404,
+                    status: None
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_urls() {
+        let path = std::env::temp_dir().join(format!("warm-urls-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# assets\nhttps://example.com/app.js\n\nhttps://example.com/a.css\n",
+        )
+        .unwrap();
+        let urls = load_urls(&path).unwrap();
+        assert_eq!(urls.len(), 2);
+        assert_eq!(urls[1].path(), "/a.css");
+
+        fs::write(&path, "https://example.com/\nnot a url\n").unwrap();
+        let err = load_urls(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().starts_with("line 2:"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{trf, Msg};
+use crate::scanner::Delay;
+use crate::targets::Targets;
+
+// 两次保存进度之间的最短间隔
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How far a tcping run got: every target before `next` in the random
+/// order and those in `done` are tested
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    // 设置和目标不同的进度不能继续
+    config: String,
+    targets: String,
+    // 十六进制,决定目标的测试顺序
+    seed: String,
+    next: u64,
+    done: BTreeSet<u64>,
+    results: Vec<Delay>,
+}
+
+/// Progress of a long scan saved to a file now and then, so that an
+/// interrupted run can continue where it stopped
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    state: State,
+    saved: Instant,
+}
+
+impl Checkpoint {
+    /// Continue the run saved in `path` if it tested the same targets under
+    /// the same `config`, else start over. Returns the seed the targets must
+    /// be tested in the order of.
+    pub fn open(path: &Path, config: &str, targets: &Targets) -> (Checkpoint, u128) {
+        let fresh = State {
+            config: config.to_string(),
+            targets: targets.fingerprint(),
+            seed: format!("{:x}", targets.seed()),
+            ..Default::default()
+        };
+        // 抽样时目标取决于顺序,按保存的顺序比较
+        let same = |state: &State| {
+            let seed = u128::from_str_radix(&state.seed, 16).ok();
+            state.config == config
+                && seed.map(|seed| targets.clone().with_seed(seed).fingerprint())
+                    == Some(state.targets.clone())
+        };
+        let state = match fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<State>(&text) {
+                Ok(state) if same(&state) => state,
+                _ => {
+                    println!("{}", trf(Msg::CheckpointMismatch, &[&path.display()]));
+                    fresh
+                }
+            },
+            Err(_) => fresh,
+        };
+        let seed = u128::from_str_radix(&state.seed, 16).unwrap_or(targets.seed());
+        let checkpoint = Checkpoint {
+            path: path.to_path_buf(),
+            state,
+            saved: Instant::now(),
+        };
+        (checkpoint, seed)
+    }
+
+    /// The index of the first target not tested yet and the tested ones after it
+    pub fn position(&self) -> (u64, BTreeSet<u64>) {
+        (self.state.next, self.state.done.clone())
+    }
+
+    /// Take the results saved by the interrupted run
+    pub fn take_results(&mut self) -> Vec<Delay> {
+        std::mem::take(&mut self.state.results)
+    }
+
+    /// Mark the `index`-th target tested, keeping `delay` if it passed the filters
+    pub fn record(&mut self, index: u64, delay: Option<&Delay>) {
+        if let Some(delay) = delay {
+            self.state.results.push(delay.clone());
+        }
+        if index != self.state.next {
+            self.state.done.insert(index);
+            return;
+        }
+        self.state.next += 1;
+        while self.state.done.remove(&self.state.next) {
+            self.state.next += 1;
+        }
+    }
+
+    /// Write the progress if it was last written long enough ago
+    pub fn save_if_due(&mut self) {
+        if self.saved.elapsed() < SAVE_INTERVAL {
+            return;
+        }
+        self.saved = Instant::now();
+        self.write();
+    }
+
+    /// The run stopped before it completed, write the progress to resume from
+    pub fn suspend(self) {
+        self.write();
+    }
+
+    fn write(&self) {
+        if let Err(error) = self.save() {
+            println!(
+                "{}",
+                trf(Msg::CannotSaveCheckpoint, &[&self.path.display(), &error])
+            );
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let text = serde_json::to_string(&self.state)?;
+        // 先写临时文件再改名,中断时不会留下写了一半的进度
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    /// The run completed, nothing is left to resume
+    pub fn finish(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(ip: &str) -> Delay {
+        Delay {
+            average_delay: Duration::from_millis(20),
+            success: 4,
+            ..Delay::failed(ip.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir =
+            std::env::temp_dir().join(format!("rustspeedtest-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        // 抽样的目标,不同的顺序下是不同的地址
+        let targets = Targets::parse("10.0.0.0/16", 0).with_limit(100);
+        let (mut checkpoint, seed) =
+            Checkpoint::open(&path, "config", &targets.clone().with_seed(42));
+        assert_eq!(seed, 42);
+        checkpoint.record(1, Some(&delay("1.1.1.1")));
+        checkpoint.record(0, None);
+        checkpoint.record(3, None);
+        assert_eq!(checkpoint.position(), (2, BTreeSet::from([3])));
+        checkpoint.save().unwrap();
+
+        // 同样的设置和目标从中断处继续,顺序不变
+        let (mut resumed, seed) = Checkpoint::open(&path, "config", &targets.clone().with_seed(7));
+        assert_eq!(seed, 42);
+        assert_eq!(resumed.position(), (2, BTreeSet::from([3])));
+        assert_eq!(resumed.take_results().len(), 1);
+        resumed.finish();
+        assert!(!path.exists());
+
+        // 设置不同时重新开始
+        checkpoint.save().unwrap();
+        let (other, seed) = Checkpoint::open(&path, "other", &targets.with_seed(7));
+        assert_eq!(seed, 7);
+        assert_eq!(other.position(), (0, BTreeSet::new()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::i18n::{tr, trf, Msg};
+use crate::input::Opts;
+use crate::output::Redaction;
+
+// 设置行的前缀
+const COMMENT_PREFIX: &str = "# rustspeedtest";
+
+/// The settings of a run that change its results. Written as a comment line
+/// to a file next to the result csv (see [`settings_path`]) so that runs
+/// measured under different settings are not compared as if they were alike.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunConfig {
+    settings: Vec<(String, String)>,
+}
+
+impl RunConfig {
+    pub fn from_opts(opts: &Opts) -> Self {
+        let stage = if opts.skip_ping {
+            "none"
+        } else if opts.cfhttping {
+            "cfhttping"
+        } else if opts.httping {
+            "httping"
+        } else if opts.udp {
+            "udping"
+        } else {
+            "tcping"
+        };
+
+        // '--redact' 时不写出能认出本机的设置
+        let local = |value: String| {
+            if opts.redact == Redaction::None || value.is_empty() {
+                value
+            } else {
+                "redacted".to_string()
+            }
+        };
+        let mut settings: Vec<(&str, String)> = vec![
+            ("stage", stage.to_string()),
+            ("port", opts.port.to_string()),
+            ("timeout", opts.timeout.to_string()),
+            ("time", opts.time.to_string()),
+            ("concurrency", opts.latency_concurrency().to_string()),
+            ("random_number", opts.random_number.to_string()),
+            ("sample_per_subnet", opts.sample_per_subnet.to_string()),
+            ("subnet_size", opts.subnet_size.to_string()),
+            ("au", opts.au.to_string()),
+            ("al", opts.al.to_string()),
+            ("interface", local(opts.interface.join(","))),
+            (
+                "fwmark",
+                local(opts.fwmark.map(|m| m.to_string()).unwrap_or_default()),
+            ),
+            ("netns", local(opts.netns.clone().unwrap_or_default())),
+            (
+                "source_ip",
+                opts.source_ip
+                    .iter()
+                    .map(|ip| opts.redact.apply(ip))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+        ];
+        match stage {
+            "tcping" => {
+                settings.push(("metric", opts.latency_metric.to_string()));
+                settings.push(("calibrate", opts.calibrate.to_string()));
+                settings.push(("probe_gap", opts.probe_gap.to_string()));
+                // 重试的失败不算丢包
+                if opts.retries > 0 {
+                    settings.push(("retries", opts.retries.to_string()));
+                }
+            }
+            "httping" => {
+                settings.push(("http_method", format!("{:?}", opts.http_method)));
+                settings.push(("http_path", opts.http_path.clone()));
+            }
+            "udping" => {
+                settings.push(("probe_size", opts.probe_size.to_string()));
+                settings.push(("probe_interval", opts.probe_interval.to_string()));
+            }
+            _ => {}
+        }
+        if let Some(max_jitter) = opts.max_jitter {
+            settings.push(("max_jitter", max_jitter.to_string()));
+        }
+        if let Some(max_ttfb) = opts.max_ttfb {
+            settings.push(("max_ttfb", max_ttfb.to_string()));
+        }
+        if let Some(max_loss) = opts.max_loss {
+            settings.push(("max_loss", max_loss.to_string()));
+        }
+        if let Some(margin) = opts.retest_margin {
+            settings.push(("retest_margin", margin.to_string()));
+        }
+        if let Some(ref filter) = opts.filter {
+            settings.push(("where", filter.to_string()));
+        }
+        if opts.top > 0 {
+            settings.push(("top", opts.top.to_string()));
+            settings.push((
+                "group_by",
+                opts.group_by.map(|g| g.to_string()).unwrap_or_default(),
+            ));
+        }
+        settings.push(("download", opts.enable_download.to_string()));
+        if opts.enable_download {
+            settings.push(("download_url", opts.download_url.clone()));
+            settings.push(("download_port", opts.download_port.to_string()));
+            settings.push(("download_timeout", opts.download_timeout.to_string()));
+            settings.push(("download_number", opts.download_number.to_string()));
+            // 并发测速时各 IP 分享带宽,速度不能和逐个测速的相比
+            if opts.download_concurrency > 1 {
+                settings.push(("download_concurrency", opts.download_concurrency.to_string()));
+            }
+        }
+
+        RunConfig {
+            settings: settings
+                .into_iter()
+                // 注释行以空格分隔,值里不能有空格
+                .map(|(key, value)| (key.to_string(), value.replace(' ', "%20")))
+                .collect(),
+        }
+    }
+
+    /// Short hash of all settings
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (key, value) in self.settings.iter() {
+            hasher.update(format!("{}={}\n", key, value).as_bytes());
+        }
+        hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// The comment line written next to a result csv
+    pub fn to_comment(&self) -> String {
+        let mut line = format!("{} config={}", COMMENT_PREFIX, self.fingerprint());
+        for (key, value) in self.settings.iter() {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+
+    /// Read back a line written by [`RunConfig::to_comment`]
+    pub fn parse_comment(line: &str) -> Option<RunConfig> {
+        let settings = line
+            .strip_prefix(COMMENT_PREFIX)?
+            .split_whitespace()
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(key, _)| *key != "config")
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Some(RunConfig { settings })
+    }
+
+    /// `key: before -> after` for every setting that differs from `other`
+    pub fn differences(&self, other: &RunConfig) -> Vec<String> {
+        let as_map = |config: &'_ RunConfig| -> HashMap<String, String> {
+            config.settings.iter().cloned().collect()
+        };
+        let (ours, theirs) = (as_map(self), as_map(other));
+
+        // 保持写入时的顺序,只在对方出现的设置排在最后
+        let mut keys: Vec<&str> = self.settings.iter().map(|(key, _)| key.as_str()).collect();
+        for (key, _) in other.settings.iter() {
+            if !ours.contains_key(key) {
+                keys.push(key);
+            }
+        }
+        keys.into_iter()
+            .filter(|&key| ours.get(key) != theirs.get(key))
+            .map(|key| {
+                format!(
+                    "{}: {} -> {}",
+                    key,
+                    ours.get(key).map_or("-", |v| v.as_str()),
+                    theirs.get(key).map_or("-", |v| v.as_str())
+                )
+            })
+            .collect()
+    }
+}
+
+/// The file next to the result csv `output` that holds its settings line, so
+/// that the csv itself stays plain for spreadsheets and other csv readers
+pub fn settings_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".meta");
+    PathBuf::from(path)
+}
+
+/// One IP of a result csv
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub ip: String,
+    pub delay_ms: Option<f64>,
+    pub speed: Option<f64>,
+}
+
+/// A result csv written by an earlier run
+#[derive(Debug)]
+pub struct ResultFile {
+    pub config: Option<RunConfig>,
+    pub rows: Vec<Row>,
+}
+
+impl ResultFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ResultFile> {
+        let path = path.as_ref();
+        let mut file = ResultFile::parse(&fs::read_to_string(path)?);
+        // 设置在旁边的文件里,之前的版本写在 csv 的第一行
+        if file.config.is_none() {
+            file.config = fs::read_to_string(settings_path(path))
+                .ok()
+                .and_then(|text| RunConfig::parse_comment(text.trim_end()));
+        }
+        Ok(file)
+    }
+
+    pub fn parse(text: &str) -> ResultFile {
+        let mut lines = text.lines().peekable();
+        // 设置写在旁边的文件里或者更旧的版本没有写设置时,第一行就是标题
+        let config = lines.peek().and_then(|line| RunConfig::parse_comment(line));
+        if config.is_some() {
+            lines.next();
+        }
+
+        let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+        let column = |name: &str| header.iter().position(|c| *c == name);
+        let (delay, speed) = (column("Delay(ms)"), column("Speed(MB/s)"));
+
+        let rows = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                let number = |i: Option<usize>| i.and_then(|i| fields.get(i)?.parse().ok());
+                Row {
+                    ip: fields[0].to_string(),
+                    delay_ms: number(delay),
+                    speed: number(speed),
+                }
+            })
+            .collect();
+
+        ResultFile { config, rows }
+    }
+}
+
+/// Warn when `before` and `after` were measured under different settings, then
+/// print the delay and speed of every IP found in both, masked by `redaction`
+pub fn display(before: &ResultFile, after: &ResultFile, redaction: Redaction) {
+    match (&before.config, &after.config) {
+        (Some(a), Some(b)) if a != b => {
+            println!(
+                "{}",
+                trf(Msg::ConfigMismatch, &[&a.fingerprint(), &b.fingerprint()])
+            );
+            for difference in a.differences(b) {
+                println!("    {}", difference);
+            }
+            println!();
+        }
+        (Some(_), Some(_)) => {}
+        _ => println!("{}\n", tr(Msg::NoConfigFingerprint)),
+    }
+
+    let after_rows: HashMap<&str, &Row> = after.rows.iter().map(|r| (r.ip.as_str(), r)).collect();
+    let show = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+
+    println!(
+        "{:<16} {:<14} {:<14} {:<14} {:<14}",
+        tr(Msg::IpAddress),
+        tr(Msg::DelayBefore),
+        tr(Msg::DelayAfter),
+        tr(Msg::SpeedBefore),
+        tr(Msg::SpeedAfter),
+    );
+    for row in before.rows.iter() {
+        if let Some(other) = after_rows.get(row.ip.as_str()) {
+            println!(
+                "{:<16} {:<14} {:<14} {:<14} {:<14}",
+                redaction.apply_text(&row.ip),
+                show(row.delay_ms),
+                show(other.delay_ms),
+                show(row.speed),
+                show(other.speed),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_roundtrip() {
+        let config = RunConfig::from_opts(&Opts::default());
+        let comment = config.to_comment();
+        assert!(comment.starts_with("# rustspeedtest config="));
+        assert_eq!(RunConfig::parse_comment(&comment), Some(config.clone()));
+
+        let other = RunConfig::from_opts(&Opts {
+            timeout: 1000,
+            ..Default::default()
+        });
+        assert_ne!(config.fingerprint(), other.fingerprint());
+        assert_eq!(config.differences(&other), vec!["timeout: 9999 -> 1000"]);
+
+        // '--redact' 时源地址和网卡不写出
+        let redacted = RunConfig::from_opts(&Opts {
+            redact: Redaction::LastOctet,
+            source_ip: vec!["192.168.1.20".parse().unwrap()],
+            interface: vec!["eth0".to_string()],
+            ..Default::default()
+        })
+        .to_comment();
+        assert!(redacted.contains(" source_ip=192.168.1.x "));
+        assert!(redacted.contains(" interface=redacted "));
+        assert!(!redacted.contains("eth0"));
+    }
+
+    #[test]
+    fn test_parse_result_file() {
+        let config = RunConfig::from_opts(&Opts::default());
+        let text = format!(
+            "{}\nIP,Loss,Delay(ms),Speed(MB/s)\n1.1.1.1,0.0,12,3.50\n1.0.0.1,1.0,0\n",
+            config.to_comment()
+        );
+        let file = ResultFile::parse(&text);
+        assert_eq!(file.config, Some(config));
+        assert_eq!(file.rows.len(), 2);
+        assert_eq!(file.rows[0].delay_ms, Some(12.0));
+        assert_eq!(file.rows[0].speed, Some(3.5));
+        assert_eq!(file.rows[1].speed, None);
+
+        // 没有设置注释的旧文件
+        let file = ResultFile::parse("IP,Loss,Delay(ms)\n1.1.1.1,0.0,12\n");
+        assert!(file.config.is_none());
+        assert_eq!(file.rows[0].ip, "1.1.1.1");
+    }
+
+    #[test]
+    fn test_load_settings_file() {
+        let config = RunConfig::from_opts(&Opts::default());
+        let path = std::env::temp_dir()
+            .join(format!("rustspeedtest-compare-test-{}.csv", std::process::id()));
+        assert_eq!(
+            settings_path(&path).file_name().unwrap().to_str().unwrap(),
+            format!("rustspeedtest-compare-test-{}.csv.meta", std::process::id())
+        );
+        fs::write(&path, "IP,Loss,Delay(ms)\n1.1.1.1,0.0,12\n").unwrap();
+        assert!(ResultFile::load(&path).unwrap().config.is_none());
+
+        fs::write(settings_path(&path), config.to_comment() + "\n").unwrap();
+        let file = ResultFile::load(&path).unwrap();
+        assert_eq!(file.config, Some(config));
+        assert_eq!(file.rows[0].delay_ms, Some(12.0));
+        let _ = fs::remove_file(settings_path(&path));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+use crate::i18n::{trf, Msg};
+
+/// Gives up on a subnet once the first few IPs probed in it all failed, so
+/// that a scan does not wait for the timeout of every address of space that
+/// is not announced at all.
+///
+/// The default value never skips.
+#[derive(Clone, Default)]
+pub struct DeadSubnets {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    // 连续这么多个 IP 失败就放弃该子网
+    after: usize,
+    // IPv4 前缀长度,IPv6 多 24 位
+    prefix: u8,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    subnets: HashMap<IpAddr, Subnet>,
+    skipped: usize,
+}
+
+#[derive(Default)]
+struct Subnet {
+    failed: usize,
+    alive: bool,
+}
+
+impl fmt::Debug for DeadSubnets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadSubnets")
+            .field("after", &self.inner.as_ref().map(|inner| inner.after))
+            .finish()
+    }
+}
+
+impl DeadSubnets {
+    /// Skip the rest of an IPv4 /`prefix` (IPv6 /`prefix`+24) once its
+    /// first `after` IPs failed. Never skips if `after` is 0.
+    pub fn new(after: usize, prefix: u8) -> Self {
+        if after == 0 {
+            return DeadSubnets::default();
+        }
+        DeadSubnets {
+            inner: Some(Arc::new(Inner {
+                after,
+                prefix,
+                state: Mutex::new(State::default()),
+            })),
+        }
+    }
+
+    /// Record whether any probe to `ip` succeeded
+    pub fn record(&self, ip: IpAddr, alive: bool) {
+        let Some(ref inner) = self.inner else {
+            return;
+        };
+        let mut state = inner.state.lock().expect("subnet lock");
+        let subnet = state.subnets.entry(subnet(ip, inner.prefix)).or_default();
+        if alive {
+            subnet.alive = true;
+        } else {
+            subnet.failed += 1;
+        }
+    }
+
+    /// Whether `ip` is skipped because its subnet looks dead
+    pub fn skips(&self, ip: IpAddr) -> bool {
+        let Some(ref inner) = self.inner else {
+            return false;
+        };
+        let mut state = inner.state.lock().expect("subnet lock");
+        let dead = state
+            .subnets
+            .get(&subnet(ip, inner.prefix))
+            .is_some_and(|subnet| !subnet.alive && subnet.failed >= inner.after);
+        state.skipped += dead as usize;
+        dead
+    }
+
+    /// Print how many IPs were skipped, if any
+    pub fn report(&self) {
+        let Some(ref inner) = self.inner else {
+            return;
+        };
+        let state = inner.state.lock().expect("subnet lock");
+        if state.skipped == 0 {
+            return;
+        }
+        let dead = state
+            .subnets
+            .values()
+            .filter(|subnet| !subnet.alive && subnet.failed >= inner.after)
+            .count();
+        println!(
+            "{}",
+            trf(
+                Msg::SkippedDeadSubnets,
+                &[&state.skipped, &dead, &inner.after]
+            )
+        );
+    }
+}
+
+/// The subnet `ip` belongs to, as its first address
+fn subnet(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let bits = (prefix as u32 + 24).min(128);
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_subnets() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let disabled = DeadSubnets::new(0, 24);
+        disabled.record(ip("10.0.0.1"), false);
+        assert!(!disabled.skips(ip("10.0.0.2")));
+
+        let dead = DeadSubnets::new(2, 24);
+        dead.record(ip("10.0.0.1"), false);
+        assert!(!dead.skips(ip("10.0.0.3")));
+        dead.record(ip("10.0.0.2"), false);
+        assert!(dead.skips(ip("10.0.0.3")));
+        // 其他子网不受影响
+        assert!(!dead.skips(ip("10.0.1.3")));
+
+        // 有一个 IP 回应的子网不放弃
+        dead.record(ip("10.0.1.1"), false);
+        dead.record(ip("10.0.1.2"), true);
+        dead.record(ip("10.0.1.3"), false);
+        assert!(!dead.skips(ip("10.0.1.4")));
+
+        assert_eq!(subnet(ip("2606:4700:1:2::1"), 24), ip("2606:4700:1::"));
+        assert_eq!(subnet(ip("10.1.2.3"), 16), ip("10.1.0.0"));
+        assert_eq!(
+            dead.inner.as_ref().unwrap().state.lock().unwrap().skipped,
+            1
+        );
+    }
+}
@@ -0,0 +1,296 @@
+// 没有下载功能时只剩 --dns 的解析,用不到查询部分
+#![cfg_attr(not(feature = "download"), allow(dead_code))]
+// 只需要经 UDP 查询 A 和 AAAA 记录,没有用 trust-dns,它会给静态构建带来十几个依赖
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+/// Default of --dns. Static builds use the built-in resolver so that no libc
+/// resolver (or glibc NSS module) is needed at runtime.
+#[cfg(feature = "builtin-dns")]
+pub const DEFAULT_RESOLVER: &str = "1.1.1.1";
+#[cfg(not(feature = "builtin-dns"))]
+pub const DEFAULT_RESOLVER: &str = "system";
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Where host names are resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsResolver {
+    /// getaddrinfo of the C library
+    System,
+    /// The built-in resolver, querying this server over UDP
+    Server(SocketAddr),
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        DEFAULT_RESOLVER.parse().unwrap()
+    }
+}
+
+impl FromStr for DnsResolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(DnsResolver::System);
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(DnsResolver::Server(SocketAddr::new(ip, 53)));
+        }
+        s.parse::<SocketAddr>()
+            .map(DnsResolver::Server)
+            .map_err(|_| format!("invalid dns resolver: {} (expected system|IP[:PORT])", s))
+    }
+}
+
+impl fmt::Display for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsResolver::System => write!(f, "system"),
+            DnsResolver::Server(server) => write!(f, "{}", server),
+        }
+    }
+}
+
+/// Resolve the A and AAAA records of `name` by asking `server`
+pub async fn lookup(server: SocketAddr, name: &str, timeout: Duration) -> io::Result<Vec<IpAddr>> {
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+
+    let mut ips = Vec::new();
+    let mut error = None;
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        match query(&socket, name, qtype, timeout).await {
+            Ok(answers) => ips.extend(answers),
+            // 只有一种地址查询失败时返回另一种的结果
+            Err(e) => error = Some(e),
+        }
+    }
+
+    match error {
+        Some(error) if ips.is_empty() => Err(error),
+        _ if ips.is_empty() => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address found for {}", name),
+        )),
+        _ => Ok(ips),
+    }
+}
+
+/// The records of type `qtype` of `name`, asked on the connected `socket`
+async fn query(
+    socket: &UdpSocket,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> io::Result<Vec<IpAddr>> {
+    let id = rand::random::<u16>();
+    socket.send(&build_query(id, name, qtype)?).await?;
+
+    let mut buf = [0u8; 1500];
+    // 丢弃不属于本次查询的迟到回应
+    tokio::time::timeout(timeout, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if let Some(answers) = parse_response(id, &buf[..len]) {
+                return answers;
+            }
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "dns query timed out"))?
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(18 + name.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // RD=1,一个问题
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host name: {}", name),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// The addresses in a response to query `id`; None if the packet answers something else
+fn parse_response(id: u16, packet: &[u8]) -> Option<io::Result<Vec<IpAddr>>> {
+    if packet.len() < 12
+        || u16::from_be_bytes([packet[0], packet[1]]) != id
+        || packet[2] & 0x80 == 0
+    {
+        return None;
+    }
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed dns response");
+
+    match packet[3] & 0x0f {
+        0 => {}
+        // NXDOMAIN 当作没有记录
+        3 => return Some(Ok(Vec::new())),
+        rcode => {
+            return Some(Err(io::Error::other(format!(
+                "dns server answered with rcode {}",
+                rcode
+            ))))
+        }
+    }
+
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = match skip_name(packet, pos) {
+            Some(pos) => pos + 4,
+            None => return Some(Err(malformed())),
+        };
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        let Some(start) = skip_name(packet, pos) else {
+            return Some(Err(malformed()));
+        };
+        let Some(header) = packet.get(start..start + 10) else {
+            return Some(Err(malformed()));
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let Some(data) = packet.get(start + 10..start + 10 + len) else {
+            return Some(Err(malformed()));
+        };
+        // CNAME 等其他记录直接跳过
+        match (rtype, len) {
+            (TYPE_A, 4) => ips.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, 16) => ips.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => {}
+        }
+        pos = start + 10 + len;
+    }
+    Some(Ok(ips))
+}
+
+/// Position right after the (possibly compressed) name at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // 压缩指针占两个字节,并且总是名字的结尾
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+/// reqwest resolver backed by [`lookup`]
+#[cfg(feature = "download")]
+pub struct ServerResolver {
+    pub server: SocketAddr,
+    pub timeout: Duration,
+}
+
+#[cfg(feature = "download")]
+impl reqwest::dns::Resolve for ServerResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let (server, timeout) = (self.server, self.timeout);
+        Box::pin(async move {
+            let ips = lookup(server, name.as_str(), timeout).await?;
+            // 端口由 reqwest 按 url 重新设置
+            let addrs: reqwest::dns::Addrs =
+                Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolver() {
+        assert_eq!("system".parse(), Ok(DnsResolver::System));
+        assert_eq!(
+            "1.1.1.1".parse(),
+            Ok(DnsResolver::Server("1.1.1.1:53".parse().unwrap()))
+        );
+        assert_eq!(
+            "[2606:4700:4700::1111]:5353".parse(),
+            Ok(DnsResolver::Server(
+                "[2606:4700:4700::1111]:5353".parse().unwrap()
+            ))
+        );
+        assert!("dns.google".parse::<DnsResolver>().is_err());
+    }
+
+    /// A server with one A record for every name, answering AAAA queries
+    /// with no records or with `aaaa_rcode`
+    async fn serve_ipv4_only(aaaa_rcode: u8) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = server.recv_from(&mut buf).await {
+                let query = &buf[..len];
+                let qtype = u16::from_be_bytes([query[len - 4], query[len - 3]]);
+                // 复制问题,再用压缩指针指向问题中的名字回答
+                let mut reply = query.to_vec();
+                reply[2] |= 0x80;
+                if qtype == TYPE_A {
+                    reply[7] = 1;
+                    reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    reply.extend_from_slice(&[104, 16, 0, 1]);
+                } else {
+                    reply[3] |= aaaa_rcode;
+                }
+                server.send_to(&reply, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_lookup_local_server() {
+        let addr = serve_ipv4_only(0).await;
+        let ips = lookup(addr, "speed.cloudflare.com", Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(ips, vec!["104.16.0.1".parse::<IpAddr>().unwrap()]);
+
+        // AAAA 查询失败(SERVFAIL)时仍返回 A 记录
+        let addr = serve_ipv4_only(2).await;
+        let ips = lookup(addr, "speed.cloudflare.com", Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(ips, vec!["104.16.0.1".parse::<IpAddr>().unwrap()]);
+
+        assert!(build_query(1, "bad..name", TYPE_A).is_err());
+    }
+}
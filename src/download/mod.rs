@@ -1,12 +1,26 @@
+#[cfg(feature = "download")]
+use futures::{stream, StreamExt};
+#[cfg(feature = "download")]
 use reqwest::{Client, ClientBuilder, Url};
+#[cfg(feature = "download")]
+use serde_json::json;
+
+#[cfg(feature = "download")]
+use crate::i18n::{tr, Msg};
+use crate::dns::DnsResolver;
+#[cfg(feature = "download")]
+use crate::dns::ServerResolver;
+use crate::progress::ProgressEvents;
+#[cfg(feature = "download")]
+use std::{io::Error, net::SocketAddr, sync::Arc, time::Instant};
 use std::{
     cmp::Ordering,
     fmt::{self},
-    io::{Error, ErrorKind},
-    net::{IpAddr, SocketAddr},
-    time::{Duration, Instant}, collections::HashMap,
+    net::IpAddr,
+    time::Duration, collections::HashMap,
 };
 
+#[cfg(feature = "download")]
 pub struct Downloader {
     ips: Vec<IpAddr>,
     tries: u8,
@@ -16,9 +30,14 @@ pub struct Downloader {
     port: u16,
     url: String,
     min_available: usize, // 最小可用数
+    dns: DnsResolver, // 重定向等目标的域名解析方式
+    events: ProgressEvents, // 进度事件
+    concurrency: usize, // 同时测速的 IP 数
 }
 
+#[cfg(feature = "download")]
 impl Downloader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         ips: Vec<IpAddr>,
         tries: u8,
@@ -38,39 +57,91 @@ impl Downloader {
             port,
             url,
             min_available,
+            dns: DnsResolver::System,
+            events: ProgressEvents::default(),
+            concurrency: 1,
         }
     }
 
+    /// Resolve host names other than the pinned download host with `dns`
+    pub fn with_dns(mut self, dns: DnsResolver) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Measure up to `concurrency` IPs at once instead of one after another
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
     pub async fn run(&self) -> Vec<Speed> {
         let mut speeds = Vec::new();
         if self.ips.is_empty() {
-            println!("No measureable IP addresss");
+            println!("{}", tr(Msg::NoMeasurableIp));
             return speeds;
         }
 
-        let mut available_count:usize = 0; // 已可用数
-
         let socket_addrs = self.ips.iter().map(|ip| SocketAddr::new(*ip, self.port));
         let url = self
             .create_url()
             .unwrap_or_else(|_| panic!("Cannot parse url: {}", self.url));
 
-        for socket_addr in socket_addrs {
-            for _ in 1..=self.tries {
-                if let Ok(speed) = self.measure_download_speed(socket_addr, url.clone()).await {
-                    speeds.push(speed);
-                    available_count += 1;
-                    if available_count >= self.min_available { // 判断是否已经满足“最小可用数”的要求
-                        return speeds;
-                    }
-                    break;
-                }
+        self.events.stage_start("download", self.ips.len());
+        // 按延迟顺序取结果,并发为 1 时与逐个测速相同
+        let mut results = stream::iter(socket_addrs)
+            .map(|socket_addr| self.measure(socket_addr, url.clone()))
+            .buffered(self.concurrency);
+        while let Some(speed) = results.next().await {
+            let Some(speed) = speed else {
+                continue;
+            };
+            speeds.push(speed);
+            // 判断是否已经满足“最小可用数”的要求,丢弃还在进行的测速
+            if speeds.len() >= self.min_available {
+                break;
             }
         }
+        self.events.stage_end("download", speeds.len());
 
         speeds
     }
 
+    /// Measure one IP, trying up to `tries` times
+    async fn measure(&self, socket_addr: SocketAddr, url: Url) -> Option<Speed> {
+        for _ in 1..=self.tries {
+            let speed = match self.measure_download_speed(socket_addr, url.clone()).await {
+                Ok(speed) => speed,
+                Err(error) => {
+                    self.events
+                        .error("download", socket_addr.ip(), &error.to_string());
+                    continue;
+                }
+            };
+            self.events.trace("download", speed.ip, || {
+                format!(
+                    "downloaded {} bytes from {} in {:?}",
+                    speed.total_download, url, speed.consume
+                )
+            });
+            self.events.result(
+                "download",
+                speed.ip,
+                true,
+                json!({"bytes": speed.total_download, "secs": speed.consume.as_secs_f64()}),
+            );
+            return Some(speed);
+        }
+        self.events.result("download", socket_addr.ip(), false, json!({}));
+        None
+    }
+
     pub async fn measure_download_speed(
         &self,
         addr: SocketAddr,
@@ -89,13 +160,20 @@ impl Downloader {
 
     #[inline]
     fn create_client(&self) -> ClientBuilder {
-        reqwest::Client::builder()
+        let builder = reqwest::Client::builder()
             .no_proxy()
             .timeout(self.timeout)
             .connect_timeout(self.connect_timeout)
-            .redirect(reqwest::redirect::Policy::limited(10))
+            .redirect(reqwest::redirect::Policy::limited(10));
         // .resolve(&self.host, addr)
         // .build()
+        match self.dns {
+            DnsResolver::System => builder,
+            DnsResolver::Server(server) => builder.dns_resolver(Arc::new(ServerResolver {
+                server,
+                timeout: self.connect_timeout,
+            })),
+        }
     }
 
     #[inline]
@@ -142,14 +220,51 @@ impl Downloader {
                 consume: elapsed_time,
             })
         } else {
-            Err(Box::new(Error::new(
-                ErrorKind::Other,
-                format!("Download failed: {:?}", response),
-            )))
+            Err(Box::new(Error::other(format!(
+                "Download failed: {:?}",
+                response
+            ))))
         }
     }
 }
 
+/// Stand-in for builds without the `download` feature: measures nothing
+#[cfg(not(feature = "download"))]
+pub struct Downloader;
+
+#[cfg(not(feature = "download"))]
+impl Downloader {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        _ips: Vec<IpAddr>,
+        _tries: u8,
+        _host: String,
+        _timeout: Duration,
+        _connect_timeout: Duration,
+        _port: u16,
+        _url: String,
+        _min_available: usize,
+    ) -> Self {
+        Downloader
+    }
+
+    pub fn with_dns(self, _dns: DnsResolver) -> Self {
+        self
+    }
+
+    pub fn with_events(self, _events: ProgressEvents) -> Self {
+        self
+    }
+
+    pub fn with_concurrency(self, _concurrency: usize) -> Self {
+        self
+    }
+
+    pub async fn run(&self) -> Vec<Speed> {
+        Vec::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct Speed {
     pub ip: IpAddr,
@@ -169,7 +284,10 @@ impl Speed {
 
 impl Ord for Speed {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.total_download.cmp(&self.total_download)
+        other
+            .total_download
+            .cmp(&self.total_download)
+            .then(self.ip.cmp(&other.ip))
     }
 }
 
@@ -206,7 +324,7 @@ impl fmt::Display for Speed {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "download"))]
 mod tests {
     use super::*;
     use std::time::Duration;
@@ -222,10 +340,49 @@ mod tests {
             port: 80,
             url: "https://www.example.com/test".to_string(),
             min_available:1,
+            dns: DnsResolver::System,
+            events: ProgressEvents::default(),
+            concurrency: 1,
         };
 
         let url = downloader.create_url();
         assert!(url.is_ok());
         assert_eq!(url.unwrap().as_str(), "https://www.example.com/test");
     }
+
+    #[tokio::test]
+    async fn test_run_concurrently() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = [0u8; 1024];
+                    let _ = stream.read(&mut request).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+                        .await;
+                });
+            }
+        });
+
+        let ips = vec!["127.0.0.1".parse().unwrap(); 4];
+        let downloader = Downloader::new(
+            ips,
+            1,
+            "localhost".to_string(),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            port,
+            format!("http://localhost:{}/", port),
+            3,
+        )
+        .with_concurrency(2);
+        let speeds = downloader.run().await;
+        // 满足最小可用数后停止
+        assert_eq!(speeds.len(), 3);
+        assert!(speeds.iter().all(|speed| speed.total_download == 5));
+    }
 }
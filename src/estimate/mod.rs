@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+/// The longest a latency stage over `targets` can take: every sample waits
+/// out the full `timeout`, with `gap` between the samples of a target, and
+/// `concurrency` targets are tested at a time
+pub fn latency_stage(
+    targets: usize,
+    concurrency: usize,
+    times: u32,
+    timeout: Duration,
+    gap: Duration,
+) -> Duration {
+    let times = times.max(1);
+    let waves = u32::try_from(targets.div_ceil(concurrency.max(1))).ok();
+    timeout
+        .checked_mul(times)
+        .zip(gap.checked_mul(times - 1))
+        .and_then(|(samples, gaps)| samples.checked_add(gaps))
+        .zip(waves)
+        .and_then(|(per_target, waves)| per_target.checked_mul(waves))
+        .unwrap_or(Duration::MAX)
+}
+
+/// About how long the download test takes: the IPs are tested one after
+/// another, each for up to `timeout`
+pub fn download_stage(ips: usize, timeout: Duration) -> Duration {
+    u32::try_from(ips)
+        .ok()
+        .and_then(|ips| timeout.checked_mul(ips))
+        .unwrap_or(Duration::MAX)
+}
+
+/// The time left for a stage at the rate so far, `None` before the first result
+pub fn remaining(done: usize, total: usize, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let left = total.saturating_sub(done) as f64 / done as f64;
+    Some(elapsed.mul_f64(left))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimates() {
+        let secs = Duration::from_secs;
+        // 3 轮,每个目标 4 次 1 秒超时加 3 次 1 秒间隔
+        assert_eq!(latency_stage(500, 200, 4, secs(1), secs(1)), secs(21));
+        assert_eq!(latency_stage(0, 200, 4, secs(1), Duration::ZERO), secs(0));
+        assert_eq!(latency_stage(1, 0, 0, secs(1), secs(1)), secs(1));
+        assert_eq!(
+            latency_stage(usize::MAX, 1, 4, secs(1), Duration::ZERO),
+            Duration::MAX
+        );
+        assert_eq!(download_stage(10, secs(5)), secs(50));
+
+        assert_eq!(remaining(0, 10, secs(5)), None);
+        assert_eq!(remaining(5, 20, secs(10)), Some(secs(30)));
+        assert_eq!(remaining(20, 20, secs(10)), Some(secs(0)));
+    }
+}
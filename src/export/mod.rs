@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::filter::Group;
+use crate::history::Measurement;
+use crate::output::Redaction;
+use crate::targets::Targets;
+
+// nginx upstream 块和前缀列表的名字
+const UPSTREAM_NAME: &str = "rustspeedtest";
+// 速度最快的 IP 的权重,其他按速度比例缩小
+const MAX_WEIGHT: f64 = 10.0;
+
+/// How `--export` writes the IPs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// one `--export-template` line per IP
+    #[default]
+    Template,
+    /// an nginx `upstream` block
+    Nginx,
+    /// HAProxy `server` lines
+    Haproxy,
+    /// BIRD static routes, or a prefix set without a next hop
+    Bird,
+    /// FRR static routes, or a prefix list without a next hop
+    Frr,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "template" => Ok(Format::Template),
+            "nginx" => Ok(Format::Nginx),
+            "haproxy" => Ok(Format::Haproxy),
+            "bird" => Ok(Format::Bird),
+            "frr" => Ok(Format::Frr),
+            _ => Err(format!(
+                "unknown export format: {} (expected template|nginx|haproxy|bird|frr)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Template => write!(f, "template"),
+            Format::Nginx => write!(f, "nginx"),
+            Format::Haproxy => write!(f, "haproxy"),
+            Format::Bird => write!(f, "bird"),
+            Format::Frr => write!(f, "frr"),
+        }
+    }
+}
+
+/// How one exported IP is written, e.g. `server {addr};` for an nginx
+/// upstream. Placeholders are `{ip}`, `{port}`, `{addr}` (`ip:port`, IPv6 in
+/// brackets), `{index}` (from 1), `{colo}`, `{delay}` (ms) and `{speed}`
+/// (MB/s). Unmeasured values are left empty. `\n` starts a new line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Template {
+    fn render(
+        &self,
+        index: usize,
+        measurement: &Measurement,
+        port: u16,
+        redaction: Redaction,
+    ) -> String {
+        let number = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+        let addr = match redaction {
+            Redaction::None => SocketAddr::new(measurement.ip, port).to_string(),
+            // 打码后不再是合法地址,直接拼接
+            _ => format!("{}:{}", redaction.apply(&measurement.ip), port),
+        };
+        self.0
+            .replace("\\n", "\n")
+            .replace("{ip}", &redaction.apply(&measurement.ip))
+            .replace("{port}", &port.to_string())
+            .replace("{addr}", &addr)
+            .replace("{index}", &index.to_string())
+            .replace("{colo}", measurement.colo.as_deref().unwrap_or_default())
+            .replace("{delay}", &number(measurement.delay_ms))
+            .replace("{speed}", &number(measurement.speed_mbps))
+    }
+}
+
+/// 1 to 10 in proportion to the speed of `measurement` against the fastest
+/// one. 1 if speed was not tested.
+fn weight(measurement: &Measurement, fastest: Option<f64>) -> u32 {
+    match (measurement.speed_mbps, fastest) {
+        (Some(speed), Some(fastest)) if fastest > 0.0 => {
+            (MAX_WEIGHT * speed / fastest).round().max(1.0) as u32
+        }
+        _ => 1,
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template("{ip}".to_string())
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains("{ip}") && !s.contains("{addr}") {
+            return Err(format!("export template '{}' has no {{ip}} or {{addr}}", s));
+        }
+        Ok(Template(s.to_string()))
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// `ranked`, which is ordered best first, interleaved so that consecutive
+/// entries come from different groups: the best of every group, then the
+/// second best of every group and so on. Groups keep the order of their best IP.
+pub fn round_robin(ranked: &[Measurement], group: Group) -> Vec<&Measurement> {
+    let mut order: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, Vec<&Measurement>> = HashMap::new();
+    for measurement in ranked.iter() {
+        let key = group.key(measurement);
+        if !groups.contains_key(&key) {
+            order.push(key.clone());
+        }
+        groups.entry(key).or_default().push(measurement);
+    }
+
+    let mut interleaved = Vec::with_capacity(ranked.len());
+    for round in 0.. {
+        let before = interleaved.len();
+        interleaved.extend(
+            order
+                .iter()
+                .filter_map(|key| groups[key].get(round).copied()),
+        );
+        if interleaved.len() == before {
+            break;
+        }
+    }
+    interleaved
+}
+
+/// Writes the selected IPs for `--export`
+#[derive(Debug, Clone, Default)]
+pub struct Exporter {
+    pub format: Format,
+    /// Only used by [`Format::Template`]
+    pub template: Template,
+    pub port: u16,
+    pub redaction: Redaction,
+    /// The IPv4 prefix length routes are aggregated from, 24 bits more for IPv6
+    pub prefix: u8,
+    /// The next hop (gateway IP or interface) of exported routes
+    pub via: Option<String>,
+}
+
+impl Exporter {
+    /// The measurements in the chosen format. Nginx and HAProxy servers are
+    /// weighted by their download speed.
+    pub fn render(&self, measurements: &[&Measurement]) -> String {
+        match self.format {
+            Format::Template | Format::Nginx | Format::Haproxy => self.servers(measurements),
+            Format::Bird | Format::Frr => self.routes(measurements),
+        }
+    }
+
+    fn servers(&self, measurements: &[&Measurement]) -> String {
+        let fastest = measurements
+            .iter()
+            .filter_map(|m| m.speed_mbps)
+            .reduce(f64::max);
+        let nginx = Template("    server {addr} weight={weight};".to_string());
+        let haproxy = Template("    server node{index} {addr} check weight {weight}".to_string());
+        let line = match self.format {
+            Format::Nginx => &nginx,
+            Format::Haproxy => &haproxy,
+            _ => &self.template,
+        };
+
+        let mut text = String::new();
+        if self.format == Format::Nginx {
+            text.push_str(&format!("upstream {} {{\n", UPSTREAM_NAME));
+        }
+        for (i, measurement) in measurements.iter().enumerate() {
+            let weight = weight(measurement, fastest).to_string();
+            text.push_str(
+                &line
+                    .render(i + 1, measurement, self.port, self.redaction)
+                    .replace("{weight}", &weight),
+            );
+            text.push('\n');
+        }
+        if self.format == Format::Nginx {
+            text.push_str("}\n");
+        }
+        text
+    }
+
+    /// The subnets of the measured IPs, merged into the fewest CIDRs, as
+    /// static routes via `via` or else as a prefix list
+    fn routes(&self, measurements: &[&Measurement]) -> String {
+        // 打码时路由不能比打码后的地址更细
+        let (v4, v6) = match self.redaction {
+            Redaction::None => (32, 128),
+            Redaction::LastOctet => (24, 64),
+        };
+        let subnets: String = measurements
+            .iter()
+            .map(|m| match m.ip {
+                IpAddr::V4(_) => format!("{}/{}\n", m.ip, self.prefix.min(v4)),
+                IpAddr::V6(_) => format!("{}/{}\n", m.ip, (self.prefix + 24).min(v6)),
+            })
+            .collect();
+        let cidrs = Targets::parse_exact(&subnets).cidrs();
+        let v6 = |cidr: &String| cidr.contains(':');
+
+        let mut text = String::new();
+        match (self.format, self.via.as_deref()) {
+            (Format::Bird, Some(via)) => {
+                // 网关写成地址,接口名要加引号
+                let via = match via.parse::<IpAddr>() {
+                    Ok(gateway) => gateway.to_string(),
+                    Err(_) => format!("\"{}\"", via),
+                };
+                for cidr in cidrs.iter() {
+                    text.push_str(&format!("route {} via {};\n", cidr, via));
+                }
+            }
+            (Format::Bird, None) => {
+                for (suffix, family) in [("v4", false), ("v6", true)] {
+                    let set: Vec<&str> = cidrs
+                        .iter()
+                        .filter(|c| v6(c) == family)
+                        .map(String::as_str)
+                        .collect();
+                    if !set.is_empty() {
+                        text.push_str(&format!(
+                            "define {}_{} = [ {} ];\n",
+                            UPSTREAM_NAME,
+                            suffix,
+                            set.join(", ")
+                        ));
+                    }
+                }
+            }
+            (_, Some(via)) => {
+                for cidr in cidrs.iter() {
+                    let ip = if v6(cidr) { "ipv6" } else { "ip" };
+                    text.push_str(&format!("{} route {} {}\n", ip, cidr, via));
+                }
+            }
+            (_, None) => {
+                for (i, cidr) in cidrs.iter().enumerate() {
+                    let ip = if v6(cidr) { "ipv6" } else { "ip" };
+                    text.push_str(&format!(
+                        "{} prefix-list {} seq {} permit {}\n",
+                        ip,
+                        UPSTREAM_NAME,
+                        (i + 1) * 5,
+                        cidr
+                    ));
+                }
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(ip: &str, colo: &str) -> Measurement {
+        Measurement {
+            ip: ip.parse().unwrap(),
+            colo: Some(colo.to_string()),
+            delay_ms: Some(12.0),
+            loss: Some(0.0),
+            speed_mbps: None,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let ranked = vec![
+            measurement("1.0.0.1", "SJC"),
+            measurement("1.0.0.2", "SJC"),
+            measurement("1.0.0.3", "LAX"),
+            measurement("1.0.0.4", "SJC"),
+            measurement("1.0.0.5", "NRT"),
+        ];
+        let ips: Vec<String> = round_robin(&ranked, Group::Colo)
+            .iter()
+            .map(|m| m.ip.to_string())
+            .collect();
+        assert_eq!(
+            ips,
+            vec!["1.0.0.1", "1.0.0.3", "1.0.0.5", "1.0.0.2", "1.0.0.4"]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let v4 = measurement("1.0.0.1", "SJC");
+        let v6 = measurement("2606:4700::1", "LAX");
+        let exporter = Exporter {
+            template: "server {addr}; # {index} {colo} {delay}ms {speed}"
+                .parse()
+                .unwrap(),
+            port: 443,
+            ..Default::default()
+        };
+        assert_eq!(
+            exporter.render(&[&v4, &v6]),
+            "server 1.0.0.1:443; # 1 SJC 12.00ms \nserver [2606:4700::1]:443; # 2 LAX 12.00ms \n"
+        );
+        let exporter = Exporter {
+            port: 443,
+            redaction: Redaction::LastOctet,
+            ..Default::default()
+        };
+        assert_eq!(exporter.render(&[&v4]), "1.0.0.x\n");
+        assert!("server backend;".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let fast = Measurement {
+            speed_mbps: Some(20.0),
+            ..measurement("1.0.0.1", "SJC")
+        };
+        let slow = Measurement {
+            speed_mbps: Some(4.0),
+            ..measurement("1.0.0.2", "SJC")
+        };
+        let untested = measurement("1.0.0.3", "SJC");
+        let servers = [&fast, &slow, &untested];
+        let exporter = |format, port| Exporter {
+            format,
+            port,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            exporter(Format::Nginx, 443).render(&servers),
+            "upstream rustspeedtest {\n    \
+             server 1.0.0.1:443 weight=10;\n    \
+             server 1.0.0.2:443 weight=2;\n    \
+             server 1.0.0.3:443 weight=1;\n}\n"
+        );
+        assert_eq!(
+            exporter(Format::Haproxy, 2053).render(&servers[..2]),
+            "    server node1 1.0.0.1:2053 check weight 10\n    \
+             server node2 1.0.0.2:2053 check weight 2\n"
+        );
+        assert_eq!("haproxy".parse(), Ok(Format::Haproxy));
+        assert!("caddy".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_render_routes() {
+        let ips = [
+            measurement("1.0.0.1", "SJC"),
+            measurement("1.0.1.9", "SJC"),
+            measurement("1.0.0.7", "SJC"),
+            measurement("2606:4700::1", "SJC"),
+        ];
+        let ips: Vec<&Measurement> = ips.iter().collect();
+        let exporter = |format, via: Option<&str>| Exporter {
+            format,
+            prefix: 24,
+            via: via.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            exporter(Format::Bird, Some("192.0.2.1")).render(&ips),
+            "route 1.0.0.0/23 via 192.0.2.1;\nroute 2606:4700::/48 via 192.0.2.1;\n"
+        );
+        assert_eq!(
+            exporter(Format::Bird, Some("wg0")).render(&ips[..1]),
+            "route 1.0.0.0/24 via \"wg0\";\n"
+        );
+        assert_eq!(
+            exporter(Format::Bird, None).render(&ips),
+            "define rustspeedtest_v4 = [ 1.0.0.0/23 ];\n\
+             define rustspeedtest_v6 = [ 2606:4700::/48 ];\n"
+        );
+        assert_eq!(
+            exporter(Format::Frr, Some("eth1")).render(&ips[..1]),
+            "ip route 1.0.0.0/24 eth1\n"
+        );
+        assert_eq!(
+            exporter(Format::Frr, None).render(&ips),
+            "ip prefix-list rustspeedtest seq 5 permit 1.0.0.0/23\n\
+             ipv6 prefix-list rustspeedtest seq 10 permit 2606:4700::/48\n"
+        );
+
+        // 打码时不导出比 /24 更细的路由
+        let redacted = Exporter {
+            format: Format::Frr,
+            prefix: 32,
+            via: Some("eth1".to_string()),
+            redaction: Redaction::LastOctet,
+            ..Default::default()
+        };
+        assert_eq!(redacted.render(&ips[..1]), "ip route 1.0.0.0/24 eth1\n");
+    }
+}
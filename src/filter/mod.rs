@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::history::Measurement;
+
+/// A `--where` expression selecting which results are kept, e.g.
+/// `delay < 80 && loss == 0 && speed > 5`.
+///
+/// Fields are `delay` (ms), `loss` (0 to 1), `speed` (MB/s) and `colo`.
+/// Comparisons (`< <= > >= == !=`) combine with `&&`, `||`, `!` and
+/// parentheses. A comparison on a field the run did not measure is false.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Delay,
+    Loss,
+    Speed,
+    Colo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// What `--top` ranks IPs within: the Cloudflare colo, or the IPv4
+/// /`prefix` subnet (IPv6 uses the prefix 24 bits longer, /40 for /16).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Colo,
+    Subnet(u8),
+}
+
+impl Group {
+    /// The group `measurement` belongs to. IPs of an unknown colo share one group.
+    pub fn key(&self, measurement: &Measurement) -> String {
+        match self {
+            Group::Colo => measurement.colo.clone().unwrap_or_default().to_uppercase(),
+            Group::Subnet(prefix) => match measurement.ip {
+                IpAddr::V4(ip) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), prefix)
+                }
+                IpAddr::V6(ip) => {
+                    let bits = (*prefix as u32 + 24).min(128);
+                    let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                    format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), bits)
+                }
+            },
+        }
+    }
+}
+
+impl FromStr for Group {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("colo") {
+            return Ok(Group::Colo);
+        }
+        match s.strip_prefix('/').map(str::parse::<u8>) {
+            Some(Ok(prefix)) if prefix <= 32 => Ok(Group::Subnet(prefix)),
+            _ => Err(format!(
+                "invalid group '{}', expected colo or a subnet like /16",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Group::Colo => write!(f, "colo"),
+            Group::Subnet(prefix) => write!(f, "/{}", prefix),
+        }
+    }
+}
+
+/// The first `k` IPs of every group of `ranked`, which is ordered best first.
+/// Without a group the first `k` IPs overall.
+pub fn top_per_group(ranked: &[Measurement], k: usize, group: Option<Group>) -> Vec<IpAddr> {
+    let mut taken: HashMap<String, usize> = HashMap::new();
+    ranked
+        .iter()
+        .filter(|measurement| {
+            let key = group
+                .map(|group| group.key(measurement))
+                .unwrap_or_default();
+            let count = taken.entry(key).or_insert(0);
+            *count += 1;
+            *count <= k
+        })
+        .map(|measurement| measurement.ip)
+        .collect()
+}
+
+impl Filter {
+    pub fn matches(&self, measurement: &Measurement) -> bool {
+        self.expr.eval(measurement)
+    }
+}
+
+impl Expr {
+    fn eval(&self, m: &Measurement) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(m) || b.eval(m),
+            Expr::And(a, b) => a.eval(m) && b.eval(m),
+            Expr::Not(a) => !a.eval(m),
+            Expr::Compare(field, op, value) => {
+                let number = match field {
+                    Field::Delay => m.delay_ms,
+                    Field::Loss => m.loss,
+                    Field::Speed => m.speed_mbps,
+                    Field::Colo => {
+                        let (Some(colo), Value::Text(text)) = (&m.colo, value) else {
+                            return false;
+                        };
+                        let equal = colo.eq_ignore_ascii_case(text);
+                        return match op {
+                            Op::Eq => equal,
+                            Op::Ne => !equal,
+                            _ => false,
+                        };
+                    }
+                };
+                let (Some(left), Value::Number(right)) = (number, value) else {
+                    return false;
+                };
+                match op {
+                    Op::Lt => left < *right,
+                    Op::Le => left <= *right,
+                    Op::Gt => left > *right,
+                    Op::Ge => left >= *right,
+                    // 测量值是浮点数,相等比较留一点余量
+                    Op::Eq => (left - right).abs() < 1e-9,
+                    Op::Ne => (left - right).abs() >= 1e-9,
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {:?} in --where expression", token));
+        }
+        Ok(Filter {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) | ('\'', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&q| q == c)
+                    .ok_or("unterminated quote in --where expression")?;
+                let text: String = chars[i + 1..i + 1 + end].iter().collect();
+                (Token::Word(text), end + 2)
+            }
+            (c, _) if c.is_ascii_alphanumeric() || c == '.' || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '.' || **c == '_')
+                    .count();
+                let word: String = chars[i..i + len].iter().collect();
+                match word.parse::<f64>() {
+                    Ok(number) => (Token::Number(number), len),
+                    Err(_) => (Token::Word(word), len),
+                }
+            }
+            (c, _) => return Err(format!("unexpected '{}' in --where expression", c)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Open) {
+            let expr = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err("missing ')' in --where expression".to_string());
+            }
+            return Ok(expr);
+        }
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let field = match self.next() {
+            Some(Token::Word(word)) => match word.to_lowercase().as_str() {
+                "delay" => Field::Delay,
+                "loss" => Field::Loss,
+                "speed" => Field::Speed,
+                "colo" => Field::Colo,
+                _ => {
+                    return Err(format!(
+                        "unknown field '{}' in --where expression (expected delay|loss|speed|colo)",
+                        word
+                    ))
+                }
+            },
+            token => return Err(format!("expected a field, found {:?}", token)),
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            token => {
+                return Err(format!(
+                    "expected a comparison after {:?}, found {:?}",
+                    field, token
+                ))
+            }
+        };
+        let value = match (field, self.next()) {
+            (Field::Colo, Some(Token::Word(text))) => Value::Text(text),
+            (Field::Colo, token) => return Err(format!("expected a colo code, found {:?}", token)),
+            (_, Some(Token::Number(number))) => Value::Number(number),
+            (_, token) => return Err(format!("expected a number, found {:?}", token)),
+        };
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(delay: f64, loss: f64, speed: Option<f64>, colo: Option<&str>) -> Measurement {
+        Measurement {
+            ip: "1.1.1.1".parse().unwrap(),
+            colo: colo.map(str::to_string),
+            delay_ms: Some(delay),
+            loss: Some(loss),
+            speed_mbps: speed,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter: Filter = "delay < 80 && loss == 0 && speed > 5".parse().unwrap();
+        assert!(filter.matches(&measurement(50.0, 0.0, Some(8.0), None)));
+        assert!(!filter.matches(&measurement(90.0, 0.0, Some(8.0), None)));
+        // 没有测速时 speed 的比较不成立
+        assert!(!filter.matches(&measurement(50.0, 0.0, None, None)));
+
+        let filter: Filter = "!(colo == sjc || colo == 'LAX') || delay<=10"
+            .parse()
+            .unwrap();
+        assert!(!filter.matches(&measurement(50.0, 0.0, None, Some("SJC"))));
+        assert!(filter.matches(&measurement(50.0, 0.0, None, Some("NRT"))));
+        assert!(filter.matches(&measurement(10.0, 0.0, None, Some("LAX"))));
+        assert_eq!(
+            filter.to_string(),
+            "!(colo == sjc || colo == 'LAX') || delay<=10"
+        );
+    }
+
+    #[test]
+    fn test_top_per_group() {
+        let ranked: Vec<Measurement> = [
+            ("1.0.0.1", "SJC"),
+            ("1.0.1.1", "LAX"),
+            ("1.1.0.1", "sjc"),
+            ("1.1.0.2", "SJC"),
+            ("1.0.0.2", "LAX"),
+        ]
+        .iter()
+        .map(|(ip, colo)| Measurement {
+            ip: ip.parse().unwrap(),
+            ..measurement(10.0, 0.0, None, Some(colo))
+        })
+        .collect();
+        let ips = |ips: Vec<IpAddr>| ips.iter().map(IpAddr::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            ips(top_per_group(&ranked, 2, Some(Group::Colo))),
+            vec!["1.0.0.1", "1.0.1.1", "1.1.0.1", "1.0.0.2"]
+        );
+        assert_eq!(
+            ips(top_per_group(&ranked, 1, "/16".parse().ok())),
+            vec!["1.0.0.1", "1.1.0.1"]
+        );
+        assert_eq!(ips(top_per_group(&ranked, 1, None)), vec!["1.0.0.1"]);
+        assert!("country".parse::<Group>().is_err());
+        assert_eq!(Group::Subnet(24).to_string(), "/24");
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        assert!("jitter < 5".parse::<Filter>().is_err());
+        assert!("delay <".parse::<Filter>().is_err());
+        assert!("(delay < 5".parse::<Filter>().is_err());
+        assert!("delay < 5 loss".parse::<Filter>().is_err());
+        assert!("colo == 5 && delay < fast".parse::<Filter>().is_err());
+    }
+}
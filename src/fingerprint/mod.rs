@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::i18n::{trf, Msg};
+
+/// Headers that differ between two responses of the same configuration
+const VOLATILE_HEADERS: [&str; 12] = [
+    "age",
+    "cf-ray",
+    "content-length",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "nel",
+    "report-to",
+    "set-cookie",
+    "x-amz-cf-id",
+    "x-request-id",
+];
+
+/// Clusters listed by [`Fingerprints::report`]
+const MAX_CLUSTERS: usize = 20;
+
+/// IPs listed for every cluster
+const MAX_LISTED_IPS: usize = 5;
+
+/// The fingerprint of an HTTP response: a hash of its status line and of its
+/// headers without the volatile ones, sorted and lowercased
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash: String,
+    pub status: String,
+    /// The Server header, empty if there is none
+    pub server: String,
+}
+
+/// The fingerprint of `response`, None if it is not HTTP
+pub fn fingerprint(response: &[u8]) -> Option<Fingerprint> {
+    let response = String::from_utf8_lossy(response);
+    let head = response.split("\r\n\r\n").next().unwrap_or_default();
+    let mut lines = head.lines();
+    let status = lines.next()?.trim();
+    if !status.starts_with("HTTP/") {
+        return None;
+    }
+
+    let mut server = String::new();
+    let mut headers: Vec<String> = Vec::new();
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        if VOLATILE_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if name == "server" {
+            server = value.trim().to_string();
+        }
+        headers.push(format!("{}: {}", name, value.trim()));
+    }
+    headers.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(status.as_bytes());
+    for header in headers.iter() {
+        hasher.update(b"\n");
+        hasher.update(header.as_bytes());
+    }
+    let hash = format!("{:x}", hasher.finalize());
+    Some(Fingerprint {
+        hash: hash[..12].to_string(),
+        status: status.to_string(),
+        server,
+    })
+}
+
+/// The IPs whose responses have the same fingerprint
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub fingerprint: Fingerprint,
+    pub ips: Vec<IpAddr>,
+}
+
+/// Groups the httping responses by [`fingerprint`] (see '--fingerprint'),
+/// to show which IPs share a configuration or return the same block page.
+///
+/// The default value records nothing.
+#[derive(Clone, Default)]
+pub struct Fingerprints {
+    clusters: Option<Arc<Mutex<HashMap<String, Cluster>>>>,
+}
+
+impl fmt::Debug for Fingerprints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fingerprints")
+            .field("enabled", &self.clusters.is_some())
+            .finish()
+    }
+}
+
+impl Fingerprints {
+    /// Record the responses if `enabled`
+    pub fn new(enabled: bool) -> Self {
+        Fingerprints {
+            clusters: enabled.then(Default::default),
+        }
+    }
+
+    /// Add `ip` to the cluster of `response`, ignored if it is not HTTP
+    pub fn record(&self, ip: IpAddr, response: &[u8]) {
+        let Some(ref clusters) = self.clusters else {
+            return;
+        };
+        let Some(fingerprint) = fingerprint(response) else {
+            return;
+        };
+        let mut clusters = clusters.lock().expect("fingerprint lock");
+        clusters
+            .entry(fingerprint.hash.clone())
+            .or_insert_with(|| Cluster {
+                fingerprint,
+                ips: Vec::new(),
+            })
+            .ips
+            .push(ip);
+    }
+
+    /// The clusters, largest first
+    pub fn clusters(&self) -> Vec<Cluster> {
+        let Some(ref clusters) = self.clusters else {
+            return Vec::new();
+        };
+        let mut clusters: Vec<Cluster> = clusters
+            .lock()
+            .expect("fingerprint lock")
+            .values()
+            .cloned()
+            .collect();
+        for cluster in clusters.iter_mut() {
+            cluster.ips.sort();
+        }
+        clusters.sort_by(|a, b| {
+            b.ips
+                .len()
+                .cmp(&a.ips.len())
+                .then_with(|| a.fingerprint.hash.cmp(&b.fingerprint.hash))
+        });
+        clusters
+    }
+
+    /// Print the largest clusters, if any response was recorded
+    pub fn report(&self) {
+        let clusters = self.clusters();
+        if clusters.is_empty() {
+            return;
+        }
+        let responses: usize = clusters.iter().map(|cluster| cluster.ips.len()).sum();
+        println!(
+            "{}",
+            trf(Msg::FingerprintClusters, &[&clusters.len(), &responses])
+        );
+        for cluster in clusters.iter().take(MAX_CLUSTERS) {
+            println!("{}", cluster);
+        }
+    }
+}
+
+impl fmt::Display for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ips: Vec<String> = self
+            .ips
+            .iter()
+            .take(MAX_LISTED_IPS)
+            .map(IpAddr::to_string)
+            .collect();
+        write!(
+            f,
+            "{} {:>6}  {} [{}] {}",
+            self.fingerprint.hash,
+            self.ips.len(),
+            self.fingerprint.status,
+            self.fingerprint.server,
+            ips.join(", ")
+        )?;
+        if self.ips.len() > MAX_LISTED_IPS {
+            write!(f, " (+{})", self.ips.len() - MAX_LISTED_IPS)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let a = fingerprint(
+            b"HTTP/1.1 403 Forbidden\r\nServer: cloudflare\r\nDate: Mon, 01 Jan 2024 00:00:00 GMT\r\nCF-RAY: 1\r\n\r\nblocked",
+        )
+        .unwrap();
+        // 易变的头、头的顺序和大小写不影响指纹
+        let b = fingerprint(
+            b"HTTP/1.1 403 Forbidden\r\ncf-ray: 2\r\nserver: cloudflare\r\nDate: Tue, 02 Jan 2024 00:00:00 GMT\r\n\r\nblocked again",
+        )
+        .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.status, "HTTP/1.1 403 Forbidden");
+        assert_eq!(a.server, "cloudflare");
+
+        let other = fingerprint(b"HTTP/1.1 200 OK\r\nServer: cloudflare\r\n\r\n").unwrap();
+        assert_ne!(a.hash, other.hash);
+        assert!(fingerprint(b"SSH-2.0-OpenSSH\r\n").is_none());
+        assert!(fingerprint(b"").is_none());
+    }
+
+    #[test]
+    fn test_clusters() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let disabled = Fingerprints::default();
+        disabled.record(ip("1.1.1.1"), b"HTTP/1.1 200 OK\r\n\r\n");
+        assert!(disabled.clusters().is_empty());
+
+        let fingerprints = Fingerprints::new(true);
+        fingerprints.record(ip("1.1.1.3"), b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n");
+        fingerprints.record(ip("1.1.1.2"), b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        fingerprints.record(ip("1.1.1.1"), b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n");
+        fingerprints.record(ip("1.1.1.4"), b"garbage");
+
+        let clusters = fingerprints.clusters();
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].ips, vec![ip("1.1.1.1"), ip("1.1.1.3")]);
+        assert_eq!(clusters[1].ips, vec![ip("1.1.1.2")]);
+        assert!(clusters[0]
+            .to_string()
+            .ends_with("HTTP/1.1 200 OK [nginx] 1.1.1.1, 1.1.1.3"));
+    }
+}
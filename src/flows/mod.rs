@@ -0,0 +1,187 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU8,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde_json::json;
+
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+
+/// The source port of the first flow, the others follow it. Below the usual
+/// ephemeral range so that it does not collide with ordinary connections.
+pub const FLOW_SOURCE_PORT_BASE: u16 = 30000;
+
+// 各流平均延迟相差超过这么多,且超过最快一条流的这个比例时,认为路径不同
+const UNSTABLE_SPREAD: Duration = Duration::from_millis(5);
+const UNSTABLE_RATIO: f64 = 0.2;
+
+/// Connect delay of one IP over every flow
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowResult {
+    pub ip: IpAddr,
+    /// Average delay per flow, `None` if no sample succeeded
+    pub delays: Vec<Option<Duration>>,
+}
+
+impl FlowResult {
+    /// The gap between the slowest and fastest flow
+    pub fn spread(&self) -> Option<Duration> {
+        let measured = self.delays.iter().flatten();
+        let fastest = measured.clone().min()?;
+        let slowest = measured.max()?;
+        Some(*slowest - *fastest)
+    }
+
+    /// Whether the flows seem to take paths of different latency, e.g. through
+    /// per-flow ECMP, so that a single flow does not represent the IP
+    pub fn is_unstable(&self) -> bool {
+        let Some(fastest) = self.delays.iter().flatten().min() else {
+            return false;
+        };
+        self.spread().is_some_and(|spread| {
+            spread > UNSTABLE_SPREAD
+                && spread.as_secs_f64() > fastest.as_secs_f64() * UNSTABLE_RATIO
+        })
+    }
+}
+
+/// Measures a few IPs over several flows, Paris-traceroute style: each flow
+/// keeps its own fixed source port across repeats, so that per-flow load
+/// balancing sends it down the same path every time
+pub struct FlowTest {
+    flows: u16,
+    times: NonZeroU8,
+    timeout: Duration,
+    port: u16,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+}
+
+impl FlowTest {
+    pub fn new(flows: u16, times: u8, timeout: Duration, port: u16) -> Self {
+        FlowTest {
+            flows: flows.max(1),
+            times: NonZeroU8::new(times).unwrap_or(NonZeroU8::new(1).unwrap()),
+            timeout,
+            port,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Test all `ips` concurrently, keeping their order
+    pub async fn run(&self, ips: &[IpAddr]) -> Vec<FlowResult> {
+        self.events.stage_start("flows", ips.len());
+
+        let results = join_all(ips.iter().map(|ip| self.test(*ip))).await;
+
+        for result in results.iter() {
+            let delays: Vec<Option<f64>> = result
+                .delays
+                .iter()
+                .map(|d| d.map(|d| d.as_secs_f64() * 1000.0))
+                .collect();
+            self.events.result(
+                "flows",
+                result.ip,
+                delays.iter().any(|d| d.is_some()),
+                json!({"delay_ms": delays, "unstable": result.is_unstable()}),
+            );
+        }
+        self.events.stage_end(
+            "flows",
+            results.iter().filter(|r| r.spread().is_some()).count(),
+        );
+
+        results
+    }
+
+    async fn test(&self, ip: IpAddr) -> FlowResult {
+        let addr = SocketAddr::new(ip, self.port);
+        let flows = self.flows as usize;
+        let mut total = vec![Duration::ZERO; flows];
+        let mut success = vec![0u32; flows];
+
+        // 轮流测量各条流,避免网络状况随时间变化被误认为流之间的差异
+        for _ in 0..self.times.get() {
+            for flow in 0..flows {
+                let source_port = FLOW_SOURCE_PORT_BASE.wrapping_add(flow as u16);
+                let start = Instant::now();
+                let connected = self
+                    .socket_options
+                    .connect_from(addr, source_port, self.timeout)
+                    .await;
+                if connected.is_ok() {
+                    total[flow] += start.elapsed();
+                    success[flow] += 1;
+                }
+            }
+        }
+
+        let delays = total
+            .iter()
+            .zip(success.iter())
+            .map(|(total, &success)| (success > 0).then(|| *total / success))
+            .collect();
+        FlowResult { ip, delays }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unstable() {
+        let result = |delays: &[u64]| FlowResult {
+            ip: "1.1.1.1".parse().unwrap(),
+            delays: delays
+                .iter()
+                .map(|&ms| (ms > 0).then(|| Duration::from_millis(ms)))
+                .collect(),
+        };
+
+        assert_eq!(
+            result(&[20, 0, 32]).spread(),
+            Some(Duration::from_millis(12))
+        );
+        assert!(result(&[20, 0, 32]).is_unstable());
+        // 相差不到 5ms,或者相对延迟很小
+        assert!(!result(&[20, 23, 21]).is_unstable());
+        assert!(!result(&[200, 210]).is_unstable());
+        assert!(!result(&[0, 0]).is_unstable());
+    }
+
+    #[tokio::test]
+    async fn test_flows() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        let results = FlowTest::new(3, 2, Duration::from_secs(1), port)
+            .run(&["127.0.0.1".parse().unwrap()])
+            .await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].delays.len(), 3);
+        assert!(results[0].delays.iter().all(|d| d.is_some()));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hdrhistogram::Histogram;
+
+// 有效数字位数,误差在 0.1% 以内
+const SIGNIFICANT_DIGITS: u8 = 3;
+// 可记录的最大延迟(微秒),更大的按此记录
+const HIGHEST_MICROS: u64 = 3_600_000_000;
+// 每翻倍一次距离 100% 的一半输出的行数,与 HdrHistogram 的默认值相同
+const TICKS_PER_HALF_DISTANCE: u32 = 5;
+
+/// Collects every successful delay sample of a latency stage into an HDR
+/// histogram (in microseconds), shared by all tasks of the stage.
+///
+/// The default value is disabled and drops all samples.
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    inner: Option<Arc<Mutex<Recorder>>>,
+}
+
+struct Recorder {
+    histogram: Histogram<u64>,
+    // 校准时测得的本机开销,记录前扣除
+    overhead: Duration,
+}
+
+impl std::fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("samples", &self.count())
+            .finish()
+    }
+}
+
+impl LatencyHistogram {
+    /// An enabled, empty histogram
+    pub fn new() -> Self {
+        let histogram = Histogram::new_with_bounds(1, HIGHEST_MICROS, SIGNIFICANT_DIGITS)
+            .expect("valid histogram bounds");
+        LatencyHistogram {
+            inner: Some(Arc::new(Mutex::new(Recorder {
+                histogram,
+                overhead: Duration::ZERO,
+            }))),
+        }
+    }
+
+    /// Subtract `overhead` from the samples recorded from now on, as the
+    /// engines do with `--calibrate`
+    pub fn set_overhead(&self, overhead: Duration) {
+        if let Some(ref inner) = self.inner {
+            inner.lock().expect("histogram lock").overhead = overhead;
+        }
+    }
+
+    pub fn record(&self, sample: Duration) {
+        if let Some(ref inner) = self.inner {
+            let mut recorder = inner.lock().expect("histogram lock");
+            let micros = sample.saturating_sub(recorder.overhead).as_micros();
+            recorder.histogram.saturating_record(micros as u64);
+        }
+    }
+
+    /// The number of samples recorded
+    pub fn count(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| {
+            inner.lock().expect("histogram lock").histogram.len()
+        })
+    }
+
+    /// The percentile distribution in the `.hgrm` text format of
+    /// HdrHistogram, with values in milliseconds, so that runs can be
+    /// compared with the HdrHistogram plotter and similar tools
+    pub fn to_hgrm(&self) -> String {
+        let mut out = String::new();
+        let Some(ref inner) = self.inner else {
+            return out;
+        };
+        let recorder = inner.lock().expect("histogram lock");
+        let histogram = &recorder.histogram;
+        let millis = |micros: u64| micros as f64 / 1000.0;
+
+        let _ = writeln!(
+            out,
+            "{:>12} {:>14} {:>10} {:>14}\n",
+            "Value", "Percentile", "TotalCount", "1/(1-Percentile)"
+        );
+        let mut total = 0;
+        for value in histogram.iter_quantiles(TICKS_PER_HALF_DISTANCE) {
+            total += value.count_since_last_iteration();
+            let quantile = value.quantile_iterated_to();
+            let _ = write!(
+                out,
+                "{:12.3} {:1.12} {:10}",
+                millis(value.value_iterated_to()),
+                quantile,
+                total
+            );
+            // 100% 这一行没有倒数
+            if quantile < 1.0 {
+                let _ = write!(out, " {:14.2}", 1.0 / (1.0 - quantile));
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(
+            out,
+            "#[Mean    = {:12.3}, StdDeviation   = {:12.3}]",
+            histogram.mean() / 1000.0,
+            histogram.stdev() / 1000.0
+        );
+        let _ = writeln!(
+            out,
+            "#[Max     = {:12.3}, Total count    = {:12}]",
+            millis(histogram.max()),
+            histogram.len()
+        );
+        out
+    }
+
+    /// Write [`LatencyHistogram::to_hgrm`] to `path`
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_hgrm())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hgrm() {
+        assert_eq!(LatencyHistogram::default().count(), 0);
+        LatencyHistogram::default().record(Duration::from_millis(1));
+
+        let histogram = LatencyHistogram::new();
+        histogram.set_overhead(Duration::from_millis(1));
+        for ms in 1..=100 {
+            histogram.clone().record(Duration::from_millis(ms + 1));
+        }
+        assert_eq!(histogram.count(), 100);
+
+        let hgrm = histogram.to_hgrm();
+        assert!(hgrm.starts_with("       Value     Percentile TotalCount 1/(1-Percentile)\n\n"));
+        let rows: Vec<Vec<&str>> = hgrm
+            .lines()
+            .skip(2)
+            .take_while(|line| !line.starts_with('#'))
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        // 扣除 1ms 开销后是 1ms 到 100ms
+        assert_eq!(rows[0][0], "1.000");
+        assert_eq!(rows[0].len(), 4);
+        let last = rows.last().unwrap();
+        assert_eq!(last.len(), 3);
+        assert!((last[0].parse::<f64>().unwrap() - 100.0).abs() < 0.1);
+        assert_eq!(last[1], "1.000000000000");
+        assert_eq!(last[2], "100");
+        assert!(hgrm.contains("Total count    =          100]"));
+    }
+}
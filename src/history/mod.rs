@@ -0,0 +1,238 @@
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+#[cfg(feature = "history")]
+use rusqlite::{params, Connection};
+
+use crate::download::Speed;
+use crate::probe::ScanResult;
+use crate::routes::CFCDNCheckResult;
+use crate::scanner::Delay;
+
+/// One usable IP measured in one run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub ip: IpAddr,
+    /// Cloudflare colo code, if the route check ran
+    pub colo: Option<String>,
+    pub delay_ms: Option<f64>,
+    pub loss: Option<f64>,
+    pub speed_mbps: Option<f64>,
+}
+
+impl Measurement {
+    /// Merge the results of all stages of one run into measurements of the usable IPs
+    pub fn from_results(
+        valis_ips: &[IpAddr],
+        latency: &ScanResult,
+        speedtest_result: &Option<Vec<Speed>>,
+        time: u8,
+    ) -> Vec<Measurement> {
+        let delays: HashMap<IpAddr, &Delay> = latency
+            .delays()
+            .into_iter()
+            .flatten()
+            .map(|delay| (delay.ip, delay))
+            .collect();
+        let routes: HashMap<IpAddr, &CFCDNCheckResult> = latency
+            .routes()
+            .into_iter()
+            .flatten()
+            .map(|route| (route.ip, route))
+            .collect();
+        let speeds: HashMap<IpAddr, &Speed> = speedtest_result
+            .iter()
+            .flatten()
+            .map(|speed| (speed.ip, speed))
+            .collect();
+
+        valis_ips
+            .iter()
+            .map(|ip| Measurement {
+                ip: *ip,
+                colo: routes
+                    .get(ip)
+                    .filter(|route| !route.location_code.is_empty())
+                    .map(|route| route.location_code.clone()),
+                delay_ms: delays
+                    .get(ip)
+                    .map(|delay| delay.average_delay.as_secs_f64() * 1000.0),
+                loss: delays
+                    .get(ip)
+                    .map(|delay| 1.0 - delay.success as f64 / time as f64),
+                speed_mbps: speeds.get(ip).map(|speed| {
+                    speed.total_download as f64 / 1024.0 / 1024.0 / speed.consume.as_secs_f64()
+                }),
+            })
+            .collect()
+    }
+}
+
+/// All runs since some point in time, loaded from the history
+#[derive(Debug, Default)]
+pub struct HistoryWindow {
+    /// Start time (unix seconds) of every run
+    pub runs: Vec<i64>,
+    /// (run start time, measurement)
+    pub measurements: Vec<(i64, Measurement)>,
+}
+
+/// Measurement history stored in a SQLite database
+#[cfg(feature = "history")]
+pub struct History {
+    conn: Connection,
+}
+
+/// Stand-in for builds without the `history` feature: cannot be opened
+#[cfg(not(feature = "history"))]
+pub struct History;
+
+#[cfg(not(feature = "history"))]
+impl History {
+    pub fn open<P: AsRef<Path>>(_path: P) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "built without the history feature",
+        ))
+    }
+
+    pub fn record(&mut self, _started: i64, _measurements: &[Measurement]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn load_since(&self, _since: i64) -> std::io::Result<HistoryWindow> {
+        Ok(HistoryWindow::default())
+    }
+}
+
+#[cfg(feature = "history")]
+impl History {
+    /// Open or create the history database at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                started INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS measurements (
+                run_id INTEGER NOT NULL REFERENCES runs(id),
+                ip TEXT NOT NULL,
+                colo TEXT,
+                delay_ms REAL,
+                loss REAL,
+                speed_mbps REAL
+            );
+            CREATE INDEX IF NOT EXISTS measurements_run ON measurements(run_id);",
+        )?;
+        Ok(History { conn })
+    }
+
+    /// Store the measurements of one run started at `started` (unix seconds)
+    pub fn record(&mut self, started: i64, measurements: &[Measurement]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("INSERT INTO runs (started) VALUES (?1)", params![started])?;
+        let run_id = tx.last_insert_rowid();
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO measurements (run_id, ip, colo, delay_ms, loss, speed_mbps)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for m in measurements {
+                stmt.execute(params![
+                    run_id,
+                    m.ip.to_string(),
+                    m.colo,
+                    m.delay_ms,
+                    m.loss,
+                    m.speed_mbps
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Load all runs started at or after `since` (unix seconds)
+    pub fn load_since(&self, since: i64) -> rusqlite::Result<HistoryWindow> {
+        let mut window = HistoryWindow::default();
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT started FROM runs WHERE started >= ?1 ORDER BY started")?;
+        for started in stmt.query_map(params![since], |row| row.get(0))? {
+            window.runs.push(started?);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT r.started, m.ip, m.colo, m.delay_ms, m.loss, m.speed_mbps
+             FROM measurements m JOIN runs r ON m.run_id = r.id
+             WHERE r.started >= ?1 ORDER BY r.started",
+        )?;
+        let rows = stmt.query_map(params![since], |row| {
+            let ip: String = row.get(1)?;
+            Ok((
+                row.get::<_, i64>(0)?,
+                ip,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })?;
+        for row in rows {
+            let (started, ip, colo, delay_ms, loss, speed_mbps) = row?;
+            // 忽略无法解析的地址
+            if let Ok(ip) = ip.parse() {
+                window.measurements.push((
+                    started,
+                    Measurement {
+                        ip,
+                        colo,
+                        delay_ms,
+                        loss,
+                        speed_mbps,
+                    },
+                ));
+            }
+        }
+
+        Ok(window)
+    }
+}
+
+#[cfg(all(test, feature = "history"))]
+mod tests {
+    use super::*;
+
+    fn measurement(ip: &str, delay_ms: f64) -> Measurement {
+        Measurement {
+            ip: ip.parse().unwrap(),
+            colo: Some("SJC".to_string()),
+            delay_ms: Some(delay_ms),
+            loss: Some(0.0),
+            speed_mbps: None,
+        }
+    }
+
+    #[test]
+    fn test_record_and_load() {
+        let mut history = History::open(":memory:").unwrap();
+        history
+            .record(100, &[measurement("1.1.1.1", 10.0)])
+            .unwrap();
+        history
+            .record(
+                200,
+                &[measurement("1.1.1.1", 12.0), measurement("1.0.0.1", 20.0)],
+            )
+            .unwrap();
+
+        let window = history.load_since(150).unwrap();
+        assert_eq!(window.runs, vec![200]);
+        assert_eq!(window.measurements.len(), 2);
+        assert_eq!(window.measurements[0].0, 200);
+
+        let window = history.load_since(0).unwrap();
+        assert_eq!(window.runs, vec![100, 200]);
+        assert_eq!(window.measurements.len(), 3);
+    }
+}
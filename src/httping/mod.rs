@@ -1,18 +1,41 @@
-use std::{cmp, net::IpAddr, time::Duration};
+use std::{
+    cmp,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use async_std::{io, net::TcpStream};
-use futures::{stream::FuturesUnordered, AsyncReadExt, AsyncWriteExt, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
+use futures::{future::LocalBoxFuture, stream, AsyncReadExt, AsyncWriteExt, Stream, StreamExt};
 use rand::seq::SliceRandom;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-#[derive(Debug, PartialEq)]
+use crate::fingerprint::Fingerprints;
+use crate::i18n::{trf, Msg};
+use crate::probe::{until_deadline, Prober, ScanResult};
+use crate::progress::ProgressEvents;
+use crate::replay::ResponseLog;
+use crate::socket::SocketOptions;
+use crate::trace::printable;
+use crate::utils;
+
+#[derive(Debug)]
 pub struct HttpingChecker<'a> {
-    // ips: Vec<IpAddr>,          // List of IP addresses to check
+    ips: Vec<IpAddr>,          // List of IP addresses to check when run as a Prober
     tries_per_ip: u8,          // Number of times to check each IP address
     request_timeout: Duration, // HTTP request timeout
     request_port: u16,         // HTTP request port
     batch_size: usize,         // Batch size for concurrent requests
     headers: &'a str,          // custom http header
+    request: HttpRequest,      // method, path and response check
+    socket_options: SocketOptions, // local socket settings
+    events: ProgressEvents,        // progress event stream
+    deadline: Option<Instant>,     // stop checking at this time
+    responses: ResponseLog,        // saved responses for replay
+    fingerprints: Fingerprints,    // responses grouped by their headers
+    max_ttfb: Option<Duration>,    // slower responses are not valid
 }
 
 const USER_AGENTS: [&str; 5] = [
@@ -23,13 +46,6 @@ const USER_AGENTS: [&str; 5] = [
     "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Edge/16.16299",
 ];
 
-const REQUEST_TEMPLATE: &str = "GET / HTTP/1.1\r\n\
-                                Accept: */*\r\n\
-                                Connection: close\r\n\
-                                Host: {}\r\n
-                                User-Agent: {}\r\n\
-                                {}\r\n\r\n";
-
 impl<'a> HttpingChecker<'a> {
     pub fn new(
         // ips_to_check: Vec<IpAddr>,
@@ -40,127 +56,242 @@ impl<'a> HttpingChecker<'a> {
         headers: &'a str,
     ) -> Self {
         HttpingChecker {
-            // ips: ips_to_check,
+            ips: Vec::new(),
             tries_per_ip,
             request_timeout,
             request_port,
             batch_size,
             headers,
+            request: HttpRequest::default(),
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            deadline: None,
+            responses: ResponseLog::default(),
+            fingerprints: Fingerprints::default(),
+            max_ttfb: None,
         }
     }
 
-    pub async fn run(&self, ips: Vec<IpAddr>) -> Vec<HttpingResult> {
-        let mut valid_result = Vec::new();
+    /// Apply local socket settings (e.g. outgoing interface) to every request
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
 
-        // use async-std
-        let mut ftrs = FuturesUnordered::new();
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
 
-        // let (tx, mut rx) = mpsc::channel(self.batch_size);
-        let total = ips.len();
-        let mut ips_iter = ips.into_iter();
+    /// Stop [`HttpingChecker::run`] at `deadline`, returning the results so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
 
-        // process bar
-        let pb = ProgressBar::new(total as u64);
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-            )
-            .unwrap()
-            .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
-        );
+    /// The IPs checked by [`Prober::probe`]
+    pub fn with_ips(mut self, ips: Vec<IpAddr>) -> Self {
+        self.ips = ips;
+        self
+    }
 
-        // Concurrently check the routes of IP addresses
-        for _ in 0..cmp::min(self.batch_size, total) {
-            if let Some(ip) = ips_iter.next() {
-                ftrs.push(self.spawn_checker_task(ip.to_owned()));
-            }
-        }
+    /// Send `request` instead of a plain `GET /`
+    pub fn with_request(mut self, request: HttpRequest) -> Self {
+        self.request = request;
+        self
+    }
+
+    /// Save every response to `responses`
+    pub fn with_response_log(mut self, responses: ResponseLog) -> Self {
+        self.responses = responses;
+        self
+    }
+
+    /// Group every response in `fingerprints`, printed after the summary
+    pub fn with_fingerprints(mut self, fingerprints: Fingerprints) -> Self {
+        self.fingerprints = fingerprints;
+        self
+    }
+
+    /// Count responses whose first byte came more than `max_ttfb` after the
+    /// start of the connect as not valid
+    pub fn with_max_ttfb(mut self, max_ttfb: Option<Duration>) -> Self {
+        self.max_ttfb = max_ttfb;
+        self
+    }
+
+    /// Yield the result of every IP as soon as it is checked, valid or not.
+    /// At most `batch_size` requests are in flight at a time.
+    pub fn stream(&self, ips: Vec<IpAddr>) -> impl Stream<Item = HttpingResult> + '_ {
+        stream::iter(ips)
+            .map(move |ip| self.spawn_checker_task(ip))
+            .buffer_unordered(cmp::max(self.batch_size, 1))
+    }
+
+    pub async fn run(&self, ips: Vec<IpAddr>) -> Vec<HttpingResult> {
+        let mut valid_result = Vec::new();
+        let total = ips.len();
+
+        self.events.stage_start("httping", total);
 
         let mut good: usize = 0;
         let mut bad: usize = 0;
-        while let Some(result) = ftrs.next().await {
+        let mut results = Box::pin(until_deadline(self.stream(ips), self.deadline));
+        while let Some(result) = results.next().await {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            self.events.result(
+                "httping",
+                result.ip,
+                result.valid,
+                json!({
+                    "ttfb_ms": result.ttfb.map(ms),
+                    "connect_ms": result.steps.map(|steps| ms(steps.connect)),
+                    "send_ms": result.steps.map(|steps| ms(steps.send)),
+                    "recv_ms": result.steps.map(|steps| ms(steps.recv)),
+                }),
+            );
             if result.valid {
                 good += 1;
                 valid_result.push(result);
-                pb.inc(1);
             } else {
                 bad += 1;
-                pb.inc(1);
-            }
-            if let Some(ip_address) = ips_iter.next() {
-                ftrs.push(self.spawn_checker_task(ip_address.to_owned()));
             }
         }
 
-        pb.finish_with_message("finshed");
+        self.events.stage_end("httping", valid_result.len());
 
         // summary all http status
         println!(
-            "total: {} \t good: {} \t bad: {}",
-            valid_result.len(),
-            good,
-            bad
+            "{}",
+            trf(Msg::HttpingSummary, &[&valid_result.len(), &good, &bad])
         );
+        if let Some(average) = average_ttfb(&valid_result) {
+            println!(
+                "{}",
+                trf(
+                    Msg::AverageTtfb,
+                    &[&format!("{:.1}", average.as_secs_f64() * 1000.0)]
+                )
+            );
+        }
+        self.fingerprints.report();
 
         valid_result
     }
 
-    async fn connect_with_retry(&self, addr: &str) -> Option<TcpStream> {
+    /// The stream and when its successful connect started
+    async fn connect_with_retry(&self, addr: SocketAddr) -> Option<(TcpStream, Instant)> {
         for _ in 1..=self.tries_per_ip {
+            let start = Instant::now();
             if let Ok(stream) = self.tcp_connect(addr).await {
-                return Some(stream);
+                return Some((stream, start));
             }
         }
         None
     }
 
     #[inline]
-    async fn spawn_checker_task(&'a self, ip_address: IpAddr) -> HttpingResult {
-        let address = format!("{}:{}", &ip_address, &self.request_port);
+    async fn spawn_checker_task(&self, ip_address: IpAddr) -> HttpingResult {
+        let address = SocketAddr::new(ip_address, self.request_port);
         let mut http_result = HttpingResult {
             ip: ip_address,
             valid: false,
+            ttfb: None,
+            steps: None,
         };
 
         // try to connect to the host
-        let mut stream = match self.connect_with_retry(&address).await {
-            Some(tcp_stream) => tcp_stream,
+        let (mut stream, start) = match self.connect_with_retry(address).await {
+            Some(connected) => connected,
             None => {
+                self.events.trace("httping", ip_address, || {
+                    format!("connect to port {} failed {} times", address.port(), self.tries_per_ip)
+                });
                 return http_result;
             }
         };
+        let connect = start.elapsed();
+        self.events.trace("httping", ip_address, || {
+            format!("connected to port {} after {:?}", address.port(), connect)
+        });
 
         // Send HTTP GET request
         let user_agent = USER_AGENTS.choose(&mut rand::thread_rng()).unwrap();
-        let request = REQUEST_TEMPLATE
-            .replace("{}", &ip_address.to_string())
-            .replace("{}", user_agent)
-            .replace("{}", self.headers);
+        let request =
+            self.request
+                .render(&utils::host_header(&ip_address), user_agent, self.headers);
 
-        if self
+        self.events.trace("httping", ip_address, || {
+            format!("request: {}", printable(request.as_bytes(), 512))
+        });
+        let sending = Instant::now();
+        if let Err(e) = self
             .write_with_timeout(&mut stream, request.as_bytes())
             .await
-            .is_err()
         {
+            self.events
+                .trace("httping", ip_address, || format!("write failed: {}", e));
             return http_result;
         }
+        let send = sending.elapsed();
 
         // Read HTTP response
+        let receiving = Instant::now();
         let mut buf = Vec::with_capacity(1024);
-        if self.read_with_timeout(&mut stream, &mut buf).await.is_err() {
-            return http_result;
+        match self.read_with_timeout(&mut stream, &mut buf, start).await {
+            Ok(ttfb) => {
+                http_result.ttfb = ttfb;
+                http_result.steps = Some(Steps {
+                    connect,
+                    send,
+                    recv: receiving.elapsed(),
+                });
+            }
+            Err(e) => {
+                self.events.trace("httping", ip_address, || {
+                    format!("read failed after {:?}: {}", start.elapsed(), e)
+                });
+                return http_result;
+            }
         }
 
         // Shutdown TCP stream
-        match stream.shutdown(std::net::Shutdown::Both) {
-            Ok(_) => (),
-            Err(_) => (),
-        } 
+        let _ = stream.shutdown(std::net::Shutdown::Both);
 
         // Check if the server returned a valid HTTP response
         let response = String::from_utf8_lossy(&buf);
-        if response.starts_with("HTTP/1.") {
-            http_result.valid = true;
+        http_result.valid = self.request.is_valid_response(&response);
+        self.responses.save(
+            ip_address,
+            self.request_port,
+            start.elapsed().as_secs_f64() * 1000.0,
+            http_result.valid,
+            &buf,
+        );
+        self.fingerprints.record(ip_address, &buf);
+        self.events.trace("httping", ip_address, || {
+            format!(
+                "response after {:?}, first byte after {:?}, {}: {}",
+                start.elapsed(),
+                http_result.ttfb,
+                if http_result.valid {
+                    "matches"
+                } else {
+                    "does not match the expected status or headers"
+                },
+                printable(&buf, 512)
+            )
+        });
+
+        // 首字节太慢的 IP 不可用
+        if let (Some(ttfb), Some(max_ttfb)) = (http_result.ttfb, self.max_ttfb) {
+            if ttfb > max_ttfb {
+                self.events.trace("httping", ip_address, || {
+                    format!("first byte after {:?}, more than {:?}", ttfb, max_ttfb)
+                });
+                http_result.valid = false;
+            }
         }
 
         http_result
@@ -176,30 +307,388 @@ impl<'a> HttpingChecker<'a> {
         Ok(())
     }
 
+    /// Read the whole response into `buf`; returns when its first byte came
+    /// after `start`, None if the response is empty
     #[inline]
     async fn read_with_timeout(
         &self,
         stream: &mut TcpStream,
         buf: &mut Vec<u8>,
-    ) -> io::Result<usize> {
+        start: Instant,
+    ) -> io::Result<Option<Duration>> {
         tokio::time::timeout(self.request_timeout, async move {
-            stream.read_to_end(buf).await
+            let mut first = [0; 1024];
+            let n = stream.read(&mut first).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            let ttfb = start.elapsed();
+            buf.extend_from_slice(&first[..n]);
+            stream.read_to_end(buf).await?;
+            Ok(Some(ttfb))
         })
         .await?
     }
 
     #[inline]
-    async fn tcp_connect(&self, address: &str) -> io::Result<TcpStream> {
-        let stream = io::timeout(self.request_timeout, async move {
-            TcpStream::connect(address).await
-        })
-        .await?;
-        Ok(stream)
+    async fn tcp_connect(&self, address: SocketAddr) -> io::Result<TcpStream> {
+        let stream = self
+            .socket_options
+            .connect(address, self.request_timeout)
+            .await?;
+        Ok(TcpStream::from(stream.into_std()?))
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpingResult {
     pub ip: IpAddr, // IP address
     pub valid: bool,
+    /// From the start of the connect to the first byte of the response
+    #[serde(default)]
+    pub ttfb: Option<Duration>,
+    /// Where the time of the request went, None if it failed
+    #[serde(default)]
+    pub steps: Option<Steps>,
+}
+
+/// The steps of one httping request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Steps {
+    /// The TCP connect that succeeded
+    pub connect: Duration,
+    /// Writing the request
+    pub send: Duration,
+    /// From the request written to the end of the response
+    pub recv: Duration,
+}
+
+/// The mean time to first byte of `results`, None if none has one
+pub fn average_ttfb(results: &[HttpingResult]) -> Option<Duration> {
+    let ttfbs: Vec<Duration> = results.iter().filter_map(|r| r.ttfb).collect();
+    if ttfbs.is_empty() {
+        return None;
+    }
+    Some(ttfbs.iter().sum::<Duration>() / ttfbs.len() as u32)
+}
+
+impl Prober for HttpingChecker<'_> {
+    fn stage(&self) -> &'static str {
+        "httping"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            // 按 IP 排序,结果的顺序与请求完成的先后无关
+            let mut results = self.run(self.ips.clone()).await;
+            results.sort_by_key(|result| result.ip);
+            ScanResult::Http(results)
+        })
+    }
+}
+
+/// HTTP method of the httping requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Method {
+    #[default]
+    Get,
+    Head,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "get" => Ok(Method::Get),
+            "head" => Ok(Method::Head),
+            _ => Err(format!("unknown http method '{}', expected get or head", s)),
+        }
+    }
+}
+
+/// The request httping sends to every IP and the check its response must pass
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    method: Method,
+    path: String,
+    headers: Vec<(String, String)>,
+    match_header: Option<(String, Regex)>,
+}
+
+impl Default for HttpRequest {
+    fn default() -> Self {
+        HttpRequest {
+            method: Method::Get,
+            path: "/".to_string(),
+            headers: Vec::new(),
+            match_header: None,
+        }
+    }
+}
+
+impl HttpRequest {
+    fn render(&self, host: &str, user_agent: &str, extra_headers: &str) -> String {
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nAccept: */*\r\nConnection: close\r\nHost: {}\r\nUser-Agent: {}\r\n",
+            self.method.as_str(),
+            self.path,
+            host,
+            user_agent
+        );
+        for (name, value) in self.headers.iter() {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !extra_headers.is_empty() {
+            request.push_str(extra_headers.trim_end());
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    /// An HTTP/1.x response that also carries the matching header, if one is required
+    pub fn is_valid_response(&self, response: &str) -> bool {
+        if !response.starts_with("HTTP/1.") {
+            return false;
+        }
+        let (name, pattern) = match self.match_header {
+            Some((ref name, ref pattern)) => (name, pattern),
+            None => return true,
+        };
+
+        response
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .any(|(n, v)| n.trim().eq_ignore_ascii_case(name) && pattern.is_match(v.trim()))
+    }
+}
+
+/// Builds an [`HttpRequest`]; every part is checked by [`HttpRequestBuilder::build`].
+///
+/// ```ignore
+/// let request = HttpRequestBuilder::new()
+///     .method(Method::Head)
+///     .path("/cdn-cgi/trace")
+///     .header("Accept-Language", "en")
+///     .match_header("Server", "^cloudflare$")
+///     .build()?;
+/// ```
+#[derive(Debug, Default)]
+pub struct HttpRequestBuilder {
+    method: Method,
+    path: Option<String>,
+    headers: Vec<(String, String)>,
+    match_header: Option<(String, String)>,
+}
+
+impl HttpRequestBuilder {
+    pub fn new() -> Self {
+        HttpRequestBuilder::default()
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Add a request header; `Host` and `Connection` are set by httping itself
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Only count responses whose header `name` matches the regex `pattern`
+    pub fn match_header(mut self, name: &str, pattern: &str) -> Self {
+        self.match_header = Some((name.to_string(), pattern.to_string()));
+        self
+    }
+
+    pub fn build(self) -> Result<HttpRequest, String> {
+        let path = self.path.unwrap_or_else(|| "/".to_string());
+        if !path.starts_with('/') || path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(format!("invalid request path '{}'", path));
+        }
+
+        for (name, value) in self.headers.iter() {
+            check_header_name(name)?;
+            if ["host", "connection"].contains(&name.to_ascii_lowercase().as_str()) {
+                return Err(format!("header '{}' is set by httping", name));
+            }
+            if value.chars().any(|c| c == '\r' || c == '\n') {
+                return Err(format!("invalid value of header '{}'", name));
+            }
+        }
+
+        let match_header = match self.match_header {
+            Some((name, pattern)) => {
+                check_header_name(&name)?;
+                let pattern = Regex::new(&pattern).map_err(|e| e.to_string())?;
+                Some((name, pattern))
+            }
+            None => None,
+        };
+
+        Ok(HttpRequest {
+            method: self.method,
+            path,
+            headers: self.headers,
+            match_header,
+        })
+    }
+}
+
+/// A header name must be a non-empty HTTP token
+fn check_header_name(name: &str) -> Result<(), String> {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(is_token) {
+        return Err(format!("invalid header name '{}'", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn test_run_local_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            }
+        });
+
+        let checker = HttpingChecker::new(1, Duration::from_secs(2), port, 1, "");
+        let results = async_std::task::block_on(checker.run(vec!["127.0.0.1".parse().unwrap()]));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].valid);
+        assert!(results[0].ttfb.is_some());
+        assert!(results[0].steps.is_some());
+    }
+
+    #[test]
+    fn test_max_ttfb() {
+        // 首字节在 200ms 后才到
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            while let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                std::thread::sleep(Duration::from_millis(200));
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            }
+        });
+
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let checker = HttpingChecker::new(1, Duration::from_secs(2), port, 1, "");
+        let results: Vec<HttpingResult> =
+            async_std::task::block_on(checker.stream(vec![ip]).collect());
+        assert!(results[0].valid);
+        assert!(results[0].ttfb.unwrap() >= Duration::from_millis(200));
+        // 等待响应的时间算在接收里
+        let steps = results[0].steps.unwrap();
+        assert!(steps.recv >= Duration::from_millis(200));
+        assert!(steps.connect < Duration::from_millis(200));
+
+        let checker = checker.with_max_ttfb(Some(Duration::from_millis(100)));
+        let results: Vec<HttpingResult> =
+            async_std::task::block_on(checker.stream(vec![ip]).collect());
+        assert!(!results[0].valid);
+        assert!(results[0].ttfb.is_some());
+    }
+
+    #[test]
+    fn test_average_ttfb() {
+        let result = |ttfb: Option<u64>| HttpingResult {
+            ip: "1.1.1.1".parse().unwrap(),
+            valid: true,
+            ttfb: ttfb.map(Duration::from_millis),
+            steps: None,
+        };
+        assert_eq!(average_ttfb(&[]), None);
+        assert_eq!(average_ttfb(&[result(None)]), None);
+        assert_eq!(
+            average_ttfb(&[result(Some(10)), result(None), result(Some(30))]),
+            Some(Duration::from_millis(20))
+        );
+    }
+
+    #[test]
+    fn test_stream_keeps_invalid() {
+        // 立即关闭连接,不返回 HTTP 响应
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            while let Ok((stream, _)) = listener.accept() {
+                drop(stream);
+            }
+        });
+
+        let checker = HttpingChecker::new(1, Duration::from_secs(2), port, 2, "");
+        let ips = vec!["127.0.0.1".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        let results: Vec<HttpingResult> =
+            async_std::task::block_on(checker.stream(ips).collect());
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| !r.valid));
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let request = HttpRequestBuilder::new()
+            .method(Method::Head)
+            .path("/cdn-cgi/trace")
+            .header("Accept-Language", "en")
+            .match_header("server", "^cloudflare$")
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.render("1.1.1.1", "ua", ""),
+            "HEAD /cdn-cgi/trace HTTP/1.1\r\nAccept: */*\r\nConnection: close\r\n\
+             Host: 1.1.1.1\r\nUser-Agent: ua\r\nAccept-Language: en\r\n\r\n"
+        );
+
+        assert!(HttpRequestBuilder::new().path("no-slash").build().is_err());
+        assert!(HttpRequestBuilder::new().header("Bad Name", "x").build().is_err());
+        assert!(HttpRequestBuilder::new().header("X", "a\r\nb").build().is_err());
+        assert!(HttpRequestBuilder::new().header("Host", "x").build().is_err());
+        assert!(HttpRequestBuilder::new().match_header("Server", "(").build().is_err());
+        assert_eq!("HEAD".parse::<Method>(), Ok(Method::Head));
+    }
+
+    #[test]
+    fn test_match_header() {
+        let request = HttpRequestBuilder::new()
+            .match_header("Server", "^cloudflare$")
+            .build()
+            .unwrap();
+        assert!(request.is_valid_response("HTTP/1.1 200 OK\r\nserver: cloudflare\r\n\r\n"));
+        assert!(!request.is_valid_response("HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n"));
+        // 只看响应头,不看响应体
+        assert!(!request.is_valid_response("HTTP/1.1 200 OK\r\n\r\nServer: cloudflare"));
+        assert!(HttpRequest::default().is_valid_response("HTTP/1.0 404 Not Found\r\n\r\n"));
+    }
 }
@@ -0,0 +1,621 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Language of user-facing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    En,
+    Zh,
+}
+
+impl FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "en" => Ok(Lang::En),
+            "zh" | "cn" => Ok(Lang::Zh),
+            _ => Err(format!("unknown language: {} (expected en|zh)", s)),
+        }
+    }
+}
+
+// 当前语言,进程内全局生效
+static LANG: AtomicU8 = AtomicU8::new(0);
+
+/// Set the language used by `tr` and `trf`
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+/// The language currently in use
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::Zh,
+        _ => Lang::En,
+    }
+}
+
+/// Guess the language from the `LC_ALL`/`LANG` environment variables
+pub fn detect_lang() -> Lang {
+    let locale = std::env::var("LC_ALL")
+        .or_else(|_| std::env::var("LANG"))
+        .unwrap_or_default();
+    if locale.to_lowercase().starts_with("zh") {
+        Lang::Zh
+    } else {
+        Lang::En
+    }
+}
+
+/// Keys of all translated messages. `{}` placeholders are filled by `trf` in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msg {
+    NoIpsResolved,
+    CannotEnterNetns,
+    CannotBindProgressSocket,
+    CannotStartWebUi,
+    WebUiListening,
+    WebUiWaiting,
+    ScanningInterface,
+    NextRunAt,
+    ScheduleNeverMatches,
+    DownloadDisabled,
+    CannotWriteResult,
+    CannotGetDownloadHost,
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    NoMeasurableIp,
+    TooManyOpenFiles,
+    Finished,
+    ProgressAddr,
+    ProgressLine,
+    ProgressRemaining,
+    StageEstimate,
+    DownloadEstimate,
+    HttpingSummary,
+    RoutesSummary,
+    DownloadResults,
+    TcpResults,
+    RouteResults,
+    UplinkResults,
+    ScanningPort,
+    PortResults,
+    PortMatrixResults,
+    PortsUnreachable,
+    Port,
+    IpAddress,
+    DownloadSpeed,
+    Sent,
+    Received,
+    Loss,
+    AvgDelay,
+    MinMaxDelay,
+    Jitter,
+    Status,
+    Location,
+    CannotOpenHistory,
+    CannotWriteHistory,
+    CannotWriteReport,
+    ReportTitle,
+    InterferenceSuspected,
+    SizeSweepResults,
+    FlowResults,
+    CacheProbeResults,
+    ConnectHandshake,
+    CacheWarmResults,
+    CannotLoadWarmUrls,
+    CreatedSigningKey,
+    CannotReadIpList,
+    SkipPingWithoutIps,
+    SkippedLatencyStage,
+    KeepAliveResults,
+    KeepAliveSetup,
+    KeepAliveFirst,
+    KeepAliveSteady,
+    KeepAliveDone,
+    KeepAliveLatencies,
+    CannotReadImport,
+    ImportSummary,
+    AverageTtfb,
+    HttpResults,
+    HttpSteps,
+    Ttfb,
+    CannotWriteAttestation,
+    CannotReadAttestation,
+    AttestationValid,
+    AttestationInvalid,
+    CannotLoadJobs,
+    InvalidJob,
+    RunningJob,
+    CannotLoadPipeline,
+    SynScanUnavailable,
+    InvalidPipelineStep,
+    PipelineStep,
+    CachedStage,
+    CannotWriteCache,
+    InvalidHttpRequest,
+    FeatureDisabled,
+    CannotReadResult,
+    CannotSaveResponses,
+    CannotReadResponses,
+    ReplaySummary,
+    SkippedDeadSubnets,
+    StoppedAfter,
+    FingerprintClusters,
+    CalibratedOverhead,
+    CalibrationFailed,
+    ConfigMismatch,
+    NoConfigFingerprint,
+    DelayBefore,
+    DelayAfter,
+    SpeedBefore,
+    SpeedAfter,
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    ProviderUpdated,
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    CannotUpdateProvider,
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    ProvidersNeedCache,
+    CannotResolveHost,
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    CannotFetchPrefixes,
+    CannotApplySocketOptions,
+    NeedsCapability,
+    InvalidInputLines,
+    ResumingScan,
+    MaxDurationReached,
+    RetestingBorderline,
+    CheckpointMismatch,
+    CannotSaveCheckpoint,
+    CannotSpillResults,
+    MemoryPressure,
+    ErrorBudgetExceeded,
+    OnlyConnectScanner,
+    RetestWithSpill,
+    ControlReachable,
+    ControlUnreachable,
+    MoreInvalidLines,
+    StrictInput,
+    KeepWarmResult,
+    KeepWarmOpenFailed,
+    IdleAlive,
+    IdleClosed,
+    IdleReset,
+    IdleSilent,
+    SizeSlope,
+    FlowSpread,
+    FlowStable,
+    FlowUnstable,
+    CacheFirstRequest,
+    CacheSecondRequest,
+    CacheMissPenalty,
+    LatencyRegression,
+    IpUnreachable,
+    LatencyRecovered,
+}
+
+impl Msg {
+    fn texts(self) -> (&'static str, &'static str) {
+        match self {
+            Msg::NoIpsResolved => (
+                "No IPs could be resolved, aborting scan.\n Please check arguments: {}",
+                "无法解析出任何 IP,已中止测试。\n 请检查参数: {}",
+            ),
+            Msg::CannotEnterNetns => (
+                "Cannot enter network namespace {}\nError message: {}",
+                "无法进入网络命名空间 {}\n错误信息: {}",
+            ),
+            Msg::CannotBindProgressSocket => (
+                "Cannot listen on progress socket {}\nError message: {}",
+                "无法监听进度 socket {}\n错误信息: {}",
+            ),
+            Msg::CannotStartWebUi => (
+                "Cannot start web ui on {}\nError message: {}",
+                "无法在 {} 启动网页界面\n错误信息: {}",
+            ),
+            Msg::WebUiListening => ("Web ui: http://{}/", "网页界面: http://{}/"),
+            Msg::WebUiWaiting => (
+                "All tests finished, web ui is still available. Press Ctrl-C to exit.",
+                "全部测试已完成,网页界面仍可访问。按 Ctrl-C 退出。",
+            ),
+            Msg::NextRunAt => ("Next run at {}", "下次运行时间: {}"),
+            Msg::ScheduleNeverMatches => (
+                "The schedule never matches any time (inside --window, if given), exiting.",
+                "定时表达式不会匹配任何时间(或不会落在 --window 窗口内),正在退出。",
+            ),
+            Msg::ScanningInterface => ("Scanning through {}", "正在通过 {} 测试"),
+            Msg::DownloadDisabled => (
+                "Disable download speed test.exiting...",
+                "未启用下载测速,正在退出...",
+            ),
+            Msg::CannotWriteResult => (
+                "Warn: Cannot write result to {}\nError message:{}",
+                "警告: 无法写入结果到 {}\n错误信息: {}",
+            ),
+            Msg::CannotGetDownloadHost => (
+                "Cannot get host for speed test url;\nError message: {}",
+                "无法从测速地址中获取域名;\n错误信息: {}",
+            ),
+            Msg::NoMeasurableIp => ("No measureable IP addresss", "没有可测速的 IP 地址"),
+            Msg::TooManyOpenFiles => (
+                "Too many open files. Please reduce batch size\nPlease try to reduce this value and then try to run again.",
+                "打开的文件过多,请减小并发数后重新运行。",
+            ),
+            Msg::Finished => ("finshed", "完成"),
+            Msg::ProgressAddr => ("Addr: {}", "地址: {}"),
+            Msg::ProgressLine => ("[{}] {}/{} ({}%) {}s", "[{}] {}/{} ({}%) {}秒"),
+            Msg::ProgressRemaining => (", ~{} left", ",剩余约 {}"),
+            Msg::StageEstimate => (
+                "Estimate: {} of {} targets takes at most {}",
+                "预计: {} 测试 {} 个目标最多需要 {}",
+            ),
+            Msg::DownloadEstimate => (
+                "Estimate: download of {} IPs takes about {}",
+                "预计: 下载测速 {} 个 IP 约需 {}",
+            ),
+            Msg::HttpingSummary => (
+                "total: {} \t good: {} \t bad: {}",
+                "总数: {} \t 正常: {} \t 异常: {}",
+            ),
+            Msg::RoutesSummary => (
+                "vaild: {} \t empty: {} \t diff: {}",
+                "有效: {} \t 无地区码: {} \t 地区不一致: {}",
+            ),
+            Msg::DownloadResults => ("Download speed test results:", "下载测速结果:"),
+            Msg::TcpResults => ("TCP scan results:", "TCP 延迟测试结果:"),
+            Msg::RouteResults => ("HTTP routing check results:", "HTTP 路由检测结果:"),
+            Msg::UplinkResults => ("Uplink comparison results:", "多出口对比结果:"),
+            Msg::ScanningPort => ("Testing port {}", "正在测试端口 {}"),
+            Msg::PortResults => ("Results per port:", "各端口测试结果:"),
+            Msg::PortMatrixResults => (
+                "Port reachability matrix (delay in ms, - is unreachable):",
+                "端口可达矩阵 (延迟 ms, - 为不可达):",
+            ),
+            Msg::PortsUnreachable => (
+                "{} more ports are unreachable on every IP",
+                "另有 {} 个端口在所有 IP 上都不可达",
+            ),
+            Msg::Port => ("Port", "端口"),
+            Msg::IpAddress => ("IP Address", "IP 地址"),
+            Msg::DownloadSpeed => ("Download Speed (MB/s)", "下载速度 (MB/s)"),
+            Msg::Sent => ("Sent", "已发送"),
+            Msg::Received => ("Received", "已接收"),
+            Msg::Loss => ("Loss", "丢包率"),
+            Msg::AvgDelay => ("Avg Delay (ms)", "平均延迟 (ms)"),
+            Msg::MinMaxDelay => ("Min/Max (ms)", "最小/最大 (ms)"),
+            Msg::Jitter => ("Jitter (ms)", "抖动 (ms)"),
+            Msg::Status => ("Status", "状态"),
+            Msg::Location => ("Location", "地区"),
+            Msg::CannotOpenHistory => (
+                "Cannot open history database {}\nError message: {}",
+                "无法打开历史数据库 {}\n错误信息: {}",
+            ),
+            Msg::CannotWriteHistory => (
+                "Warn: Cannot record results into history database {}\nError message: {}",
+                "警告: 无法将结果记录到历史数据库 {}\n错误信息: {}",
+            ),
+            Msg::CannotWriteReport => (
+                "Cannot write report to {}\nError message: {}",
+                "无法写入报告到 {}\n错误信息: {}",
+            ),
+            Msg::LatencyRegression => (
+                "ALERT: {} delay regressed to {} ms (baseline {} ms)",
+                "告警: {} 延迟升高到 {} ms (基线 {} ms)",
+            ),
+            Msg::IpUnreachable => (
+                "ALERT: {} keeps failing (baseline {} ms)",
+                "告警: {} 持续不可用 (基线 {} ms)",
+            ),
+            Msg::LatencyRecovered => (
+                "RECOVERED: {} delay back to {} ms (baseline {} ms)",
+                "已恢复: {} 延迟回落到 {} ms (基线 {} ms)",
+            ),
+            Msg::InterferenceSuspected => (
+                "Interference suspected on {} IPs (connected, then reset during TLS/HTTP): {}",
+                "{} 个 IP 疑似受到干扰 (已连接但 TLS/HTTP 握手被重置): {}",
+            ),
+            Msg::SizeSweepResults => ("Payload size sweep results:", "负载大小扫描结果:"),
+            Msg::SizeSlope => ("Slope (ms/KB)", "斜率 (ms/KB)"),
+            Msg::FlowResults => ("Per-flow latency results:", "各流延迟结果:"),
+            Msg::CacheProbeResults => ("CDN cache probe results (status age TTFB):", "CDN 缓存探测结果 (状态 Age 首字节时间):"),
+            Msg::ConnectHandshake => ("Connect/TLS (ms)", "连接/握手 (ms)"),
+            Msg::CacheWarmResults => (
+                "Cache warming results (warmed URLs, status/cache status per URL):",
+                "缓存预热结果 (成功的 URL 数, 每个 URL 的状态码/缓存状态):",
+            ),
+            Msg::CannotLoadWarmUrls => (
+                "Cannot load URLs to warm from {}\nError message: {}",
+                "无法从 {} 读取要预热的 URL\n错误信息: {}",
+            ),
+            Msg::AverageTtfb => (
+                "Average time to first byte of the good responses: {} ms",
+                "正常响应的平均首字节时间: {} ms",
+            ),
+            Msg::HttpResults => ("HTTP check results:", "HTTP 检测结果:"),
+            Msg::Ttfb => ("TTFB (ms)", "首字节时间 (ms)"),
+            Msg::HttpSteps => ("Connect/Send/Recv (ms)", "连接/发送/接收 (ms)"),
+            Msg::CannotReadImport => (
+                "Cannot read CloudflareSpeedTest result {}\nError message: {}",
+                "无法读取 CloudflareSpeedTest 结果 {}\n错误信息: {}",
+            ),
+            Msg::ImportSummary => (
+                "{} IPs imported, {} kept, ranked by {}",
+                "导入 {} 个 IP, 保留 {} 个, 按 {} 排序",
+            ),
+            Msg::KeepAliveResults => (
+                "Keep-alive results ({} requests over one connection, ms):",
+                "长连接测试结果 (同一连接上的 {} 个请求, ms):",
+            ),
+            Msg::KeepAliveSetup => ("Setup", "建立连接"),
+            Msg::KeepAliveFirst => ("First", "首个请求"),
+            Msg::KeepAliveSteady => ("Steady", "后续平均"),
+            Msg::KeepAliveDone => ("Done", "完成"),
+            Msg::KeepAliveLatencies => ("Per request", "每个请求"),
+            Msg::CannotReadIpList => (
+                "Cannot read the IPs of {}\nError message: {}",
+                "无法读取 {} 中的 IP\n错误信息: {}",
+            ),
+            Msg::SkipPingWithoutIps => (
+                "--skip-ping needs the IPs to test from --ips-from",
+                "--skip-ping 需要用 --ips-from 指定要测速的 IP",
+            ),
+            Msg::SkippedLatencyStage => (
+                "Skipped the latency test, testing the download speed of {} IPs from {}",
+                "跳过延迟测试, 对 {} 个 IP (来自 {}) 测速",
+            ),
+            Msg::CreatedSigningKey => (
+                "Created signing key {} with public key {}",
+                "已创建签名密钥 {}, 公钥为 {}",
+            ),
+            Msg::CannotWriteAttestation => (
+                "Cannot write signed summary {}\nError message: {}",
+                "无法写入签名摘要 {}\n错误信息: {}",
+            ),
+            Msg::CannotReadAttestation => (
+                "Cannot read signed summary {}\nError message: {}",
+                "无法读取签名摘要 {}\n错误信息: {}",
+            ),
+            Msg::AttestationValid => (
+                "Valid: {} results measured by version {} from {} to {}, config {}, public key {}",
+                "验证通过: {} 个结果, 由版本 {} 在 {} 至 {} 测得, 配置 {}, 公钥 {}",
+            ),
+            Msg::AttestationInvalid => ("Invalid: {}", "验证失败: {}"),
+            Msg::CacheFirstRequest => ("First request", "第一次请求"),
+            Msg::CacheSecondRequest => ("Second request", "第二次请求"),
+            Msg::CacheMissPenalty => ("Miss penalty (ms)", "未命中代价 (ms)"),
+            Msg::FlowSpread => ("Spread", "差值"),
+            Msg::FlowStable => ("stable", "稳定"),
+            Msg::FlowUnstable => ("unstable (ECMP?)", "不稳定 (ECMP?)"),
+            Msg::KeepWarmResult => (
+                "Idle connection to {} after {}s: {} (survived {}/{})",
+                "到 {} 的空闲连接在 {} 秒后: {} (存活 {}/{})",
+            ),
+            Msg::KeepWarmOpenFailed => (
+                "Warn: Cannot open idle connection to {}\nError message: {}",
+                "警告: 无法建立到 {} 的空闲连接\n错误信息: {}",
+            ),
+            Msg::IdleAlive => ("alive", "存活"),
+            Msg::IdleClosed => ("closed by server", "被服务端关闭"),
+            Msg::IdleReset => ("reset", "被重置"),
+            Msg::IdleSilent => ("silently dropped", "被静默丢弃"),
+            Msg::CannotLoadJobs => (
+                "Cannot load jobs from {}\nError message: {}",
+                "无法从 {} 读取任务\n错误信息: {}",
+            ),
+            Msg::InvalidJob => (
+                "Warn: Skip job {}\nError message: {}",
+                "警告: 跳过任务 {}\n错误信息: {}",
+            ),
+            Msg::RunningJob => ("Running job {}", "正在运行任务 {}"),
+            Msg::SynScanUnavailable => (
+                "Warn: Cannot send raw SYN probes, using tcp connects instead\nError message: {}",
+                "警告: 无法发送原始 SYN 探测包,改用 tcp 连接测试\n错误信息: {}",
+            ),
+            Msg::CannotLoadPipeline => (
+                "Cannot load the pipeline from {}\nError message: {}",
+                "无法从 {} 读取流水线\n错误信息: {}",
+            ),
+            Msg::InvalidPipelineStep => (
+                "Invalid options of pipeline step {}\nError message: {}",
+                "流水线步骤 {} 的选项无效\n错误信息: {}",
+            ),
+            Msg::PipelineStep => ("Pipeline step {} on {} IPs", "流水线步骤 {},共 {} 个IP"),
+            Msg::CachedStage => (
+                "Reusing cached {} results for {} IPs",
+                "复用 {} 阶段缓存结果,共 {} 个IP",
+            ),
+            Msg::FeatureDisabled => (
+                "This build does not support these options, rebuild with the '{}' feature",
+                "当前构建不支持这些选项,请启用 '{}' 特性后重新编译",
+            ),
+            Msg::CannotSaveResponses => (
+                "Cannot open response file {}\nError message: {}",
+                "无法打开响应文件 {}\n错误信息: {}",
+            ),
+            Msg::CannotReadResponses => (
+                "Cannot read saved responses {}\nError message: {}",
+                "无法读取保存的响应 {}\n错误信息: {}",
+            ),
+            Msg::StoppedAfter => (
+                "Found {} IPs that pass the filters, stopped the latency test early",
+                "已找到 {} 个符合条件的 IP, 提前结束延迟测试",
+            ),
+            Msg::SkippedDeadSubnets => (
+                "Skipped {} IPs in {} subnets where the first {} IPs tested did not answer",
+                "跳过了 {} 个 IP, 它们所在的 {} 个子网中最先测试的 {} 个 IP 都没有回应",
+            ),
+            Msg::FingerprintClusters => (
+                "{} distinct response fingerprints among {} responses (hash, count, status [server], IPs):",
+                "{} 种不同的响应指纹, 共 {} 个响应 (哈希, IP 数, 状态 [服务器], IP):",
+            ),
+            Msg::ReplaySummary => (
+                "{} responses, {} match the rules (the run that saved them: {})",
+                "共 {} 个响应, {} 个符合规则 (保存时: {})",
+            ),
+            Msg::CannotReadResult => (
+                "Cannot read result file {}\nError message: {}",
+                "无法读取结果文件 {}\n错误信息: {}",
+            ),
+            Msg::CalibratedOverhead => (
+                "Calibrated probe overhead: {} ms, subtracted from every delay",
+                "校准的探测开销: {} ms,已从每个延迟中扣除",
+            ),
+            Msg::CalibrationFailed => (
+                "Warn: Cannot calibrate the probe overhead, delays are not corrected\nError message: {}",
+                "警告: 无法校准探测开销,延迟未作修正\n错误信息: {}",
+            ),
+            Msg::ConfigMismatch => (
+                "WARNING: the two results were measured with different settings ({} vs {}), \
+                 differences below may come from the settings rather than the network:",
+                "警告: 两次结果的测试设置不同 ({} 与 {}),下面的差异可能来自设置而不是网络:",
+            ),
+            Msg::NoConfigFingerprint => (
+                "WARNING: a result file has no settings line, cannot tell whether the runs are comparable",
+                "警告: 有结果文件缺少设置行,无法判断两次测试是否可比",
+            ),
+            Msg::DelayBefore => ("Before(ms)", "之前(ms)"),
+            Msg::DelayAfter => ("After(ms)", "之后(ms)"),
+            Msg::SpeedBefore => ("Before(MB/s)", "之前(MB/s)"),
+            Msg::SpeedAfter => ("After(MB/s)", "之后(MB/s)"),
+            Msg::ProviderUpdated => (
+                "Updated {}: {} address ranges saved to {}",
+                "已更新 {}: {} 个地址段保存到 {}",
+            ),
+            Msg::CannotUpdateProvider => (
+                "Warn: Cannot update {}, keeping the current list\nError message: {}",
+                "警告: 无法更新 {},继续使用当前列表\n错误信息: {}",
+            ),
+            Msg::CannotResolveHost => (
+                "Warn: Cannot resolve {}, skipping it\nError message: {}",
+                "警告: 无法解析 {},已跳过\n错误信息: {}",
+            ),
+            Msg::CannotFetchPrefixes => (
+                "Warn: Cannot fetch the prefixes announced by {}, skipping it\nError message: {}",
+                "警告: 无法获取 {} 宣告的前缀,已跳过\n错误信息: {}",
+            ),
+            Msg::CannotApplySocketOptions => (
+                "Cannot apply --interface/--fwmark to the probe sockets\nError message: {}",
+                "无法在探测 socket 上应用 --interface/--fwmark\n错误信息: {}",
+            ),
+            Msg::NeedsCapability => (
+                "Hint: this needs {}, run as root or grant it once with: sudo setcap {}+ep {}",
+                "提示: 需要 {} 权限,请以 root 运行,或授予一次: sudo setcap {}+ep {}",
+            ),
+            Msg::MaxDurationReached => (
+                "Stopped the latency test at --max-duration, continuing with the {} usable IPs found",
+                "已到 --max-duration 时间上限,停止延迟测试,继续使用已找到的 {} 个可用 IP",
+            ),
+            Msg::RetestingBorderline => (
+                "Re-testing {} IPs within --retest-margin of --au/--al",
+                "重新测试 {} 个在 --au/--al 的 --retest-margin 范围内的 IP",
+            ),
+            Msg::ResumingScan => (
+                "Resuming the saved scan: {} of {} IPs already tested",
+                "继续保存的测试: {} / {} 个 IP 已测试",
+            ),
+            Msg::CheckpointMismatch => (
+                "Warn: {} was saved by a run with other targets or settings, starting over",
+                "警告: {} 由目标或设置不同的测试保存,重新开始",
+            ),
+            Msg::CannotSaveCheckpoint => (
+                "Warn: Cannot save the progress to {}\nError message: {}",
+                "警告: 无法保存进度到 {}\n错误信息: {}",
+            ),
+            Msg::ErrorBudgetExceeded => (
+                "Warn: {}% of the last {} targets failed, pausing to check the control target {}",
+                "警告: 最近测试的目标有 {}% 失败(共 {} 个),暂停测试并检查对照目标 {}",
+            ),
+            Msg::OnlyConnectScanner => (
+                "{} only works with the tcp connect scan, not with {}",
+                "{} 只能用于 tcp 连接扫描,不能和 {} 一起使用",
+            ),
+            Msg::RetestWithSpill => (
+                "--retest-margin cannot be used with {}: the results spilled to disk are not re-tested",
+                "--retest-margin 不能和 {} 一起使用: 换出到磁盘的结果不会再测",
+            ),
+            Msg::ControlReachable => (
+                "{} is reachable, the failures come from the targets. Resuming",
+                "{} 可以连接,失败来自测试目标本身,继续测试",
+            ),
+            Msg::ControlUnreachable => (
+                "Error: {} is unreachable too. The local network seems down, or a captive portal or \
+                 firewall blocks outgoing connections. Aborting (with --resume the progress is kept)",
+                "错误: {} 也无法连接,本地网络可能已断开,或被认证页面、防火墙拦截了连接。退出测试(使用 --resume 时保留进度)",
+            ),
+            Msg::CannotSpillResults => (
+                "Warn: Cannot write results to {}, keeping them all in memory\nError message: {}",
+                "警告: 无法写入结果到 {},全部保留在内存中\n错误信息: {}",
+            ),
+            Msg::MemoryPressure => (
+                "Warn: Memory use of {} MB is near --max-memory {} MB, writing further tcping results to disk",
+                "警告: 内存占用 {} MB 接近 --max-memory {} MB,之后的 tcping 结果写入磁盘",
+            ),
+            Msg::InvalidInputLines => (
+                "Warn: Skipped {} lines that are not an IP, CIDR or range:",
+                "警告: 跳过了 {} 行不是 IP、CIDR 或范围的输入:",
+            ),
+            Msg::MoreInvalidLines => ("    ... and {} more", "    ... 还有 {} 行"),
+            Msg::StrictInput => (
+                "Stopped because of --strict-input",
+                "因为 --strict-input 而停止",
+            ),
+            Msg::ProvidersNeedCache => (
+                "'update-providers' saves the lists into the --cache directory, please set it",
+                "'update-providers' 将列表保存到 --cache 目录,请指定该选项",
+            ),
+            Msg::InvalidHttpRequest => (
+                "Invalid httping request: {}",
+                "httping 请求参数无效: {}",
+            ),
+            Msg::CannotWriteCache => (
+                "Warn: Cannot write cache to {}\nError message: {}",
+                "警告: 无法写入缓存 {}\n错误信息: {}",
+            ),
+            Msg::ReportTitle => (
+                "rustspeedtest report: {} runs since {}",
+                "rustspeedtest 报告: 共 {} 次运行,自 {} 起",
+            ),
+        }
+    }
+}
+
+/// Translate `msg` into the current language
+pub fn tr(msg: Msg) -> &'static str {
+    let (en, zh) = msg.texts();
+    match lang() {
+        Lang::En => en,
+        Lang::Zh => zh,
+    }
+}
+
+/// Translate `msg` and fill its `{}` placeholders with `args` in order
+pub fn trf(msg: Msg, args: &[&dyn fmt::Display]) -> String {
+    let mut text = tr(msg).to_string();
+    for arg in args {
+        text = text.replacen("{}", &arg.to_string(), 1);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!("zh".parse(), Ok(Lang::Zh));
+        assert!("fr".parse::<Lang>().is_err());
+
+        set_lang(Lang::En);
+        assert_eq!(
+            trf(Msg::HttpingSummary, &[&3, &2, &1]),
+            "total: 3 \t good: 2 \t bad: 1"
+        );
+        set_lang(Lang::Zh);
+        assert_eq!(tr(Msg::Loss), "丢包率");
+        set_lang(Lang::En);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    cmp::Ordering, collections::HashSet, fmt, fs, io, net::IpAddr, path::Path, str::FromStr,
+};
+
+use crate::history::Measurement;
+use crate::output::Redaction;
+
+/// What 'import' ranks the IPs by, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankBy {
+    Delay,
+    Speed,
+    Loss,
+}
+
+impl RankBy {
+    /// The speed if any IP of `measurements` has one, else the delay, as
+    /// CloudflareSpeedTest sorts its own results
+    pub fn default_for(measurements: &[Measurement]) -> Self {
+        if measurements.iter().any(|m| m.speed_mbps.is_some()) {
+            RankBy::Speed
+        } else {
+            RankBy::Delay
+        }
+    }
+}
+
+impl FromStr for RankBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(RankBy::Delay),
+            "speed" => Ok(RankBy::Speed),
+            "loss" => Ok(RankBy::Loss),
+            _ => Err(format!(
+                "unknown sort key: {} (expected delay|speed|loss)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RankBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RankBy::Delay => "delay",
+            RankBy::Speed => "speed",
+            RankBy::Loss => "loss",
+        })
+    }
+}
+
+/// Read a result.csv written by CloudflareSpeedTest
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Measurement>> {
+    parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parse a CloudflareSpeedTest result, with the Chinese header of the
+/// original ('IP 地址,已发送,已接收,丢包率,平均延迟,下载速度 (MB/s),地区码') or
+/// the English one of its forks. The columns are found by their names.
+pub fn parse(text: &str) -> Result<Vec<Measurement>, String> {
+    let mut lines = text.trim_start_matches('\u{feff}').lines();
+    let first = lines.next().unwrap_or_default();
+    let header: Vec<String> = first
+        .split(',')
+        .map(|name| name.split_whitespace().collect::<String>().to_lowercase())
+        .collect();
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|column| names.iter().any(|name| column.contains(name)))
+    };
+    let loss = column(&["丢包", "loss"]);
+    let delay = column(&["延迟", "delay", "latency"]);
+    let speed = column(&["速度", "speed"]);
+    let colo = column(&["地区", "colo", "region"]);
+    if loss.is_none() || delay.is_none() {
+        return Err(format!(
+            "not a CloudflareSpeedTest result, unknown header: {}",
+            first
+        ));
+    }
+
+    let mut measurements = Vec::new();
+    for (number, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let ip: IpAddr = fields[0]
+            .parse()
+            .map_err(|_| format!("line {}: invalid IP {}", number + 2, fields[0]))?;
+        let number = |i: Option<usize>| i.and_then(|i| fields.get(i)?.parse().ok());
+        measurements.push(Measurement {
+            ip,
+            colo: colo
+                .and_then(|i| fields.get(i))
+                .filter(|colo| !colo.is_empty() && **colo != "N/A")
+                .map(|colo| colo.to_string()),
+            delay_ms: number(delay),
+            loss: number(loss),
+            speed_mbps: number(speed),
+        });
+    }
+    Ok(measurements)
+}
+
+/// The IPs of a result file of this tool or of CloudflareSpeedTest, or of a
+/// list with one IP per line, in their order and without duplicates. Lines
+/// that do not start with an IP, like headers and comments, are skipped.
+pub fn load_ips<P: AsRef<Path>>(path: P) -> io::Result<Vec<IpAddr>> {
+    let ips = parse_ips(&fs::read_to_string(path)?);
+    if ips.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no IP found"));
+    }
+    Ok(ips)
+}
+
+fn parse_ips(text: &str) -> Vec<IpAddr> {
+    let mut seen = HashSet::new();
+    text.lines()
+        .filter_map(|line| line.split(',').next()?.trim().parse::<IpAddr>().ok())
+        .filter(|ip| seen.insert(*ip))
+        .collect()
+}
+
+/// Sort `measurements` best first by `by`; the ones without that value last
+pub fn rank(measurements: &mut [Measurement], by: RankBy) {
+    measurements.sort_by(|a, b| match by {
+        RankBy::Delay => ascending(a.delay_ms, b.delay_ms).then(ascending(a.loss, b.loss)),
+        RankBy::Speed => ascending(a.speed_mbps.map(|s| -s), b.speed_mbps.map(|s| -s))
+            .then(ascending(a.delay_ms, b.delay_ms)),
+        RankBy::Loss => ascending(a.loss, b.loss).then(ascending(a.delay_ms, b.delay_ms)),
+    });
+}
+
+fn ascending(a: Option<f64>, b: Option<f64>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// `measurements` in the result csv format of this tool, as read by 'compare',
+/// with the IPs masked by `redaction`
+pub fn to_csv(measurements: &[Measurement], redaction: Redaction) -> String {
+    let show = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+    let mut csv = String::from("IP,Loss,Delay(ms),Speed(MB/s),Colo\n");
+    for m in measurements {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            redaction.apply(&m.ip),
+            show(m.loss),
+            show(m.delay_ms),
+            show(m.speed_mbps),
+            m.colo.as_deref().unwrap_or_default()
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::ResultFile;
+
+    const RESULT: &str = "\u{feff}IP 地址,已发送,已接收,丢包率,平均延迟,下载速度 (MB/s),地区码
+104.16.1.1,4,4,0.00,150.25,12.50,LAX
+104.16.1.2,4,3,0.25,120.00,0.00,N/A
+104.16.1.3,4,4,0.00,130.50,20.10,
+";
+
+    #[test]
+    fn test_parse() {
+        let measurements = parse(RESULT).unwrap();
+        assert_eq!(measurements.len(), 3);
+        assert_eq!(
+            measurements[0],
+            Measurement {
+                ip: "104.16.1.1".parse().unwrap(),
+                colo: Some("LAX".to_string()),
+                delay_ms: Some(150.25),
+                loss: Some(0.0),
+                speed_mbps: Some(12.5),
+            }
+        );
+        assert_eq!(measurements[1].colo, None);
+        assert_eq!(measurements[2].colo, None);
+
+        // 英文表头,没有下载速度
+        let english = "IP Address,Sent,Received,Packet Loss,Average Delay\n1.1.1.1,4,4,0.00,80.5\n";
+        let measurements = parse(english).unwrap();
+        assert_eq!(measurements[0].delay_ms, Some(80.5));
+        assert_eq!(measurements[0].speed_mbps, None);
+        assert_eq!(RankBy::default_for(&measurements), RankBy::Delay);
+
+        assert!(parse("IP,Delay(ms)\n").is_err());
+        assert!(
+            parse("IP 地址,已发送,已接收,丢包率,平均延迟\nnot-an-ip,4,4,0,1\n")
+                .unwrap_err()
+                .starts_with("line 2")
+        );
+    }
+
+    #[test]
+    fn test_rank() {
+        let ips = |measurements: &[Measurement]| -> Vec<String> {
+            measurements.iter().map(|m| m.ip.to_string()).collect()
+        };
+        let mut measurements = parse(RESULT).unwrap();
+        assert_eq!(RankBy::default_for(&measurements), RankBy::Speed);
+        rank(&mut measurements, RankBy::Speed);
+        assert_eq!(
+            ips(&measurements),
+            ["104.16.1.3", "104.16.1.1", "104.16.1.2"]
+        );
+        rank(&mut measurements, RankBy::Delay);
+        assert_eq!(
+            ips(&measurements),
+            ["104.16.1.2", "104.16.1.3", "104.16.1.1"]
+        );
+        rank(&mut measurements, RankBy::Loss);
+        assert_eq!(
+            ips(&measurements),
+            ["104.16.1.3", "104.16.1.1", "104.16.1.2"]
+        );
+        assert_eq!("loss".parse(), Ok(RankBy::Loss));
+        assert!("jitter".parse::<RankBy>().is_err());
+
+        // 转换后的文件可以用 compare 读取
+        let converted = ResultFile::parse(&to_csv(&measurements, Redaction::None));
+        assert!(converted.config.is_none());
+        assert_eq!(converted.rows[0].ip, "104.16.1.3");
+        assert_eq!(converted.rows[0].delay_ms, Some(130.5));
+        assert_eq!(converted.rows[0].speed, Some(20.1));
+        let redacted = ResultFile::parse(&to_csv(&measurements, Redaction::LastOctet));
+        assert_eq!(redacted.rows[0].ip, "104.16.1.x");
+    }
+
+    #[test]
+    fn test_parse_ips() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            parse_ips(RESULT),
+            vec![ip("104.16.1.1"), ip("104.16.1.2"), ip("104.16.1.3")]
+        );
+        // 本工具的结果文件和逐行的列表,保持顺序并去重
+        let ours = "# rustspeedtest config=0 stage=tcping\nIP,Loss,Delay(ms)\n1.0.0.2,0.0,10\n1.0.0.1,0.0,12\n";
+        assert_eq!(parse_ips(ours), vec![ip("1.0.0.2"), ip("1.0.0.1")]);
+        assert_eq!(
+            parse_ips("2606:4700::1\n1.1.1.1\n\n2606:4700::1\n"),
+            vec![ip("2606:4700::1"), ip("1.1.1.1")]
+        );
+        assert!(parse_ips("1.1.1.0/24\n").is_empty());
+    }
+}
@@ -1,19 +1,60 @@
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
 use structopt::StructOpt;
+use url::Url;
+
+use crate::budget::parse_error_budget;
+use crate::dns::{self, DnsResolver};
+use crate::export::{Format as ExportFormat, Template};
+use crate::filter::{Filter, Group};
+use crate::providers::{Provider, Source};
+use crate::httping::Method;
+use crate::i18n::Lang;
+use crate::import::RankBy;
+use crate::output::Redaction;
+use crate::probe::Ports;
+use crate::report::ReportFormat;
+use crate::rtt::parse_factor;
+use crate::scanner::{DelayKey, LatencyMetric};
+use crate::schedule::{Schedule, TimeWindow};
+use crate::udping::UdpPayload;
+use crate::utils::{parse_duration, parse_fraction};
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "rustspeedtest",setting = structopt::clap::AppSettings::TrailingVarArg)]
 pub struct Opts {
     /// The number of threads for speedtest. More threads mean faster speedtest, but may not be suitable for weak devices (e.g. routers). (max: ulimit -n)
+    /// Overridden per stage by --tcping-concurrency and --httping-concurrency.
     #[structopt(short = "n", long, default_value = "200")]
     pub number: usize,
 
+    /// The number of IPs the tcping stage tests at once (also --port-matrix). -n when not set.
+    #[structopt(long)]
+    pub tcping_concurrency: Option<usize>,
+
+    /// The number of IPs the httping and cfhttping stages test at once. Every test is a TLS session,
+    /// so this is usually far lower than for tcping. -n when not set.
+    #[structopt(long)]
+    pub httping_concurrency: Option<usize>,
+
+    /// The number of IPs the download speed test measures at once. They share the local bandwidth,
+    /// so only raise this when it is well above the speed of a single IP.
+    #[structopt(long, default_value = "1")]
+    pub download_concurrency: usize,
+
     /// The number of delay times for speedtest. The number of times to delay test a single IP.
     #[structopt(long, default_value = "4")]
     pub time: u8,
 
-    /// The port to use for delay test.
+    /// The ports to use for delay test, e.g. '443,2053,8443' or '8440-8450'. With several ports every IP is
+    /// tested on each and the results are listed per IP and port, without the download speed test.
     #[structopt(short = "p", long, default_value = "443")]
-    pub port: u16,
+    pub port: Ports,
+
+    /// Test every IP on every --port at once and write a matrix of the delay per port and IP instead
+    /// of ranking the IPs. Meant for a few IPs over a long port list, e.g. '-p 1-10000 --port-matrix'.
+    #[structopt(long)]
+    pub port_matrix: bool,
 
     /// The number of results to display. The number of results to display after speedtest, set to 0 to not display results and exit directly.
     #[structopt(short = "d", long, default_value = "10")]
@@ -31,6 +72,16 @@ pub struct Opts {
     #[structopt(short, long)]
     pub enable_download: bool,
 
+    /// Skip the latency stage and only run the download speed test, on the IPs of --ips-from in the
+    /// order of the file, e.g. the ones another tool selected. Implies --enable-download.
+    #[structopt(long)]
+    pub skip_ping: bool,
+
+    /// Also test the IPs listed in this file: a result CSV of this tool or of CloudflareSpeedTest, or
+    /// one IP per line. Lines that do not start with an IP are skipped.
+    #[structopt(long, parse(from_os_str))]
+    pub ips_from: Option<PathBuf>,
+
     /// The number of download speed test. 0 is all test.
     #[structopt(long, default_value = "10")]
     pub download_number: usize,
@@ -39,6 +90,11 @@ pub struct Opts {
     #[structopt(long, default_value = "443")]
     pub download_port: u16,
 
+    /// How host names given with '-a' or met by the download speed test (e.g. redirects) are resolved:
+    /// 'system' for the C library resolver, or a DNS server IP[:PORT] for the built-in one.
+    #[structopt(long, alias = "resolver", default_value = dns::DEFAULT_RESOLVER)]
+    pub dns: DnsResolver,
+
     /// Random count of IPs to test for all CIDR. 0 is all.
     #[structopt(short = "rn", long, default_value = "0")]
     pub random_number: usize,
@@ -51,6 +107,32 @@ pub struct Opts {
     #[structopt(long, default_value = "0")]
     pub al: u128,
 
+    /// The jitter upper limit to filter the IPs, unit is ms. The jitter is the mean difference between
+    /// consecutive delay samples of an IP (see --time).
+    #[structopt(long)]
+    pub max_jitter: Option<u64>,
+
+    /// The time to first byte upper limit of --httping, unit is ms: from the start of the connect to
+    /// the first byte of the response. Slower IPs are not valid.
+    #[structopt(long)]
+    pub max_ttfb: Option<u64>,
+
+    /// The loss rate upper limit of tcping and udping, e.g. '0.2' or '20%' of --time. Lossier IPs
+    /// are dropped right after the latency test, before the download test and the output.
+    #[structopt(long, parse(try_from_str = parse_fraction))]
+    pub max_loss: Option<f64>,
+
+    /// Test the tcping/udping IPs whose average delay is within this share (e.g. '10%') of --au or
+    /// --al once more, and keep or drop them by the average of both rounds, so that IPs close to a
+    /// limit do not flip between runs. Not with --memory-limit or --max-memory.
+    #[structopt(long, parse(try_from_str = parse_fraction))]
+    pub retest_margin: Option<f64>,
+
+    /// Once 100 IPs have answered, cut the tcping timeout down to this many times the p99 of their
+    /// delays (e.g. '3'), so dead IPs are given up on sooner. Never longer than --timeout.
+    #[structopt(long, parse(try_from_str = parse_factor))]
+    pub adaptive_timeout: Option<f64>,
+
     /// The download url for download speed test
     #[structopt(
         short = "u",
@@ -59,6 +141,11 @@ pub struct Opts {
     )]
     pub download_url: String,
 
+    /// What the delay represents (tcp|tls|http-ttfb): the tcp connect only, plus the TLS handshake,
+    /// or up to the first byte of an HTTPS response. TLS uses the host of --download-url as SNI.
+    #[structopt(long, default_value = "tcp")]
+    pub latency_metric: LatencyMetric,
+
     /// speed test timeout;
     #[structopt(long, default_value = "5")]
     pub download_timeout: u64,
@@ -67,15 +154,376 @@ pub struct Opts {
     #[structopt(short, long)]
     pub cfhttping: bool,
 
-    /// Check routes times
-    #[structopt(long,default_value = "5")]
+    /// How many requests --cfhttping sends to every IP
+    #[structopt(long,default_value = "10")]
     pub check_times:u64,
 
     /// Check http ping
     #[structopt(long)]
     pub httping: bool,
 
-    /// The files or CIDRs to process [default=ip.txt].
+    /// The HTTP method of the httping requests (get|head).
+    #[structopt(long, default_value = "get")]
+    pub http_method: Method,
+
+    /// The path of the httping requests.
+    #[structopt(long, default_value = "/")]
+    pub http_path: String,
+
+    /// An extra header of the httping requests as 'Name: value'. Can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub http_header: Vec<String>,
+
+    /// Only count an httping response whose header matches a regex, given as 'Name: regex',
+    /// e.g. 'Server: ^cloudflare$'.
+    #[structopt(long)]
+    pub http_match_header: Option<String>,
+
+    /// Append every httping response to this file as a JSON line, to try other --http-match-header
+    /// rules on it later with the 'replay' subcommand instead of scanning again.
+    #[structopt(long, parse(from_os_str))]
+    pub save_responses: Option<PathBuf>,
+
+    /// Group the httping responses by a hash of their status line and headers, leaving out the
+    /// ones that change on every response such as Date, and print the groups. IPs behind the same
+    /// configuration, or interception boxes returning the same block page, end up in one group.
+    #[structopt(long)]
+    pub fingerprint: bool,
+
+    /// Measure latency with small UDP packets to --port instead of TCP connects. By default the peer must
+    /// echo the packets back, e.g. a UDP echo service on the far end of a GRE/WireGuard tunnel.
+    #[structopt(long)]
+    pub udp: bool,
+
+    /// What every UDP probe sends (with --udp): 'echo', 'dns' (a query any DNS server on port 53 answers),
+    /// 'quic' (an Initial any QUIC server on port 443 answers) or 'hex:<bytes>' where any reply counts.
+    #[structopt(long, default_value = "echo")]
+    pub udp_payload: UdpPayload,
+
+    /// Run the tcp connects from a single epoll thread instead of one task per IP. Lighter on
+    /// small ARM/RISC-V routers. Only used with '--latency-metric tcp'.
+    #[structopt(long)]
+    pub raw_scanner: bool,
+
+    /// Measure the SYN to SYN/ACK time with raw packets instead of full tcp connects, without completing
+    /// the handshakes. Much cheaper per IP for very large sweeps. Linux and IPv4 only; needs CAP_NET_RAW,
+    /// without it the connect scan is used. Only used with '--latency-metric tcp'.
+    #[structopt(long)]
+    pub syn_scan: bool,
+
+    /// Before the tcp scan, measure this host's own per-probe overhead with connects to a local
+    /// listener and subtract it from every delay. Improves small RTTs on slow single-board computers.
+    #[structopt(long)]
+    pub calibrate: bool,
+
+    /// The gap in milliseconds between two tcp probes to the same IP. Spreads the `--time` probes
+    /// of an IP out, interleaved with other IPs, instead of sending them back to back. 0 is no gap.
+    #[structopt(long, default_value = "0")]
+    pub probe_gap: u64,
+
+    /// Retry a tcp probe this many times when it fails with a transient error (connection reset,
+    /// EAGAIN) instead of counting it as lost.
+    #[structopt(long, default_value = "0")]
+    pub retries: u8,
+
+    /// The wait in milliseconds before the first --retries retry, doubled for every retry after it.
+    #[structopt(long, default_value = "50")]
+    pub retry_backoff: u64,
+
+    /// The size in bytes of every UDP probe packet (with --udp).
+    #[structopt(long, default_value = "148")]
+    pub probe_size: usize,
+
+    /// The interval in milliseconds between UDP probe packets to the same IP (with --udp).
+    #[structopt(long, default_value = "1000")]
+    pub probe_interval: u64,
+
+    /// Measure the latency of the top --display IPs at each of these payload sizes in bytes, e.g.
+    /// '--size-sweep 64,512,1400'. Uses UDP echo with --udp, otherwise padded HTTPS requests.
+    #[structopt(long, use_delimiter = true)]
+    pub size_sweep: Vec<usize>,
+
+    /// Measure the top --display IPs over this many TCP flows, each with its own fixed source
+    /// port, and report whether the latency differs between them (e.g. per-flow ECMP). 0 is off.
+    #[structopt(long, default_value = "0")]
+    pub flows: u16,
+
+    /// Open one connection to each of the top --display IPs and send this many requests of
+    /// --keepalive-url one after the other over it, reporting the latency of every request. Tells the
+    /// connection setup from the latency of the edge on an open connection, as a proxy sees it. 0 is off.
+    #[structopt(long, default_value = "0")]
+    pub keepalive_requests: usize,
+
+    /// The URL requested by --keepalive-requests, with its Host and SNI.
+    #[structopt(long, default_value = "https://speed.cloudflare.com/__down?bytes=0")]
+    pub keepalive_url: Url,
+
+    /// Request this cacheable URL twice from each of the top --display IPs and report the
+    /// CF-Cache-Status (or X-Cache) and Age of both responses, whether the edge serves it from its
+    /// cache and how much slower the first request was when it missed, e.g.
+    /// '--cache-probe https://example.com/static/app.js'.
+    #[structopt(long)]
+    pub cache_probe: Option<Url>,
+
+    /// After the selection, request every URL listed in this file (one per line) through each of
+    /// the top --display IPs, with the Host and SNI of the URL, so that their edges have the objects
+    /// cached before traffic is switched to them. Bodies are read to the end within --download-timeout.
+    #[structopt(long, parse(from_os_str))]
+    pub warm_urls: Option<PathBuf>,
+
+    /// Write a summary of the results with the settings, their hash and the start and end time to
+    /// this JSON file, signed with the Ed25519 key in --sign-key. Others can check with 'verify' that
+    /// a shared list of fast IPs came from an unmodified run.
+    #[structopt(long, parse(from_os_str))]
+    pub attest: Option<PathBuf>,
+
+    /// The PKCS#8 key --attest signs with. Created, readable only by you, when it does not exist.
+    #[structopt(long, default_value = "rustspeedtest.key", parse(from_os_str))]
+    pub sign_key: PathBuf,
+
+    /// How many random addresses to probe from each IPv6 prefix too large to scan in full
+    /// (more than 65536 addresses, e.g. a /32).
+    #[structopt(long, default_value = "1024")]
+    pub ipv6_samples: usize,
+
+    /// The network interface to send probes from. Repeat it to compare the same targets across several uplinks.
+    /// Example: '--interface eth0 --interface ppp0'.
+    #[structopt(long, number_of_values = 1)]
+    pub interface: Vec<String>,
+
+    /// Set this firewall mark (SO_MARK) on all probe sockets, e.g. '--fwmark 0x100'. Needs CAP_NET_ADMIN.
+    #[structopt(long, parse(try_from_str = parse_fwmark))]
+    pub fwmark: Option<u32>,
+
+    /// Send probes from this local address, e.g. the one of a second ISP on a multi-homed host. Only
+    /// applies to targets of the same IP version. Give several ('--source-ips 192.0.2.10,198.51.100.7')
+    /// to test the same targets from each and compare them in a source x target latency matrix.
+    #[structopt(long, alias = "source-ips", use_delimiter = true)]
+    pub source_ip: Vec<IpAddr>,
+
+    /// Enter this network namespace (name under /var/run/netns or a path) before creating any socket.
+    #[structopt(long)]
+    pub netns: Option<String>,
+
+    /// Mask addresses in all results, reports, trace lines and progress events so they can be shared
+    /// (none|last-octet).
+    /// 'last-octet' hides the final IPv4 octet or everything after the /64 of IPv6.
+    #[structopt(long, default_value = "none")]
+    pub redact: Redaction,
+
+    /// Listen on this unix socket and stream newline-delimited JSON progress and result events to its clients.
+    #[structopt(long, parse(from_os_str))]
+    pub progress_socket: Option<std::path::PathBuf>,
+
+    /// When stdout is not a terminal (e.g. cron or CI logs), print the progress as a line this often
+    /// and at every 10% instead of drawing progress bars, e.g. '30s' or '1m'.
+    #[structopt(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    pub progress_interval: Duration,
+
+    /// Log every connect, request, timing and raw response for this IP to stderr in all stages, and why
+    /// it was dropped, e.g. '--trace-ip 104.16.1.1'. Can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub trace_ip: Vec<IpAddr>,
+
+    /// Serve a web ui with live progress and results on this address, e.g. '--web 127.0.0.1:8080'.
+    #[structopt(long)]
+    pub web: Option<std::net::SocketAddr>,
+
+    /// Run as a daemon and repeat all tests on this cron schedule (minute hour day month weekday, local time).
+    /// Example: '--schedule "0 3 * * *"'. A run missed while the host was suspended is caught up after wake-up.
+    #[structopt(long)]
+    pub schedule: Option<Schedule>,
+
+    /// In '--schedule' mode, only start runs inside this daily window of local time, e.g. '--window 02:00-06:00'
+    /// to keep probing and downloads to off-peak hours. Scheduled runs outside of it are skipped.
+    #[structopt(long)]
+    pub window: Option<TimeWindow>,
+
+    /// In '--schedule' mode, keep an idle HTTPS connection to the best IP open between runs and report
+    /// whether it survived, was closed, reset or silently dropped. Uses the host of --download-url as SNI.
+    #[structopt(long)]
+    pub keep_warm: bool,
+
+    /// In '--schedule' mode, a delay this many deviations above an IP's moving baseline counts as anomalous.
+    #[structopt(long, default_value = "3.0")]
+    pub alert_threshold: f64,
+
+    /// In '--schedule' mode, alert only after this many anomalous (or failed) runs of an IP in a row.
+    #[structopt(long, default_value = "3")]
+    pub alert_after: usize,
+
+    /// Language of messages and summaries (en|zh). Detected from LANG when not set.
+    #[structopt(long)]
+    pub lang: Option<Lang>,
+
+    /// Record the results of every run into this SQLite database, for use by the 'report' subcommand.
+    #[structopt(long, parse(from_os_str))]
+    pub history: Option<PathBuf>,
+
+    /// Run the jobs of this YAML file instead of a single test. Each job sets its own targets, port,
+    /// stages and output; '--concurrency' in the file limits how many run at the same time.
+    #[structopt(long, parse(from_os_str))]
+    pub jobs: Option<PathBuf>,
+
+    /// Run the steps of this YAML pipeline instead of the fixed stages, e.g. tcping, keep the best 500,
+    /// httping, keep the best 100, download test 20. The file sets the targets and shared options.
+    #[structopt(long, parse(from_os_str))]
+    pub pipeline: Option<PathBuf>,
+
+    /// Keep the results of the latency stages in this directory and reuse them while the targets and
+    /// stage options are unchanged, e.g. when only the download settings differ between runs.
+    #[structopt(long, parse(from_os_str))]
+    pub cache: Option<PathBuf>,
+
+    /// How long cached stage results stay valid (with --cache), e.g. '30m', '1h'.
+    #[structopt(long, default_value = "1h", parse(try_from_str = parse_duration))]
+    pub cache_ttl: Duration,
+
+    /// Only keep the results matching this expression over delay (ms), loss (0-1), speed (MB/s) and
+    /// colo, e.g. --where "delay < 80 && loss == 0 && speed > 5". Applies to the display, CSV and history.
+    #[structopt(long = "where")]
+    pub filter: Option<Filter>,
+
+    /// Only keep the best this many IPs of every --group-by group, e.g. '--top 3 --group-by colo' for a
+    /// failover list. Best is the fastest if the download speed test ran, else the lowest delay. 0 is all.
+    #[structopt(long, default_value = "0")]
+    pub top: usize,
+
+    /// Group the results for --top by Cloudflare colo ('colo') or by subnet (e.g. '/16'). Without it
+    /// --top keeps the best IPs overall.
+    #[structopt(long)]
+    pub group_by: Option<Group>,
+
+    /// Also write the kept IPs to this file in --export-format, interleaved round-robin
+    /// across the --group-by groups (default colo if known, else /16) for load-balancer configs.
+    #[structopt(long, parse(from_os_str))]
+    pub export: Option<PathBuf>,
+
+    /// The line written per IP by --export, e.g. 'server {addr};'. Placeholders: {ip} {port} {addr}
+    /// {index} {colo} {delay} {speed}.
+    #[structopt(long, default_value = "{ip}")]
+    pub export_template: Template,
+
+    /// Write --export as an nginx upstream block ('nginx') or HAProxy server lines ('haproxy'), weighted
+    /// 1-10 by download speed, as BIRD ('bird') or FRR ('frr') routes to the /--subnet-size subnets of
+    /// the IPs merged into the fewest CIDRs, or as --export-template lines ('template').
+    #[structopt(long, default_value = "template")]
+    pub export_format: ExportFormat,
+
+    /// The next hop (gateway IP or interface) of the routes exported by '--export-format bird|frr'.
+    /// Without it a prefix list is written instead.
+    #[structopt(long)]
+    pub route_via: Option<String>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+
+    /// Also test the edge ranges of this CDN (cloudflare|cloudfront|fastly|gcore), the addresses a
+    /// host name resolves to (see --dns), the prefixes an ASN announces (e.g. AS13335), or the lines of
+    /// stdin ('-', e.g. 'cat list.txt | rustspeedtest -a -'), repeatable.
+    /// CDN ranges come from the lists saved by 'update-providers' in the --cache directory, or else the
+    /// ones built in. ASN prefixes are fetched from RIPEstat and kept a day in the --cache directory.
+    #[structopt(short = "a", long = "add", alias = "provider", number_of_values = 1)]
+    pub sources: Vec<Source>,
+
+    /// Skip the addresses of this IP, CIDR, range or file of them, repeatable. Removed before
+    /// --random-number samples, e.g. to avoid known-bad ranges or your own infrastructure.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Save the progress of the tcping stage to this file every 30s, and if it already holds the progress
+    /// of an interrupted run with the same targets and settings, continue where that run stopped.
+    /// Removed when the stage completes. Only the tcp connect scan implements it.
+    #[structopt(long, parse(from_os_str))]
+    pub resume: Option<PathBuf>,
+
+    /// Stop the latency scan after this long, e.g. '30m', and go on to the download test and output
+    /// with the IPs tested so far. With --resume the progress is kept to continue from next time.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub max_duration: Option<Duration>,
+
+    /// Keep at most this many tcping results in memory and write the others to sorted files in the
+    /// temp directory, appended to the CSV output at the end. Only the results kept in memory go on
+    /// to the download test, display and filters. 0 keeps all in memory.
+    #[structopt(long, default_value = "0")]
+    pub memory_limit: usize,
+
+    /// Watch the resident memory (Linux only) and once it gets near this many MB, e.g. 400 on a
+    /// 512MB router, keep only the best 1000 tcping results in memory and write the others to disk
+    /// as --memory-limit does, instead of being killed mid-scan.
+    #[structopt(long)]
+    pub max_memory: Option<u64>,
+
+    /// Decimal places of the delays in the results and the CSV output, e.g. 3 for microseconds.
+    /// 0 prints whole milliseconds.
+    #[structopt(long, default_value = "0")]
+    pub latency_precision: usize,
+
+    /// Rank the tcping/udping results (loss|median|latency|min|max|streak|score). 'loss' (or
+    /// 'median') puts the most successful samples first and then the lowest median delay, 'latency'
+    /// only looks at the median. 'min', 'max' and 'streak' rank by the best sample, the worst one (IPs without
+    /// congestion spikes first) or the longest run of successes (IPs that do not fail
+    /// sporadically) after the loss. 'score' is the median plus the loss rate times --loss-weight.
+    #[structopt(long, default_value = "loss")]
+    pub sort_by: DelayKey,
+
+    /// The ms that losing all samples adds to the score of '--sort-by score', e.g. with 1000 a
+    /// 10% loss weighs like 100ms more delay.
+    #[structopt(long, default_value = "1000")]
+    pub loss_weight: f64,
+
+    /// Record every tcping/udping sample into an HDR histogram and write its percentile distribution
+    /// to this file (e.g. 'hist.hgrm', values in ms), to compare runs with HdrHistogram tools.
+    /// The latency stage is then never taken from --cache.
+    #[structopt(long, parse(from_os_str))]
+    pub latency_histogram: Option<PathBuf>,
+
+    /// Print how long every stage may take for the given targets and options, and exit without
+    /// testing. The estimates are also printed before every run.
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Pause the tcping scan when more than this share of the last 100 targets failed (e.g. '30%'),
+    /// and check that --control-target answers: resume if it does, abort if the local network
+    /// seems down or behind a captive portal. Only the tcp connect scan implements it.
+    #[structopt(long, parse(try_from_str = parse_error_budget))]
+    pub error_budget: Option<f64>,
+
+    /// A host:port that should always answer, checked when --error-budget is exceeded.
+    #[structopt(long, default_value = "1.1.1.1:443")]
+    pub control_target: std::net::SocketAddr,
+
+    /// Probe --control-target this often during the tcping scan (e.g. '10s') and write its latest
+    /// delay next to every result (Sentinel(ms)), to discount periods when the uplink was congested.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub sentinel_interval: Option<Duration>,
+
+    /// Exit instead of warning when an input file or argument has lines that are not an IP, CIDR or range.
+    #[structopt(long)]
+    pub strict_input: bool,
+
+    /// Test only this many random IPs of every /--subnet-size subnet, so that a sample spreads
+    /// evenly over all subnets instead of clustering in the largest ranges. Applied before
+    /// --random-number. 0 is off.
+    #[structopt(long, default_value = "0")]
+    pub sample_per_subnet: usize,
+
+    /// The IPv4 prefix length grouping IPs for --sample-per-subnet and the routes of
+    /// '--export-format bird|frr'. IPv6 uses the prefix 24 bits longer (/48 for /24).
+    #[structopt(long, default_value = "24", parse(try_from_str = parse_subnet_size))]
+    pub subnet_size: u8,
+
+    /// Give up on a --subnet-size subnet once the first this many IPs tcping tested in it all failed,
+    /// e.g. '--skip-dead-subnets 8'. Saves the timeouts of unannounced space. 0 is off.
+    #[structopt(long, default_value = "0")]
+    pub skip_dead_subnets: usize,
+
+    /// End the latency test as soon as this many IPs passed --au, --al and --max-jitter, dropping
+    /// the tests still running, like --download-number does for the download test. 0 is off.
+    #[structopt(long, default_value = "0")]
+    pub stop_after: usize,
+
+    /// The files, CIDRs or IP ranges (1.0.0.1-255, 1.0.0.*) to process [default=ip.txt].
     /// Example: 'rustspeedtest -n 2500 -d 20 -- 192.168.1.1/24'.
     #[structopt(last = true)]
     pub args: Vec<String>,
@@ -85,22 +533,108 @@ impl Default for Opts {
     fn default() -> Self {
         Opts {
             number: 200,
+            tcping_concurrency: None,
+            httping_concurrency: None,
             time: 4,
-            port: 443,
+            port: Ports::from(443),
+            port_matrix: false,
             display: 10,
             timeout: 9999,
             output: "result.csv".to_string(),
             enable_download: true,
+            skip_ping: false,
+            ips_from: None,
             download_port: 443,
+            dns: DnsResolver::default(),
             download_number: 10,
+            download_concurrency: 1,
             random_number: 0,
             au: 9999,
             al: 0,
+            max_jitter: None,
+            max_ttfb: None,
+            max_loss: None,
+            retest_margin: None,
+            adaptive_timeout: None,
             download_url: "https://speed.cloudflare.com/__down?bytes=200000000".to_string(),
             download_timeout: 5,
+            latency_metric: LatencyMetric::Tcp,
             cfhttping:false,
             check_times:10,
             httping:false,
+            http_method: Method::Get,
+            http_path: "/".to_string(),
+            http_header: vec![],
+            http_match_header: None,
+            save_responses: None,
+            fingerprint: false,
+            udp: false,
+            udp_payload: UdpPayload::Echo,
+            raw_scanner: false,
+            syn_scan: false,
+            calibrate: false,
+            probe_gap: 0,
+            retries: 0,
+            retry_backoff: 50,
+            probe_size: 148,
+            probe_interval: 1000,
+            size_sweep: vec![],
+            flows: 0,
+            cache_probe: None,
+            warm_urls: None,
+            keepalive_requests: 0,
+            keepalive_url: Url::parse("https://speed.cloudflare.com/__down?bytes=0")
+                .expect("valid url"),
+            attest: None,
+            sign_key: PathBuf::from("rustspeedtest.key"),
+            ipv6_samples: crate::utils::DEFAULT_IPV6_SAMPLES,
+            interface: vec![],
+            fwmark: None,
+            source_ip: vec![],
+            netns: None,
+            redact: Redaction::None,
+            lang: None,
+            progress_socket: None,
+            progress_interval: Duration::from_secs(10),
+            trace_ip: Vec::new(),
+            web: None,
+            schedule: None,
+            window: None,
+            keep_warm: false,
+            alert_threshold: 3.0,
+            alert_after: 3,
+            history: None,
+            jobs: None,
+            pipeline: None,
+            cache: None,
+            cache_ttl: Duration::from_secs(3600),
+            filter: None,
+            top: 0,
+            group_by: None,
+            export: None,
+            export_template: Template::default(),
+            export_format: ExportFormat::default(),
+            route_via: None,
+            cmd: None,
+            sources: vec![],
+            exclude: vec![],
+            resume: None,
+            max_duration: None,
+            memory_limit: 0,
+            max_memory: None,
+            latency_precision: 0,
+            sort_by: DelayKey::Loss,
+            loss_weight: 1000.0,
+            latency_histogram: None,
+            dry_run: false,
+            error_budget: None,
+            control_target: "1.1.1.1:443".parse().unwrap(),
+            sentinel_interval: None,
+            strict_input: false,
+            sample_per_subnet: 0,
+            subnet_size: 24,
+            skip_dead_subnets: 0,
+            stop_after: 0,
             args: vec![],
         }
     }
@@ -108,12 +642,356 @@ impl Default for Opts {
 
 impl Opts {
     pub fn read() -> Self {
-        let mut opts = Opts::from_args();
+        let mut opts = Opts::from_args().standalone_stage();
 
-        if opts.args.is_empty() {
+        if opts.args.is_empty() && opts.sources.is_empty() && opts.ips_from.is_none() {
             opts.args = vec!["ip.txt".to_string()];
         }
+        if opts.skip_ping {
+            opts.enable_download = true;
+        }
 
         opts
     }
+
+    /// Turn the 'httping' and 'route' subcommands into the options of a scan that
+    /// only runs that stage
+    fn standalone_stage(mut self) -> Self {
+        let (stage, route) = match self.cmd.take() {
+            Some(Command::Httping(stage)) => (stage, false),
+            Some(Command::Route(stage)) => (stage, true),
+            cmd => {
+                self.cmd = cmd;
+                return self;
+            }
+        };
+        self.httping = !route;
+        self.cfhttping = route;
+        self.udp = false;
+        self.enable_download = false;
+        self.args = stage.inputs;
+        self.output = match stage.output {
+            Some(output) => output,
+            None if route => "route.csv".to_string(),
+            None => "httping.csv".to_string(),
+        };
+        self
+    }
+
+    /// The number of IPs the latency stage chosen by the options tests at once
+    pub fn latency_concurrency(&self) -> usize {
+        let stage = if self.httping || self.cfhttping {
+            self.httping_concurrency
+        } else if self.udp {
+            None
+        } else {
+            self.tcping_concurrency
+        };
+        stage.unwrap_or(self.number)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum Command {
+    /// Summarize the recorded history into per-colo and per-IP trends.
+    /// Example: 'rustspeedtest report --since 30d --format html -o report.html'.
+    Report(ReportOpts),
+    /// Compare two result files IP by IP, warning loudly when they were measured with different
+    /// settings. Example: 'rustspeedtest compare yesterday.csv result.csv'.
+    Compare(CompareOpts),
+    /// Download the current edge ranges of the '-a' providers into the --cache directory, e.g. from
+    /// a weekly cron job. Example: 'rustspeedtest --cache ~/.cache/rustspeedtest update-providers'.
+    UpdateProviders(UpdateProvidersOpts),
+    /// Check the httping responses saved with '--save-responses' against other match rules, without
+    /// scanning again. Example: 'rustspeedtest replay responses.jsonl --http-match-header "Server: ^cloudflare$"'.
+    Replay(ReplayOpts),
+    /// Check the signature of a summary written with '--attest' and that its settings match their
+    /// hash. Example: 'rustspeedtest verify attestation.json --public-key 3b6a27bc...'.
+    Verify(VerifyOpts),
+    /// Re-rank the result.csv of CloudflareSpeedTest (the Go tool), to compare it with these results
+    /// or test its IPs again. Example: 'rustspeedtest import result.csv --top 5 --ips ips.txt'.
+    Import(ImportOpts),
+    /// Only check which IPs answer HTTP as --httping does, with the --http-* options, and write them
+    /// to their own file. Example: 'rustspeedtest --http-path /cdn-cgi/trace httping ips.txt'.
+    Httping(StageOpts),
+    /// Only check the Cloudflare colo of the IPs as --cfhttping does, and write them to their own
+    /// file. Example: 'rustspeedtest route 104.16.0.0/24 -o colos.csv'.
+    Route(StageOpts),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct StageOpts {
+    /// The files, CIDRs or IP ranges to check [default: ip.txt].
+    pub inputs: Vec<String>,
+
+    /// The file to write the results to [default: httping.csv or route.csv].
+    #[structopt(short = "o", long)]
+    pub output: Option<String>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ImportOpts {
+    /// The result.csv written by CloudflareSpeedTest.
+    #[structopt(parse(from_os_str))]
+    pub file: PathBuf,
+
+    /// Rank the IPs by delay, speed or loss [default: speed if the file has download speeds, else delay].
+    #[structopt(long)]
+    pub sort: Option<RankBy>,
+
+    /// Only keep the IPs matching an expression, as --where of a scan.
+    #[structopt(long = "where")]
+    pub filter: Option<Filter>,
+
+    /// Only keep the best this many IPs of every --group-by group. 0 is all.
+    #[structopt(long, default_value = "0")]
+    pub top: usize,
+
+    /// Group the IPs for --top by colo ('colo') or by subnet (e.g. '/16').
+    #[structopt(long)]
+    pub group_by: Option<Group>,
+
+    /// The number of IPs to display.
+    #[structopt(short = "d", long, default_value = "10")]
+    pub display: usize,
+
+    /// Write the ranked IPs to this file in the result CSV format of this tool, e.g. for 'compare'.
+    #[structopt(short = "o", long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// Write the ranked IPs one per line to this file, to test them again, e.g. only their download
+    /// speed with 'rustspeedtest -e --al 0 -- ips.txt'.
+    #[structopt(long, parse(from_os_str))]
+    pub ips: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct VerifyOpts {
+    /// The file written by '--attest'.
+    #[structopt(parse(from_os_str))]
+    pub attestation: PathBuf,
+
+    /// Only accept a summary signed with this hex encoded public key.
+    #[structopt(long)]
+    pub public_key: Option<String>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct UpdateProvidersOpts {
+    /// The providers to update [default: all].
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    pub providers: Vec<Provider>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ReplayOpts {
+    /// The file written by '--save-responses'.
+    #[structopt(parse(from_os_str))]
+    pub responses: PathBuf,
+
+    /// Only count a response whose header matches a regex, given as 'Name: regex'.
+    #[structopt(long)]
+    pub http_match_header: Option<String>,
+
+    /// The number of responses to display, matching and fastest first.
+    #[structopt(short = "d", long, default_value = "10")]
+    pub display: usize,
+
+    /// Write every replayed response to this CSV file.
+    #[structopt(short = "o", long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct CompareOpts {
+    /// The earlier result file.
+    #[structopt(parse(from_os_str))]
+    pub before: PathBuf,
+
+    /// The later result file.
+    #[structopt(parse(from_os_str))]
+    pub after: PathBuf,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ReportOpts {
+    /// Only include runs within this period before now, e.g. '12h', '7d', '2w'.
+    #[structopt(long, default_value = "30d", parse(try_from_str = parse_duration))]
+    pub since: Duration,
+
+    /// The report format (markdown|html).
+    #[structopt(long, default_value = "markdown")]
+    pub format: ReportFormat,
+
+    /// The history database written by '--history'.
+    #[structopt(long, default_value = "history.db", parse(from_os_str))]
+    pub history: PathBuf,
+
+    /// The file to write the report to. Printed to stdout when not set.
+    #[structopt(short = "o", long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// Add a table comparing every IP during this daily peak window of local time with off-peak hours,
+    /// e.g. '--peak 19:00-23:00', and flag the IPs that are only good off-peak.
+    #[structopt(long)]
+    pub peak: Option<TimeWindow>,
+
+    /// The off-peak window compared with --peak, e.g. '--off-peak 02:00-06:00'. All other times when not set.
+    #[structopt(long)]
+    pub off_peak: Option<TimeWindow>,
+
+    /// How much worse in percent the peak median delay, speed or availability of an IP may be than off-peak
+    /// before it is flagged (with --peak).
+    #[structopt(long, default_value = "50")]
+    pub peak_tolerance: u32,
+}
+
+/// Parse a firewall mark given in decimal or `0x` prefixed hex
+fn parse_fwmark(src: &str) -> Result<u32, std::num::ParseIntError> {
+    match src.strip_prefix("0x").or_else(|| src.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => src.parse(),
+    }
+}
+
+/// Parse an IPv4 prefix length for --subnet-size
+fn parse_subnet_size(src: &str) -> Result<u8, String> {
+    match src.trim_start_matches('/').parse::<u8>() {
+        Ok(prefix) if prefix <= 32 => Ok(prefix),
+        _ => Err(format!("invalid subnet size '{}', expected 0 to 32", src)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::{parse_fwmark, Command, Opts, Provider, RankBy, Source};
+
+    #[test]
+    fn test_parse_fwmark() {
+        assert_eq!(parse_fwmark("0x100"), Ok(256));
+        assert_eq!(parse_fwmark("256"), Ok(256));
+        assert!(parse_fwmark("0xZZ").is_err());
+    }
+
+    #[test]
+    fn test_source_ips() {
+        let opts = Opts::from_iter(&[
+            "rustspeedtest",
+            "--source-ips",
+            "192.0.2.10,198.51.100.7",
+            "--source-ip",
+            "::1",
+            "--",
+            "1.1.1.1",
+        ]);
+        assert_eq!(opts.source_ip.len(), 3);
+        assert_eq!(opts.args, vec!["1.1.1.1"]);
+    }
+
+    #[test]
+    fn test_size_sweep() {
+        let opts = Opts::from_iter(&["rustspeedtest", "--size-sweep", "64,512,1400"]);
+        assert_eq!(opts.size_sweep, vec![64, 512, 1400]);
+    }
+
+    #[test]
+    fn test_report_subcommand() {
+        let opts = Opts::from_iter(&["rustspeedtest", "report", "--since", "7d"]);
+        match opts.cmd {
+            Some(Command::Report(report)) => {
+                assert_eq!(report.since.as_secs(), 7 * 24 * 3600);
+                assert_eq!(report.history.to_str(), Some("history.db"));
+            }
+            _ => panic!("report subcommand not parsed"),
+        }
+
+        let opts = Opts::from_iter(&["rustspeedtest", "-n", "10", "--", "1.1.1.1"]);
+        assert!(opts.cmd.is_none());
+        assert!(opts.size_sweep.is_empty());
+        assert_eq!(opts.args, vec!["1.1.1.1"]);
+    }
+
+    #[test]
+    fn test_compare_subcommand() {
+        let opts = Opts::from_iter(&["rustspeedtest", "compare", "old.csv", "result.csv"]);
+        match opts.cmd {
+            Some(Command::Compare(compare)) => {
+                assert_eq!(compare.before.to_str(), Some("old.csv"));
+                assert_eq!(compare.after.to_str(), Some("result.csv"));
+            }
+            _ => panic!("compare subcommand not parsed"),
+        }
+    }
+
+    #[test]
+    fn test_standalone_stages() {
+        let opts = Opts::from_iter(&["rustspeedtest", "-e", "route", "1.0.0.0/24"]).standalone_stage();
+        assert!(opts.cmd.is_none());
+        assert!(opts.cfhttping && !opts.httping);
+        assert!(!opts.enable_download);
+        assert_eq!(opts.args, vec!["1.0.0.0/24"]);
+        assert_eq!(opts.output, "route.csv");
+
+        let opts = Opts::from_iter(&["rustspeedtest", "httping", "-o", "ok.csv"]).standalone_stage();
+        assert!(opts.httping && !opts.cfhttping);
+        assert!(opts.args.is_empty());
+        assert_eq!(opts.output, "ok.csv");
+
+        let opts = Opts::from_iter(&["rustspeedtest", "compare", "a.csv", "b.csv"]).standalone_stage();
+        assert!(matches!(opts.cmd, Some(Command::Compare(_))));
+    }
+
+    #[test]
+    fn test_import_subcommand() {
+        let opts = Opts::from_iter(&[
+            "rustspeedtest",
+            "import",
+            "result.csv",
+            "--sort",
+            "delay",
+            "--top",
+            "3",
+        ]);
+        match opts.cmd {
+            Some(Command::Import(import)) => {
+                assert_eq!(import.file.to_str(), Some("result.csv"));
+                assert_eq!(import.sort, Some(RankBy::Delay));
+                assert_eq!(import.top, 3);
+                assert!(import.ips.is_none());
+            }
+            _ => panic!("import subcommand not parsed"),
+        }
+    }
+
+    #[test]
+    fn test_providers() {
+        let opts = Opts::from_iter(&[
+            "rustspeedtest",
+            "-a",
+            "cloudflare",
+            "-a",
+            "speed.example.com",
+            "-a",
+            "-",
+        ]);
+        assert_eq!(
+            opts.sources,
+            vec![
+                Source::Provider(Provider::Cloudflare),
+                Source::Host("speed.example.com".to_string()),
+                Source::Stdin
+            ]
+        );
+        assert!(opts.args.is_empty());
+
+        let opts = Opts::from_iter(&["rustspeedtest", "--cache", "/tmp", "update-providers", "gcore"]);
+        match opts.cmd {
+            Some(Command::UpdateProviders(update)) => {
+                assert_eq!(update.providers, vec![Provider::Gcore])
+            }
+            _ => panic!("update-providers subcommand not parsed"),
+        }
+    }
 }
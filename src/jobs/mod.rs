@@ -0,0 +1,164 @@
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::input::Opts;
+
+/// A file of jobs run one after another (or a few at a time) in one process.
+///
+/// ```yaml
+/// concurrency: 2
+/// jobs:
+///   - name: cloudflare
+///     targets: [ip.txt]
+///     port: 443
+///     stages: [tcping, download]
+///     output: cloudflare.csv
+///   - name: other
+///     targets: ["104.16.0.0/24"]
+///     stages: [httping]
+///     output: other.csv
+///     args: ["-n", "500", "--timeout", "2000"]
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct JobFile {
+    /// How many jobs run at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    pub jobs: Vec<Job>,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+/// A measurement stage a job can enable
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Tcping,
+    Httping,
+    Cfhttping,
+    Udp,
+    Download,
+}
+
+/// One job: its own targets, port, stages and output path
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub name: String,
+    /// Files or CIDRs, as the trailing arguments on the command line
+    pub targets: Vec<String>,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub stages: Vec<Stage>,
+    pub output: Option<String>,
+    /// Any other command line options
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl JobFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        JobFile::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: JobFile = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+        if let Some(job) = file.jobs.iter().find(|job| job.targets.is_empty()) {
+            return Err(format!("job '{}' has no targets", job.name));
+        }
+        Ok(file)
+    }
+}
+
+impl Job {
+    /// The command line equivalent to this job
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec!["rustspeedtest".to_string()];
+        args.extend(self.args.iter().cloned());
+
+        if let Some(port) = self.port {
+            args.push("--port".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(ref output) = self.output {
+            args.push("--output".to_string());
+            args.push(output.clone());
+        }
+        for stage in self.stages.iter() {
+            let flag = match stage {
+                // 默认就是 tcp 测试
+                Stage::Tcping => continue,
+                Stage::Httping => "--httping",
+                Stage::Cfhttping => "--cfhttping",
+                Stage::Udp => "--udp",
+                Stage::Download => "--enable-download",
+            };
+            args.push(flag.to_string());
+        }
+
+        args.push("--".to_string());
+        args.extend(self.targets.iter().cloned());
+        args
+    }
+
+    /// Parse the options of this job as if given on the command line
+    pub fn opts(&self) -> Result<Opts, String> {
+        Opts::from_iter_safe(self.to_args()).map_err(|e| e.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOBS: &str = "
+concurrency: 2
+jobs:
+  - name: cloudflare
+    targets: [ip.txt]
+    port: 8443
+    stages: [cfhttping, download]
+    output: cf.csv
+  - name: other
+    targets: [\"104.16.0.0/24\"]
+    args: [\"-n\", \"500\"]
+";
+
+    #[test]
+    fn test_parse() {
+        let file = JobFile::parse(JOBS).unwrap();
+        assert_eq!(file.concurrency, 2);
+        assert_eq!(file.jobs.len(), 2);
+        assert_eq!(file.jobs[0].stages, vec![Stage::Cfhttping, Stage::Download]);
+        assert!(file.jobs[1].stages.is_empty());
+
+        assert!(JobFile::parse("jobs:\n  - name: empty\n    targets: []\n").is_err());
+        assert!(JobFile::parse("jobs:\n  - name: x\n    targets: [a]\n    typo: 1\n").is_err());
+        assert_eq!(
+            JobFile::parse("jobs: []").unwrap().concurrency,
+            default_concurrency()
+        );
+    }
+
+    #[test]
+    fn test_opts() {
+        let file = JobFile::parse(JOBS).unwrap();
+
+        let opts = file.jobs[0].opts().unwrap();
+        assert_eq!(opts.port.first(), 8443);
+        assert_eq!(opts.output, "cf.csv");
+        assert!(opts.cfhttping);
+        assert!(opts.enable_download);
+        assert_eq!(opts.args, vec!["ip.txt"]);
+
+        let opts = file.jobs[1].opts().unwrap();
+        assert_eq!(opts.number, 500);
+        assert!(!opts.enable_download);
+        assert_eq!(opts.args, vec!["104.16.0.0/24"]);
+    }
+}
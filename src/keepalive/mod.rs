@@ -0,0 +1,410 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::{Position, Url};
+
+use crate::cacheprobe::status_code;
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+use crate::tls::{self, TlsConnector};
+
+/// Bytes read of a response head before giving up on it
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// The requests sent to one IP over a single connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAliveResult {
+    pub ip: IpAddr,
+    /// The TCP connect and, for https, the TLS handshake; None if it failed
+    pub setup: Option<Duration>,
+    /// From sending every request to the end of its response, in order. Fewer
+    /// than asked if the connection failed or was closed by the server.
+    pub requests: Vec<Duration>,
+}
+
+impl KeepAliveResult {
+    /// The mean latency of the requests after the first, on the warm connection
+    pub fn steady(&self) -> Option<Duration> {
+        let warm = self.requests.get(1..).filter(|warm| !warm.is_empty())?;
+        Some(warm.iter().sum::<Duration>() / warm.len() as u32)
+    }
+}
+
+/// Opens one connection per IP and sends a number of requests one after the
+/// other over it, to tell the cost of setting up a connection from the
+/// latency of the edge once the connection is open, as a proxy sees it
+pub struct KeepAliveTest {
+    url: Url,
+    requests: usize,
+    timeout: Duration,
+    tls: TlsConnector,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+}
+
+impl KeepAliveTest {
+    /// Send `requests` requests of `url`, each and the connection setup within `timeout`
+    pub fn new(url: Url, requests: usize, timeout: Duration) -> Self {
+        KeepAliveTest {
+            url,
+            requests,
+            timeout,
+            tls: tls::connector(),
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every connection
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Test all `ips` concurrently, keeping their order
+    pub async fn run(&self, ips: &[IpAddr]) -> Vec<KeepAliveResult> {
+        self.events.stage_start("keepalive", ips.len());
+
+        let results = join_all(ips.iter().map(|ip| self.test(*ip))).await;
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        for result in results.iter() {
+            self.events.result(
+                "keepalive",
+                result.ip,
+                result.requests.len() == self.requests,
+                json!({
+                    "setup_ms": result.setup.map(ms),
+                    "requests_ms": result.requests.iter().copied().map(ms).collect::<Vec<f64>>(),
+                    "steady_ms": result.steady().map(ms),
+                }),
+            );
+        }
+        self.events.stage_end(
+            "keepalive",
+            results
+                .iter()
+                .filter(|r| r.requests.len() == self.requests)
+                .count(),
+        );
+
+        results
+    }
+
+    async fn test(&self, ip: IpAddr) -> KeepAliveResult {
+        let port = self.url.port_or_known_default().unwrap_or(443);
+        let addr = SocketAddr::new(ip, port);
+        let host = self.url.host_str().unwrap_or_default();
+        let mut result = KeepAliveResult {
+            ip,
+            setup: None,
+            requests: Vec::new(),
+        };
+
+        let start = Instant::now();
+        let stream = match tokio::time::timeout(
+            self.timeout,
+            self.socket_options.connect(addr, self.timeout),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return self.failed(result, e),
+            Err(_) => return self.failed(result, io::ErrorKind::TimedOut.into()),
+        };
+        if self.url.scheme() == "https" {
+            let stream =
+                match tokio::time::timeout(self.timeout, tls::handshake(&self.tls, host, stream))
+                    .await
+                {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return self.failed(result, e),
+                    Err(_) => return self.failed(result, io::ErrorKind::TimedOut.into()),
+                };
+            result.setup = Some(start.elapsed());
+            result.requests = self.send(stream, ip).await;
+        } else {
+            result.setup = Some(start.elapsed());
+            result.requests = self.send(stream, ip).await;
+        }
+        result
+    }
+
+    fn failed(&self, result: KeepAliveResult, error: io::Error) -> KeepAliveResult {
+        self.events.trace("keepalive", result.ip, || {
+            format!("connect failed: {}", error)
+        });
+        result
+    }
+
+    /// Send the requests one after the other over `stream`, until one fails
+    /// or the server closes the connection
+    async fn send<S>(&self, mut stream: S, ip: IpAddr) -> Vec<Duration>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustspeedtest\r\nAccept: */*\r\nConnection: keep-alive\r\n\r\n",
+            &self.url[Position::BeforePath..Position::AfterQuery],
+            self.url.host_str().unwrap_or_default()
+        );
+
+        let mut latencies = Vec::with_capacity(self.requests);
+        while latencies.len() < self.requests {
+            let start = Instant::now();
+            let response = tokio::time::timeout(self.timeout, async {
+                stream.write_all(request.as_bytes()).await?;
+                read_response(&mut stream).await
+            })
+            .await;
+            match response {
+                Ok(Ok((code, reusable))) => {
+                    let latency = start.elapsed();
+                    self.events.trace("keepalive", ip, || {
+                        format!(
+                            "request {}: {} after {:?}",
+                            latencies.len() + 1,
+                            code,
+                            latency
+                        )
+                    });
+                    latencies.push(latency);
+                    if !reusable {
+                        self.events.trace("keepalive", ip, || {
+                            "the server closes the connection".to_string()
+                        });
+                        break;
+                    }
+                }
+                Ok(Err(e)) => {
+                    self.events.trace("keepalive", ip, || {
+                        format!("request {} failed: {}", latencies.len() + 1, e)
+                    });
+                    break;
+                }
+                Err(_) => {
+                    self.events.trace("keepalive", ip, || {
+                        format!("request {} timed out", latencies.len() + 1)
+                    });
+                    break;
+                }
+            }
+        }
+        let _ = stream.shutdown().await;
+        latencies
+    }
+}
+
+/// Read one whole response from `stream`. Returns its status code and whether
+/// the connection can carry another request.
+async fn read_response<S>(stream: &mut S) -> io::Result<(u16, bool)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 4096];
+    let body_start = loop {
+        if let Some(end) = find(&head, b"\r\n\r\n") {
+            break end + 4;
+        }
+        if head.len() > MAX_HEAD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response head too long",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    };
+    let mut body = head.split_off(body_start);
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let code = status_code(&head);
+    let (framing, keep_alive) = framing(&head, code);
+
+    let complete = |body: &[u8]| -> io::Result<bool> {
+        match framing {
+            Framing::Empty => Ok(true),
+            Framing::Length(length) => Ok(body.len() >= length),
+            Framing::Chunked => chunked_complete(body)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk")),
+            Framing::UntilClose => Ok(false),
+        }
+    };
+    while !complete(&body)? {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            // 没有长度的响应以关闭连接结束
+            if framing == Framing::UntilClose {
+                return Ok((code, false));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    Ok((code, keep_alive))
+}
+
+/// How the end of a response body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+/// The framing of the response with `head` and status `code`, and whether
+/// the server keeps the connection open after it
+fn framing(head: &str, code: u16) -> (Framing, bool) {
+    let mut length = None;
+    let mut chunked = false;
+    let mut close = !head.starts_with("HTTP/1.1");
+    for line in head.lines().skip(1) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_ascii_lowercase();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse().ok(),
+            "transfer-encoding" => chunked = value.contains("chunked"),
+            "connection" if value.contains("close") => close = true,
+            "connection" if value.contains("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+    let framing = if (100..200).contains(&code) || code == 204 || code == 304 {
+        Framing::Empty
+    } else if chunked {
+        Framing::Chunked
+    } else if let Some(length) = length {
+        Framing::Length(length)
+    } else {
+        Framing::UntilClose
+    };
+    (framing, !close && framing != Framing::UntilClose)
+}
+
+/// Whether `body` holds a whole chunked body, None if it is malformed
+fn chunked_complete(body: &[u8]) -> Option<bool> {
+    let mut rest = body;
+    loop {
+        let Some(end) = find(rest, b"\r\n") else {
+            return Some(false);
+        };
+        let line = std::str::from_utf8(&rest[..end]).ok()?;
+        let size = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+        rest = &rest[end + 2..];
+        if size == 0 {
+            // 最后一块之后是 trailer,以空行结束
+            return Some(rest.starts_with(b"\r\n") || find(rest, b"\r\n\r\n").is_some());
+        }
+        if rest.len() < size + 2 {
+            return Some(false);
+        }
+        rest = &rest[size + 2..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(
+            framing("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", 200),
+            (Framing::Length(5), true)
+        );
+        assert_eq!(
+            framing(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+                200
+            ),
+            (Framing::Chunked, false)
+        );
+        assert_eq!(
+            framing("HTTP/1.1 204 No Content\r\n\r\n", 204),
+            (Framing::Empty, true)
+        );
+        assert_eq!(
+            framing("HTTP/1.0 200 OK\r\n\r\n", 200),
+            (Framing::UntilClose, false)
+        );
+
+        assert_eq!(chunked_complete(b"5\r\nhello\r\n0\r\n\r\n"), Some(true));
+        assert_eq!(chunked_complete(b"5\r\nhello\r\n"), Some(false));
+        assert_eq!(chunked_complete(b"5;ext=1\r\nhel"), Some(false));
+        assert_eq!(chunked_complete(b"zz\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        // 每个连接最多回答 3 个请求,之后关闭
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    for answered in 1..=3 {
+                        if stream.read(&mut buf).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let response = if answered == 2 {
+                            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n"
+                        } else if answered == 3 {
+                            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                        } else {
+                            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                        };
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        let url = Url::parse(&format!("http://example.com:{}/", port)).unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let test = KeepAliveTest::new(url.clone(), 2, Duration::from_secs(1));
+        let results = test.run(&[ip]).await;
+        assert!(results[0].setup.is_some());
+        assert_eq!(results[0].requests.len(), 2);
+        assert_eq!(results[0].steady(), Some(results[0].requests[1]));
+
+        // 服务器在第 3 个请求后关闭连接
+        let test = KeepAliveTest::new(url, 5, Duration::from_secs(1));
+        let results = test.run(&[ip]).await;
+        assert_eq!(results[0].requests.len(), 3);
+
+        let unreachable = Url::parse("http://example.com:1/").unwrap();
+        let results = KeepAliveTest::new(unreachable, 2, Duration::from_secs(1))
+            .run(&[ip])
+            .await;
+        assert_eq!(results[0].setup, None);
+        assert!(results[0].requests.is_empty());
+        assert_eq!(results[0].steady(), None);
+    }
+}
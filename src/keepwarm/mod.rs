@@ -0,0 +1,207 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::socket::SocketOptions;
+use crate::tls::{self, TlsConnector, TlsStream};
+
+/// What happened to an idle connection by the time it was checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleOutcome {
+    /// Still answered a request
+    Alive,
+    /// Closed cleanly by the peer (FIN), e.g. a server idle timeout
+    Closed,
+    /// Torn down with a reset
+    Reset,
+    /// No answer at all, the flow was most likely dropped silently on the path
+    Silent,
+}
+
+impl IdleOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdleOutcome::Alive => "alive",
+            IdleOutcome::Closed => "closed",
+            IdleOutcome::Reset => "reset",
+            IdleOutcome::Silent => "silent",
+        }
+    }
+}
+
+/// A connection kept idle between daemon runs
+pub struct WarmConnection<S> {
+    pub ip: IpAddr,
+    server_name: String,
+    opened: Instant,
+    stream: S,
+}
+
+impl WarmConnection<TlsStream<TcpStream>> {
+    /// Open an HTTPS connection to `addr` and leave it idle
+    pub async fn open(
+        addr: SocketAddr,
+        server_name: &str,
+        connector: &TlsConnector,
+        socket_options: &SocketOptions,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let stream = tokio::time::timeout(timeout, async {
+            let stream = socket_options.connect(addr, timeout).await?;
+            tls::handshake(connector, server_name, stream).await
+        })
+        .await??;
+        Ok(WarmConnection::new(addr.ip(), server_name, stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WarmConnection<S> {
+    pub fn new(ip: IpAddr, server_name: &str, stream: S) -> Self {
+        WarmConnection {
+            ip,
+            server_name: server_name.to_string(),
+            opened: Instant::now(),
+            stream,
+        }
+    }
+
+    /// Send a request over the idle connection and classify the answer.
+    /// Returns the outcome and how long the connection was idle.
+    pub async fn check(mut self, timeout: Duration) -> (IdleOutcome, Duration) {
+        let idle = self.opened.elapsed();
+        let request = format!(
+            "HEAD / HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustspeedtest\r\n\r\n",
+            self.server_name
+        );
+
+        let mut first = [0; 1];
+        // 先看对端是否早已关闭或重置了连接,再发送请求
+        let outcome = match tokio::time::timeout(Duration::ZERO, self.stream.read(&mut first)).await
+        {
+            Ok(result) => Ok(result),
+            Err(_) => {
+                tokio::time::timeout(timeout, async {
+                    self.stream.write_all(request.as_bytes()).await?;
+                    self.stream.read(&mut first).await
+                })
+                .await
+            }
+        };
+
+        let outcome = match outcome {
+            Ok(Ok(0)) => IdleOutcome::Closed,
+            Ok(Ok(_)) => IdleOutcome::Alive,
+            Ok(Err(e)) => match e.kind() {
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe => IdleOutcome::Reset,
+                // TLS 层在对端关闭后读到的 EOF
+                io::ErrorKind::UnexpectedEof => IdleOutcome::Closed,
+                _ => IdleOutcome::Reset,
+            },
+            Err(_) => IdleOutcome::Silent,
+        };
+        let _ = self.stream.shutdown().await;
+        (outcome, idle)
+    }
+}
+
+/// How many idle connections survived so far
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Survival {
+    pub checked: usize,
+    pub alive: usize,
+}
+
+impl Survival {
+    pub fn record(&mut self, outcome: IdleOutcome) {
+        self.checked += 1;
+        if outcome == IdleOutcome::Alive {
+            self.alive += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Start a server that handles every connection with `handler`
+    async fn server<F, Fut>(handler: F) -> SocketAddr
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                handler(stream).await;
+            }
+        });
+        addr
+    }
+
+    async fn check(addr: SocketAddr) -> IdleOutcome {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let conn = WarmConnection::new(addr.ip(), "example.com", stream);
+        // 模拟空闲,让对端的关闭先到达
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        conn.check(Duration::from_millis(200)).await.0
+    }
+
+    #[tokio::test]
+    async fn test_outcomes() {
+        let alive = server(|mut stream| async move {
+            tokio::spawn(async move {
+                let mut buf = [0; 256];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+            });
+        })
+        .await;
+        assert_eq!(check(alive).await, IdleOutcome::Alive);
+
+        let closed = server(|stream| async move { drop(stream) }).await;
+        assert_eq!(check(closed).await, IdleOutcome::Closed);
+
+        let reset = server(|stream| async move {
+            stream.set_linger(Some(Duration::ZERO)).unwrap();
+            drop(stream);
+        })
+        .await;
+        assert_eq!(check(reset).await, IdleOutcome::Reset);
+
+        let silent = server(|stream| async move {
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                drop(stream);
+            });
+        })
+        .await;
+        assert_eq!(check(silent).await, IdleOutcome::Silent);
+    }
+
+    #[test]
+    fn test_survival() {
+        let mut survival = Survival::default();
+        survival.record(IdleOutcome::Alive);
+        survival.record(IdleOutcome::Silent);
+        assert_eq!(
+            survival,
+            Survival {
+                checked: 2,
+                alive: 1
+            }
+        );
+    }
+}
@@ -1,141 +1,1831 @@
-use httping::{HttpingChecker, HttpingResult};
-use rand::seq::index::sample;
-use std::collections::HashSet;
+use httping::{HttpRequest, HttpRequestBuilder, HttpingChecker};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::fs;
+use std::io::{self, IsTerminal};
 use std::net::IpAddr;
-use std::time::Duration;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, TimeZone};
 
 use download::{Downloader, Speed};
-use routes::{CFCDNCheckResult, CloudflareChecker};
+use routes::CloudflareChecker;
+
+use anomaly::{Alert, AnomalyDetector};
+use attest::{Attestation, Summary};
+use cache::StageCache;
+use compare::{ResultFile, RunConfig};
+use deadnet::DeadSubnets;
+use export::Exporter;
+use filter::{top_per_group, Filter, Group};
+use fingerprint::Fingerprints;
+use cacheprobe::{CacheProbe, CacheResponse, CacheResult, CacheWarmer, WarmResult};
+use flows::{FlowResult, FlowTest, FLOW_SOURCE_PORT_BASE};
+use histogram::LatencyHistogram;
+use history::{History, Measurement};
+use i18n::{tr, trf, Msg};
+use import::RankBy;
+use input::{
+    Command, CompareOpts, ImportOpts, Opts, ReplayOpts, ReportOpts, UpdateProvidersOpts,
+    VerifyOpts,
+};
+use jobs::{Job, JobFile};
+use keepalive::{KeepAliveResult, KeepAliveTest};
+use keepwarm::{IdleOutcome, Survival, WarmConnection};
+use memguard::MemoryGuard;
+use output::{PortComparison, PortMatrix, Redaction, UplinkComparison};
+use pipeline::{Pipeline, Step};
+use probe::{expired, Prober, ScanResult, StopAfter};
+use portscan::PortScanner;
+use progress::{Observer, ProgressBars, ProgressEvents, ProgressLines};
+use providers::Source;
+use rawscan::RawScanner;
+use replay::ResponseLog;
+use retry::RetryPolicy;
+use rtt::AdaptiveTimeout;
+use schedule::Schedule;
+use scanner::{sort_delays, Delay, LatencyMetric, Scanner};
+use sentinel::Sentinel;
+use socket::SocketOptions;
+use synscan::SynScanner;
+use targets::Targets;
+use trace::Tracer;
+use sweep::{SizeSweep, SweepMode, SweepResult};
+use udping::UdpPinger;
+
+mod anomaly;
+mod attest;
+mod budget;
+mod cache;
+mod cacheprobe;
+mod checkpoint;
+mod compare;
+mod deadnet;
+mod dns;
+mod download;
+mod estimate;
+mod export;
+mod filter;
+mod fingerprint;
+mod flows;
+mod histogram;
+mod history;
+mod httping;
+mod i18n;
+mod import;
+mod input;
+mod jobs;
+mod keepalive;
+mod keepwarm;
+mod memguard;
+mod output;
+mod pipeline;
+mod portscan;
+mod probe;
+mod progress;
+mod providers;
+mod rawscan;
+mod replay;
+mod report;
+mod retry;
+mod routes;
+mod rtt;
+mod scanner;
+mod schedule;
+mod sentinel;
+mod socket;
+mod spill;
+mod synscan;
+mod sweep;
+mod targets;
+mod tls;
+mod trace;
+mod udping;
+mod utils;
+mod web;
+
+// 守护模式下检查时钟的间隔
+const SCHEDULE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+fn main() {
+    let opts: Opts = Opts::read();
+    i18n::set_lang(opts.lang.unwrap_or_else(i18n::detect_lang));
+
+    if let Some(feature) = missing_feature(&opts) {
+        println!("{}", trf(Msg::FeatureDisabled, &[&feature]));
+        std::process::exit(1);
+    }
+
+    match opts.cmd {
+        Some(Command::Report(ref report)) => {
+            run_report(report, opts.redact);
+            return;
+        }
+        Some(Command::Compare(ref compare)) => {
+            run_compare(compare, opts.redact);
+            return;
+        }
+        Some(Command::Replay(ref replay)) => {
+            run_replay(replay, opts.redact);
+            return;
+        }
+        Some(Command::Verify(ref verify)) => {
+            run_verify(verify);
+            return;
+        }
+        Some(Command::Import(ref import)) => {
+            run_import(import, opts.redact);
+            return;
+        }
+        Some(Command::UpdateProviders(ref update)) => {
+            run_update_providers(&opts, update);
+            return;
+        }
+        // Opts::read 已经把它们换成只运行该阶段的扫描
+        Some(Command::Httping(_) | Command::Route(_)) | None => {}
+    }
+
+    if opts.skip_ping && opts.ips_from.is_none() {
+        println!("{}", tr(Msg::SkipPingWithoutIps));
+        std::process::exit(1);
+    }
+    let Some(listed) = load_listed(&opts) else {
+        std::process::exit(1);
+    };
+
+    let jobs = match opts.jobs {
+        Some(ref path) => match JobFile::load(path) {
+            Ok(jobs) => Some(jobs),
+            Err(error) => {
+                println!("{}", trf(Msg::CannotLoadJobs, &[&path.display(), &error]));
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let pipeline = match opts.pipeline {
+        Some(ref path) => match Pipeline::load(path) {
+            Ok(pipeline) => Some(pipeline),
+            Err(error) => {
+                println!("{}", trf(Msg::CannotLoadPipeline, &[&path.display(), &error]));
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // 任务文件和流水线自带目标
+    let batch = jobs.is_some() || pipeline.is_some();
+
+    if !batch && !check_input_lines(&opts) {
+        std::process::exit(1);
+    }
+
+    // 批量模式下目标由各个任务指定
+    let ips = if batch {
+        Targets::default()
+    } else {
+        parse_addresses_from_opt(&opts, &listed)
+    };
+
+    if !batch && ips.is_empty() {
+        println!("{}", trf(Msg::NoIpsResolved, &[&format!("{:?}", opts)]));
+        std::process::exit(1);
+    }
+
+    if opts.dry_run && !batch {
+        print_estimates(&ips, &opts);
+        return;
+    }
+
+    // 进入指定的网络命名空间,必须在创建任何线程之前
+    if let Some(ref netns) = opts.netns {
+        if let Err(error) = socket::enter_netns(netns) {
+            println!("{}", trf(Msg::CannotEnterNetns, &[netns, &error]));
+            print_capability_hint(&error, socket::NETNS_CAPABILITY);
+            std::process::exit(1);
+        }
+    }
+    // 在命名空间内检查接口和 fwmark,权限不足时现在就退出,而不是每个探测都失败
+    if !batch {
+        check_socket_options(&opts);
+    }
+
+    // create a tokio runtime
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .global_queue_interval(u32::MAX)
+        .event_interval(31)
+        .build()
+        .unwrap();
+
+    // 进度事件,输出不是终端时改为逐行显示进度
+    let progress: Arc<dyn Observer> = if io::stdout().is_terminal() {
+        Arc::new(ProgressBars::default().with_redaction(opts.redact))
+    } else {
+        Arc::new(ProgressLines::new(opts.progress_interval))
+    };
+    let events = if opts.progress_socket.is_some() || opts.web.is_some() {
+        ProgressEvents::new()
+    } else {
+        ProgressEvents::default()
+    }
+    .with_observer(progress)
+    .with_tracer(Tracer::new(opts.trace_ip.iter().copied(), opts.redact))
+    .with_redaction(opts.redact);
+    {
+        let _guard = rt.enter();
+        if let Some(ref path) = opts.progress_socket {
+            if let Err(error) = events.listen_unix(path) {
+                println!(
+                    "{}",
+                    trf(Msg::CannotBindProgressSocket, &[&path.display(), &error])
+                );
+                std::process::exit(1);
+            }
+        }
+        if let Some(addr) = opts.web {
+            match web::serve(addr, &events) {
+                Ok(addr) => println!("{}", trf(Msg::WebUiListening, &[&addr])),
+                Err(error) => {
+                    println!("{}", trf(Msg::CannotStartWebUi, &[&addr, &error]));
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(ref jobs) = jobs {
+        run_jobs(&rt, jobs, &events);
+        keep_web_ui(&rt, &opts);
+        return;
+    }
+    if let Some(ref pipeline) = pipeline {
+        run_pipeline(&rt, pipeline, &events);
+        keep_web_ui(&rt, &opts);
+        return;
+    }
+
+    match opts.schedule {
+        Some(ref schedule) => run_daemon(schedule, &rt, ips, &listed, &opts, &events),
+        None => {
+            run_once(&rt, ips, &listed, &opts, &events);
+            keep_web_ui(&rt, &opts);
+        }
+    }
+}
+
+/// Run every job of `file`, at most `file.concurrency` at the same time
+fn run_jobs(rt: &tokio::runtime::Runtime, file: &JobFile, events: &ProgressEvents) {
+    let next = AtomicUsize::new(0);
+    let workers = file.concurrency.clamp(1, file.jobs.len().max(1));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(job) = file.jobs.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    run_job(rt, job, events);
+                }
+            });
+        }
+    });
+}
+
+fn run_job(rt: &tokio::runtime::Runtime, job: &Job, events: &ProgressEvents) {
+    println!("{}", trf(Msg::RunningJob, &[&job.name]));
+
+    let opts = job.opts().and_then(|opts| match missing_feature(&opts) {
+        Some(feature) => Err(trf(Msg::FeatureDisabled, &[&feature])),
+        None => Ok(opts),
+    });
+    let opts = match opts {
+        Ok(opts) => opts,
+        Err(error) => {
+            println!("{}", trf(Msg::InvalidJob, &[&job.name, &error]));
+            return;
+        }
+    };
+    if !check_input_lines(&opts) {
+        return;
+    }
+    let Some(listed) = load_listed(&opts) else {
+        return;
+    };
+    let ips = parse_addresses_from_opt(&opts, &listed);
+    if ips.is_empty() {
+        println!("{}", trf(Msg::NoIpsResolved, &[&format!("{:?}", opts)]));
+        return;
+    }
+
+    run_once(rt, ips, &listed, &opts, events);
+}
+
+/// Run the steps of `pipeline` one after another, each on the IPs the steps
+/// before it kept, and write the results of the last latency stage and download test
+fn run_pipeline(rt: &tokio::runtime::Runtime, pipeline: &Pipeline, events: &ProgressEvents) {
+    let step_opts = |step: Option<&Step>| {
+        let opts = pipeline.opts(step).and_then(|opts| match missing_feature(&opts) {
+            Some(feature) => Err(trf(Msg::FeatureDisabled, &[&feature])),
+            None => Ok(opts),
+        });
+        if let Err(ref error) = opts {
+            let name = step.map_or("args", Step::name);
+            println!("{}", trf(Msg::InvalidPipelineStep, &[&name, error]));
+        }
+        opts.ok()
+    };
+    let Some(opts) = step_opts(None) else {
+        return;
+    };
+    if !check_input_lines(&opts) {
+        return;
+    }
+    let Some(listed) = load_listed(&opts) else {
+        return;
+    };
+    let ips = parse_addresses_from_opt(&opts, &listed);
+    if ips.is_empty() {
+        println!("{}", trf(Msg::NoIpsResolved, &[&format!("{:?}", opts)]));
+        return;
+    }
+    check_socket_options(&opts);
+
+    let started = Local::now().timestamp();
+    let deadline = opts.max_duration.map(|duration| Instant::now() + duration);
+    let histogram = LatencyHistogram::default();
+    let spill_dir = spill::scan_dir();
+    // 最近一次延迟测试的结果和选项,输出时使用
+    let mut latency: Option<(ScanResult, Opts)> = None;
+    let mut valis_ips: Vec<IpAddr> = ips.iter().collect();
+    let mut speedtest_result: Option<Vec<Speed>> = None;
+
+    for step in pipeline.steps.iter() {
+        println!(
+            "{}",
+            trf(Msg::PipelineStep, &[&step.name(), &valis_ips.len()])
+        );
+        let stage_opts = match step.stage() {
+            Some(_) => match step_opts(Some(step)) {
+                Some(opts) => Some(opts),
+                None => return,
+            },
+            None => None,
+        };
+        match (step, stage_opts, latency.as_mut().map(|(latency, _)| latency)) {
+            (Step::Download(_), Some(stage_opts), _) => {
+                speedtest_result =
+                    Some(rt.block_on(run_downloader(&valis_ips, &stage_opts, events)));
+            }
+            (_, Some(stage_opts), _) => {
+                let count = step.stage().and_then(|stage| stage.count);
+                let targets = Targets::from(
+                    valis_ips
+                        .iter()
+                        .copied()
+                        .take(count.unwrap_or(usize::MAX))
+                        .collect::<Vec<IpAddr>>(),
+                );
+                let port = stage_opts.port.first();
+                let Some(mut result) = run_latency_stage(
+                    rt,
+                    &targets,
+                    &stage_opts,
+                    port,
+                    deadline,
+                    &histogram,
+                    &spill_dir,
+                    events,
+                ) else {
+                    return;
+                };
+                valis_ips = result.valid_ips();
+                let keep: HashSet<IpAddr> = valis_ips.iter().copied().collect();
+                retain_results(
+                    &keep,
+                    &mut valis_ips,
+                    &mut result,
+                    &mut speedtest_result,
+                    events,
+                    &format!("the {} step", step.name()),
+                );
+                latency = Some((result, stage_opts));
+            }
+            (Step::Top(n), None, Some(latency)) => {
+                let measurements =
+                    Measurement::from_results(&valis_ips, latency, &speedtest_result, opts.time);
+                let keep: HashSet<IpAddr> = top_per_group(
+                    &rank_measurements(measurements, &speedtest_result),
+                    *n,
+                    opts.group_by,
+                )
+                .into_iter()
+                .collect();
+                retain_results(
+                    &keep,
+                    &mut valis_ips,
+                    latency,
+                    &mut speedtest_result,
+                    events,
+                    &format!("top: {}", n),
+                );
+            }
+            (Step::Where(filter), None, Some(latency)) => {
+                // 加载时已经检查过表达式
+                let Ok(filter) = filter.parse::<Filter>() else {
+                    return;
+                };
+                let keep: HashSet<IpAddr> =
+                    Measurement::from_results(&valis_ips, latency, &speedtest_result, opts.time)
+                        .into_iter()
+                        .filter(|measurement| filter.matches(measurement))
+                        .map(|measurement| measurement.ip)
+                        .collect();
+                retain_results(
+                    &keep,
+                    &mut valis_ips,
+                    latency,
+                    &mut speedtest_result,
+                    events,
+                    &format!("where: {}", filter),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // 第一步总是延迟测试
+    let Some((latency, opts)) = latency else {
+        return;
+    };
+    if opts.display != 0 {
+        display_results(&latency, &speedtest_result, &opts);
+    }
+    write_results(&valis_ips, &latency, speedtest_result, &opts, &spill_dir, started);
+}
+
+/// Run all stages again at every time matched by `schedule`, forever.
+///
+/// The wall clock is polled instead of sleeping until the next run, so a run missed while
+/// the host was suspended is caught up once right after wake-up.
+/// IPs that were usable at least once are monitored for sustained latency regressions.
+/// With `--keep-warm` an idle connection to the best IP is held open between runs.
+fn run_daemon(
+    schedule: &Schedule,
+    rt: &tokio::runtime::Runtime,
+    ips: Targets,
+    listed: &[IpAddr],
+    opts: &Opts,
+    events: &ProgressEvents,
+) {
+    let mut detector = AnomalyDetector::new(opts.alert_threshold, opts.alert_after);
+    let mut monitored: HashSet<IpAddr> = HashSet::new();
+    let mut warm: Option<WarmConnection<_>> = None;
+    let mut survival = Survival::default();
+
+    loop {
+        let next = match schedule.next_within(Local::now().naive_local(), opts.window) {
+            Some(next) => next,
+            None => {
+                println!("{}", tr(Msg::ScheduleNeverMatches));
+                std::process::exit(1);
+            }
+        };
+        println!("{}", trf(Msg::NextRunAt, &[&next]));
+
+        while Local::now().naive_local() < next {
+            std::thread::sleep(SCHEDULE_POLL_INTERVAL);
+        }
+
+        // 检查上一轮保持的空闲连接是否还活着
+        if let Some(conn) = warm.take() {
+            let ip = conn.ip;
+            let (outcome, idle) = rt.block_on(conn.check(Duration::from_millis(opts.timeout)));
+            survival.record(outcome);
+            report_idle_outcome(ip, outcome, idle, survival, opts, events);
+        }
+
+        let measurements = run_once(rt, ips.clone(), listed, opts, events);
+
+        let delays: HashMap<IpAddr, Option<f64>> =
+            measurements.iter().map(|m| (m.ip, m.delay_ms)).collect();
+        monitored.extend(delays.keys());
+        for ip in monitored.iter() {
+            let alert = match delays.get(ip) {
+                Some(Some(delay_ms)) => detector.observe(*ip, Some(*delay_ms)),
+                // 本次结果中没有延迟数据 (如 httping 模式),不参与判断
+                Some(None) => None,
+                None => detector.observe(*ip, None),
+            };
+            if let Some(alert) = alert {
+                report_alert(&alert, opts, events);
+            }
+        }
+
+        // 与当前最好的 IP 保持一条空闲连接,直到下一轮
+        if opts.keep_warm {
+            if let Some(best) = measurements.first() {
+                let addr = std::net::SocketAddr::new(best.ip, opts.port.first());
+                let server_name = tls_server_name(opts);
+                let opened = rt.block_on(WarmConnection::open(
+                    addr,
+                    &server_name,
+                    &tls::connector(),
+                    &socket_options_from_opt(opts),
+                    Duration::from_millis(opts.timeout),
+                ));
+                match opened {
+                    Ok(conn) => warm = Some(conn),
+                    Err(error) => println!(
+                        "{}",
+                        trf(Msg::KeepWarmOpenFailed, &[&opts.redact.apply(&best.ip), &error])
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// Print how an idle connection fared and forward it to the progress events
+fn report_idle_outcome(
+    ip: IpAddr,
+    outcome: IdleOutcome,
+    idle: Duration,
+    survival: Survival,
+    opts: &Opts,
+    events: &ProgressEvents,
+) {
+    let label = match outcome {
+        IdleOutcome::Alive => tr(Msg::IdleAlive),
+        IdleOutcome::Closed => tr(Msg::IdleClosed),
+        IdleOutcome::Reset => tr(Msg::IdleReset),
+        IdleOutcome::Silent => tr(Msg::IdleSilent),
+    };
+    println!(
+        "{}",
+        trf(
+            Msg::KeepWarmResult,
+            &[
+                &opts.redact.apply(&ip),
+                &idle.as_secs(),
+                &label,
+                &survival.alive,
+                &survival.checked
+            ]
+        )
+    );
+    events.result(
+        "keep_warm",
+        ip,
+        outcome == IdleOutcome::Alive,
+        serde_json::json!({"outcome": outcome.as_str(), "idle_s": idle.as_secs()}),
+    );
+}
+
+/// Print an alert and forward it to the progress events
+fn report_alert(alert: &Alert, opts: &Opts, events: &ProgressEvents) {
+    match *alert {
+        Alert::Regression {
+            ip,
+            delay_ms,
+            baseline_ms,
+        } => {
+            let baseline = format!("{:.1}", baseline_ms);
+            match delay_ms {
+                Some(delay_ms) => println!(
+                    "{}",
+                    trf(
+                        Msg::LatencyRegression,
+                        &[&opts.redact.apply(&ip), &format!("{:.1}", delay_ms), &baseline]
+                    )
+                ),
+                None => println!(
+                    "{}",
+                    trf(Msg::IpUnreachable, &[&opts.redact.apply(&ip), &baseline])
+                ),
+            }
+            events.alert(
+                "regression",
+                ip,
+                serde_json::json!({"delay_ms": delay_ms, "baseline_ms": baseline_ms}),
+            );
+        }
+        Alert::Recovered {
+            ip,
+            delay_ms,
+            baseline_ms,
+        } => {
+            println!(
+                "{}",
+                trf(
+                    Msg::LatencyRecovered,
+                    &[
+                        &opts.redact.apply(&ip),
+                        &format!("{:.1}", delay_ms),
+                        &format!("{:.1}", baseline_ms)
+                    ]
+                )
+            );
+            events.alert(
+                "recovered",
+                ip,
+                serde_json::json!({"delay_ms": delay_ms, "baseline_ms": baseline_ms}),
+            );
+        }
+    }
+}
+
+/// Run all enabled stages once, write the results and return the measurements of the usable IPs
+fn run_once(
+    rt: &tokio::runtime::Runtime,
+    ips: Targets,
+    listed: &[IpAddr],
+    opts: &Opts,
+    events: &ProgressEvents,
+) -> Vec<Measurement> {
+    // 延迟测试的时间上限,从本次运行开始计算
+    let deadline = opts.max_duration.map(|duration| Instant::now() + duration);
+    let histogram = match opts.latency_histogram {
+        Some(_) => LatencyHistogram::new(),
+        None => LatencyHistogram::default(),
+    };
+    print_estimates(&ips, opts);
+    let spill_dir = spill::scan_dir();
+
+    // 多出口对比测试
+    if opts.interface.len() > 1 || opts.source_ip.len() > 1 {
+        let comparison = rt.block_on(run_uplink_comparison(ips, opts, deadline, events));
+        if opts.display != 0 {
+            comparison.display(opts.display, opts.time, opts.redact);
+        }
+        if let Err(error) = comparison.write_to_csv(&opts.output, opts.time, opts.redact) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&opts.output, &error]));
+        }
+        return Vec::new();
+    }
+
+    // 少量 IP 的端口可达矩阵
+    if opts.port_matrix {
+        let matrix = rt.block_on(run_port_matrix(ips, opts, deadline, events));
+        if opts.display != 0 {
+            matrix.display(opts.redact);
+        }
+        if let Err(error) = matrix.write_to_csv(&opts.output, opts.redact) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&opts.output, &error]));
+        }
+        return Vec::new();
+    }
+
+    // 多端口测试,每个端口的结果分开列出
+    if opts.port.len() > 1 {
+        let mut comparison = PortComparison::default();
+        for port in opts.port.iter() {
+            println!("{}", trf(Msg::ScanningPort, &[&port]));
+            let Some(latency) = run_latency_stage(
+                rt, &ips, opts, port, deadline, &histogram, &spill_dir, events,
+            ) else {
+                return Vec::new();
+            };
+            let measurements =
+                Measurement::from_results(&latency.valid_ips(), &latency, &None, opts.time);
+            comparison.insert(port, measurements);
+        }
+        write_histogram(opts, &histogram);
+        if opts.display != 0 {
+            comparison.display(opts.display, opts.redact);
+        }
+        if let Err(error) = comparison.write_to_csv(&opts.output, opts.redact) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&opts.output, &error]));
+        }
+        return Vec::new();
+    }
+
+    let started = Local::now().timestamp();
+
+    // 测速结果
+    let mut speedtest_result: Option<Vec<Speed>> = None;
+
+    // 在测试之前读取,以免测试完才发现列表有误
+    let warm_urls = opts.warm_urls.as_ref().map(|path| match cacheprobe::load_urls(path) {
+        Ok(urls) => urls,
+        Err(error) => {
+            println!("{}", trf(Msg::CannotLoadWarmUrls, &[&path.display(), &error]));
+            std::process::exit(1);
+        }
+    });
+
+    let latency = if opts.skip_ping {
+        listed_ips(&ips, listed, opts)
+    } else {
+        let port = opts.port.first();
+        run_latency_stage(rt, &ips, opts, port, deadline, &histogram, &spill_dir, events)
+    };
+    let Some(mut latency) = latency else {
+        return Vec::new();
+    };
+    write_histogram(opts, &histogram);
+    // 可用IP地址集合
+    let mut valis_ips = latency.valid_ips();
+
+    // 是否启用下载测速
+    if !opts.enable_download {
+        println!("{}", tr(Msg::DownloadDisabled));
+    } else {
+        speedtest_result = Some(rt.block_on(run_downloader(&valis_ips, opts, events)));
+    }
+
+    // 按 --where 筛选,之后的显示和输出只包含匹配的 IP
+    if let Some(ref filter) = opts.filter {
+        let keep: HashSet<IpAddr> =
+            Measurement::from_results(&valis_ips, &latency, &speedtest_result, opts.time)
+                .into_iter()
+                .filter(|measurement| filter.matches(measurement))
+                .map(|measurement| measurement.ip)
+                .collect();
+        retain_results(
+            &keep,
+            &mut valis_ips,
+            &mut latency,
+            &mut speedtest_result,
+            events,
+            &format!("--where {}", filter),
+        );
+    }
+
+    // 按 --top/--group-by 只保留每组最好的几个
+    if opts.top > 0 {
+        let measurements =
+            Measurement::from_results(&valis_ips, &latency, &speedtest_result, opts.time);
+        let keep: HashSet<IpAddr> = top_per_group(
+            &rank_measurements(measurements, &speedtest_result),
+            opts.top,
+            opts.group_by,
+        )
+        .into_iter()
+        .collect();
+        retain_results(
+            &keep,
+            &mut valis_ips,
+            &mut latency,
+            &mut speedtest_result,
+            events,
+            &format!("--top {}", opts.top),
+        );
+    }
+
+    // 简单显示结果
+    if opts.display != 0 {
+        display_results(&latency, &speedtest_result, opts);
+    }
+
+    // 负载大小扫描
+    if !opts.size_sweep.is_empty() {
+        let top: Vec<IpAddr> = valis_ips.iter().take(opts.display.max(1)).cloned().collect();
+        let results = rt.block_on(run_size_sweep(&top, &opts.size_sweep, opts, events));
+        if opts.display != 0 {
+            display_size_sweep(&opts.size_sweep, &results, opts);
+        }
+    }
+
+    // 多流延迟稳定性测试
+    if opts.flows > 0 {
+        let top: Vec<IpAddr> = valis_ips.iter().take(opts.display.max(1)).cloned().collect();
+        let flow_test = FlowTest::new(
+            opts.flows,
+            opts.time,
+            Duration::from_millis(opts.timeout),
+            opts.port.first(),
+        )
+        .with_socket_options(socket_options_from_opt(opts))
+        .with_events(events.clone());
+        let results = rt.block_on(flow_test.run(&top));
+        if opts.display != 0 {
+            display_flows(&results, opts);
+        }
+    }
+
+    // 同一连接上的连续请求延迟
+    if opts.keepalive_requests > 0 {
+        let top: Vec<IpAddr> = valis_ips.iter().take(opts.display.max(1)).cloned().collect();
+        let test = KeepAliveTest::new(
+            opts.keepalive_url.clone(),
+            opts.keepalive_requests,
+            Duration::from_millis(opts.timeout),
+        )
+        .with_socket_options(socket_options_from_opt(opts))
+        .with_events(events.clone());
+        let results = rt.block_on(test.run(&top));
+        if opts.display != 0 {
+            display_keepalive(&results, opts);
+        }
+    }
+
+    // CDN 缓存命中探测
+    if let Some(ref url) = opts.cache_probe {
+        let top: Vec<IpAddr> = valis_ips.iter().take(opts.display.max(1)).cloned().collect();
+        let probe = CacheProbe::new(url.clone(), Duration::from_millis(opts.timeout))
+            .with_socket_options(socket_options_from_opt(opts))
+            .with_events(events.clone());
+        let results = rt.block_on(probe.run(&top));
+        if opts.display != 0 {
+            display_cache_probe(&results, opts);
+        }
+    }
+
+    // 预热选出的 IP 的缓存
+    if let Some(urls) = warm_urls {
+        let top: Vec<IpAddr> = valis_ips.iter().take(opts.display.max(1)).cloned().collect();
+        let warmer = CacheWarmer::new(urls, Duration::from_secs(opts.download_timeout))
+            .with_socket_options(socket_options_from_opt(opts))
+            .with_events(events.clone());
+        let results = rt.block_on(warmer.run(&top));
+        if opts.display != 0 {
+            display_cache_warm(&results, opts);
+        }
+    }
+
+    write_results(&valis_ips, &latency, speedtest_result, opts, &spill_dir, started)
+}
+
+/// Record the results of a run started at `started` to '--history', '--export' and the CSV output,
+/// with the tcping results it spilled to `spill_dir`
+fn write_results(
+    valis_ips: &[IpAddr],
+    latency: &ScanResult,
+    speedtest_result: Option<Vec<Speed>>,
+    opts: &Opts,
+    spill_dir: &Path,
+    started: i64,
+) -> Vec<Measurement> {
+    let measurements =
+        Measurement::from_results(valis_ips, latency, &speedtest_result, opts.time);
+
+    // 记录到历史数据库
+    if let Some(ref path) = opts.history {
+        if let Err(error) = History::open(path).and_then(|mut h| h.record(started, &measurements)) {
+            println!("{}", trf(Msg::CannotWriteHistory, &[&path.display(), &error]));
+        }
+    }
+
+    if let Some(ref path) = opts.export {
+        if let Err(error) = export_results(path, &measurements, &speedtest_result, opts) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&path.display(), &error]));
+        }
+    }
+
+    // 写入到csv文件中
+    match utils::write_to_csv(valis_ips, latency, speedtest_result, opts, spill_dir) {
+        Ok(_) => {}
+        Err(error) => {
+            println!("{}", trf(Msg::CannotWriteResult, &[&opts.output, &error]));
+        }
+    }
+
+    if let Some(ref path) = opts.attest {
+        attest_results(path, &measurements, opts, started);
+    }
+
+    measurements
+}
+
+/// Sign a summary of `measurements` with '--sign-key' and write it to `path`
+fn attest_results(path: &Path, measurements: &[Measurement], opts: &Opts, started: i64) {
+    let summary = Summary::new(
+        &RunConfig::from_opts(opts),
+        started,
+        Local::now().timestamp(),
+        measurements,
+        opts.redact,
+    );
+    let result = Attestation::sign(summary, &opts.sign_key).and_then(|(attestation, created)| {
+        if created {
+            println!(
+                "{}",
+                trf(
+                    Msg::CreatedSigningKey,
+                    &[&opts.sign_key.display(), &attestation.public_key]
+                )
+            );
+        }
+        attestation.write(path)
+    });
+    if let Err(error) = result {
+        println!(
+            "{}",
+            trf(Msg::CannotWriteAttestation, &[&path.display(), &error])
+        );
+    }
+}
+
+/// The IPs of --ips-from, in the order of the file. Empty without it, None if it cannot be read.
+fn load_listed(opts: &Opts) -> Option<Vec<IpAddr>> {
+    let Some(ref path) = opts.ips_from else {
+        return Some(Vec::new());
+    };
+    match import::load_ips(path) {
+        Ok(listed) => Some(listed),
+        Err(error) => {
+            println!("{}", trf(Msg::CannotReadIpList, &[&path.display(), &error]));
+            None
+        }
+    }
+}
+
+/// The IPs of --ips-from that are still targets after --exclude and the sampling, in the order
+/// of the file, for --skip-ping
+fn listed_ips(ips: &Targets, listed: &[IpAddr], opts: &Opts) -> Option<ScanResult> {
+    let path = opts.ips_from.as_ref()?;
+    let mut listed: Vec<IpAddr> = listed.iter().copied().filter(|&ip| ips.covers(ip)).collect();
+    // 抽样时只留下抽中的,集合的大小不超过列表本身
+    if ips.is_sampled() {
+        let wanted: HashSet<IpAddr> = listed.iter().copied().collect();
+        let sampled: HashSet<IpAddr> = ips.iter().filter(|ip| wanted.contains(ip)).collect();
+        listed.retain(|ip| sampled.contains(ip));
+    }
+    println!(
+        "{}",
+        trf(Msg::SkippedLatencyStage, &[&listed.len(), &path.display()])
+    );
+    Some(ScanResult::Listed(listed))
+}
+
+/// Run the latency stage chosen by the options on `port`, through '--cache', spilling to
+/// `spill_dir`. None if the options are invalid.
+#[allow(clippy::too_many_arguments)]
+fn run_latency_stage(
+    rt: &tokio::runtime::Runtime,
+    ips: &Targets,
+    opts: &Opts,
+    port: u16,
+    deadline: Option<Instant>,
+    histogram: &LatencyHistogram,
+    spill_dir: &Path,
+    events: &ProgressEvents,
+) -> Option<ScanResult> {
+    if let Err(error) = retest_in_memory(opts) {
+        println!("{}", error);
+        return None;
+    }
+    // 靠近 --au/--al 的 IP 先放宽限制保留下来,再测一轮后决定去留
+    let widened = opts.retest_margin.map(|margin| {
+        let mut widened = opts.clone();
+        widened.au = opts.au.saturating_add((opts.au as f64 * margin).ceil() as u128);
+        widened.al = opts.al.saturating_sub((opts.al as f64 * margin).ceil() as u128);
+        widened
+    });
+    // tcp 和 udp 和 http 和 cfhttp 选择其中一个
+    let stage_opts = widened.as_ref().unwrap_or(opts);
+    let prober = latency_prober(
+        ips.clone(),
+        stage_opts,
+        port,
+        deadline,
+        histogram,
+        spill_dir,
+        events,
+    );
+    let (prober, options) = match prober {
+        Ok(prober) => prober,
+        Err(error) => {
+            println!("{}", error);
+            return None;
+        }
+    };
+    let options = match opts.retest_margin {
+        Some(margin) => format!("{} {} retest={}", port, options, margin),
+        None => format!("{} {}", port, options),
+    };
+    let mut latency = cached_stage(opts, prober.stage(), ips, &options, || {
+        rt.block_on(prober.probe())
+    });
+    if let ScanResult::Delays(ref mut delays) = latency {
+        if let Some(margin) = opts.retest_margin {
+            retest_borderline(rt, delays, opts, port, margin, events);
+        }
+        sort_delays(delays, opts.sort_by, opts.time, opts.loss_weight);
+        // 丢包过多的 IP 不进入下载测速和输出
+        if let Some(max_loss) = opts.max_loss {
+            delays.retain(|delay| {
+                let loss = delay.loss(opts.time);
+                if loss > max_loss {
+                    events.trace(prober.stage(), delay.ip, || {
+                        format!("dropped by --max-loss: loss {:.2}", loss)
+                    });
+                }
+                loss <= max_loss
+            });
+        }
+    }
+    if expired(deadline) {
+        println!(
+            "{}",
+            trf(Msg::MaxDurationReached, &[&latency.valid_ips().len()])
+        );
+    }
+    Some(latency)
+}
+
+/// Test the IPs within `margin` of '--au' or '--al' once more and keep only the
+/// ones whose delay over both rounds is inside the limits. `delays` were
+/// measured with the limits widened by `margin`.
+fn retest_borderline(
+    rt: &tokio::runtime::Runtime,
+    delays: &mut Vec<Delay>,
+    opts: &Opts,
+    port: u16,
+    margin: f64,
+    events: &ProgressEvents,
+) {
+    let near = |limit: u128, delay: &Delay| {
+        let ms = delay.average_delay.as_secs_f64() * 1000.0;
+        limit > 0 && (ms - limit as f64).abs() <= limit as f64 * margin
+    };
+    let borderline: HashSet<IpAddr> = delays
+        .iter()
+        .filter(|d| d.success > 0 && (near(opts.au, d) || near(opts.al, d)))
+        .map(|d| d.ip)
+        .collect();
+    if !borderline.is_empty() {
+        println!("{}", trf(Msg::RetestingBorderline, &[&borderline.len()]));
+        // 不限延迟,超出限制的结果也要保留,否则会被当成失败
+        let mut unlimited = opts.clone();
+        unlimited.au = u128::MAX;
+        unlimited.al = 0;
+        unlimited.max_jitter = None;
+        unlimited.max_loss = None;
+        unlimited.stop_after = 0;
+        unlimited.resume = None;
+        // 只有几个 IP,不换出到磁盘
+        unlimited.memory_limit = 0;
+        unlimited.max_memory = None;
+        let targets = Targets::from(borderline.iter().copied().collect::<Vec<IpAddr>>());
+        let prober = latency_prober(
+            targets,
+            &unlimited,
+            port,
+            None,
+            // 再测的样本合并进原来的结果,不再记入直方图,每个 IP 只记一轮
+            &LatencyHistogram::default(),
+            &spill::scan_dir(),
+            events,
+        );
+        let retested = match prober {
+            Ok((prober, _)) => rt.block_on(prober.probe()),
+            Err(error) => {
+                println!("{}", error);
+                return;
+            }
+        };
+        let rounds: HashMap<IpAddr, &Delay> = retested
+            .delays()
+            .unwrap_or_default()
+            .iter()
+            .map(|delay| (delay.ip, delay))
+            .collect();
+        for delay in delays.iter_mut().filter(|d| borderline.contains(&d.ip)) {
+            let failed = Delay::failed(delay.ip);
+            *delay = delay.merge(rounds.get(&delay.ip).copied().unwrap_or(&failed));
+        }
+    }
+    // 按原来的限制筛选,只被干扰而没有成功测量的 IP 照旧保留
+    let stage = if opts.udp { "udping" } else { "tcping" };
+    delays.retain(|delay| {
+        let keep = delay.success == 0 || delay.within(opts.au, opts.al);
+        if !keep {
+            events.trace(stage, delay.ip, || {
+                format!(
+                    "dropped after re-test: average delay {:.3}ms",
+                    delay.average_delay.as_secs_f64() * 1000.0
+                )
+            });
+        }
+        keep
+    });
+}
+
+/// Print how long the stages of a run over `ips` may take, before starting it
+fn print_estimates(ips: &Targets, opts: &Opts) {
+    let (stage, times, gap) = if opts.cfhttping {
+        ("cfhttping", opts.check_times.min(u32::MAX as u64) as u32, 0)
+    } else if opts.httping {
+        ("httping", opts.time as u32, 0)
+    } else if opts.udp {
+        ("udping", opts.time as u32, opts.probe_interval)
+    } else if opts.port_matrix {
+        ("port_matrix", opts.time as u32, 0)
+    } else {
+        ("tcping", opts.time as u32, opts.probe_gap)
+    };
+    // 端口矩阵同时测试所有端口,多端口测试依次测试每个端口
+    let (targets, rounds) = if opts.port_matrix {
+        (ips.len().saturating_mul(opts.port.len()), 1)
+    } else {
+        (ips.len(), opts.port.len() as u32)
+    };
+    let mut at_most = estimate::latency_stage(
+        targets,
+        opts.latency_concurrency(),
+        times,
+        Duration::from_millis(opts.timeout),
+        Duration::from_millis(gap),
+    )
+    .saturating_mul(rounds);
+    if let Some(max_duration) = opts.max_duration {
+        at_most = at_most.min(max_duration);
+    }
+    // --skip-ping 不测延迟
+    if !opts.skip_ping {
+        println!(
+            "{}",
+            trf(
+                Msg::StageEstimate,
+                &[&stage, &targets, &utils::human_readable_duration(at_most)]
+            )
+        );
+    }
+
+    // 只有单个端口的普通测试会继续下载测速
+    if opts.enable_download && !opts.port_matrix && opts.port.len() == 1 {
+        let download = estimate::download_stage(
+            opts.download_number,
+            Duration::from_secs(opts.download_timeout),
+        );
+        println!(
+            "{}",
+            trf(
+                Msg::DownloadEstimate,
+                &[&opts.download_number, &utils::human_readable_duration(download)]
+            )
+        );
+    }
+}
+
+/// Write the samples of the latency stage to '--latency-histogram'
+fn write_histogram(opts: &Opts, histogram: &LatencyHistogram) {
+    if let Some(ref path) = opts.latency_histogram {
+        if let Err(error) = histogram.write_to(path) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&path.display(), &error]));
+        }
+    }
+}
+
+/// Write the kept IPs to the '--export' file, best first and interleaved across groups
+fn export_results(
+    path: &Path,
+    measurements: &[Measurement],
+    speedtest_result: &Option<Vec<Speed>>,
+    opts: &Opts,
+) -> io::Result<()> {
+    let ranked = rank_measurements(measurements.to_vec(), speedtest_result);
+    // 没有指定分组时,测到了 colo 就按 colo 轮转
+    let group = opts.group_by.unwrap_or(if ranked.iter().any(|m| m.colo.is_some()) {
+        Group::Colo
+    } else {
+        Group::Subnet(16)
+    });
+    let exporter = Exporter {
+        format: opts.export_format,
+        template: opts.export_template.clone(),
+        port: opts.port.first(),
+        redaction: opts.redact,
+        prefix: opts.subnet_size,
+        via: opts.route_via.clone(),
+    };
+    fs::write(path, exporter.render(&export::round_robin(&ranked, group)))
+}
+
+/// Drop the results of every IP not in `keep` from all stages, tracing the
+/// dropped IPs as removed by `reason`
+fn retain_results(
+    keep: &HashSet<IpAddr>,
+    valis_ips: &mut Vec<IpAddr>,
+    latency: &mut ScanResult,
+    speedtest_result: &mut Option<Vec<Speed>>,
+    events: &ProgressEvents,
+    reason: &str,
+) {
+    for ip in valis_ips.iter().filter(|ip| !keep.contains(ip)) {
+        events.trace("filter", *ip, || format!("dropped by {}", reason));
+    }
+    valis_ips.retain(|ip| keep.contains(ip));
+    latency.retain(|ip| keep.contains(ip));
+    if let Some(ref mut speeds) = speedtest_result {
+        speeds.retain(|speed| keep.contains(&speed.ip));
+    }
+}
+
+/// Order `measurements` best first: by download speed if it was tested, the IPs it did not reach
+/// keeping their delay order after them
+fn rank_measurements(
+    mut measurements: Vec<Measurement>,
+    speedtest_result: &Option<Vec<Speed>>,
+) -> Vec<Measurement> {
+    if let Some(ref speeds) = speedtest_result {
+        // 测速结果已经按速度排好序
+        let rank: HashMap<IpAddr, usize> = speeds
+            .iter()
+            .enumerate()
+            .map(|(i, speed)| (speed.ip, i))
+            .collect();
+        measurements.sort_by_key(|m| rank.get(&m.ip).copied().unwrap_or(usize::MAX));
+    }
+    measurements
+}
+
+/// Run a latency stage, or reuse its results from '--cache' if the same targets were tested
+/// with the same options within '--cache-ttl'. `options` holds the stage specific settings.
+fn cached_stage<T, F>(opts: &Opts, stage: &str, ips: &Targets, options: &str, run: F) -> T
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> T,
+{
+    let dir = match opts.cache {
+        // 缓存里没有每次测量的样本,导出直方图时总是重新测试;
+        // 也没有 httping 的回应,保存回应或按回应分组时同样要重新测试
+        Some(ref dir)
+            if opts.latency_histogram.is_none()
+                && opts.save_responses.is_none()
+                && !opts.fingerprint =>
+        {
+            dir
+        }
+        _ => return run(),
+    };
+
+    // 所有延迟阶段共用的设置
+    let options = format!(
+        "{} {} {} {} {:?} {:?} {:?} {:?} {:?} {}",
+        opts.timeout,
+        opts.time,
+        opts.au,
+        opts.al,
+        opts.max_jitter,
+        opts.interface,
+        opts.fwmark,
+        opts.source_ip,
+        opts.netns,
+        options
+    );
+    let cache = StageCache::new(dir, opts.cache_ttl);
+    let key = StageCache::key(stage, ips, &options);
+
+    if let Some(results) = cache.get::<T>(stage, &key) {
+        println!("{}", trf(Msg::CachedStage, &[&stage, &ips.len()]));
+        return results;
+    }
+
+    let results = run();
+    // 可能到时或找到足够的 IP 后被截断,或者部分结果在磁盘上,不是完整的结果
+    if opts.max_duration.is_some()
+        || opts.stop_after > 0
+        || opts.memory_limit > 0
+        || opts.max_memory.is_some()
+    {
+        return results;
+    }
+    if let Err(error) = cache.put(stage, &key, &results) {
+        println!("{}", trf(Msg::CannotWriteCache, &[&dir.display(), &error]));
+    }
+    results
+}
+
+/// Compare two result files, warning when their settings differ
+fn run_compare(compare: &CompareOpts, redaction: Redaction) {
+    let load = |path: &std::path::Path| match ResultFile::load(path) {
+        Ok(file) => file,
+        Err(error) => {
+            println!("{}", trf(Msg::CannotReadResult, &[&path.display(), &error]));
+            std::process::exit(1);
+        }
+    };
+    compare::display(&load(&compare.before), &load(&compare.after), redaction);
+}
+
+/// Re-rank the results of CloudflareSpeedTest with the filters of a scan
+fn run_import(import: &ImportOpts, redaction: Redaction) {
+    let mut measurements = match import::load(&import.file) {
+        Ok(measurements) => measurements,
+        Err(error) => {
+            println!(
+                "{}",
+                trf(Msg::CannotReadImport, &[&import.file.display(), &error])
+            );
+            std::process::exit(1);
+        }
+    };
+    let imported = measurements.len();
+    if let Some(ref filter) = import.filter {
+        measurements.retain(|measurement| filter.matches(measurement));
+    }
+    let by = import
+        .sort
+        .unwrap_or_else(|| RankBy::default_for(&measurements));
+    import::rank(&mut measurements, by);
+    if import.top > 0 {
+        let keep: HashSet<IpAddr> = top_per_group(&measurements, import.top, import.group_by)
+            .into_iter()
+            .collect();
+        measurements.retain(|measurement| keep.contains(&measurement.ip));
+    }
+
+    println!(
+        "{}",
+        trf(Msg::ImportSummary, &[&imported, &measurements.len(), &by])
+    );
+    let show = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+    println!(
+        "{:<16} {:<8} {:<14} {:<22} {:<8}",
+        tr(Msg::IpAddress),
+        tr(Msg::Loss),
+        tr(Msg::AvgDelay),
+        tr(Msg::DownloadSpeed),
+        tr(Msg::Location)
+    );
+    for measurement in measurements.iter().take(import.display) {
+        println!(
+            "{:<16} {:<8} {:<14} {:<22} {:<8}",
+            redaction.apply(&measurement.ip),
+            show(measurement.loss),
+            show(measurement.delay_ms),
+            show(measurement.speed_mbps),
+            measurement.colo.as_deref().unwrap_or_default()
+        );
+    }
+
+    if let Some(ref path) = import.output {
+        if let Err(error) = fs::write(path, import::to_csv(&measurements, redaction)) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&path.display(), &error]));
+        }
+    }
+    if let Some(ref path) = import.ips {
+        let ips: String = measurements
+            .iter()
+            .map(|measurement| format!("{}\n", redaction.apply(&measurement.ip)))
+            .collect();
+        if let Err(error) = fs::write(path, ips) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&path.display(), &error]));
+        }
+    }
+}
+
+/// Check a summary written by '--attest'; exits with 1 if it is not valid
+fn run_verify(verify: &VerifyOpts) {
+    let attestation = match Attestation::load(&verify.attestation) {
+        Ok(attestation) => attestation,
+        Err(error) => {
+            println!(
+                "{}",
+                trf(
+                    Msg::CannotReadAttestation,
+                    &[&verify.attestation.display(), &error]
+                )
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = attestation.verify(verify.public_key.as_deref()) {
+        println!("{}", trf(Msg::AttestationInvalid, &[&error]));
+        std::process::exit(1);
+    }
+    let summary = &attestation.summary;
+    let time = |timestamp: i64| match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    };
+    println!(
+        "{}",
+        trf(
+            Msg::AttestationValid,
+            &[
+                &summary.results.len(),
+                &summary.version,
+                &time(summary.started),
+                &time(summary.finished),
+                &summary.config,
+                &attestation.public_key
+            ]
+        )
+    );
+    println!("{}", summary.settings);
+}
+
+/// Check the responses saved by '--save-responses' against the rules of `replay` again, without
+/// touching the network
+fn run_replay(replay: &ReplayOpts, redaction: Redaction) {
+    let responses = match replay::load(&replay.responses) {
+        Ok(responses) => responses,
+        Err(error) => {
+            println!(
+                "{}",
+                trf(Msg::CannotReadResponses, &[&replay.responses.display(), &error])
+            );
+            std::process::exit(1);
+        }
+    };
+    let mut builder = HttpRequestBuilder::new();
+    if let Some(ref header) = replay.http_match_header {
+        let Some((name, pattern)) = header.split_once(':') else {
+            let error = format!("expected 'Name: regex', got '{}'", header);
+            println!("{}", trf(Msg::InvalidHttpRequest, &[&error]));
+            std::process::exit(1);
+        };
+        builder = builder.match_header(name.trim(), pattern.trim());
+    }
+    let request = match builder.build() {
+        Ok(request) => request,
+        Err(error) => {
+            println!("{}", trf(Msg::InvalidHttpRequest, &[&error]));
+            std::process::exit(1);
+        }
+    };
+
+    let replayed = replay::replay(&responses, &request);
+    let matched = replayed.iter().filter(|(valid, _)| *valid).count();
+    let saved = responses.iter().filter(|saved| saved.valid).count();
+    println!(
+        "{}",
+        trf(Msg::ReplaySummary, &[&responses.len(), &matched, &saved])
+    );
+    for (valid, saved) in replayed.iter().take(replay.display) {
+        println!(
+            "{:<40} {:<6} {:>10.2} {}",
+            format!("{}:{}", redaction.apply(&saved.ip), saved.port),
+            valid,
+            saved.elapsed_ms,
+            saved.status()
+        );
+    }
+
+    if let Some(ref path) = replay.output {
+        let mut csv = String::from("IP,Port,Matched,Response Time(ms),Status\n");
+        for (valid, saved) in replayed.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{:.2},\"{}\"\n",
+                redaction.apply(&saved.ip),
+                saved.port,
+                valid,
+                saved.elapsed_ms,
+                saved.status().replace('"', "\"\"")
+            ));
+        }
+        if let Err(error) = fs::write(path, csv) {
+            println!("{}", trf(Msg::CannotWriteResult, &[&path.display(), &error]));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Download the provider lists into the cache directory
+#[cfg(feature = "download")]
+fn run_update_providers(opts: &Opts, update: &UpdateProvidersOpts) {
+    let Some(ref dir) = opts.cache else {
+        println!("{}", tr(Msg::ProvidersNeedCache));
+        std::process::exit(1);
+    };
+    let providers = if update.providers.is_empty() {
+        providers::Provider::ALL.to_vec()
+    } else {
+        update.providers.clone()
+    };
 
-use input::Opts;
-use scanner::{Delay, Scanner};
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    for provider in providers {
+        match rt.block_on(provider.update(dir)) {
+            Ok(count) => println!(
+                "{}",
+                trf(
+                    Msg::ProviderUpdated,
+                    &[&provider, &count, &provider.path(dir).display()]
+                )
+            ),
+            Err(error) => println!("{}", trf(Msg::CannotUpdateProvider, &[&provider, &error])),
+        }
+    }
+}
 
-mod download;
-mod httping;
-mod input;
-mod routes;
-mod scanner;
-mod utils;
+#[cfg(not(feature = "download"))]
+fn run_update_providers(_opts: &Opts, _update: &UpdateProvidersOpts) {}
 
-fn main() {
-    let opts: Opts = Opts::read();
-    let ips = parse_addresses_from_opt(&opts);
+/// Summarize the history database into a trend report
+fn run_report(report: &ReportOpts, redaction: Redaction) {
+    let history = match History::open(&report.history) {
+        Ok(history) => history,
+        Err(error) => {
+            println!("{}", trf(Msg::CannotOpenHistory, &[&report.history.display(), &error]));
+            std::process::exit(1);
+        }
+    };
 
-    if ips.is_empty() {
-        println!(
-            "No IPs could be resolved, aborting scan.\n Please check arguments: {:?}",
-            opts
-        );
-        std::process::exit(1);
+    let since = Local::now().timestamp() - report.since.as_secs() as i64;
+    let window = match history.load_since(since) {
+        Ok(window) => window,
+        Err(error) => {
+            println!("{}", trf(Msg::CannotOpenHistory, &[&report.history.display(), &error]));
+            std::process::exit(1);
+        }
+    };
+
+    let since = match Local.timestamp_opt(since, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => since.to_string(),
+    };
+    let title = trf(Msg::ReportTitle, &[&window.runs.len(), &since]);
+    let mut tables = report::build_report(&window, redaction);
+    if let Some(peak) = report.peak {
+        tables.push(report::peak_report(
+            &window,
+            peak,
+            report.off_peak,
+            report.peak_tolerance as f64 / 100.0,
+            redaction,
+        ));
     }
+    let text = report::render(&title, &tables, report.format);
 
-    // create a tokio runtime
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .global_queue_interval(u32::MAX)
-        .event_interval(31)
-        .build()
-        .unwrap();
+    match report.output {
+        Some(ref path) => {
+            if let Err(error) = fs::write(path, text) {
+                println!("{}", trf(Msg::CannotWriteReport, &[&path.display(), &error]));
+                std::process::exit(1);
+            }
+        }
+        None => print!("{}", text),
+    }
+}
 
-    // tcp 测试结果
-    let mut tcping_result: Option<Vec<Delay>> = None;
-    // http 测试结果
-    let mut httping_result: Option<Vec<HttpingResult>> = None;
-    // http cf-ray 结果
-    let mut cfcdn_result: Option<Vec<CFCDNCheckResult>> = None;
-    // 可用IP地址集合
-    let mut valis_ips: Vec<IpAddr> = Vec::new();
-    // 测速结果
-    let mut speedtest_result: Option<Vec<Speed>> = None;
+/// Keep serving the web ui after all stages finished until interrupted
+fn keep_web_ui(rt: &tokio::runtime::Runtime, opts: &Opts) {
+    if opts.web.is_some() {
+        println!("{}", tr(Msg::WebUiWaiting));
+        let _ = rt.block_on(tokio::signal::ctrl_c());
+    }
+}
 
-    // tcp 和 http 和 cfhttp 选择其中一个
-    if opts.cfhttping {
-        cfcdn_result = Some(rt.block_on(run_checker(ips, &opts)));
-        if let Some(ref record) = cfcdn_result {
-            valis_ips = record.iter().map(|r| r.ip).collect();
+fn display_size_sweep(sizes: &[usize], results: &[SweepResult], opts: &Opts) {
+    println!("{}", tr(Msg::SizeSweepResults));
+    let mut header = format!("{:<16}", tr(Msg::IpAddress));
+    for size in sizes {
+        header.push_str(&format!(" {:<9}", format!("{}B", size)));
+    }
+    header.push_str(&format!(" {}", tr(Msg::SizeSlope)));
+    println!("{}", header);
+
+    for result in results {
+        let mut line = format!("{:<16}", opts.redact.apply(&result.ip));
+        for delay in result.delays.iter() {
+            let cell = match delay {
+                Some(delay) => format!("{:.1}", delay.as_secs_f64() * 1000.0),
+                None => "-".to_string(),
+            };
+            line.push_str(&format!(" {:<9}", cell));
         }
-    } else if opts.httping {
-        httping_result = Some(async_std::task::block_on(run_httping(ips, &opts)));
-        if let Some(ref record) = httping_result {
-            valis_ips = record.iter().map(|r| r.ip).collect();
+        match result.slope_ms_per_kb(sizes) {
+            Some(slope) => line.push_str(&format!(" {:.2}", slope)),
+            None => line.push_str(" -"),
         }
-    } else {
-        tcping_result = Some(rt.block_on(run_scanner(ips, &opts)));
-        if let Some(ref record) = tcping_result {
-            valis_ips = record.iter().map(|r| r.ip).collect();
+        println!("{}", line);
+    }
+}
+
+fn display_flows(results: &[FlowResult], opts: &Opts) {
+    println!("{}", tr(Msg::FlowResults));
+    let mut header = format!("{:<16}", tr(Msg::IpAddress));
+    for flow in 0..opts.flows {
+        header.push_str(&format!(" {:<9}", format!(":{}", FLOW_SOURCE_PORT_BASE.wrapping_add(flow))));
+    }
+    header.push_str(&format!(" {}", tr(Msg::FlowSpread)));
+    println!("{}", header);
+
+    for result in results {
+        let mut line = format!("{:<16}", opts.redact.apply(&result.ip));
+        for delay in result.delays.iter() {
+            let cell = match delay {
+                Some(delay) => format!("{:.1}", delay.as_secs_f64() * 1000.0),
+                None => "-".to_string(),
+            };
+            line.push_str(&format!(" {:<9}", cell));
+        }
+        match result.spread() {
+            Some(spread) => {
+                let verdict = if result.is_unstable() {
+                    Msg::FlowUnstable
+                } else {
+                    Msg::FlowStable
+                };
+                line.push_str(&format!(
+                    " {:.1} {}",
+                    spread.as_secs_f64() * 1000.0,
+                    tr(verdict)
+                ))
+            }
+            None => line.push_str(" -"),
         }
+        println!("{}", line);
     }
+}
 
-    // 是否启用下载测速
-    if !opts.enable_download {
-        println!("Disable download speed test.exiting...");
-    } else {
-        speedtest_result = Some(rt.block_on(run_downloader(&valis_ips, &opts)));
+fn display_keepalive(results: &[KeepAliveResult], opts: &Opts) {
+    println!(
+        "{}",
+        trf(Msg::KeepAliveResults, &[&opts.keepalive_requests])
+    );
+    println!(
+        "{:<16} {:<10} {:<10} {:<10} {:<10} {}",
+        tr(Msg::IpAddress),
+        tr(Msg::KeepAliveSetup),
+        tr(Msg::KeepAliveFirst),
+        tr(Msg::KeepAliveSteady),
+        tr(Msg::KeepAliveDone),
+        tr(Msg::KeepAliveLatencies)
+    );
+
+    let ms = |d: Option<Duration>| match d {
+        Some(d) => format!("{:.1}", d.as_secs_f64() * 1000.0),
+        None => "-".to_string(),
+    };
+    for result in results {
+        let latencies: Vec<String> = result.requests.iter().map(|d| ms(Some(*d))).collect();
+        println!(
+            "{:<16} {:<10} {:<10} {:<10} {:<10} {}",
+            opts.redact.apply(&result.ip),
+            ms(result.setup),
+            ms(result.requests.first().copied()),
+            ms(result.steady()),
+            format!("{}/{}", result.requests.len(), opts.keepalive_requests),
+            latencies.join(" ")
+        );
     }
+}
 
-    // 简单显示结果
-    if opts.display != 0 {
-        display_results(&tcping_result, &cfcdn_result, &speedtest_result, &opts);
+fn display_cache_probe(results: &[CacheResult], opts: &Opts) {
+    println!("{}", tr(Msg::CacheProbeResults));
+    println!(
+        "{:<16} {:<24} {:<24} {}",
+        tr(Msg::IpAddress),
+        tr(Msg::CacheFirstRequest),
+        tr(Msg::CacheSecondRequest),
+        tr(Msg::CacheMissPenalty)
+    );
+
+    let cell = |response: &Option<CacheResponse>| match response {
+        Some(response) => format!(
+            "{} {} {:.1}",
+            response.status.as_deref().unwrap_or("-"),
+            response
+                .age
+                .map_or("-".to_string(), |age| format!("{}s", age)),
+            response.ttfb.as_secs_f64() * 1000.0
+        ),
+        None => "-".to_string(),
+    };
+    for result in results {
+        let penalty = match result.miss_penalty() {
+            Some(penalty) => format!("{:.1}", penalty.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<16} {:<24} {:<24} {}",
+            opts.redact.apply(&result.ip),
+            cell(&result.first),
+            cell(&result.second),
+            penalty
+        );
     }
+}
 
-    // 写入到csv文件中
-    match utils::write_to_csv(
-        &valis_ips,
-        tcping_result,
-        // httping_result,
-        cfcdn_result,
-        speedtest_result,
-        &opts,
-    ) {
-        Ok(_) => {}
-        Err(error) => {
-            println!(
-                "Warn: Cannot write result to {}\nError message:{}",
-                &opts.output, error,
-            );
-        }
+fn display_cache_warm(results: &[WarmResult], opts: &Opts) {
+    println!("{}", tr(Msg::CacheWarmResults));
+    for result in results {
+        let responses: Vec<String> = result
+            .responses
+            .iter()
+            .map(|response| match response {
+                Some(warmed) => match warmed.status {
+                    Some(ref status) => format!("{}/{}", warmed.code, status),
+                    None => warmed.code.to_string(),
+                },
+                None => "-".to_string(),
+            })
+            .collect();
+        println!(
+            "{:<16} {:<9} {}",
+            opts.redact.apply(&result.ip),
+            format!("{}/{}", result.warmed(), result.responses.len()),
+            responses.join(" ")
+        );
     }
 }
 
 fn display_results(
-    tcping_result: &Option<Vec<Delay>>,
-    cfcdn_result: &Option<Vec<CFCDNCheckResult>>,
+    latency: &ScanResult,
     speedtest_result: &Option<Vec<Speed>>,
     opts: &Opts,
 ) {
     if let Some(ref results) = speedtest_result {
-        println!("Download speed test results:");
-        println!("{:<16} {:<12}", "IP Address", "Download Speed (MB/s)");
+        println!("{}", tr(Msg::DownloadResults));
+        println!("{:<16} {:<12}", tr(Msg::IpAddress), tr(Msg::DownloadSpeed));
         for record in results.iter().take(opts.display) {
             let download_speed = record.total_download as f64
                 / 1024.0
                 / 1024.0
                 / record.consume.as_secs_f32() as f64;
-            println!("{:<16} {:<12.2}", record.ip, download_speed);
+            println!("{:<16} {:<12.2}", opts.redact.apply(&record.ip), download_speed);
         }
-    } else if let Some(ref results) = tcping_result {
-        println!("TCP scan results:");
+    } else if let Some(results) = latency.delays() {
+        println!("{}", tr(Msg::TcpResults));
+        // 测量到 TLS/HTTP 时把连接和之后的时间分开显示
+        let split = results.iter().any(|r| r.connect_delay.is_some());
         println!(
-            "{:<16} {:<9} {:<9} {:<8} {:<14}",
-            "IP Address", "Sent", "Received", "Loss", "Avg Delay (ms)"
+            "{:<16} {:<9} {:<9} {:<8} {:<14} {:<14} {:<10} {:<14}{}",
+            tr(Msg::IpAddress),
+            tr(Msg::Sent),
+            tr(Msg::Received),
+            tr(Msg::Loss),
+            tr(Msg::AvgDelay),
+            tr(Msg::MinMaxDelay),
+            tr(Msg::Jitter),
+            "P50/P90/P99",
+            if split {
+                format!(" {}", tr(Msg::ConnectHandshake))
+            } else {
+                String::new()
+            }
         );
         for record in results.iter().take(opts.display) {
-            let delay_ms = record.average_delay.as_millis();
+            let ms = |duration| utils::format_millis(duration, opts.latency_precision);
             let loss_percent = 100.0 * (1.0 - record.success as f64 / opts.time as f64);
+            let percentiles = record.percentiles;
+            let connect_handshake = match (record.connect_delay, record.handshake_delay()) {
+                (Some(connect), Some(handshake)) if split => format!(
+                    " {:.1}/{:.1}",
+                    connect.as_secs_f64() * 1000.0,
+                    handshake.as_secs_f64() * 1000.0
+                ),
+                _ if split => " -".to_string(),
+                _ => String::new(),
+            };
             println!(
-                "{:<16} {:<9} {:<9} {:<8} {:<14}",
-                record.ip,
+                "{:<16} {:<9} {:<9} {:<8} {:<14} {:<14} {:<10.1} {:<14}{}",
+                opts.redact.apply(&record.ip),
                 opts.time,
                 record.success,
                 format!("{:.1}%", loss_percent),
-                delay_ms
+                ms(record.average_delay),
+                format!("{}/{}", ms(record.min_delay), ms(record.max_delay)),
+                record.jitter.as_secs_f64() * 1000.0,
+                format!(
+                    "{}/{}/{}",
+                    ms(percentiles.p50),
+                    ms(percentiles.p90),
+                    ms(percentiles.p99)
+                ),
+                connect_handshake
+            );
+        }
+        let interfered: Vec<String> = results
+            .iter()
+            .filter(|r| r.interference_suspected())
+            .take(opts.display)
+            .map(|r| opts.redact.apply(&r.ip))
+            .collect();
+        if !interfered.is_empty() {
+            let count = results.iter().filter(|r| r.interference_suspected()).count();
+            println!(
+                "{}",
+                trf(Msg::InterferenceSuspected, &[&count, &interfered.join(", ")])
             );
         }
-    } else if let Some(ref results) = cfcdn_result {
-        println!("HTTP routing check results:");
+    } else if let Some(results) = latency.http() {
+        println!("{}", tr(Msg::HttpResults));
+        println!(
+            "{:<16} {:<10} {}",
+            tr(Msg::IpAddress),
+            tr(Msg::Ttfb),
+            tr(Msg::HttpSteps)
+        );
+        let ms = |d: Duration| format!("{:.1}", d.as_secs_f64() * 1000.0);
+        for record in results.iter().take(opts.display) {
+            let ttfb = record.ttfb.map_or("-".to_string(), ms);
+            let steps = record.steps.map_or("-".to_string(), |steps| {
+                format!("{}/{}/{}", ms(steps.connect), ms(steps.send), ms(steps.recv))
+            });
+            println!(
+                "{:<16} {:<10} {}",
+                opts.redact.apply(&record.ip),
+                ttfb,
+                steps
+            );
+        }
+    } else if let Some(results) = latency.routes() {
+        println!("{}", tr(Msg::RouteResults));
         println!(
             "{:<16} {:<9} {:<9} {:<8}",
-            "IP Address", "Status", "Location", ""
+            tr(Msg::IpAddress),
+            tr(Msg::Status),
+            tr(Msg::Location),
+            ""
         );
         for record in results.iter().take(opts.display) {
             let status_code = match record.route_status {
@@ -145,29 +1835,20 @@ fn display_results(
             };
             println!(
                 "{:<16} {:<9} {:<9} {:<8}",
-                record.ip, status_code, record.location_code, ""
+                opts.redact.apply(&record.ip),
+                status_code,
+                record.location_code,
+                ""
             );
         }
     }
 }
 
-async fn run_httping(ips: Vec<IpAddr>, opts: &Opts) -> Vec<HttpingResult> {
-    let httping_checker = HttpingChecker::new(
-        opts.time,
-        Duration::from_millis(opts.timeout),
-        opts.port,
-        opts.number,
-        "",
-    );
-
-    httping_checker.run(ips).await
-}
-
-async fn run_downloader(ips: &[IpAddr], opts: &Opts) -> Vec<Speed> {
+async fn run_downloader(ips: &[IpAddr], opts: &Opts, events: &ProgressEvents) -> Vec<Speed> {
     let domain: String = match utils::get_domain_from_url(opts.download_url.as_str()) {
         Ok(h) => h,
         Err(e) => {
-            println!("Cannot get host for speed test url;\nError message: {}", e);
+            println!("{}", trf(Msg::CannotGetDownloadHost, &[&e]));
             std::process::exit(1);
         }
     };
@@ -182,66 +1863,604 @@ async fn run_downloader(ips: &[IpAddr], opts: &Opts) -> Vec<Speed> {
         opts.download_port,
         opts.download_url.to_string(),
         opts.download_number,
-    );
+    )
+    .with_dns(opts.dns)
+    .with_events(events.clone())
+    .with_concurrency(opts.download_concurrency);
 
     let mut speedtest_result = downloader.run().await;
     speedtest_result.sort();
     speedtest_result
 }
 
-async fn run_scanner(ips: Vec<IpAddr>, opts: &Opts) -> Vec<Delay> {
-    let scanner = Scanner::new(
+/// The latency engine chosen by the options, with the options that change its results, or the
+/// message why the options are invalid
+fn latency_prober(
+    ips: Targets,
+    opts: &Opts,
+    port: u16,
+    deadline: Option<Instant>,
+    histogram: &LatencyHistogram,
+    spill_dir: &Path,
+    events: &ProgressEvents,
+) -> Result<(Box<dyn Prober>, String), String> {
+    let timeout = Duration::from_millis(opts.timeout);
+    let socket_options = socket_options_from_opt(opts);
+
+    // 只有 tcping 按需生成目标,其他引擎需要完整的列表
+    if opts.cfhttping {
+        only_connect_scanner(opts, "--cfhttping")?;
+        let checker = CloudflareChecker::new(ips.iter().collect(), opts.check_times, timeout, 80, opts.latency_concurrency())
+            .with_socket_options(socket_options)
+            .with_events(events.clone())
+            .with_deadline(deadline);
+        Ok((Box::new(checker), format!("{}", opts.check_times)))
+    } else if opts.httping {
+        only_connect_scanner(opts, "--httping")?;
+        let request = http_request_from_opt(opts)
+            .map_err(|error| trf(Msg::InvalidHttpRequest, &[&error]))?;
+        let responses = match opts.save_responses {
+            Some(ref path) => ResponseLog::open(path)
+                .map_err(|error| trf(Msg::CannotSaveResponses, &[&path.display(), &error]))?,
+            None => ResponseLog::default(),
+        };
+        let checker = HttpingChecker::new(opts.time, timeout, port, opts.latency_concurrency(), "")
+            .with_socket_options(socket_options)
+            .with_events(events.clone())
+            .with_deadline(deadline)
+            .with_request(request)
+            .with_ips(ips.iter().collect())
+            .with_response_log(responses)
+            .with_fingerprints(Fingerprints::new(opts.fingerprint))
+            .with_max_ttfb(opts.max_ttfb.map(Duration::from_millis));
+        let options = format!(
+            "{:?} {} {:?} {:?} {:?}",
+            opts.http_method,
+            opts.http_path,
+            opts.http_header,
+            opts.http_match_header,
+            opts.max_ttfb
+        );
+        Ok((Box::new(checker), options))
+    } else if opts.udp {
+        only_connect_scanner(opts, "--udp")?;
+        let pinger = UdpPinger::new(
+            ips.iter().collect(),
+            opts.latency_concurrency(),
+            timeout,
+            opts.time,
+            port,
+            opts.probe_size,
+            Duration::from_millis(opts.probe_interval),
+            opts.au,
+            opts.al,
+        )
+        .with_socket_options(socket_options)
+        .with_events(events.clone())
+        .with_deadline(deadline)
+        .with_max_jitter(max_jitter(opts))
+        .with_stop_after(stop_after(opts))
+        .with_histogram(histogram.clone())
+        .with_payload(opts.udp_payload.clone());
+        let options = format!(
+            "{} {} {}",
+            opts.probe_size, opts.probe_interval, opts.udp_payload
+        );
+        Ok((Box::new(pinger), options))
+    } else if opts.syn_scan
+        && opts.latency_metric == LatencyMetric::Tcp
+        && syn_scan_available(&socket_options)
+    {
+        only_connect_scanner(opts, "--syn-scan")?;
+        let scanner = SynScanner::new(
+            ips,
+            opts.latency_concurrency(),
+            timeout,
+            opts.time,
+            port,
+            opts.au,
+            opts.al,
+        )
+        .with_socket_options(socket_options)
+        .with_events(events.clone())
+        .with_deadline(deadline)
+        .with_max_jitter(max_jitter(opts))
+        .with_stop_after(stop_after(opts))
+        .with_histogram(histogram.clone());
+        // 只测到 SYN/ACK,与完整连接的结果不同,不共用缓存
+        Ok((Box::new(scanner), "syn".to_string()))
+    } else if opts.raw_scanner && opts.latency_metric == LatencyMetric::Tcp {
+        only_connect_scanner(opts, "--raw-scanner")?;
+        let mut scanner = RawScanner::new(
+            ips,
+            opts.latency_concurrency(),
+            timeout,
+            opts.time,
+            port,
+            opts.au,
+            opts.al,
+        )
+        .with_socket_options(socket_options)
+        .with_events(events.clone())
+        .with_calibration(opts.calibrate)
+        .with_probe_gap(Duration::from_millis(opts.probe_gap))
+        .with_deadline(deadline)
+        .with_max_jitter(max_jitter(opts))
+        .with_max_loss(opts.max_loss)
+        .with_stop_after(stop_after(opts))
+        .with_histogram(histogram.clone())
+        .with_adaptive_timeout(adaptive_timeout(opts))
+        .with_retry(retry_policy(opts))
+        .with_dead_subnets(dead_subnets(opts));
+        if spills(opts) {
+            scanner = scanner.with_memory_limit(spill_dir, opts.memory_limit);
+        }
+        if opts.port.len() == 1 {
+            scanner = scanner.with_memory_guard(MemoryGuard::new(opts.max_memory, spill_dir));
+        }
+        // 和 Scanner 的测量结果相同,可以共用缓存,但不测 --sentinel-interval
+        let options = tcping_options(opts, None);
+        Ok((Box::new(scanner), options))
+    } else {
+        // 丢包过多的 IP 在换出到磁盘之前就丢弃
+        let mut scanner = scanner_from_opt(ips, opts, port, socket_options, events)
+            .with_deadline(deadline)
+            .with_max_loss(opts.max_loss)
+            .with_histogram(histogram.clone());
+        if let Some(ref path) = opts.resume {
+            let config = format!("{} {}", RunConfig::from_opts(opts).fingerprint(), port);
+            scanner = scanner.with_checkpoint(path, &config);
+        }
+        if spills(opts) {
+            scanner = scanner.with_memory_limit(spill_dir, opts.memory_limit);
+        }
+        if opts.port.len() == 1 {
+            scanner = scanner.with_memory_guard(MemoryGuard::new(opts.max_memory, spill_dir));
+        }
+        let options = tcping_options(opts, opts.sentinel_interval);
+        Ok((Box::new(scanner), options))
+    }
+}
+
+/// Refuse the options only the tcp connect scanner implements for the `engine` chosen instead
+fn only_connect_scanner(opts: &Opts, engine: &str) -> Result<(), String> {
+    if opts.error_budget.is_some() {
+        return Err(trf(Msg::OnlyConnectScanner, &[&"--error-budget", &engine]));
+    }
+    if opts.resume.is_some() {
+        return Err(trf(Msg::OnlyConnectScanner, &[&"--resume", &engine]));
+    }
+    Ok(())
+}
+
+/// Refuse '--retest-margin' when results may be spilled to disk, where they
+/// would keep the widened limits without being re-tested
+fn retest_in_memory(opts: &Opts) -> Result<(), String> {
+    if opts.retest_margin.is_none() || opts.port.len() != 1 {
+        return Ok(());
+    }
+    if opts.memory_limit > 0 {
+        return Err(trf(Msg::RetestWithSpill, &[&"--memory-limit"]));
+    }
+    if opts.max_memory.is_some() {
+        return Err(trf(Msg::RetestWithSpill, &[&"--max-memory"]));
+    }
+    Ok(())
+}
+
+/// The settings the results of the tcp connect scanners depend on, for their '--cache' key.
+/// `sentinel` is the '--sentinel-interval' the scanner writes a Sentinel column with.
+fn tcping_options(opts: &Opts, sentinel: Option<Duration>) -> String {
+    format!(
+        "{} {} {} {} {:?} {} {} {:?} {:?} {:?}",
+        opts.latency_metric,
+        tls_server_name(opts),
+        opts.calibrate,
+        opts.probe_gap,
+        opts.adaptive_timeout,
+        opts.retries,
+        opts.skip_dead_subnets,
+        sentinel,
+        opts.error_budget,
+        opts.max_loss
+    )
+}
+
+fn scanner_from_opt(
+    ips: Targets,
+    opts: &Opts,
+    port: u16,
+    socket_options: SocketOptions,
+    events: &ProgressEvents,
+) -> Scanner {
+    let server_name = match opts.latency_metric {
+        LatencyMetric::Tcp => String::new(),
+        _ => tls_server_name(opts),
+    };
+
+    Scanner::new(
         ips,
-        opts.number,
+        opts.latency_concurrency(),
         Duration::from_millis(opts.timeout),
         opts.time,
-        opts.port,
+        port,
         opts.au,
         opts.al,
-    );
+    )
+    .with_socket_options(socket_options)
+    .with_events(events.clone())
+    .with_latency_metric(opts.latency_metric, &server_name)
+    .with_calibration(opts.calibrate)
+    .with_probe_gap(Duration::from_millis(opts.probe_gap))
+    .with_max_jitter(max_jitter(opts))
+    .with_stop_after(stop_after(opts))
+    .with_error_budget(opts.error_budget, opts.control_target)
+    .with_adaptive_timeout(adaptive_timeout(opts))
+    .with_retry(retry_policy(opts))
+    .with_dead_subnets(dead_subnets(opts))
+    .with_sentinel(opts.sentinel_interval.map(|interval| {
+        Sentinel::new(opts.control_target, interval, Duration::from_millis(opts.timeout))
+            .with_socket_options(socket_options_from_opt(opts))
+            .with_events(events.clone())
+    }))
+}
+
+/// Whether the tcping results beyond '--memory-limit' go to disk. Only the
+/// plain single-port run writes them back into its output, which is also
+/// when '--max-memory' can fall back to disk.
+fn spills(opts: &Opts) -> bool {
+    opts.memory_limit > 0 && opts.port.len() == 1
+}
+
+/// A fresh '--adaptive-timeout' for one scan, disabled when not set
+fn adaptive_timeout(opts: &Opts) -> AdaptiveTimeout {
+    opts.adaptive_timeout
+        .map_or_else(AdaptiveTimeout::default, AdaptiveTimeout::new)
+}
+
+/// A fresh '--skip-dead-subnets' for one scan, disabled when not set
+fn dead_subnets(opts: &Opts) -> DeadSubnets {
+    DeadSubnets::new(opts.skip_dead_subnets, opts.subnet_size)
+}
+
+/// A fresh '--stop-after' for one scan, disabled when not set
+fn stop_after(opts: &Opts) -> StopAfter {
+    StopAfter::new(opts.stop_after)
+}
+
+fn retry_policy(opts: &Opts) -> RetryPolicy {
+    RetryPolicy::new(opts.retries, Duration::from_millis(opts.retry_backoff))
+}
+
+fn max_jitter(opts: &Opts) -> Option<Duration> {
+    opts.max_jitter.map(Duration::from_millis)
+}
 
-    let mut result = scanner.run().await;
+async fn run_scanner(
+    ips: Targets,
+    opts: &Opts,
+    socket_options: SocketOptions,
+    deadline: Option<Instant>,
+    events: &ProgressEvents,
+) -> Vec<Delay> {
+    let mut result = scanner_from_opt(ips, opts, opts.port.first(), socket_options, events)
+        .with_deadline(deadline)
+        .run()
+        .await;
     result.sort();
     result
 }
 
-async fn run_checker(ips: Vec<IpAddr>, opts: &Opts) -> Vec<CFCDNCheckResult> {
-    let checker = CloudflareChecker::new(
-        ips,
-        10,
+async fn run_size_sweep(
+    ips: &[IpAddr],
+    sizes: &[usize],
+    opts: &Opts,
+    events: &ProgressEvents,
+) -> Vec<SweepResult> {
+    let mode = if opts.udp {
+        SweepMode::UdpEcho {
+            interval: Duration::from_millis(opts.probe_interval),
+        }
+    } else {
+        SweepMode::Https {
+            server_name: tls_server_name(opts),
+            tls: tls::connector(),
+        }
+    };
+
+    let sweep = SizeSweep::new(
+        sizes.to_vec(),
+        opts.time,
         Duration::from_millis(opts.timeout),
-        80,
-        opts.number,
-    );
-    let mut result = checker.check_routes().await;
-    result.sort();
-    result
+        opts.port.first(),
+        mode,
+    )
+    .with_socket_options(socket_options_from_opt(opts))
+    .with_events(events.clone());
+
+    sweep.run(ips).await
 }
 
-fn parse_addresses_from_opt(opts: &Opts) -> Vec<IpAddr> {
-    let mut ips: Vec<IpAddr> = Vec::new();
-    for arg in opts.args.iter() {
-        let content: String = match std::fs::read_to_string(arg) {
-            Ok(text) => text,
-            Err(_) => arg.to_string(),
-        };
-        let parse_ips = utils::parse_addresses(&content);
-        ips.extend(parse_ips.iter());
+/// TLS 握手需要 SNI,取自测速地址
+fn tls_server_name(opts: &Opts) -> String {
+    match utils::get_domain_from_url(opts.download_url.as_str()) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("{}", trf(Msg::CannotGetDownloadHost, &[&e]));
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_port_matrix(
+    ips: Targets,
+    opts: &Opts,
+    deadline: Option<Instant>,
+    events: &ProgressEvents,
+) -> PortMatrix {
+    let ips: Vec<IpAddr> = ips.iter().collect();
+    let scanner = PortScanner::new(
+        ips.clone(),
+        opts.port.clone(),
+        opts.latency_concurrency(),
+        Duration::from_millis(opts.timeout),
+        opts.time,
+    )
+    .with_socket_options(socket_options_from_opt(opts))
+    .with_events(events.clone())
+    .with_deadline(deadline);
+
+    let mut matrix = PortMatrix::new(ips, opts.port.iter().collect());
+    for (port, delay) in scanner.run().await {
+        matrix.insert(port, delay);
     }
+    matrix
+}
 
-    let set: HashSet<IpAddr> = ips.into_iter().collect();
-    let uniq_ips: Vec<IpAddr> = set.into_iter().collect();
+async fn run_uplink_comparison(
+    ips: Targets,
+    opts: &Opts,
+    deadline: Option<Instant>,
+    events: &ProgressEvents,
+) -> UplinkComparison {
+    let uplinks = uplinks(opts);
+    let mut comparison =
+        UplinkComparison::new(uplinks.iter().map(|(name, _)| name.clone()).collect());
+    for (name, socket_options) in uplinks {
+        println!("{}", trf(Msg::ScanningInterface, &[&name]));
+        let result = run_scanner(ips.clone(), opts, socket_options, deadline, events).await;
+        comparison.insert(&name, result);
+    }
+    comparison
+}
 
-    let sample_size = if opts.random_number > 0 && opts.random_number < uniq_ips.len() {
-        opts.random_number
+/// Every combination of '--interface' and '--source-ip', named after what it uses
+fn uplinks(opts: &Opts) -> Vec<(String, SocketOptions)> {
+    let interfaces: Vec<Option<String>> = if opts.interface.is_empty() {
+        vec![None]
+    } else {
+        opts.interface.iter().cloned().map(Some).collect()
+    };
+    let sources: Vec<Option<IpAddr>> = if opts.source_ip.is_empty() {
+        vec![None]
     } else {
-        uniq_ips.len()
+        opts.source_ip.iter().copied().map(Some).collect()
     };
 
-    sample(&mut rand::thread_rng(), uniq_ips.len(), sample_size)
-        .into_iter()
-        .map(|i| uniq_ips[i])
-        .collect()
+    let mut uplinks = Vec::new();
+    for interface in interfaces.iter() {
+        for source_ip in sources.iter() {
+            let name = match (interface, source_ip) {
+                (Some(interface), Some(ip)) => format!("{}/{}", interface, ip),
+                (Some(interface), None) => interface.clone(),
+                (None, Some(ip)) => ip.to_string(),
+                (None, None) => String::new(),
+            };
+            let socket_options = SocketOptions {
+                interface: interface.clone(),
+                source_ip: *source_ip,
+                ..socket_options_from_opt(opts)
+            };
+            uplinks.push((name, socket_options));
+        }
+    }
+    uplinks
+}
+
+/// The httping request described by the '--http-*' options
+fn http_request_from_opt(opts: &Opts) -> Result<HttpRequest, String> {
+    let split = |header: &str| match header.split_once(':') {
+        Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+        None => Err(format!("expected 'Name: value', got '{}'", header)),
+    };
+
+    let mut builder = HttpRequestBuilder::new()
+        .method(opts.http_method)
+        .path(&opts.http_path);
+    for header in opts.http_header.iter() {
+        let (name, value) = split(header)?;
+        builder = builder.header(&name, &value);
+    }
+    if let Some(ref header) = opts.http_match_header {
+        let (name, pattern) = split(header)?;
+        builder = builder.match_header(&name, &pattern);
+    }
+    builder.build()
+}
+
+/// The cargo feature `opts` need that this binary was built without, if any
+fn missing_feature(opts: &Opts) -> Option<&'static str> {
+    let history = opts.history.is_some() || matches!(opts.cmd, Some(Command::Report(_)));
+    let tls = opts.latency_metric != LatencyMetric::Tcp
+        || opts.keep_warm
+        || (!opts.size_sweep.is_empty() && !opts.udp)
+        || opts
+            .cache_probe
+            .as_ref()
+            .is_some_and(|url| url.scheme() == "https")
+        || (opts.keepalive_requests > 0 && opts.keepalive_url.scheme() == "https")
+        || opts.attest.is_some()
+        || matches!(opts.cmd, Some(Command::Verify(_)));
+
+    let download = opts.enable_download
+        || matches!(opts.cmd, Some(Command::UpdateProviders(_)))
+        || opts.sources.iter().any(|source| matches!(source, Source::Asn(_)));
+
+    if download && !cfg!(feature = "download") {
+        Some("download")
+    } else if history && !cfg!(feature = "history") {
+        Some("history")
+    } else if tls && !cfg!(feature = "tls") {
+        Some("tls")
+    } else {
+        None
+    }
+}
+
+/// Exit with a clear message when the socket options of `opts` cannot be applied
+fn check_socket_options(opts: &Opts) {
+    for (_, options) in uplinks(opts) {
+        if let Err((error, capability)) = options.check() {
+            println!("{}", trf(Msg::CannotApplySocketOptions, &[&error]));
+            print_capability_hint(&error, capability);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Whether '--syn-scan' can open its raw socket; if not, says so and that the
+/// connect scan is used instead
+fn syn_scan_available(socket_options: &SocketOptions) -> bool {
+    match synscan::available(socket_options) {
+        Ok(()) => true,
+        Err(error) => {
+            println!("{}", trf(Msg::SynScanUnavailable, &[&error]));
+            print_capability_hint(&error, synscan::SYN_CAPABILITY);
+            false
+        }
+    }
+}
+
+/// Suggest granting `capability` when `error` is a missing privilege
+fn print_capability_hint(error: &std::io::Error, capability: &str) {
+    if error.kind() != std::io::ErrorKind::PermissionDenied {
+        return;
+    }
+    let exe = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "rustspeedtest".to_string());
+    println!("{}", trf(Msg::NeedsCapability, &[&capability, &capability, &exe]));
+}
+
+fn socket_options_from_opt(opts: &Opts) -> SocketOptions {
+    SocketOptions {
+        interface: opts.interface.first().cloned(),
+        fwmark: opts.fwmark,
+        source_ip: opts.source_ip.first().copied(),
+    }
+}
+
+/// The addresses '-a' adds for `source`; none if a host name cannot be resolved
+fn source_targets(source: &Source, opts: &Opts) -> Targets {
+    match source {
+        Source::Provider(provider) => {
+            Targets::parse(&provider.list(opts.cache.as_deref()), opts.ipv6_samples)
+        }
+        Source::Stdin => Targets::read(std::io::stdin().lock(), opts.ipv6_samples),
+        Source::Asn(asn) => {
+            #[cfg(feature = "download")]
+            match providers::announced_prefixes(*asn, opts.cache.as_deref()) {
+                Ok(text) => Targets::parse(&text, opts.ipv6_samples),
+                Err(error) => {
+                    println!("{}", trf(Msg::CannotFetchPrefixes, &[&source, &error]));
+                    Targets::default()
+                }
+            }
+            // missing_feature 已经拒绝了这种情况
+            #[cfg(not(feature = "download"))]
+            unreachable!("AS{} needs the download feature", asn)
+        }
+        Source::Host(host) => {
+            let timeout = Duration::from_millis(opts.timeout);
+            match providers::resolve(host, opts.dns, timeout) {
+                Ok(ips) => Targets::from(ips),
+                Err(error) => {
+                    println!("{}", trf(Msg::CannotResolveHost, &[host, &error]));
+                    Targets::default()
+                }
+            }
+        }
+    }
+}
+
+// 无效输入行最多列出的条数
+const MAX_INVALID_LINES_SHOWN: usize = 10;
+
+/// Warn about the lines of the arguments and '--exclude' files that are not an IP, CIDR or range.
+/// Returns false if there are some and '--strict-input' is set.
+fn check_input_lines(opts: &Opts) -> bool {
+    let mut invalid = Vec::new();
+    for arg in opts.args.iter().chain(opts.exclude.iter()) {
+        match std::fs::read_to_string(arg) {
+            Ok(text) => invalid.extend(
+                Targets::invalid_lines(&text)
+                    .into_iter()
+                    .map(|(number, line)| format!("{}:{}: {}", arg, number, line)),
+            ),
+            Err(_) if !Targets::invalid_lines(arg).is_empty() => invalid.push(arg.to_string()),
+            Err(_) => {}
+        }
+    }
+    if invalid.is_empty() {
+        return true;
+    }
+
+    println!("{}", trf(Msg::InvalidInputLines, &[&invalid.len()]));
+    for line in invalid.iter().take(MAX_INVALID_LINES_SHOWN) {
+        println!("    {}", line);
+    }
+    if invalid.len() > MAX_INVALID_LINES_SHOWN {
+        println!(
+            "{}",
+            trf(
+                Msg::MoreInvalidLines,
+                &[&(invalid.len() - MAX_INVALID_LINES_SHOWN)]
+            )
+        );
+    }
+    if opts.strict_input {
+        println!("{}", tr(Msg::StrictInput));
+        return false;
+    }
+    true
+}
+
+/// The targets of all arguments and the `listed` IPs of '--ips-from', deduplicated, in a random
+/// order and sampled by '--random-number'.
+/// The hosts of a CIDR are only generated while they are tested.
+fn parse_addresses_from_opt(opts: &Opts, listed: &[IpAddr]) -> Targets {
+    let read = |arg: &String| match std::fs::read_to_string(arg) {
+        Ok(text) => text,
+        Err(_) => arg.to_string(),
+    };
+
+    let mut targets = Targets::default();
+    for arg in opts.args.iter() {
+        targets = targets.union(Targets::parse(&read(arg), opts.ipv6_samples));
+    }
+    for source in opts.sources.iter() {
+        targets = targets.union(source_targets(source, opts));
+    }
+    targets = targets.union(Targets::from(listed.to_vec()));
+
+    let mut excluded = Targets::default();
+    for arg in opts.exclude.iter() {
+        excluded = excluded.union(Targets::parse_exact(&read(arg)));
+    }
+    targets
+        .exclude(&excluded)
+        .with_per_subnet(opts.sample_per_subnet, opts.subnet_size)
+        .with_limit(opts.random_number)
 }
 
 #[cfg(test)]
@@ -252,7 +2471,7 @@ mod test {
 
     use crate::download::Downloader;
     use crate::input::Opts;
-    use crate::parse_addresses_from_opt;
+    use crate::{cached_stage, check_input_lines, parse_addresses_from_opt, retest_in_memory};
     use crate::utils::parse_addresses;
 
     use super::scanner;
@@ -262,7 +2481,7 @@ mod test {
     use rand::seq::SliceRandom;
 
     fn default_test_ips() -> String {
-        return "173.245.48.0/20
+        "173.245.48.0/20
         103.21.244.0/22
         103.22.200.0/22
         103.31.4.0/22
@@ -277,7 +2496,7 @@ mod test {
         104.24.0.0/14
         172.64.0.0/13
         131.0.72.0/22"
-            .to_string();
+            .to_string()
     }
 
     #[test]
@@ -317,7 +2536,7 @@ mod test {
     // / Makes sure the network is available
     pub fn fulltest_from_cloudflare() {
         let ips_v4 = &default_test_ips();
-        let ips = utils::parse_addresses(&ips_v4);
+        let ips = utils::parse_addresses(ips_v4);
         assert!(!ips.is_empty());
 
         let scan = scanner::Scanner::new(
@@ -385,21 +2604,88 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_check_input_lines() {
+        let mut opts = Opts {
+            args: vec!["1.1.1.1".to_string(), "1.1.1.0/33".to_string()],
+            ..Default::default()
+        };
+        assert!(check_input_lines(&opts));
+        opts.strict_input = true;
+        assert!(!check_input_lines(&opts));
+        opts.args.pop();
+        assert!(check_input_lines(&opts));
+    }
+
+    #[test]
+    fn test_cached_stage_bypass() {
+        let dir = std::env::temp_dir()
+            .join(format!("rustspeedtest-cache-test-{}", std::process::id()));
+        let mut opts = Opts {
+            cache: Some(dir.clone()),
+            ..Default::default()
+        };
+        let ips = crate::targets::Targets::from(vec!["1.1.1.1".parse::<IpAddr>().unwrap()]);
+        let runs = std::cell::Cell::new(0);
+        let stage = |opts: &Opts| {
+            cached_stage(opts, "httping", &ips, "", || {
+                runs.set(runs.get() + 1);
+                vec![1u8]
+            })
+        };
+        stage(&opts);
+        stage(&opts);
+        assert_eq!(runs.get(), 1);
+
+        // 要保存的回应只有真正测试时才有
+        opts.save_responses = Some(dir.join("responses.jsonl"));
+        stage(&opts);
+        assert_eq!(runs.get(), 2);
+        opts.save_responses = None;
+        opts.fingerprint = true;
+        stage(&opts);
+        assert_eq!(runs.get(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_retest_in_memory() {
+        let mut opts = Opts {
+            retest_margin: Some(0.1),
+            ..Default::default()
+        };
+        assert!(retest_in_memory(&opts).is_ok());
+        opts.memory_limit = 1000;
+        assert!(retest_in_memory(&opts).is_err());
+        opts.memory_limit = 0;
+        opts.max_memory = Some(64);
+        assert!(retest_in_memory(&opts).is_err());
+        opts.retest_margin = None;
+        assert!(retest_in_memory(&opts).is_ok());
+    }
+
     #[test]
     fn test_parse_addresses_from_opt() {
-        let mut opts = Opts::default();
-        opts.random_number = 0;
-        opts.args = vec!["192.168.1.1/24".to_string(), "192.168.1.1/28".to_string()];
+        let mut opts = Opts {
+            random_number: 0,
+            args: vec!["192.168.1.1/24".to_string(), "192.168.1.1/28".to_string()],
+            ..Default::default()
+        };
 
-        let ips = parse_addresses_from_opt(&opts);
+        let ips = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(ips.len(), 256);
 
         opts.random_number = 50;
-        let ips = parse_addresses_from_opt(&opts);
+        let ips = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(ips.len(), opts.random_number);
 
         opts.random_number = 9999;
-        let ips = parse_addresses_from_opt(&opts);
+        let ips = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(ips.len(), 256);
+
+        opts.sample_per_subnet = 4;
+        opts.subnet_size = 28;
+        let ips = parse_addresses_from_opt(&opts, &[]);
+        assert_eq!(ips.len(), 64);
     }
 }
@@ -0,0 +1,154 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::i18n::{trf, Msg};
+use crate::scanner::Delay;
+use crate::spill::{self, Spill};
+
+// 两次读取内存占用之间的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// 占用达到上限的这个比例时开始写入磁盘,留出余量给还在测试的 IP
+const PRESSURE_RATIO: f64 = 0.8;
+/// Results kept in memory once a scan spills because of '--max-memory'
+pub const FALLBACK_LIMIT: usize = 1000;
+
+/// Watches the resident memory of the process during a scan (see
+/// '--max-memory') and tells the scanner to keep only the best results in
+/// memory and write the others to disk once it gets near the limit, instead
+/// of being killed by the OOM killer. Only Linux reports the resident memory.
+///
+/// The default value never reports pressure.
+#[derive(Clone, Default)]
+pub struct MemoryGuard {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    // 上限,字节
+    limit: u64,
+    pressure: AtomicBool,
+    // 本次扫描的换出目录
+    dir: PathBuf,
+}
+
+impl fmt::Debug for MemoryGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryGuard")
+            .field("limit", &self.inner.as_ref().map(|inner| inner.limit))
+            .field("pressure", &self.under_pressure())
+            .finish()
+    }
+}
+
+impl MemoryGuard {
+    /// Start watching for `limit_mb` MiB, disabled if None, and spill to
+    /// `dir` under pressure. The watchdog thread ends with the last clone of
+    /// the guard.
+    pub fn new(limit_mb: Option<u64>, dir: &Path) -> Self {
+        let Some(limit_mb) = limit_mb else {
+            return MemoryGuard::default();
+        };
+        let inner = Arc::new(Inner {
+            limit: limit_mb.saturating_mul(1024 * 1024),
+            pressure: AtomicBool::new(false),
+            dir: dir.to_path_buf(),
+        });
+        let watched = Arc::downgrade(&inner);
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            let Some(inner) = watched.upgrade() else {
+                return;
+            };
+            let Some(rss) = rss() else {
+                return;
+            };
+            if rss as f64 >= inner.limit as f64 * PRESSURE_RATIO {
+                inner.pressure.store(true, Ordering::Relaxed);
+                println!(
+                    "{}",
+                    trf(
+                        Msg::MemoryPressure,
+                        &[&(rss / 1024 / 1024), &(inner.limit / 1024 / 1024)]
+                    )
+                );
+                return;
+            }
+        });
+        MemoryGuard { inner: Some(inner) }
+    }
+
+    /// Whether the memory got near the limit; stays so for the rest of the scan
+    pub fn under_pressure(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_some_and(|inner| inner.pressure.load(Ordering::Relaxed))
+    }
+
+    /// A spill to the directory of the guard keeping [`FALLBACK_LIMIT`]
+    /// results in memory, starting with the results of the scan so far
+    pub fn spill(&self, results: impl IntoIterator<Item = Delay>) -> Spill {
+        let dir = match self.inner {
+            Some(ref inner) => inner.dir.clone(),
+            None => spill::scan_dir(),
+        };
+        let mut spill = Spill::new(&dir, FALLBACK_LIMIT);
+        for delay in results {
+            spill.push(delay);
+        }
+        spill
+    }
+}
+
+/// Resident memory of this process in bytes, None where it is not known
+pub fn rss() -> Option<u64> {
+    parse_vm_rss(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+// /proc/self/status 中的一行 'VmRSS:     1234 kB'
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\trustspeedtest\nVmPeak:\t  20000 kB\nVmRSS:\t   1536 kB\nThreads:\t4\n";
+        assert_eq!(parse_vm_rss(status), Some(1536 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tx\n"), None);
+        assert_eq!(parse_vm_rss("VmRSS:\tmany kB\n"), None);
+    }
+
+    #[test]
+    fn test_guard() {
+        assert!(!MemoryGuard::default().under_pressure());
+        let dir = spill::scan_dir();
+        assert!(!MemoryGuard::new(None, &dir).under_pressure());
+        if rss().is_none() {
+            return;
+        }
+
+        // 测试进程本身就超过 1 MiB
+        let guard = MemoryGuard::new(Some(1), &dir);
+        let start = Instant::now();
+        while !guard.under_pressure() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(guard.under_pressure());
+        assert!(!MemoryGuard::new(Some(u64::MAX / 1024 / 1024), &dir).under_pressure());
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::history::Measurement;
+use crate::i18n::{tr, trf, Msg};
+use crate::scanner::Delay;
+
+/// How IP addresses are masked in displayed and written results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redaction {
+    /// show full addresses
+    #[default]
+    None,
+    /// mask the last octet of IPv4 and everything after the /64 of IPv6
+    LastOctet,
+}
+
+impl Redaction {
+    /// Format `ip` according to the redaction mode
+    pub fn apply(&self, ip: &IpAddr) -> String {
+        match (self, ip) {
+            (Redaction::None, _) => ip.to_string(),
+            (Redaction::LastOctet, IpAddr::V4(v4)) => {
+                let octets = v4.octets();
+                format!("{}.{}.{}.x", octets[0], octets[1], octets[2])
+            }
+            (Redaction::LastOctet, IpAddr::V6(v6)) => {
+                let mut segments = v6.segments();
+                segments[4..].fill(0);
+                format!("{}/64", Ipv6Addr::from(segments))
+            }
+        }
+    }
+
+    /// Format the address `text` read back from a file, unchanged if it is not an IP
+    pub fn apply_text(&self, text: &str) -> String {
+        match text.parse::<IpAddr>() {
+            Ok(ip) => self.apply(&ip),
+            Err(_) => text.to_string(),
+        }
+    }
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Redaction::None),
+            "last-octet" => Ok(Redaction::LastOctet),
+            _ => Err(format!("unknown redaction mode: {} (expected none|last-octet)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redaction::None => write!(f, "none"),
+            Redaction::LastOctet => write!(f, "last-octet"),
+        }
+    }
+}
+
+/// Latency results of the same targets measured out of several uplinks
+pub struct UplinkComparison {
+    /// Uplinks in the order they were tested
+    pub interfaces: Vec<String>,
+    /// Results keyed by (interface, ip)
+    pub records: HashMap<(String, IpAddr), Delay>,
+}
+
+impl UplinkComparison {
+    pub fn new(interfaces: Vec<String>) -> Self {
+        UplinkComparison {
+            interfaces,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Add the scan results of one uplink
+    pub fn insert(&mut self, interface: &str, delays: Vec<Delay>) {
+        // 握手被重置的 IP 没有可比较的延迟
+        for delay in delays.into_iter().filter(|d| d.success > 0) {
+            self.records.insert((interface.to_string(), delay.ip), delay);
+        }
+    }
+
+    /// All measured IPs, fastest first by their best delay across uplinks
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let mut best: HashMap<IpAddr, Duration> = HashMap::new();
+        for ((_, ip), delay) in self.records.iter() {
+            let entry = best.entry(*ip).or_insert(delay.average_delay);
+            if delay.average_delay < *entry {
+                *entry = delay.average_delay;
+            }
+        }
+
+        let mut ips: Vec<(IpAddr, Duration)> = best.into_iter().collect();
+        ips.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        ips.into_iter().map(|(ip, _)| ip).collect()
+    }
+
+    fn get(&self, interface: &str, ip: &IpAddr) -> Option<&Delay> {
+        self.records.get(&(interface.to_string(), *ip))
+    }
+
+    /// Print a per-uplink comparison table
+    pub fn display(&self, limit: usize, time: u8, redaction: Redaction) {
+        println!("{}", tr(Msg::UplinkResults));
+        let mut title = format!("{:<16}", tr(Msg::IpAddress));
+        for interface in self.interfaces.iter() {
+            title.push_str(&format!(" {:<20}", interface));
+        }
+        println!("{}", title);
+
+        for ip in self.ips().iter().take(limit) {
+            let mut line = format!("{:<16}", redaction.apply(ip));
+            for interface in self.interfaces.iter() {
+                let cell = match self.get(interface, ip) {
+                    Some(delay) => format!(
+                        "{}ms/{:.1}%",
+                        delay.average_delay.as_millis(),
+                        100.0 * loss_rate(delay, time)
+                    ),
+                    None => "-".to_string(),
+                };
+                line.push_str(&format!(" {:<20}", cell));
+            }
+            println!("{}", line);
+        }
+    }
+
+    /// Render the comparison as csv, one Loss/Delay column pair per uplink
+    pub fn to_csv(&self, time: u8, redaction: Redaction) -> String {
+        let mut csv = String::from("IP");
+        for interface in self.interfaces.iter() {
+            csv.push_str(&format!(",{0} Loss,{0} Delay(ms)", interface));
+        }
+        csv.push('\n');
+
+        for ip in self.ips().iter() {
+            csv.push_str(&redaction.apply(ip));
+            for interface in self.interfaces.iter() {
+                match self.get(interface, ip) {
+                    Some(delay) => csv.push_str(&format!(
+                        ",{:.1},{}",
+                        loss_rate(delay, time),
+                        delay.average_delay.as_millis()
+                    )),
+                    None => csv.push_str(",,"),
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn write_to_csv(
+        &self,
+        path: &str,
+        time: u8,
+        redaction: Redaction,
+    ) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_csv(time, redaction))?;
+        Ok(())
+    }
+}
+
+/// Latency results of the same targets tested on several ports
+#[derive(Default)]
+pub struct PortComparison {
+    /// (port, result), fastest first
+    rows: Vec<(u16, Measurement)>,
+}
+
+impl PortComparison {
+    /// Add the usable IPs measured on one port
+    pub fn insert(&mut self, port: u16, measurements: Vec<Measurement>) {
+        self.rows
+            .extend(measurements.into_iter().map(|measurement| (port, measurement)));
+        self.rows.sort_by(|a, b| {
+            let delay = |row: &(u16, Measurement)| row.1.delay_ms.unwrap_or(f64::MAX);
+            delay(a)
+                .total_cmp(&delay(b))
+                .then(a.1.ip.cmp(&b.1.ip))
+                .then(a.0.cmp(&b.0))
+        });
+    }
+
+    /// Print the fastest `limit` (IP, port) pairs
+    pub fn display(&self, limit: usize, redaction: Redaction) {
+        println!("{}", tr(Msg::PortResults));
+        println!(
+            "{:<16} {:<6} {:<8} {:<14}",
+            tr(Msg::IpAddress),
+            tr(Msg::Port),
+            tr(Msg::Loss),
+            tr(Msg::AvgDelay)
+        );
+        for (port, measurement) in self.rows.iter().take(limit) {
+            println!(
+                "{:<16} {:<6} {:<8} {:<14}",
+                redaction.apply(&measurement.ip),
+                port,
+                measurement
+                    .loss
+                    .map(|loss| format!("{:.1}%", 100.0 * loss))
+                    .unwrap_or_default(),
+                measurement
+                    .delay_ms
+                    .map(|delay| format!("{:.0}", delay))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    /// Render the results as csv, one row per (IP, port)
+    pub fn to_csv(&self, redaction: Redaction) -> String {
+        let colo = self.rows.iter().any(|(_, m)| m.colo.is_some());
+        let mut csv = String::from("IP,Port,Loss,Delay(ms)");
+        if colo {
+            csv.push_str(",Area");
+        }
+        csv.push('\n');
+
+        let number = |value: Option<f64>, precision: usize| {
+            value
+                .map(|v| format!("{:.*}", precision, v))
+                .unwrap_or_default()
+        };
+        for (port, measurement) in self.rows.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{}",
+                redaction.apply(&measurement.ip),
+                port,
+                number(measurement.loss, 1),
+                number(measurement.delay_ms, 0)
+            ));
+            if colo {
+                csv.push(',');
+                csv.push_str(measurement.colo.as_deref().unwrap_or_default());
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn write_to_csv(&self, path: &str, redaction: Redaction) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_csv(redaction))?;
+        Ok(())
+    }
+}
+
+/// The delay of a few IPs on many ports, one row per port and one column per IP
+pub struct PortMatrix {
+    ips: Vec<IpAddr>,
+    ports: Vec<u16>,
+    cells: HashMap<(u16, IpAddr), Delay>,
+}
+
+impl PortMatrix {
+    pub fn new(ips: Vec<IpAddr>, ports: Vec<u16>) -> Self {
+        PortMatrix {
+            ips,
+            ports,
+            cells: HashMap::new(),
+        }
+    }
+
+    /// Add the result of one (IP, port) pair
+    pub fn insert(&mut self, port: u16, delay: Delay) {
+        self.cells.insert((port, delay.ip), delay);
+    }
+
+    // 连接成功的平均延迟,不通时为 None
+    fn delay_ms(&self, port: u16, ip: &IpAddr) -> Option<u128> {
+        self.cells
+            .get(&(port, *ip))
+            .filter(|delay| delay.success > 0)
+            .map(|delay| delay.average_delay.as_millis())
+    }
+
+    /// Print the ports reachable on at least one IP, and how many are not
+    pub fn display(&self, redaction: Redaction) {
+        println!("{}", tr(Msg::PortMatrixResults));
+        let mut header = format!("{:<6}", tr(Msg::Port));
+        for ip in self.ips.iter() {
+            header.push_str(&format!(" {:<16}", redaction.apply(ip)));
+        }
+        println!("{}", header);
+
+        let mut closed = 0;
+        for port in self.ports.iter() {
+            let delays: Vec<Option<u128>> =
+                self.ips.iter().map(|ip| self.delay_ms(*port, ip)).collect();
+            if delays.iter().all(|delay| delay.is_none()) {
+                closed += 1;
+                continue;
+            }
+            let mut line = format!("{:<6}", port);
+            for delay in delays {
+                let cell = delay.map(|d| d.to_string()).unwrap_or("-".to_string());
+                line.push_str(&format!(" {:<16}", cell));
+            }
+            println!("{}", line);
+        }
+        if closed > 0 {
+            println!("{}", trf(Msg::PortsUnreachable, &[&closed]));
+        }
+    }
+
+    /// Render the matrix as csv, the delay in ms or empty where the port is unreachable
+    pub fn to_csv(&self, redaction: Redaction) -> String {
+        let mut csv = String::from("Port");
+        for ip in self.ips.iter() {
+            csv.push(',');
+            csv.push_str(&redaction.apply(ip));
+        }
+        csv.push('\n');
+
+        for port in self.ports.iter() {
+            csv.push_str(&port.to_string());
+            for ip in self.ips.iter() {
+                csv.push(',');
+                if let Some(delay) = self.delay_ms(*port, ip) {
+                    csv.push_str(&delay.to_string());
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn write_to_csv(&self, path: &str, redaction: Redaction) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_csv(redaction))?;
+        Ok(())
+    }
+}
+
+#[inline]
+fn loss_rate(delay: &Delay, time: u8) -> f64 {
+    1.0 - (delay.success as f64 / time as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(ip: &str, millis: u64, success: u8) -> Delay {
+        Delay {
+            average_delay: Duration::from_millis(millis),
+            success,
+            ..Delay::failed(ip.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_uplink_comparison_csv() {
+        let mut comparison = UplinkComparison::new(vec!["eth0".to_string(), "eth1".to_string()]);
+        comparison.insert("eth0", vec![delay("1.1.1.1", 50, 4), delay("1.0.0.1", 30, 2)]);
+        comparison.insert("eth1", vec![delay("1.1.1.1", 20, 4)]);
+
+        assert_eq!(
+            comparison.ips(),
+            vec!["1.1.1.1".parse::<IpAddr>().unwrap(), "1.0.0.1".parse().unwrap()]
+        );
+        assert_eq!(
+            comparison.to_csv(4, Redaction::None),
+            "IP,eth0 Loss,eth0 Delay(ms),eth1 Loss,eth1 Delay(ms)\n\
+             1.1.1.1,0.0,50,0.0,20\n\
+             1.0.0.1,0.5,30,,\n"
+        );
+    }
+
+    #[test]
+    fn test_port_comparison_csv() {
+        let measurement = |ip: &str, delay: f64| Measurement {
+            ip: ip.parse().unwrap(),
+            colo: None,
+            delay_ms: Some(delay),
+            loss: Some(0.5),
+            speed_mbps: None,
+        };
+        let mut comparison = PortComparison::default();
+        comparison.insert(443, vec![measurement("1.1.1.1", 50.0), measurement("1.0.0.1", 30.0)]);
+        comparison.insert(2053, vec![measurement("1.1.1.1", 20.0)]);
+
+        assert_eq!(
+            comparison.to_csv(Redaction::None),
+            "IP,Port,Loss,Delay(ms)\n\
+             1.1.1.1,2053,0.5,20\n\
+             1.0.0.1,443,0.5,30\n\
+             1.1.1.1,443,0.5,50\n"
+        );
+    }
+
+    #[test]
+    fn test_port_matrix_csv() {
+        let mut matrix = PortMatrix::new(
+            vec!["1.1.1.1".parse().unwrap(), "1.0.0.1".parse().unwrap()],
+            vec![443, 2053, 8443],
+        );
+        matrix.insert(443, delay("1.1.1.1", 50, 4));
+        matrix.insert(443, delay("1.0.0.1", 30, 2));
+        matrix.insert(2053, delay("1.1.1.1", 0, 0));
+        matrix.insert(8443, delay("1.0.0.1", 20, 4));
+
+        assert_eq!(
+            matrix.to_csv(Redaction::None),
+            "Port,1.1.1.1,1.0.0.1\n\
+             443,50,30\n\
+             2053,,\n\
+             8443,,20\n"
+        );
+    }
+
+    #[test]
+    fn test_redaction() {
+        let v4: IpAddr = "104.16.1.23".parse().unwrap();
+        let v6: IpAddr = "2606:4700:1:2:3:4:5:6".parse().unwrap();
+
+        assert_eq!(Redaction::None.apply(&v4), "104.16.1.23");
+        assert_eq!(Redaction::LastOctet.apply(&v4), "104.16.1.x");
+        assert_eq!(Redaction::LastOctet.apply(&v6), "2606:4700:1:2::/64");
+        assert_eq!("last-octet".parse(), Ok(Redaction::LastOctet));
+        assert!("all".parse::<Redaction>().is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::filter::Filter;
+use crate::input::Opts;
+
+/// A custom chain of stages with filters between them, run over one set of
+/// targets, for flows the command line cannot express.
+///
+/// ```yaml
+/// targets: [ip.txt]
+/// args: ["--timeout", "2000"]
+/// output: result.csv
+/// steps:
+///   - tcping: {}
+///   - top: 500
+///   - httping: {concurrency: 20, args: ["--http-path", "/cdn-cgi/trace"]}
+///   - top: 100
+///   - download: {count: 20, concurrency: 2}
+///   - where: "speed > 5"
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    /// Files or CIDRs, as the trailing arguments on the command line
+    pub targets: Vec<String>,
+    /// Command line options shared by all steps
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub output: Option<String>,
+    // 每个步骤写成 `- top: 500` 这样的单键映射,而不是 YAML 标签
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub steps: Vec<Step>,
+}
+
+/// The options of one stage, on top of the pipeline's
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StageOptions {
+    pub port: Option<u16>,
+    /// How many of the best IPs kept so far are tested. All when not set,
+    /// except for the download test, which then tests '--download-number'.
+    pub count: Option<usize>,
+    /// How many IPs the stage tests at once, as its '--*-concurrency' option
+    pub concurrency: Option<usize>,
+    /// Any other command line options, which must not repeat the pipeline's
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// One step of a pipeline
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Tcping(StageOptions),
+    Httping(StageOptions),
+    Cfhttping(StageOptions),
+    Udp(StageOptions),
+    Download(StageOptions),
+    /// Keep the best this many IPs, grouped by '--group-by' if set
+    Top(usize),
+    /// Keep the IPs matching this '--where' expression
+    Where(String),
+}
+
+impl Step {
+    /// The stage options, `None` for the filters
+    pub fn stage(&self) -> Option<&StageOptions> {
+        match self {
+            Step::Tcping(stage)
+            | Step::Httping(stage)
+            | Step::Cfhttping(stage)
+            | Step::Udp(stage)
+            | Step::Download(stage) => Some(stage),
+            Step::Top(_) | Step::Where(_) => None,
+        }
+    }
+
+    /// Whether this step is a latency stage
+    pub fn is_latency(&self) -> bool {
+        matches!(
+            self,
+            Step::Tcping(_) | Step::Httping(_) | Step::Cfhttping(_) | Step::Udp(_)
+        )
+    }
+
+    /// Name of the step in messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Tcping(_) => "tcping",
+            Step::Httping(_) => "httping",
+            Step::Cfhttping(_) => "cfhttping",
+            Step::Udp(_) => "udp",
+            Step::Download(_) => "download",
+            Step::Top(_) => "top",
+            Step::Where(_) => "where",
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Pipeline::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let pipeline: Pipeline = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+        if pipeline.targets.is_empty() {
+            return Err("the pipeline has no targets".to_string());
+        }
+        // 后面的步骤都基于延迟测试的结果
+        if !pipeline.steps.first().is_some_and(Step::is_latency) {
+            return Err("the first step must be tcping, httping, cfhttping or udp".to_string());
+        }
+        for step in pipeline.steps.iter() {
+            if let Step::Where(ref filter) = step {
+                filter.parse::<Filter>()?;
+            }
+        }
+        Ok(pipeline)
+    }
+
+    /// The command line equivalent to `step`, or to the pipeline's shared
+    /// options when `None`
+    pub fn to_args(&self, step: Option<&Step>) -> Vec<String> {
+        let mut args = vec!["rustspeedtest".to_string()];
+        args.extend(self.args.iter().cloned());
+
+        if let Some(stage) = step.and_then(Step::stage) {
+            args.extend(stage.args.iter().cloned());
+            if let Some(port) = stage.port {
+                args.push("--port".to_string());
+                args.push(port.to_string());
+            }
+            if let (Some(Step::Download(_)), Some(count)) = (step, stage.count) {
+                args.push("--download-number".to_string());
+                args.push(count.to_string());
+            }
+            if let Some(concurrency) = stage.concurrency {
+                let flag = match step {
+                    Some(Step::Httping(_)) | Some(Step::Cfhttping(_)) => "--httping-concurrency",
+                    Some(Step::Download(_)) => "--download-concurrency",
+                    Some(Step::Udp(_)) => "--number",
+                    _ => "--tcping-concurrency",
+                };
+                args.push(flag.to_string());
+                args.push(concurrency.to_string());
+            }
+        }
+        let flag = match step {
+            Some(Step::Httping(_)) => Some("--httping"),
+            Some(Step::Cfhttping(_)) => Some("--cfhttping"),
+            Some(Step::Udp(_)) => Some("--udp"),
+            Some(Step::Download(_)) => Some("--enable-download"),
+            _ => None,
+        };
+        if let Some(flag) = flag {
+            args.push(flag.to_string());
+        }
+        if let Some(ref output) = self.output {
+            args.push("--output".to_string());
+            args.push(output.clone());
+        }
+
+        args.push("--".to_string());
+        args.extend(self.targets.iter().cloned());
+        args
+    }
+
+    /// Parse the options of `step` as if given on the command line
+    pub fn opts(&self, step: Option<&Step>) -> Result<Opts, String> {
+        Opts::from_iter_safe(self.to_args(step)).map_err(|e| e.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIPELINE: &str = "
+targets: [\"104.16.0.0/24\"]
+args: [\"--timeout\", \"2000\"]
+output: out.csv
+steps:
+  - tcping: {}
+  - top: 500
+  - httping: {port: 8443, concurrency: 20, args: [\"--http-path\", \"/cdn-cgi/trace\"]}
+  - top: 100
+  - download: {count: 20, concurrency: 2}
+  - where: \"speed > 5\"
+";
+
+    #[test]
+    fn test_parse() {
+        let pipeline = Pipeline::parse(PIPELINE).unwrap();
+        assert_eq!(pipeline.steps.len(), 6);
+        assert_eq!(pipeline.steps[1], Step::Top(500));
+        assert_eq!(pipeline.steps[4].stage().unwrap().count, Some(20));
+        assert!(pipeline.steps[2].is_latency());
+        assert!(!pipeline.steps[4].is_latency());
+
+        assert!(Pipeline::parse("targets: [a]\nsteps: [{top: 5}]\n").is_err());
+        assert!(Pipeline::parse("targets: []\nsteps: [{tcping: {}}]\n").is_err());
+        assert!(
+            Pipeline::parse("targets: [a]\nsteps: [{tcping: {}}, {where: \"delay <\"}]\n").is_err()
+        );
+        assert!(Pipeline::parse("targets: [a]\nsteps: [{tcping: {typo: 1}}]\n").is_err());
+    }
+
+    #[test]
+    fn test_opts() {
+        let pipeline = Pipeline::parse(PIPELINE).unwrap();
+        let base = pipeline.opts(None).unwrap();
+        assert_eq!(base.timeout, 2000);
+        assert_eq!(base.output, "out.csv");
+        assert!(!base.httping);
+
+        let httping = pipeline.opts(Some(&pipeline.steps[2])).unwrap();
+        assert!(httping.httping);
+        assert_eq!(httping.port.first(), 8443);
+        assert_eq!(httping.http_path, "/cdn-cgi/trace");
+        assert_eq!(httping.timeout, 2000);
+        assert_eq!(httping.latency_concurrency(), 20);
+
+        let download = pipeline.opts(Some(&pipeline.steps[4])).unwrap();
+        assert!(download.enable_download);
+        assert_eq!(download.download_number, 20);
+        assert_eq!(download.download_concurrency, 2);
+        assert_eq!(download.latency_concurrency(), 200);
+    }
+}
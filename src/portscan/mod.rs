@@ -0,0 +1,207 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU8,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt};
+use serde_json::json;
+
+use crate::i18n::{tr, Msg};
+use crate::probe::{until_deadline, Ports};
+use crate::progress::ProgressEvents;
+use crate::scanner::{Delay, Percentiles, Streak};
+use crate::socket::SocketOptions;
+
+/// A tcp connect scanner for a few IPs over many ports, testing every
+/// (IP, port) pair at once instead of one port after another
+pub struct PortScanner {
+    // 测试IP地址集合
+    ips: Vec<IpAddr>,
+    // 每个IP测试的端口
+    ports: Ports,
+    // 同时测试的最大数量
+    batch_size: usize,
+    // 同个地址测试的次数
+    times: NonZeroU8,
+    // 超时设置
+    timeout: Duration,
+    // 本地 socket 设置
+    socket_options: SocketOptions,
+    // 进度事件
+    events: ProgressEvents,
+    // 到时停止测试,保留已有的结果
+    deadline: Option<Instant>,
+}
+
+impl PortScanner {
+    pub fn new(
+        ips: Vec<IpAddr>,
+        ports: Ports,
+        batch_size: usize,
+        timeout: Duration,
+        times: u8,
+    ) -> Self {
+        PortScanner {
+            ips,
+            ports,
+            batch_size: batch_size.max(1),
+            times: NonZeroU8::new(times).unwrap_or(NonZeroU8::new(1).unwrap()),
+            timeout,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            deadline: None,
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Stop at `deadline`, returning the pairs measured so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Measure every (IP, port) pair, unfiltered and in no particular order
+    pub async fn run(&self) -> Vec<(u16, Delay)> {
+        let total = self.ips.len() * self.ports.len();
+        self.events.stage_start("port_matrix", total);
+
+        // 按端口依次排列,同一个 IP 的连接分散开
+        let pairs = self
+            .ports
+            .iter()
+            .flat_map(|port| self.ips.iter().map(move |ip| SocketAddr::new(*ip, port)));
+        let delays = stream::iter(pairs)
+            .map(|addr| {
+                let times = self.times;
+                let timeout = self.timeout;
+                let socket_options = self.socket_options.clone();
+                tokio::spawn(async move {
+                    (
+                        addr.port(),
+                        PortScanner::probe(addr, times, timeout, &socket_options).await,
+                    )
+                })
+            })
+            .buffer_unordered(self.batch_size)
+            .filter_map(|delay| async move { delay.ok() });
+        let delays = until_deadline(delays, self.deadline);
+        tokio::pin!(delays);
+
+        let mut res = Vec::with_capacity(total);
+        while let Some((port, delay)) = delays.next().await {
+            self.events.result(
+                "port_matrix",
+                delay.ip,
+                delay.success > 0,
+                json!({
+                    "port": port,
+                    "delay_ms": delay.average_delay.as_millis() as u64,
+                    "success": delay.success,
+                    "streak": delay.streak,
+                }),
+            );
+            res.push((port, delay));
+        }
+
+        self.events.stage_end(
+            "port_matrix",
+            res.iter().filter(|(_, delay)| delay.success > 0).count(),
+        );
+        res
+    }
+
+    async fn probe(
+        addr: SocketAddr,
+        times: NonZeroU8,
+        timeout: Duration,
+        socket_options: &SocketOptions,
+    ) -> Delay {
+        let mut total = Duration::ZERO;
+        let mut success = 0;
+        let mut streak = Streak::default();
+        for _ in 0..times.get() {
+            let start = Instant::now();
+            match socket_options.connect(addr, timeout).await {
+                Ok(_) => {
+                    total += start.elapsed();
+                    success += 1;
+                    streak.add(true);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EMFILE) => {
+                    panic!("{}", tr(Msg::TooManyOpenFiles));
+                }
+                Err(_) => streak.add(false),
+            }
+        }
+        Delay {
+            ip: addr.ip(),
+            average_delay: if success > 0 {
+                total / success as u32
+            } else {
+                Duration::ZERO
+            },
+            success,
+            interference: 0,
+            jitter: Duration::ZERO,
+            percentiles: Percentiles::default(),
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            sentinel_delay: None,
+            kernel_rtt: None,
+            connect_delay: None,
+            streak: streak.value(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_port_scanner() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        // 拿一个空闲端口后关闭,连接会被拒绝
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        let ports: Ports = format!("{},{}", open, closed).parse().unwrap();
+        let mut results = PortScanner::new(
+            vec!["127.0.0.1".parse().unwrap()],
+            ports,
+            10,
+            Duration::from_secs(1),
+            2,
+        )
+        .run()
+        .await;
+        results.sort_by_key(|(port, _)| *port != open);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, open);
+        assert_eq!(results[0].1.success, 2);
+        assert_eq!(results[1].0, closed);
+        assert_eq!(results[1].1.success, 0);
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use futures::{future, future::LocalBoxFuture, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::httping::HttpingResult;
+use crate::i18n::{trf, Msg};
+use crate::routes::CFCDNCheckResult;
+use crate::scanner::Delay;
+
+/// A latency engine: probes its IPs and keeps the usable ones, best first
+pub trait Prober {
+    /// Name of the stage in progress events and the stage cache
+    fn stage(&self) -> &'static str;
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult>;
+}
+
+/// Whether the time limit of a scan, if any, is up
+pub fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// End `stream` when `deadline` passes, dropping the items still in flight
+pub fn until_deadline<S: Stream>(
+    stream: S,
+    deadline: Option<Instant>,
+) -> impl Stream<Item = S::Item> {
+    stream.take_until(async move {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => future::pending().await,
+        }
+    })
+}
+
+/// Ends a latency stage once enough IPs passed its filters (see
+/// '--stop-after'), like the minimum count of the download stage.
+///
+/// The default value never stops.
+#[derive(Clone, Default)]
+pub struct StopAfter {
+    inner: Option<Arc<(usize, AtomicUsize)>>,
+}
+
+impl fmt::Debug for StopAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StopAfter")
+            .field("count", &self.inner.as_ref().map(|inner| inner.0))
+            .finish()
+    }
+}
+
+impl StopAfter {
+    /// Stop after `count` valid IPs, never if `count` is 0
+    pub fn new(count: usize) -> Self {
+        StopAfter {
+            inner: (count > 0).then(|| Arc::new((count, AtomicUsize::new(0)))),
+        }
+    }
+
+    /// Count one more IP that passed the filters
+    pub fn found(&self) {
+        if let Some(ref inner) = self.inner {
+            inner.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether enough IPs were found to stop
+    pub fn reached(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_some_and(|inner| inner.1.load(Ordering::Relaxed) >= inner.0)
+    }
+
+    /// Print that the stage stopped early, if it did
+    pub fn report(&self) {
+        if let Some(ref inner) = self.inner {
+            if self.reached() {
+                println!("{}", trf(Msg::StoppedAfter, &[&inner.0]));
+            }
+        }
+    }
+}
+
+/// The ports every IP is tested on, given as `443,2053` or ranges like
+/// `8440-8450`, in the order given and without duplicates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ports(Vec<u16>);
+
+impl Ports {
+    /// The port used where only one can be, e.g. by the keep-warm connection
+    pub fn first(&self) -> u16 {
+        self.0[0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl From<u16> for Ports {
+    fn from(port: u16) -> Self {
+        Ports(vec![port])
+    }
+}
+
+impl FromStr for Ports {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |text: &str| {
+            text.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port '{}'", text.trim()))
+        };
+        let mut ports = Vec::new();
+        for part in s.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (port(first)?, port(last)?),
+                None => (port(part)?, port(part)?),
+            };
+            if first > last {
+                return Err(format!("invalid port range '{}'", part));
+            }
+            for port in first..=last {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+        Ok(Ports(ports))
+    }
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports: Vec<String> = self.0.iter().map(u16::to_string).collect();
+        write!(f, "{}", ports.join(","))
+    }
+}
+
+/// The results of a latency stage, whichever engine ran it
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResult {
+    /// tcping and udping
+    Delays(Vec<Delay>),
+    /// cfhttping
+    Routes(Vec<CFCDNCheckResult>),
+    /// httping
+    Http(Vec<HttpingResult>),
+    /// The IPs of --ips-from, untested because of --skip-ping
+    Listed(Vec<IpAddr>),
+}
+
+impl ScanResult {
+    /// The IPs usable by the next stages, in order
+    pub fn valid_ips(&self) -> Vec<IpAddr> {
+        match self {
+            // 只被干扰而没有成功测量的 IP 不可用
+            ScanResult::Delays(delays) => delays
+                .iter()
+                .filter(|d| d.success > 0)
+                .map(|d| d.ip)
+                .collect(),
+            ScanResult::Routes(routes) => routes.iter().map(|r| r.ip).collect(),
+            ScanResult::Http(results) => results.iter().map(|r| r.ip).collect(),
+            ScanResult::Listed(ips) => ips.clone(),
+        }
+    }
+
+    /// Keep only the results of the IPs for which `keep` is true
+    pub fn retain(&mut self, keep: impl Fn(&IpAddr) -> bool) {
+        match self {
+            ScanResult::Delays(delays) => delays.retain(|d| keep(&d.ip)),
+            ScanResult::Routes(routes) => routes.retain(|r| keep(&r.ip)),
+            ScanResult::Http(results) => results.retain(|r| keep(&r.ip)),
+            ScanResult::Listed(ips) => ips.retain(keep),
+        }
+    }
+
+    pub fn delays(&self) -> Option<&[Delay]> {
+        match self {
+            ScanResult::Delays(delays) => Some(delays),
+            _ => None,
+        }
+    }
+
+    pub fn routes(&self) -> Option<&[CFCDNCheckResult]> {
+        match self {
+            ScanResult::Routes(routes) => Some(routes),
+            _ => None,
+        }
+    }
+
+    pub fn http(&self) -> Option<&[HttpingResult]> {
+        match self {
+            ScanResult::Http(results) => Some(results),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::httping::HttpingChecker;
+
+    #[test]
+    fn test_valid_ips() {
+        let delay = |ip: &str, success| Delay {
+            average_delay: Duration::from_millis(10),
+            success,
+            interference: 1,
+            ..Delay::failed(ip.parse().unwrap())
+        };
+        let result = ScanResult::Delays(vec![delay("1.1.1.1", 2), delay("1.0.0.1", 0)]);
+        assert_eq!(result.valid_ips(), vec!["1.1.1.1".parse::<IpAddr>().unwrap()]);
+        assert!(result.routes().is_none());
+
+        let mut result = result;
+        result.retain(|ip| ip.to_string() == "1.0.0.1");
+        assert_eq!(result.delays().unwrap().len(), 1);
+        assert!(result.valid_ips().is_empty());
+    }
+
+    #[test]
+    fn test_ports() {
+        let ports: Ports = "443,2053, 8440-8443,443".parse().unwrap();
+        assert_eq!(
+            ports.iter().collect::<Vec<_>>(),
+            vec![443, 2053, 8440, 8441, 8442, 8443]
+        );
+        assert_eq!(ports.first(), 443);
+        assert_eq!(ports.to_string(), "443,2053,8440,8441,8442,8443");
+        assert_eq!("80".parse(), Ok(Ports::from(80)));
+        assert!("8450-8440".parse::<Ports>().is_err());
+        assert!("443,https".parse::<Ports>().is_err());
+        assert!("".parse::<Ports>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_until_deadline() {
+        // 一个结果后永远等待的流,到时结束
+        let items = futures::stream::iter([1]).chain(futures::stream::pending());
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let items: Vec<i32> = until_deadline(items, Some(deadline)).collect().await;
+        assert_eq!(items, vec![1]);
+        assert!(expired(Some(deadline)));
+        assert!(!expired(None));
+
+        let items: Vec<i32> = until_deadline(futures::stream::iter([1, 2]), None)
+            .collect()
+            .await;
+        assert_eq!(items, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_stop_after() {
+        let disabled = StopAfter::new(0);
+        disabled.found();
+        assert!(!disabled.reached());
+
+        let stop = StopAfter::new(2);
+        let shared = stop.clone();
+        stop.found();
+        assert!(!shared.reached());
+        shared.found();
+        assert!(stop.reached());
+    }
+
+    #[test]
+    fn test_httping_prober() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    tokio::spawn(async move {
+                        let mut buf = [0; 1024];
+                        let _ = stream.read(&mut buf).await;
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+                    });
+                }
+            });
+
+            // httping 基于 async-std,也要能在 tokio 运行时中运行
+            let prober: Box<dyn Prober> = Box::new(
+                HttpingChecker::new(1, Duration::from_secs(1), port, 1, "")
+                    .with_ips(vec!["127.0.0.1".parse().unwrap()]),
+            );
+            let result = prober.probe().await;
+            assert_eq!(prober.stage(), "httping");
+            assert_eq!(result.valid_ips().len(), 1);
+        });
+    }
+}
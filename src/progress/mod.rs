@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::UnixListener,
+    sync::broadcast::{self, error::RecvError, Receiver},
+};
+
+use crate::estimate;
+use crate::i18n::{tr, trf, Msg};
+use crate::output::Redaction;
+use crate::trace::Tracer;
+use crate::utils::human_readable_duration;
+
+// 每个客户端最多缓存的事件数,超出后丢弃最旧的事件
+const EVENT_BUFFER: usize = 4096;
+
+// 逐行进度每前进这么多百分比也输出一行
+const LINE_STEP_PERCENT: usize = 10;
+
+/// Receives the lifecycle events of every stage as they happen, e.g. to draw
+/// progress bars or a custom UI. All methods do nothing by default.
+pub trait Observer: Send + Sync {
+    fn on_stage_start(&self, _stage: &str, _total: usize) {}
+
+    fn on_result(&self, _stage: &str, _ip: IpAddr, _valid: bool, _detail: &Value) {}
+
+    // 目前只有下载阶段上报错误
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    fn on_error(&self, _stage: &str, _ip: IpAddr, _error: &str) {}
+
+    fn on_stage_end(&self, _stage: &str, _valid: usize) {}
+}
+
+/// Broadcasts JSON progress and result events of all stages to its subscribers,
+/// and calls its observers.
+///
+/// The default value has no observer and drops all JSON events.
+#[derive(Clone, Default)]
+pub struct ProgressEvents {
+    tx: Option<broadcast::Sender<Value>>,
+    observers: Vec<Arc<dyn Observer>>,
+    tracer: Tracer,
+    redaction: Redaction,
+}
+
+impl fmt::Debug for ProgressEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressEvents")
+            .field("tx", &self.tx)
+            .field("observers", &self.observers.len())
+            .field("tracer", &self.tracer)
+            .field("redaction", &self.redaction)
+            .finish()
+    }
+}
+
+impl ProgressEvents {
+    /// An enabled event stream without any subscriber yet
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        ProgressEvents {
+            tx: Some(tx),
+            observers: Vec::new(),
+            tracer: Tracer::default(),
+            redaction: Redaction::None,
+        }
+    }
+
+    /// Also report every event to `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Log the operations of all stages on the IPs `tracer` traces
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    /// Mask the IPs of the JSON events, see '--redact'
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Log `message` about `ip` in `stage` if `ip` is traced, see [`Tracer::log`]
+    pub fn trace(&self, stage: &str, ip: IpAddr, message: impl FnOnce() -> String) {
+        self.tracer.log(stage, ip, message);
+    }
+
+    /// Receive all events emitted from now on. `None` if the stream is disabled.
+    pub fn subscribe(&self) -> Option<Receiver<Value>> {
+        self.tx.as_ref().map(|tx| tx.subscribe())
+    }
+
+    /// Listen on `path` and stream newline-delimited events to every connected client.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn listen_unix<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let sender = match self.tx {
+            Some(ref tx) => tx.clone(),
+            None => return Ok(()),
+        };
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut rx = sender.subscribe();
+                tokio::spawn(async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok(event) => {
+                                let line = format!("{}\n", event);
+                                if stream.write_all(line.as_bytes()).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    fn emit(&self, event: Value) {
+        if let Some(ref tx) = self.tx {
+            // 没有订阅者时发送失败,直接忽略
+            let _ = tx.send(event);
+        }
+    }
+
+    /// A stage starts measuring `total` targets
+    pub fn stage_start(&self, stage: &str, total: usize) {
+        for observer in self.observers.iter() {
+            observer.on_stage_start(stage, total);
+        }
+        self.emit(json!({"event": "stage_start", "stage": stage, "total": total}));
+    }
+
+    /// One target of a stage has been measured
+    pub fn result(&self, stage: &str, ip: IpAddr, valid: bool, detail: Value) {
+        self.trace(stage, ip, || format!("result valid={} {}", valid, detail));
+        for observer in self.observers.iter() {
+            observer.on_result(stage, ip, valid, &detail);
+        }
+        self.emit(json!({
+            "event": "result",
+            "stage": stage,
+            "ip": self.redaction.apply(&ip),
+            "valid": valid,
+            "detail": detail,
+        }));
+    }
+
+    /// Measuring one target of a stage failed with `error`
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    pub fn error(&self, stage: &str, ip: IpAddr, error: &str) {
+        self.trace(stage, ip, || format!("error: {}", error));
+        for observer in self.observers.iter() {
+            observer.on_error(stage, ip, error);
+        }
+        self.emit(json!({
+            "event": "error",
+            "stage": stage,
+            "ip": self.redaction.apply(&ip),
+            "error": error,
+        }));
+    }
+
+    /// A stage has finished with `valid` usable targets
+    pub fn stage_end(&self, stage: &str, valid: usize) {
+        for observer in self.observers.iter() {
+            observer.on_stage_end(stage, valid);
+        }
+        self.emit(json!({"event": "stage_end", "stage": stage, "valid": valid}));
+    }
+
+    /// A monitored IP regressed or recovered
+    pub fn alert(&self, kind: &str, ip: IpAddr, detail: Value) {
+        self.emit(json!({
+            "event": "alert",
+            "kind": kind,
+            "ip": self.redaction.apply(&ip),
+            "detail": detail,
+        }));
+    }
+}
+
+/// Draws a progress bar per running stage on the terminal.
+///
+/// Stages of the same name running at once (e.g. concurrent jobs) share one bar.
+#[derive(Default)]
+pub struct ProgressBars {
+    // 阶段名 -> (进度条, 正在运行的次数)
+    bars: Mutex<HashMap<String, (ProgressBar, usize)>>,
+    redaction: Redaction,
+}
+
+impl ProgressBars {
+    /// Mask the IP shown next to the bars, see '--redact'
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+}
+
+impl Observer for ProgressBars {
+    fn on_stage_start(&self, stage: &str, total: usize) {
+        let mut bars = self.bars.lock().unwrap();
+        match bars.get_mut(stage) {
+            Some((bar, running)) => {
+                bar.inc_length(total as u64);
+                *running += 1;
+            }
+            None => {
+                let bar = ProgressBar::new(total as u64);
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} ~{eta} {msg}",
+                    )
+                    .unwrap()
+                    .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
+                );
+                bars.insert(stage.to_string(), (bar, 1));
+            }
+        }
+    }
+
+    fn on_result(&self, stage: &str, ip: IpAddr, _valid: bool, _detail: &Value) {
+        if let Some((bar, _)) = self.bars.lock().unwrap().get(stage) {
+            bar.set_message(trf(Msg::ProgressAddr, &[&self.redaction.apply(&ip)]));
+            bar.inc(1);
+        }
+    }
+
+    fn on_stage_end(&self, stage: &str, _valid: usize) {
+        let mut bars = self.bars.lock().unwrap();
+        if let Some((bar, running)) = bars.get_mut(stage) {
+            *running -= 1;
+            if *running == 0 {
+                bar.finish_with_message(tr(Msg::Finished));
+                bars.remove(stage);
+            }
+        }
+    }
+}
+
+/// Prints the progress of every running stage as a plain line now and then,
+/// for logs where a progress bar would be garbled (e.g. cron or CI).
+///
+/// A line is printed every `interval` and whenever a stage passes another
+/// tenth of its targets.
+pub struct ProgressLines {
+    interval: Duration,
+    // 阶段名 -> 进度
+    stages: Mutex<HashMap<String, LineProgress>>,
+}
+
+struct LineProgress {
+    done: usize,
+    total: usize,
+    // 正在运行的次数,同名阶段共用一个进度
+    running: usize,
+    started: Instant,
+    printed: Instant,
+    printed_step: usize,
+}
+
+impl ProgressLines {
+    pub fn new(interval: Duration) -> Self {
+        ProgressLines {
+            interval,
+            stages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn line(stage: &str, progress: &LineProgress) -> String {
+        let elapsed = progress.started.elapsed();
+        let mut line = trf(
+            Msg::ProgressLine,
+            &[
+                &stage,
+                &progress.done,
+                &progress.total,
+                &(progress.done * 100 / progress.total.max(1)),
+                &elapsed.as_secs(),
+            ],
+        );
+        // 按目前的速度估计剩余时间
+        if progress.done < progress.total {
+            if let Some(left) = estimate::remaining(progress.done, progress.total, elapsed) {
+                line.push_str(&trf(
+                    Msg::ProgressRemaining,
+                    &[&human_readable_duration(left)],
+                ));
+            }
+        }
+        line
+    }
+}
+
+impl Observer for ProgressLines {
+    fn on_stage_start(&self, stage: &str, total: usize) {
+        let mut stages = self.stages.lock().unwrap();
+        match stages.get_mut(stage) {
+            Some(progress) => {
+                progress.total += total;
+                progress.running += 1;
+            }
+            None => {
+                let now = Instant::now();
+                stages.insert(
+                    stage.to_string(),
+                    LineProgress {
+                        done: 0,
+                        total,
+                        running: 1,
+                        started: now,
+                        printed: now,
+                        printed_step: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    fn on_result(&self, stage: &str, _ip: IpAddr, _valid: bool, _detail: &Value) {
+        let mut stages = self.stages.lock().unwrap();
+        let Some(progress) = stages.get_mut(stage) else {
+            return;
+        };
+        progress.done += 1;
+        let step = progress.done * 100 / progress.total.max(1) / LINE_STEP_PERCENT;
+        if step > progress.printed_step || progress.printed.elapsed() >= self.interval {
+            println!("{}", ProgressLines::line(stage, progress));
+            progress.printed = Instant::now();
+            progress.printed_step = step;
+        }
+    }
+
+    fn on_stage_end(&self, stage: &str, _valid: usize) {
+        let mut stages = self.stages.lock().unwrap();
+        if let Some(progress) = stages.get_mut(stage) {
+            progress.running -= 1;
+            if progress.running == 0 {
+                println!(
+                    "{} {}",
+                    ProgressLines::line(stage, progress),
+                    tr(Msg::Finished)
+                );
+                stages.remove(stage);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Observer for Recorder {
+        fn on_stage_start(&self, stage: &str, total: usize) {
+            self.calls.lock().unwrap().push(format!("start {} {}", stage, total));
+        }
+
+        fn on_result(&self, _stage: &str, ip: IpAddr, valid: bool, _detail: &Value) {
+            self.calls.lock().unwrap().push(format!("result {} {}", ip, valid));
+        }
+
+        fn on_error(&self, _stage: &str, ip: IpAddr, error: &str) {
+            self.calls.lock().unwrap().push(format!("error {} {}", ip, error));
+        }
+
+        fn on_stage_end(&self, stage: &str, valid: usize) {
+            self.calls.lock().unwrap().push(format!("end {} {}", stage, valid));
+        }
+    }
+
+    #[test]
+    fn test_progress_lines() {
+        let lines = ProgressLines::new(Duration::from_secs(3600));
+        let ip: IpAddr = "1.1.1.1".parse().unwrap();
+        let printed = |lines: &ProgressLines| lines.stages.lock().unwrap()["tcping"].printed_step;
+
+        // 同名阶段共用进度
+        lines.on_stage_start("tcping", 10);
+        lines.on_stage_start("tcping", 10);
+        lines.on_result("tcping", ip, true, &json!({}));
+        assert_eq!(printed(&lines), 0);
+        lines.on_result("tcping", ip, true, &json!({}));
+        assert_eq!(printed(&lines), 1);
+
+        lines.on_stage_end("tcping", 2);
+        assert!(lines.stages.lock().unwrap().contains_key("tcping"));
+        lines.on_stage_end("tcping", 2);
+        assert!(lines.stages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_observer() {
+        let recorder = Arc::new(Recorder::default());
+        // 没有 JSON 订阅者时也会通知观察者
+        let events = ProgressEvents::default().with_observer(recorder.clone());
+        let ip: IpAddr = "1.1.1.1".parse().unwrap();
+
+        events.stage_start("download", 1);
+        events.error("download", ip, "timed out");
+        events.result("download", ip, false, json!({}));
+        events.stage_end("download", 0);
+
+        assert_eq!(
+            *recorder.calls.lock().unwrap(),
+            vec![
+                "start download 1",
+                "error 1.1.1.1 timed out",
+                "result 1.1.1.1 false",
+                "end download 0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_events() {
+        let path = std::env::temp_dir().join(format!("rst-progress-{}.sock", std::process::id()));
+        let events = ProgressEvents::new().with_redaction(Redaction::LastOctet);
+        events.listen_unix(&path).unwrap();
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        // 等待服务端完成 accept 和订阅
+        while events.tx.as_ref().unwrap().receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        events.stage_start("tcping", 2);
+        events.result("tcping", "1.1.1.1".parse().unwrap(), true, json!({"delay_ms": 12}));
+
+        let first: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(first["event"], "stage_start");
+        assert_eq!(first["total"], 2);
+        let second: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(second["ip"], "1.1.1.x");
+        assert_eq!(second["detail"]["delay_ms"], 12);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
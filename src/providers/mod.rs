@@ -0,0 +1,349 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(feature = "download")]
+use cidr_utils::cidr::IpCidr;
+
+use crate::dns::{self, DnsResolver};
+
+/// How long a saved prefix list of an ASN is used before it is fetched again
+#[cfg(feature = "download")]
+pub const ASN_CACHE_TTL: Duration = Duration::from_secs(24 * 3600);
+
+// RIPEstat 的宣告前缀接口
+#[cfg(feature = "download")]
+const ANNOUNCED_PREFIXES_URL: &str =
+    "https://stat.ripe.net/data/announced-prefixes/data.json?resource=AS";
+
+/// Something '-a' adds to the candidate IPs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The edge ranges of a CDN
+    Provider(Provider),
+    /// The addresses a host name resolves to
+    Host(String),
+    /// The prefixes an autonomous system announces
+    Asn(u32),
+    /// The lines of the standard input, written as '-'
+    Stdin,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(Source::Stdin);
+        }
+        if let Ok(provider) = s.parse() {
+            return Ok(Source::Provider(provider));
+        }
+        let asn = s.strip_prefix("AS").or_else(|| s.strip_prefix("as"));
+        if let Some(Ok(asn)) = asn.map(str::parse) {
+            return Ok(Source::Asn(asn));
+        }
+        // 至少两级,每级只含字母数字和连字符
+        let labels: Vec<&str> = s.trim_end_matches('.').split('.').collect();
+        let valid = labels.len() > 1
+            && labels.iter().all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if valid {
+            Ok(Source::Host(s.to_string()))
+        } else {
+            Err(format!(
+                "unknown source: {} (expected cloudflare|cloudfront|fastly|gcore, a host name or an ASN like AS13335)",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Provider(provider) => write!(f, "{}", provider),
+            Source::Host(host) => write!(f, "{}", host),
+            Source::Asn(asn) => write!(f, "AS{}", asn),
+            Source::Stdin => write!(f, "-"),
+        }
+    }
+}
+
+/// The A and AAAA addresses of `host`, looked up with `dns`
+pub fn resolve(host: &str, dns: DnsResolver, timeout: Duration) -> io::Result<Vec<IpAddr>> {
+    match dns {
+        DnsResolver::System => Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect()),
+        // 单线程运行时不会创建线程,之后仍可进入网络命名空间
+        DnsResolver::Server(server) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(dns::lookup(server, host, timeout)),
+    }
+}
+
+/// A CDN whose edge ranges are built in, so no ip.txt is needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Cloudflare,
+    Cloudfront,
+    Fastly,
+    Gcore,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 4] = [
+        Provider::Cloudflare,
+        Provider::Cloudfront,
+        Provider::Fastly,
+        Provider::Gcore,
+    ];
+
+    /// Where the provider publishes its current ranges
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    pub fn urls(&self) -> &'static [&'static str] {
+        match self {
+            Provider::Cloudflare => &[
+                "https://www.cloudflare.com/ips-v4",
+                "https://www.cloudflare.com/ips-v6",
+            ],
+            Provider::Cloudfront => {
+                &["https://d7uri8nf7uskq.cloudfront.net/tools/list-cloudfront-ips"]
+            }
+            Provider::Fastly => &["https://api.fastly.com/public-ip-list"],
+            Provider::Gcore => &["https://api.gcore.com/cdn/public-ip-list"],
+        }
+    }
+
+    /// The ranges compiled into the binary
+    pub fn embedded(&self) -> &'static str {
+        match self {
+            Provider::Cloudflare => include_str!("cloudflare.txt"),
+            Provider::Cloudfront => include_str!("cloudfront.txt"),
+            Provider::Fastly => include_str!("fastly.txt"),
+            Provider::Gcore => include_str!("gcore.txt"),
+        }
+    }
+
+    /// The file in `dir` holding the ranges saved by 'update-providers'
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        dir.join("providers").join(format!("{}.txt", self))
+    }
+
+    /// One CIDR per line: the saved copy in `dir` if there is one, else the embedded list
+    pub fn list(&self, dir: Option<&Path>) -> String {
+        dir.and_then(|dir| fs::read_to_string(self.path(dir)).ok())
+            .unwrap_or_else(|| self.embedded().to_string())
+    }
+
+    /// Download the current ranges and save them into `dir`, returning how many were saved
+    #[cfg(feature = "download")]
+    pub async fn update(&self, dir: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+        let ranges = fetch_cidrs(self.urls()).await?;
+        let text = format!("# {}\n{}\n", self.urls().join(" "), ranges.join("\n"));
+        save(&self.path(dir), &text)?;
+        Ok(ranges.len())
+    }
+}
+
+/// The file in `dir` holding the prefixes announced by `asn`
+#[cfg(feature = "download")]
+pub fn asn_path(asn: u32, dir: &Path) -> PathBuf {
+    dir.join("providers").join(format!("as{}.txt", asn))
+}
+
+/// The prefixes announced by `asn`, one per line. A copy saved in `dir` less
+/// than [`ASN_CACHE_TTL`] ago is used as is, otherwise they are fetched from
+/// RIPEstat and saved; an older copy is still better than nothing when that fails.
+#[cfg(feature = "download")]
+pub fn announced_prefixes(
+    asn: u32,
+    dir: Option<&Path>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let saved = dir.and_then(|dir| {
+        let path = asn_path(asn, dir);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        Some((fs::read_to_string(path).ok()?, age))
+    });
+    if let Some((text, age)) = saved.as_ref() {
+        if *age < ASN_CACHE_TTL {
+            return Ok(text.clone());
+        }
+    }
+
+    let url = format!("{}{}", ANNOUNCED_PREFIXES_URL, asn);
+    let fetched = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(fetch_cidrs(&[url.as_str()]));
+    match (fetched, saved) {
+        (Ok(ranges), _) => {
+            let text = format!("# {}\n{}\n", url, ranges.join("\n"));
+            if let Some(dir) = dir {
+                save(&asn_path(asn, dir), &text)?;
+            }
+            Ok(text)
+        }
+        (Err(_), Some((text, _))) => Ok(text),
+        (Err(error), None) => Err(error),
+    }
+}
+
+/// Every CIDR found at `urls`
+#[cfg(feature = "download")]
+async fn fetch_cidrs(urls: &[&str]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+
+    let mut ranges = Vec::new();
+    for url in urls {
+        let body = client
+            .get(*url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        ranges.extend(extract_cidrs(&body));
+    }
+    // 空列表多半是格式变了,保留旧的
+    if ranges.is_empty() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no address range found in the response",
+        )));
+    }
+    Ok(ranges)
+}
+
+#[cfg(feature = "download")]
+fn save(path: &Path, text: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, text)
+}
+
+/// Every CIDR in `body`, whether it is plain text or JSON
+#[cfg(feature = "download")]
+fn extract_cidrs(body: &str) -> Vec<String> {
+    let pattern = regex::Regex::new(r"[0-9A-Fa-f:.]+/\d{1,3}").unwrap();
+    pattern
+        .find_iter(body)
+        .map(|m| m.as_str())
+        .filter(|cidr| IpCidr::from_str(cidr).is_ok())
+        .map(str::to_string)
+        .collect()
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Provider::ALL
+            .into_iter()
+            .find(|provider| provider.to_string() == s.to_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "unknown provider: {} (expected cloudflare|cloudfront|fastly|gcore)",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Provider::Cloudflare => "cloudflare",
+            Provider::Cloudfront => "cloudfront",
+            Provider::Fastly => "fastly",
+            Provider::Gcore => "gcore",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::targets::Targets;
+
+    #[test]
+    fn test_embedded_lists() {
+        for provider in Provider::ALL {
+            assert_eq!(provider.to_string().parse(), Ok(provider));
+            assert!(!Targets::parse(provider.embedded(), 0).is_empty());
+        }
+        assert!("akamai".parse::<Provider>().is_err());
+    }
+
+    #[test]
+    fn test_saved_list() {
+        let dir = std::env::temp_dir().join(format!("rst-providers-{}", std::process::id()));
+        assert_eq!(
+            Provider::Fastly.list(Some(&dir)),
+            Provider::Fastly.embedded()
+        );
+
+        fs::create_dir_all(dir.join("providers")).unwrap();
+        fs::write(Provider::Fastly.path(&dir), "151.101.0.0/24\n").unwrap();
+        assert_eq!(
+            Targets::parse(&Provider::Fastly.list(Some(&dir)), 0).len(),
+            256
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!("Fastly".parse(), Ok(Source::Provider(Provider::Fastly)));
+        assert_eq!(
+            "speed.example.com".parse(),
+            Ok(Source::Host("speed.example.com".to_string()))
+        );
+        assert_eq!("AS13335".parse(), Ok(Source::Asn(13335)));
+        assert_eq!("-".parse(), Ok(Source::Stdin));
+        assert_eq!(Source::Asn(13335).to_string(), "AS13335");
+        assert!("akamai".parse::<Source>().is_err());
+        assert!("bad_host.example.com".parse::<Source>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_system() {
+        let ips = resolve("localhost", DnsResolver::System, Duration::from_secs(1)).unwrap();
+        assert!(!ips.is_empty() && ips.iter().all(|ip| ip.is_loopback()));
+    }
+
+    #[cfg(feature = "download")]
+    #[test]
+    fn test_saved_asn_prefixes() {
+        // 新保存的列表直接使用,不访问网络
+        let dir = std::env::temp_dir().join(format!("rst-asn-{}", std::process::id()));
+        save(&asn_path(64512, &dir), "192.0.2.0/24\n").unwrap();
+        let text = announced_prefixes(64512, Some(&dir)).unwrap();
+        assert_eq!(Targets::parse(&text, 0).len(), 256);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "download")]
+    #[test]
+    fn test_extract_cidrs() {
+        let json = r#"{"addresses":["23.235.32.0/20","43.249.72.0/22"],"ipv6_addresses":["2a04:4e40::/32"]}"#;
+        assert_eq!(
+            extract_cidrs(json),
+            vec!["23.235.32.0/20", "43.249.72.0/22", "2a04:4e40::/32"]
+        );
+        assert_eq!(extract_cidrs("173.245.48.0/20\n103.21.244.0/22\n").len(), 2);
+        assert!(extract_cidrs("<html>10/10</html>").is_empty());
+    }
+}
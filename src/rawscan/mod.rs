@@ -0,0 +1,713 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::future::LocalBoxFuture;
+use mio::{net::TcpStream, Events, Interest, Poll, Token};
+use serde_json::json;
+
+use crate::deadnet::DeadSubnets;
+use crate::histogram::LatencyHistogram;
+use crate::i18n::{tr, Msg};
+use crate::memguard::MemoryGuard;
+use crate::probe::{expired, Prober, ScanResult, StopAfter};
+use crate::progress::ProgressEvents;
+use crate::retry::RetryPolicy;
+use crate::rtt::AdaptiveTimeout;
+use crate::scanner::{
+    drop_reason, report_overhead, Delay, Jitter, KernelRtt, Percentiles, Streak,
+    CALIBRATION_SAMPLES,
+};
+use crate::socket::{self, SocketOptions};
+use crate::spill::Spill;
+use crate::targets::Targets;
+
+// 每次 poll 最多取回的事件数
+const EVENTS_CAPACITY: usize = 4096;
+
+/// A tcp connect scanner driving all sockets from one thread with epoll
+/// (kqueue on BSD) instead of one tokio task per IP. Spends less memory and
+/// fewer wakeups per target on small CPUs, e.g. ARM or RISC-V routers.
+#[derive(Debug, Clone)]
+pub struct RawScanner {
+    // 测试IP地址集合
+    targets: Targets,
+    // 同时测试的最大数量
+    batch_size: usize,
+    // 同个IP测试的次数
+    times: u8,
+    // 超时设置
+    timeout: Duration,
+    // 设定端口
+    target_port: u16,
+    // 平均延迟上限
+    max_average_delay: u128,
+    // 平均延迟下限
+    min_average_delay: u128,
+    // 抖动上限
+    max_jitter: Option<Duration>,
+    // 丢包比例上限,超过的 IP 不进内存也不换出
+    max_loss: Option<f64>,
+    // 本地 socket 设置
+    socket_options: SocketOptions,
+    // 进度事件
+    events: ProgressEvents,
+    // 先测量本机开销并从延迟中扣除
+    calibrate: bool,
+    // 同个IP两次测量之间的间隔,为 0 时连续测量
+    probe_gap: Duration,
+    // 到时停止测试,保留已有的结果
+    deadline: Option<Instant>,
+    // 找到足够多的可用 IP 后停止测试
+    stop_after: StopAfter,
+    // 内存中最多保留的结果数,其余写到磁盘
+    memory_limit: Option<(PathBuf, usize)>,
+    // 内存占用接近 --max-memory 时改为写入磁盘
+    memory_guard: MemoryGuard,
+    // 所有成功测量的延迟分布
+    histogram: LatencyHistogram,
+    // 根据已测到的往返时间缩短超时
+    adaptive_timeout: AdaptiveTimeout,
+    // 暂时性错误的重试
+    retry: RetryPolicy,
+    // 前几个 IP 都失败的子网跳过剩下的 IP
+    dead_subnets: DeadSubnets,
+}
+
+/// One connect in flight
+struct Attempt {
+    stream: TcpStream,
+    tally: Tally,
+    start: Instant,
+    deadline: Instant,
+}
+
+/// The samples of one IP so far
+struct Tally {
+    ip: IpAddr,
+    done: u8,
+    // 当前这次测量已重试的次数
+    retried: u8,
+    success: u8,
+    total: Duration,
+    jitter: Jitter,
+    kernel_rtt: KernelRtt,
+    streak: Streak,
+    samples: Vec<Duration>,
+}
+
+impl RawScanner {
+    pub fn new(
+        ips: impl Into<Targets>,
+        batch_size: usize,
+        timeout: Duration,
+        times: u8,
+        port: u16,
+        avg_delay_upper: u128,
+        avg_delay_lower: u128,
+    ) -> Self {
+        RawScanner {
+            targets: ips.into(),
+            batch_size: batch_size.max(1),
+            times: times.max(1),
+            timeout: if timeout.is_zero() {
+                Duration::from_millis(10)
+            } else {
+                timeout
+            },
+            target_port: if port == 0 { 80 } else { port },
+            max_average_delay: avg_delay_upper,
+            min_average_delay: avg_delay_lower,
+            max_jitter: None,
+            max_loss: None,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            calibrate: false,
+            probe_gap: Duration::ZERO,
+            deadline: None,
+            stop_after: StopAfter::default(),
+            memory_limit: None,
+            memory_guard: MemoryGuard::default(),
+            histogram: LatencyHistogram::default(),
+            adaptive_timeout: AdaptiveTimeout::default(),
+            retry: RetryPolicy::default(),
+            dead_subnets: DeadSubnets::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Keep only the best `limit` results in memory and spill the others to
+    /// sorted segments in `dir`, see [`crate::spill`]
+    pub fn with_memory_limit(mut self, dir: &Path, limit: usize) -> Self {
+        self.memory_limit = Some((dir.to_path_buf(), limit));
+        self
+    }
+
+    /// Spill like [`Self::with_memory_limit`] once `guard` reports memory pressure
+    pub fn with_memory_guard(mut self, guard: MemoryGuard) -> Self {
+        self.memory_guard = guard;
+        self
+    }
+
+    /// Drop the IPs whose jitter is above `max_jitter`
+    pub fn with_max_jitter(mut self, max_jitter: Option<Duration>) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Drop the IPs that lost more than `max_loss` of their samples
+    pub fn with_max_loss(mut self, max_loss: Option<f64>) -> Self {
+        self.max_loss = max_loss;
+        self
+    }
+
+    /// Subtract the overhead measured by [`RawScanner::overhead`] from every delay
+    pub fn with_calibration(mut self, calibrate: bool) -> Self {
+        self.calibrate = calibrate;
+        self
+    }
+
+    /// Wait `gap` after each sample before probing the same IP again. Waiting
+    /// IPs hold no socket, so other IPs are probed in between.
+    pub fn with_probe_gap(mut self, gap: Duration) -> Self {
+        self.probe_gap = gap;
+        self
+    }
+
+    /// Stop the scan at `deadline`, keeping the delays measured so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// End the scan once `stop_after` counts enough IPs that pass the filters
+    pub fn with_stop_after(mut self, stop_after: StopAfter) -> Self {
+        self.stop_after = stop_after;
+        self
+    }
+
+    /// Record every successful sample into `histogram`
+    pub fn with_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    /// Shorten the timeout of every connect to what `adaptive_timeout`
+    /// derives from the round trips so far, and record them into it
+    pub fn with_adaptive_timeout(mut self, adaptive_timeout: AdaptiveTimeout) -> Self {
+        self.adaptive_timeout = adaptive_timeout;
+        self
+    }
+
+    /// Connect again as `retry` says when a connect fails with a transient error
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Skip the IPs of the subnets `dead_subnets` gives up on
+    pub fn with_dead_subnets(mut self, dead_subnets: DeadSubnets) -> Self {
+        self.dead_subnets = dead_subnets;
+        self
+    }
+
+    /// The average delay of connects to a local listener on the calling thread
+    pub fn overhead(&self) -> io::Result<Duration> {
+        // 连接数小于积压队列,不需要 accept
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let port = listener.local_addr()?.port();
+        let local = RawScanner::new(
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST)],
+            1,
+            self.timeout,
+            CALIBRATION_SAMPLES,
+            port,
+            u128::MAX,
+            0,
+        );
+        let mut overhead = None;
+        local.scan(|delay| overhead = Some(delay))?;
+        match overhead {
+            Some(delay) if delay.success > 0 => Ok(delay.average_delay),
+            _ => Err(io::Error::other("no local connect succeeded")),
+        }
+    }
+
+    /// Measure every IP on the calling thread and pass its delay, unfiltered,
+    /// to `on_delay` as soon as all its samples are taken
+    pub fn scan(&self, mut on_delay: impl FnMut(Delay)) -> io::Result<()> {
+        let mut poll = Poll::new()?;
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        // 槽位下标就是 mio 的 Token
+        let mut slots: Vec<Option<Attempt>> = Vec::with_capacity(self.batch_size);
+        let mut free: Vec<usize> = Vec::new();
+        // 自适应超时会变化,截止时间不一定按开始顺序,用最小堆
+        let mut deadlines: BinaryHeap<Reverse<(Instant, usize)>> = BinaryHeap::new();
+        // 目标按需生成,内存只和并发数有关
+        let mut pending = self.targets.iter();
+        // 还要再测或重试的 IP 到期后优先,按到期时间有序
+        let mut again: VecDeque<(Instant, Tally)> = VecDeque::new();
+        let mut in_flight = 0;
+
+        loop {
+            // 到时或找到足够的 IP 后放弃还在测试的 IP
+            if expired(self.deadline) || self.stop_after.reached() {
+                return Ok(());
+            }
+
+            while in_flight < self.batch_size {
+                let ready = matches!(again.front(), Some((at, _)) if *at <= Instant::now());
+                let tally = match ready.then(|| again.pop_front()).flatten() {
+                    Some((_, tally)) => tally,
+                    None => match pending.find(|ip| !self.skip_dead(*ip)) {
+                        Some(ip) => Tally {
+                            ip,
+                            done: 0,
+                            retried: 0,
+                            success: 0,
+                            total: Duration::ZERO,
+                            jitter: Jitter::default(),
+                            kernel_rtt: KernelRtt::default(),
+                            streak: Streak::default(),
+                            samples: Vec::new(),
+                        },
+                        None => break,
+                    },
+                };
+
+                let start = Instant::now();
+                let mut stream = match self.connect(tally.ip) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        if e.raw_os_error() == Some(libc::EMFILE) {
+                            panic!("{}", tr(Msg::TooManyOpenFiles));
+                        }
+                        if let Some(tally) = self.retry(tally, &e, &mut again) {
+                            self.tally(tally, None, &mut again, &mut on_delay);
+                        }
+                        continue;
+                    }
+                };
+                let slot = free.pop().unwrap_or(slots.len());
+                poll.registry()
+                    .register(&mut stream, Token(slot), Interest::WRITABLE)?;
+                let deadline = start + self.adaptive_timeout.timeout(self.timeout);
+                let attempt = Attempt {
+                    stream,
+                    tally,
+                    start,
+                    deadline,
+                };
+                if slot == slots.len() {
+                    slots.push(Some(attempt));
+                } else {
+                    slots[slot] = Some(attempt);
+                }
+                deadlines.push(Reverse((deadline, slot)));
+                in_flight += 1;
+            }
+
+            if in_flight == 0 && again.is_empty() {
+                return Ok(());
+            }
+
+            // 醒来处理最早的超时,或最早结束间隔的 IP
+            let wake = match (deadlines.peek(), again.front()) {
+                (Some(&Reverse((deadline, _))), Some(&(at, _))) => Some(deadline.min(at)),
+                (Some(&Reverse((deadline, _))), None) => Some(deadline),
+                (None, Some(&(at, _))) => Some(at),
+                (None, None) => None,
+            };
+            let wake = match (wake, self.deadline) {
+                (Some(wake), Some(deadline)) => Some(wake.min(deadline)),
+                (wake, deadline) => wake.or(deadline),
+            };
+            let wait = wake.map(|wake| wake.saturating_duration_since(Instant::now()));
+            match poll.poll(&mut events, wait) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+
+            let now = Instant::now();
+            for event in events.iter() {
+                let slot = event.token().0;
+                let Some(mut attempt) = slots[slot].take() else {
+                    continue;
+                };
+                let error = match attempt.stream.take_error() {
+                    Ok(None) => attempt.stream.peer_addr().err(),
+                    Ok(Some(e)) | Err(e) => Some(e),
+                };
+                let connected = error.is_none();
+                if connected {
+                    let rtt = socket::kernel_rtt(&attempt.stream).ok();
+                    attempt.tally.kernel_rtt.add(rtt);
+                }
+                poll.registry().deregister(&mut attempt.stream)?;
+                free.push(slot);
+                in_flight -= 1;
+
+                let elapsed = connected.then(|| now - attempt.start);
+                let tally = match error {
+                    Some(ref e) => self.retry(attempt.tally, e, &mut again),
+                    None => Some(attempt.tally),
+                };
+                if let Some(tally) = tally {
+                    self.tally(tally, elapsed, &mut again, &mut on_delay);
+                }
+            }
+
+            while let Some(&Reverse((deadline, slot))) = deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                deadlines.pop();
+                // 槽位已经完成或被复用时,队列里的是旧的截止时间
+                if !matches!(slots[slot], Some(ref attempt) if attempt.deadline == deadline) {
+                    continue;
+                }
+                let mut attempt = slots[slot].take().unwrap();
+                poll.registry().deregister(&mut attempt.stream)?;
+                free.push(slot);
+                in_flight -= 1;
+                self.tally(attempt.tally, None, &mut again, &mut on_delay);
+            }
+        }
+    }
+
+    /// Start a non-blocking connect to `ip`
+    fn connect(&self, ip: IpAddr) -> io::Result<TcpStream> {
+        let addr = SocketAddr::new(ip, self.target_port);
+        let socket = self.socket_options.raw_tcp_socket(&addr)?;
+        // 关闭时直接 RST,十万级的目标不会堆积 TIME_WAIT
+        socket.set_linger(Some(Duration::ZERO))?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(TcpStream::from_std(socket.into()))
+    }
+
+    /// Whether `ip` is skipped as part of a dead subnet, reported as an invalid result
+    fn skip_dead(&self, ip: IpAddr) -> bool {
+        if !self.dead_subnets.skips(ip) {
+            return false;
+        }
+        self.events.trace("tcping", ip, || "skipped: dead subnet".to_string());
+        self.events
+            .result("tcping", ip, false, json!({"skipped": "dead subnet"}));
+        true
+    }
+
+    /// Queue the sample of `tally` to be taken again after a backoff when
+    /// `error` is transient and retries are left, or give `tally` back
+    fn retry(
+        &self,
+        mut tally: Tally,
+        error: &io::Error,
+        again: &mut VecDeque<(Instant, Tally)>,
+    ) -> Option<Tally> {
+        if !self.retry.retries(tally.retried, error) {
+            return Some(tally);
+        }
+        let backoff = self.retry.backoff(tally.retried);
+        self.events.trace("tcping", tally.ip, || {
+            format!(
+                "sample {}/{}: {}, retrying in {:?}",
+                tally.done + 1,
+                self.times,
+                error,
+                backoff
+            )
+        });
+        tally.retried += 1;
+        requeue(again, Instant::now() + backoff, tally);
+        None
+    }
+
+    /// Record one more sample; `elapsed` is None if the connect failed
+    fn tally(
+        &self,
+        mut tally: Tally,
+        elapsed: Option<Duration>,
+        again: &mut VecDeque<(Instant, Tally)>,
+        on_delay: &mut impl FnMut(Delay),
+    ) {
+        tally.done += 1;
+        tally.retried = 0;
+        self.events.trace("tcping", tally.ip, || match elapsed {
+            Some(elapsed) => format!(
+                "sample {}/{} to port {}: connected after {:.1}ms",
+                tally.done,
+                self.times,
+                self.target_port,
+                elapsed.as_secs_f64() * 1000.0
+            ),
+            None => format!(
+                "sample {}/{} to port {}: refused or timed out",
+                tally.done, self.times, self.target_port
+            ),
+        });
+        tally.streak.add(elapsed.is_some());
+        if let Some(elapsed) = elapsed {
+            tally.success += 1;
+            tally.total += elapsed;
+            tally.jitter.add(elapsed);
+            tally.samples.push(elapsed);
+            self.histogram.record(elapsed);
+            self.adaptive_timeout.record(elapsed);
+        }
+        if tally.done == self.times {
+            self.dead_subnets.record(tally.ip, tally.success > 0);
+        }
+        if tally.done < self.times {
+            requeue(again, Instant::now() + self.probe_gap, tally);
+            return;
+        }
+
+        on_delay(Delay {
+            ip: tally.ip,
+            average_delay: if tally.success != 0 {
+                tally.total / tally.success as u32
+            } else {
+                Duration::from_secs(0)
+            },
+            success: tally.success,
+            interference: 0,
+            jitter: tally.jitter.value(),
+            percentiles: Percentiles::of(&mut tally.samples),
+            // 已按延迟排好序
+            min_delay: tally.samples.first().copied().unwrap_or_default(),
+            max_delay: tally.samples.last().copied().unwrap_or_default(),
+            sentinel_delay: None,
+            kernel_rtt: tally.kernel_rtt.value(),
+            connect_delay: None,
+            streak: tally.streak.value(),
+        });
+    }
+
+    pub async fn run(&self) -> Vec<Delay> {
+        let scanner = self.clone();
+        // 事件循环会阻塞,放到单独的线程里
+        let scan = tokio::task::spawn_blocking(move || {
+            let mut res = Vec::new();
+            let mut spill = scanner
+                .memory_limit
+                .as_ref()
+                .map(|(dir, limit)| Spill::new(dir, *limit));
+            let mut valid_count = 0;
+            let overhead = if scanner.calibrate {
+                report_overhead(scanner.overhead())
+            } else {
+                Duration::ZERO
+            };
+            scanner.histogram.set_overhead(overhead);
+            scanner.events.stage_start("tcping", scanner.targets.len());
+
+            let scanned = scanner.scan(|mut delay| {
+                delay.subtract(overhead);
+                let loss = delay.loss(scanner.times);
+                let lossy = scanner.max_loss.is_some_and(|max| loss > max);
+                let valid = delay.within(scanner.max_average_delay, scanner.min_average_delay)
+                    && scanner.max_jitter.is_none_or(|max| delay.jitter <= max)
+                    && !lossy;
+                if !valid {
+                    scanner.events.trace("tcping", delay.ip, || match lossy && delay.success > 0 {
+                        true => format!("dropped by --max-loss: loss {:.2}", loss),
+                        false => {
+                            drop_reason(&delay, scanner.max_average_delay, scanner.min_average_delay)
+                        }
+                    });
+                }
+                scanner.events.result(
+                    "tcping",
+                    delay.ip,
+                    valid,
+                    json!({
+                        "delay_ms": delay.average_delay.as_millis() as u64,
+                        "jitter_ms": delay.jitter.as_secs_f64() * 1000.0,
+                        "p50_ms": delay.percentiles.p50.as_secs_f64() * 1000.0,
+                        "p90_ms": delay.percentiles.p90.as_secs_f64() * 1000.0,
+                        "p99_ms": delay.percentiles.p99.as_secs_f64() * 1000.0,
+                        "success": delay.success,
+                        "streak": delay.streak,
+                        "metric": "tcp",
+                        "interference": 0,
+                        "kernel_rtt_ms": delay.kernel_rtt.map(|d| d.as_secs_f64() * 1000.0),
+                    }),
+                );
+                if valid {
+                    valid_count += 1;
+                    scanner.stop_after.found();
+                    if spill.is_none() && scanner.memory_guard.under_pressure() {
+                        spill = Some(scanner.memory_guard.spill(res.drain(..)));
+                    }
+                    match spill {
+                        Some(ref mut spill) => spill.push(delay),
+                        None => res.push(delay),
+                    }
+                }
+            });
+            if let Some(spill) = spill {
+                res.extend(spill.finish());
+            }
+            if let Err(e) = scanned {
+                eprintln!("{}", e);
+            }
+
+            scanner.events.stage_end("tcping", valid_count);
+            scanner.dead_subnets.report();
+            scanner.stop_after.report();
+            res
+        });
+        scan.await.unwrap_or_default()
+    }
+}
+
+/// Queue `tally` to be probed again at `at`, keeping `again` ordered by time
+fn requeue(again: &mut VecDeque<(Instant, Tally)>, at: Instant, tally: Tally) {
+    let index = again.partition_point(|(due, _)| *due <= at);
+    again.insert(index, (at, tally));
+}
+
+impl Prober for RawScanner {
+    fn stage(&self) -> &'static str {
+        "tcping"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            let mut delays = self.run().await;
+            delays.sort();
+            ScanResult::Delays(delays)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use futures::StreamExt;
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+    use crate::scanner::Scanner;
+
+    /// A listener accepting connections to every 127.0.0.0/8 address
+    fn loopback_listener() -> u16 {
+        // 积压队列要大于并发数,否则 SYN 被丢弃后要等 1 秒重传,测的就不是扫描器了
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())
+            .unwrap();
+        socket.listen(4096).unwrap();
+        let listener = TcpListener::from(socket);
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || for _ in listener.incoming() {});
+        port
+    }
+
+    #[test]
+    fn test_scan_local() {
+        let port = loopback_listener();
+        // 没有监听的端口直接被拒绝
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()];
+        let scanner = RawScanner::new(ips.clone(), 1, Duration::from_secs(1), 3, port, 9999, 0);
+        let mut delays = Vec::new();
+        scanner.scan(|d| delays.push(d)).unwrap();
+        assert_eq!(delays.len(), 2);
+        assert!(delays.iter().all(|d| d.success == 3));
+
+        let scanner = RawScanner::new(ips, 4, Duration::from_secs(1), 2, closed_port, 9999, 0);
+        let mut delays = Vec::new();
+        scanner.scan(|d| delays.push(d)).unwrap();
+        assert_eq!(delays.len(), 2);
+        assert!(delays.iter().all(|d| d.success == 0));
+    }
+
+    #[test]
+    fn test_probe_gap() {
+        let port = loopback_listener();
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()];
+        let scanner = RawScanner::new(ips, 1, Duration::from_secs(1), 3, port, 9999, 0)
+            .with_probe_gap(Duration::from_millis(100));
+        let start = Instant::now();
+        let mut delays = Vec::new();
+        scanner.scan(|d| delays.push(d)).unwrap();
+        // 两个 IP 的间隔重叠,总时间只多两个间隔
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(400));
+        assert!(delays.iter().all(|d| d.success == 3));
+    }
+
+    /// Throughput against Scanner on loopback targets:
+    /// cargo test --release bench_loopback -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_loopback() {
+        const TARGETS: u32 = 100_000;
+        const BATCH: usize = 500;
+
+        let port = loopback_listener();
+        let base = u32::from(Ipv4Addr::new(127, 1, 0, 0));
+        let ips: Vec<IpAddr> = (0..TARGETS)
+            .map(|i| IpAddr::V4(Ipv4Addr::from(base + i)))
+            .collect();
+        let timeout = Duration::from_secs(2);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let scanner = Scanner::new(ips.clone(), BATCH, timeout, 1, port, 9999, 0);
+        let start = Instant::now();
+        let tokio_measured = rt.block_on(async {
+            scanner
+                .stream()
+                .fold(0, |n, d| async move { n + d.success as u32 })
+                .await
+        });
+        let tokio_elapsed = start.elapsed();
+
+        let raw = RawScanner::new(ips, BATCH, timeout, 1, port, 9999, 0);
+        let start = Instant::now();
+        let mut measured = 0;
+        raw.scan(|d| measured += d.success as u32).unwrap();
+        let raw_elapsed = start.elapsed();
+
+        let rate = |elapsed: Duration| TARGETS as f64 / elapsed.as_secs_f64();
+        println!(
+            "epoll: {} ok in {:?} ({:.0}/s), tokio: {} ok in {:?} ({:.0}/s)",
+            measured,
+            raw_elapsed,
+            rate(raw_elapsed),
+            tokio_measured,
+            tokio_elapsed,
+            rate(tokio_elapsed)
+        );
+        assert_eq!(measured, TARGETS);
+        assert_eq!(tokio_measured, TARGETS);
+        // 单线程的事件循环至少要跟上多线程的 tokio
+        assert!(raw_elapsed < tokio_elapsed * 2);
+    }
+}
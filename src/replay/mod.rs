@@ -0,0 +1,185 @@
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::httping::HttpRequest;
+
+/// Bytes kept of every response; the match rules only look at the head
+pub const MAX_SAVED_BYTES: usize = 16 * 1024;
+
+/// One httping response as saved by '--save-responses'
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedResponse {
+    pub ip: IpAddr,
+    pub port: u16,
+    /// From the start of the connect to the end of the response
+    pub elapsed_ms: f64,
+    /// Whether it matched the rules of the run that saved it
+    pub valid: bool,
+    pub response: String,
+}
+
+impl SavedResponse {
+    /// The status line of the response, empty if it is not HTTP
+    pub fn status(&self) -> &str {
+        self.response.lines().next().unwrap_or_default()
+    }
+}
+
+/// Appends every httping response to a file as a JSON line, for 'replay'.
+///
+/// The default value saves nothing.
+#[derive(Clone, Default)]
+pub struct ResponseLog {
+    file: Option<Arc<Mutex<LineWriter<fs::File>>>>,
+}
+
+impl fmt::Debug for ResponseLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseLog")
+            .field("enabled", &self.file.is_some())
+            .finish()
+    }
+}
+
+impl ResponseLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(ResponseLog {
+            file: Some(Arc::new(Mutex::new(LineWriter::new(file)))),
+        })
+    }
+
+    /// Save `response`, cut after [`MAX_SAVED_BYTES`]
+    pub fn save(&self, ip: IpAddr, port: u16, elapsed_ms: f64, valid: bool, response: &[u8]) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+        let response = &response[..response.len().min(MAX_SAVED_BYTES)];
+        let saved = SavedResponse {
+            ip,
+            port,
+            elapsed_ms,
+            valid,
+            response: String::from_utf8_lossy(response).into_owned(),
+        };
+        let line = serde_json::to_string(&saved).expect("serializable response");
+        // 写入失败不影响测试
+        let _ = writeln!(file.lock().expect("response log lock"), "{}", line);
+    }
+}
+
+/// Read the responses saved by '--save-responses'
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<SavedResponse>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let mut responses = Vec::new();
+    for (number, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, e),
+            )
+        })?;
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+/// Check every saved response against the rules of `request` again, the
+/// matching ones first and fastest first
+pub fn replay<'a>(
+    responses: &'a [SavedResponse],
+    request: &HttpRequest,
+) -> Vec<(bool, &'a SavedResponse)> {
+    let mut replayed: Vec<(bool, &'a SavedResponse)> = responses
+        .iter()
+        .map(|saved| (request.is_valid_response(&saved.response), saved))
+        .collect();
+    replayed.sort_by(|(a_valid, a), (b_valid, b)| {
+        b_valid
+            .cmp(a_valid)
+            .then(a.elapsed_ms.total_cmp(&b.elapsed_ms))
+    });
+    replayed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httping::HttpRequestBuilder;
+
+    #[test]
+    fn test_save_and_replay() {
+        let path = std::env::temp_dir().join(format!("responses-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let log = ResponseLog::open(&path).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        log.save(
+            ip("1.1.1.1"),
+            80,
+            30.0,
+            true,
+            b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n",
+        );
+        log.save(
+            ip("1.1.1.2"),
+            80,
+            20.0,
+            true,
+            b"HTTP/1.1 200 OK\r\nServer: cloudflare\r\n\r\n",
+        );
+        log.save(ip("1.1.1.3"), 80, 10.0, false, b"SSH-2.0-OpenSSH\r\n");
+        log.save(
+            ip("1.1.1.4"),
+            80,
+            40.0,
+            true,
+            b"HTTP/1.1 403 Forbidden\r\nServer: cloudflare\r\n\r\n",
+        );
+        drop(log);
+
+        let responses = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0].status(), "HTTP/1.1 200 OK");
+
+        // 规则不变时与原来的结果相同,较快的在前
+        let replayed = replay(&responses, &HttpRequest::default());
+        let order: Vec<(bool, IpAddr)> = replayed.iter().map(|(valid, r)| (*valid, r.ip)).collect();
+        assert_eq!(
+            order,
+            vec![
+                (true, ip("1.1.1.2")),
+                (true, ip("1.1.1.1")),
+                (true, ip("1.1.1.4")),
+                (false, ip("1.1.1.3")),
+            ]
+        );
+
+        let cloudflare = HttpRequestBuilder::new()
+            .match_header("Server", "^cloudflare$")
+            .build()
+            .unwrap();
+        let matched: Vec<IpAddr> = replay(&responses, &cloudflare)
+            .into_iter()
+            .filter(|(valid, _)| *valid)
+            .map(|(_, r)| r.ip)
+            .collect();
+        assert_eq!(matched, vec![ip("1.1.1.2"), ip("1.1.1.4")]);
+
+        assert!(load("/nonexistent/responses.jsonl").is_err());
+    }
+}
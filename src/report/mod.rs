@@ -0,0 +1,538 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use chrono::{Local, NaiveDate, NaiveTime, TimeZone};
+
+use crate::history::{HistoryWindow, Measurement};
+use crate::output::Redaction;
+use crate::schedule::{time_bucket, TimeWindow};
+
+/// Output format of a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(format!(
+                "unknown report format: {} (expected markdown|html)",
+                s
+            )),
+        }
+    }
+}
+
+/// A titled table of a report
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub title: String,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn new(title: &str, headers: &[&str]) -> Self {
+        Table {
+            title: title.to_string(),
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+}
+
+/// Median of `values`, `None` if empty
+pub fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        Some((sorted[mid - 1] + sorted[mid]) / 2.0)
+    } else {
+        Some(sorted[mid])
+    }
+}
+
+fn fmt_opt(value: Option<f64>) -> String {
+    value
+        .map(|v| format!("{:.2}", v))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn percent(part: usize, total: usize) -> String {
+    if total == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", 100.0 * part as f64 / total as f64)
+    }
+}
+
+fn local_date(ts: i64) -> NaiveDate {
+    Local
+        .timestamp_opt(ts, 0)
+        .single()
+        .map(|t| t.date_naive())
+        .unwrap_or_default()
+}
+
+fn local_time(ts: i64) -> NaiveTime {
+    Local
+        .timestamp_opt(ts, 0)
+        .single()
+        .map(|t| t.time())
+        .unwrap_or_default()
+}
+
+fn delays(measurements: &[&(i64, Measurement)]) -> Vec<f64> {
+    measurements
+        .iter()
+        .filter_map(|(_, m)| m.delay_ms)
+        .collect()
+}
+
+fn speeds(measurements: &[&(i64, Measurement)]) -> Vec<f64> {
+    measurements
+        .iter()
+        .filter_map(|(_, m)| m.speed_mbps)
+        .collect()
+}
+
+/// Per-colo, per-IP and time-of-day trend tables over all runs of `window`
+pub fn build_report(window: &HistoryWindow, redaction: Redaction) -> Vec<Table> {
+    let total_runs = window.runs.len();
+
+    let mut by_colo: BTreeMap<String, Vec<&(i64, Measurement)>> = BTreeMap::new();
+    let mut by_ip: HashMap<IpAddr, Vec<&(i64, Measurement)>> = HashMap::new();
+    let mut by_bucket: BTreeMap<String, Vec<&(i64, Measurement)>> = BTreeMap::new();
+    for record in window.measurements.iter() {
+        by_bucket
+            .entry(time_bucket(local_time(record.0)))
+            .or_default()
+            .push(record);
+        let colo = record.1.colo.clone().unwrap_or_else(|| "-".to_string());
+        by_colo.entry(colo).or_default().push(record);
+        by_ip.entry(record.1.ip).or_default().push(record);
+    }
+
+    let mut colo_table = Table::new(
+        "Colo summary",
+        &[
+            "Colo",
+            "IPs",
+            "Samples",
+            "Availability",
+            "Median Delay(ms)",
+            "Median Speed(MB/s)",
+        ],
+    );
+    let mut trend_table = Table::new(
+        "Colo daily trend",
+        &[
+            "Colo",
+            "Date",
+            "Samples",
+            "Median Delay(ms)",
+            "Median Speed(MB/s)",
+        ],
+    );
+    for (colo, records) in by_colo.iter() {
+        let ips: HashSet<IpAddr> = records.iter().map(|(_, m)| m.ip).collect();
+        let runs: HashSet<i64> = records.iter().map(|(ts, _)| *ts).collect();
+        colo_table.rows.push(vec![
+            colo.clone(),
+            ips.len().to_string(),
+            records.len().to_string(),
+            percent(runs.len(), total_runs),
+            fmt_opt(median(&delays(records))),
+            fmt_opt(median(&speeds(records))),
+        ]);
+
+        let mut by_day: BTreeMap<NaiveDate, Vec<&(i64, Measurement)>> = BTreeMap::new();
+        for record in records.iter() {
+            by_day
+                .entry(local_date(record.0))
+                .or_default()
+                .push(*record);
+        }
+        for (day, records) in by_day.iter() {
+            trend_table.rows.push(vec![
+                colo.clone(),
+                day.to_string(),
+                records.len().to_string(),
+                fmt_opt(median(&delays(records))),
+                fmt_opt(median(&speeds(records))),
+            ]);
+        }
+    }
+
+    let mut ip_table = Table::new(
+        "IP summary",
+        &[
+            "IP",
+            "Colo",
+            "Runs",
+            "Availability",
+            "Median Delay(ms)",
+            "Median Speed(MB/s)",
+            "Delay Trend(ms)",
+        ],
+    );
+    let mut ips: Vec<(&IpAddr, &Vec<&(i64, Measurement)>)> = by_ip.iter().collect();
+    // 可用率高的排在前面,其次按延迟中位数
+    ips.sort_by(|a, b| {
+        b.1.len().cmp(&a.1.len()).then_with(|| {
+            let x = median(&delays(a.1)).unwrap_or(f64::MAX);
+            let y = median(&delays(b.1)).unwrap_or(f64::MAX);
+            x.partial_cmp(&y).unwrap().then(a.0.cmp(b.0))
+        })
+    });
+    for (ip, records) in ips {
+        let runs: HashSet<i64> = records.iter().map(|(ts, _)| *ts).collect();
+        let colo = records
+            .iter()
+            .rev()
+            .find_map(|(_, m)| m.colo.clone())
+            .unwrap_or_else(|| "-".to_string());
+        // 后一半与前一半延迟中位数之差,正数表示变慢
+        let samples = delays(records);
+        let half = samples.len() / 2;
+        let trend = match (median(&samples[..half]), median(&samples[half..])) {
+            (Some(before), Some(after)) => format!("{:+.2}", after - before),
+            _ => "-".to_string(),
+        };
+        ip_table.rows.push(vec![
+            redaction.apply(ip),
+            colo,
+            runs.len().to_string(),
+            percent(runs.len(), total_runs),
+            fmt_opt(median(&samples)),
+            fmt_opt(median(&speeds(records))),
+            trend,
+        ]);
+    }
+
+    // 按运行开始的本地时间分段,用于比较高峰与闲时
+    let mut time_table = Table::new(
+        "Time of day",
+        &[
+            "Local Time",
+            "Runs",
+            "Samples",
+            "Median Delay(ms)",
+            "Median Speed(MB/s)",
+        ],
+    );
+    for (bucket, records) in by_bucket.iter() {
+        let runs: HashSet<i64> = records.iter().map(|(ts, _)| *ts).collect();
+        time_table.rows.push(vec![
+            bucket.clone(),
+            runs.len().to_string(),
+            records.len().to_string(),
+            fmt_opt(median(&delays(records))),
+            fmt_opt(median(&speeds(records))),
+        ]);
+    }
+
+    vec![colo_table, trend_table, ip_table, time_table]
+}
+
+// 一侧时段内某个 IP 的统计
+struct Side {
+    runs: usize,
+    availability: Option<f64>,
+    delay: Option<f64>,
+    speed: Option<f64>,
+}
+
+impl Side {
+    fn of(records: &[&(i64, Measurement)], total_runs: usize) -> Self {
+        let runs: HashSet<i64> = records.iter().map(|(ts, _)| *ts).collect();
+        Side {
+            runs: runs.len(),
+            availability: (total_runs > 0).then(|| runs.len() as f64 / total_runs as f64),
+            delay: median(&delays(records)),
+            speed: median(&speeds(records)),
+        }
+    }
+}
+
+/// Compare every IP during the `peak` window with `off_peak` (all other
+/// times when `None`), and flag the IPs that are only good off-peak: their
+/// peak median delay is more than `tolerance` (a fraction) above the
+/// off-peak one, or their peak speed or availability more than `tolerance`
+/// below it
+pub fn peak_report(
+    window: &HistoryWindow,
+    peak: TimeWindow,
+    off_peak: Option<TimeWindow>,
+    tolerance: f64,
+    redaction: Redaction,
+) -> Table {
+    let in_peak = |ts: i64| peak.contains(local_time(ts));
+    let in_off_peak = |ts: i64| match off_peak {
+        Some(off_peak) => off_peak.contains(local_time(ts)),
+        None => !in_peak(ts),
+    };
+    let peak_runs = window.runs.iter().filter(|ts| in_peak(**ts)).count();
+    let off_peak_runs = window.runs.iter().filter(|ts| in_off_peak(**ts)).count();
+
+    type Records<'a> = Vec<&'a (i64, Measurement)>;
+    let mut by_ip: BTreeMap<IpAddr, (Records, Records)> = BTreeMap::new();
+    for record in window.measurements.iter() {
+        if in_peak(record.0) {
+            by_ip.entry(record.1.ip).or_default().0.push(record);
+        } else if in_off_peak(record.0) {
+            by_ip.entry(record.1.ip).or_default().1.push(record);
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (ip, (peak_records, off_peak_records)) in by_ip.iter() {
+        let peak = Side::of(peak_records, peak_runs);
+        let off = Side::of(off_peak_records, off_peak_runs);
+        let worse_delay =
+            matches!((peak.delay, off.delay), (Some(p), Some(o)) if p > o * (1.0 + tolerance));
+        let worse_speed =
+            matches!((peak.speed, off.speed), (Some(p), Some(o)) if p < o * (1.0 - tolerance));
+        let worse_availability = matches!(
+            (peak.availability, off.availability),
+            (Some(p), Some(o)) if p < o * (1.0 - tolerance)
+        );
+        // 两个时段都有运行才能比较
+        let verdict = if peak_runs == 0 || off.runs == 0 {
+            "-"
+        } else if worse_delay || worse_speed || worse_availability {
+            "off-peak only"
+        } else {
+            "ok"
+        };
+        let colo = peak_records
+            .iter()
+            .chain(off_peak_records.iter())
+            .find_map(|(_, m)| m.colo.clone())
+            .unwrap_or_else(|| "-".to_string());
+        rows.push(vec![
+            redaction.apply(ip),
+            colo,
+            percent(peak.runs, peak_runs),
+            percent(off.runs, off_peak_runs),
+            fmt_opt(peak.delay),
+            fmt_opt(off.delay),
+            fmt_opt(peak.speed),
+            fmt_opt(off.speed),
+            verdict.to_string(),
+        ]);
+    }
+    // 被标记的 IP 排在前面
+    rows.sort_by_key(|row| row[8] != "off-peak only");
+
+    let mut table = Table::new(
+        &format!(
+            "Peak ({}) vs off-peak ({})",
+            peak,
+            off_peak.map_or_else(|| "other times".to_string(), |w| w.to_string())
+        ),
+        &[
+            "IP",
+            "Colo",
+            "Peak Availability",
+            "Off-peak Availability",
+            "Peak Delay(ms)",
+            "Off-peak Delay(ms)",
+            "Peak Speed(MB/s)",
+            "Off-peak Speed(MB/s)",
+            "Verdict",
+        ],
+    );
+    table.rows = rows;
+    table
+}
+
+/// Render `tables` below a `title` heading
+pub fn render(title: &str, tables: &[Table], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Markdown => render_markdown(title, tables),
+        ReportFormat::Html => render_html(title, tables),
+    }
+}
+
+fn render_markdown(title: &str, tables: &[Table]) -> String {
+    let mut out = format!("# {}\n", title);
+    for table in tables {
+        out.push_str(&format!("\n## {}\n\n", table.title));
+        out.push_str(&format!("| {} |\n", table.headers.join(" | ")));
+        out.push_str(&format!("|{}\n", "---|".repeat(table.headers.len())));
+        for row in table.rows.iter() {
+            out.push_str(&format!("| {} |\n", row.join(" | ")));
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn render_html(title: &str, tables: &[Table]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n",
+        escape_html(title)
+    );
+    for table in tables {
+        out.push_str(&format!(
+            "<h2>{}</h2>\n<table border=\"1\">\n<tr>",
+            escape_html(&table.title)
+        ));
+        for header in table.headers.iter() {
+            out.push_str(&format!("<th>{}</th>", escape_html(header)));
+        }
+        out.push_str("</tr>\n");
+        for row in table.rows.iter() {
+            out.push_str("<tr>");
+            for cell in row {
+                out.push_str(&format!("<td>{}</td>", escape_html(cell)));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ts: i64, ip: &str, colo: &str, delay_ms: f64) -> (i64, Measurement) {
+        (
+            ts,
+            Measurement {
+                ip: ip.parse().unwrap(),
+                colo: Some(colo.to_string()),
+                delay_ms: Some(delay_ms),
+                loss: Some(0.0),
+                speed_mbps: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), Some(2.5));
+    }
+
+    #[test]
+    fn test_build_report() {
+        let window = HistoryWindow {
+            runs: vec![100, 200],
+            measurements: vec![
+                record(100, "1.1.1.1", "SJC", 10.0),
+                record(200, "1.1.1.1", "SJC", 14.0),
+                record(200, "1.0.0.1", "LAX", 30.0),
+            ],
+        };
+        let tables = build_report(&window, Redaction::None);
+
+        let colo = &tables[0];
+        assert_eq!(colo.rows[0][0], "LAX");
+        assert_eq!(colo.rows[0][3], "50.0%");
+        assert_eq!(colo.rows[1][0], "SJC");
+        assert_eq!(colo.rows[1][3], "100.0%");
+        assert_eq!(colo.rows[1][4], "12.00");
+
+        let ip = &tables[2];
+        assert_eq!(ip.rows[0][0], "1.1.1.1");
+        assert_eq!(ip.rows[0][6], "+4.00");
+        assert_eq!(ip.rows[1][6], "-");
+
+        // 两次运行相隔 100 秒且不跨整点,在任何时区都落在同一时段
+        let time = &tables[3];
+        assert_eq!(time.rows.len(), 1);
+        assert_eq!(time.rows[0][1], "2");
+        assert_eq!(time.rows[0][2], "3");
+        assert_eq!(time.rows[0][4], "-");
+    }
+
+    #[test]
+    fn test_peak_report() {
+        // 按本地时间构造时间戳,使结果与时区无关
+        let at = |h| {
+            Local
+                .with_ymd_and_hms(2023, 1, 2, h, 0, 0)
+                .single()
+                .unwrap()
+                .timestamp()
+        };
+        let (evening, night) = (at(20), at(3));
+        let window = HistoryWindow {
+            runs: vec![night, evening],
+            measurements: vec![
+                record(night, "1.1.1.1", "SJC", 10.0),
+                record(evening, "1.1.1.1", "SJC", 40.0),
+                record(night, "1.0.0.1", "LAX", 20.0),
+                record(evening, "1.0.0.1", "LAX", 22.0),
+                record(night, "1.0.0.2", "LAX", 20.0),
+            ],
+        };
+        let table = peak_report(
+            &window,
+            "19:00-23:00".parse().unwrap(),
+            None,
+            0.5,
+            Redaction::None,
+        );
+        assert_eq!(table.title, "Peak (19:00-23:00) vs off-peak (other times)");
+        assert_eq!(table.rows[0][0], "1.0.0.2");
+        assert_eq!(table.rows[0][2], "0.0%");
+        assert_eq!(table.rows[0][8], "off-peak only");
+        assert_eq!(table.rows[1][0], "1.1.1.1");
+        assert_eq!(table.rows[1][8], "off-peak only");
+        assert_eq!(table.rows[2][0], "1.0.0.1");
+        assert_eq!(table.rows[2][4], "22.00");
+        assert_eq!(table.rows[2][8], "ok");
+
+        // 没有高峰时段的运行时无法比较
+        let table = peak_report(
+            &window,
+            "12:00-13:00".parse().unwrap(),
+            None,
+            0.5,
+            Redaction::LastOctet,
+        );
+        assert!(table.rows.iter().all(|row| row[8] == "-"));
+        assert!(table.rows.iter().all(|row| row[0].ends_with(".x")));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut table = Table::new("T", &["A", "B"]);
+        table.rows.push(vec!["1".to_string(), "<2>".to_string()]);
+
+        let md = render("Report", &[table], ReportFormat::Markdown);
+        assert_eq!(
+            md,
+            "# Report\n\n## T\n\n| A | B |\n|---|---|\n| 1 | <2> |\n"
+        );
+
+        let table = Table::new("T", &["A"]);
+        let html = render("Report", &[table], ReportFormat::Html);
+        assert!(html.contains("<th>A</th>"));
+    }
+}
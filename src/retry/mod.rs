@@ -0,0 +1,67 @@
+use std::{io, time::Duration};
+
+/// The backoff never grows beyond this
+pub const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Tries a sample again after an exponential backoff when it failed with a
+/// transient error (see [`is_transient`]), instead of counting it as lost.
+///
+/// The default value never retries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    retries: u8,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u8, backoff: Duration) -> Self {
+        RetryPolicy { retries, backoff }
+    }
+
+    /// Whether a sample that failed with `error` after `retried` retries is tried again
+    pub fn retries(&self, retried: u8, error: &io::Error) -> bool {
+        retried < self.retries && is_transient(error)
+    }
+
+    /// The wait before retry number `retried`, counted from 0: the backoff,
+    /// doubled for every retry before
+    pub fn backoff(&self, retried: u8) -> Duration {
+        // 位移超过 16 时早已达到上限
+        let factor = 1u32 << retried.min(16);
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+/// Whether `error` is likely gone on the next try: the connection was reset
+/// or the local stack was momentarily out of resources
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    ) || error.raw_os_error() == Some(libc::EAGAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(is_transient(&reset));
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EAGAIN)));
+        assert!(!is_transient(&refused));
+
+        assert!(!RetryPolicy::default().retries(0, &reset));
+        let policy = RetryPolicy::new(2, Duration::from_millis(50));
+        assert!(policy.retries(0, &reset));
+        assert!(policy.retries(1, &reset));
+        assert!(!policy.retries(2, &reset));
+        assert!(!policy.retries(0, &refused));
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(50));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(255), MAX_BACKOFF);
+    }
+}
@@ -1,14 +1,23 @@
 use std::cmp::Ordering;
-use std::time::Duration;
-use std::{collections::HashMap, net::IpAddr};
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, SocketAddr};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::mpsc,
 };
 
+use crate::i18n::{trf, Msg};
+use crate::probe::{Prober, ScanResult};
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+use crate::trace::printable;
+use crate::utils;
+
 /// Checker struct, used to check the Cloudflare CDN IP routes
 pub struct CloudflareChecker {
     ips: Vec<IpAddr>, // List of IP addresses to check
@@ -16,6 +25,9 @@ pub struct CloudflareChecker {
     request_timeout: Duration, // HTTP request timeout
     request_port: u16,         // HTTP request port
     batch_size: usize,         // Batch size for concurrent requests
+    socket_options: SocketOptions, // Local socket settings
+    events: ProgressEvents,        // Progress event stream
+    deadline: Option<Instant>,     // Stop checking at this time
 }
 
 impl CloudflareChecker {
@@ -34,9 +46,30 @@ impl CloudflareChecker {
             request_timeout,
             request_port,
             batch_size,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            deadline: None,
         }
     }
 
+    /// Apply local socket settings (e.g. outgoing interface) to every request
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Stop checking at `deadline`, returning the results so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
     /// Check if the Cloudflare CDN IP's location code is consistent across multiple HTTP requests
     pub async fn check_routes(&self) -> Vec<CFCDNCheckResult> {
         let mut valid_result = Vec::new();
@@ -44,15 +77,7 @@ impl CloudflareChecker {
         let total = self.ips.len();
         let mut ips_iter = self.ips.clone().into_iter();
 
-        // process bar
-        let pb = ProgressBar::new(total as u64);
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-            )
-            .unwrap()
-            .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
-        );
+        self.events.stage_start("route", total);
 
         // Concurrently check the routes of IP addresses
         for _ in 0..std::cmp::min(total, self.batch_size) {
@@ -61,6 +86,8 @@ impl CloudflareChecker {
             let tries_per_ip = self.tries_per_ip;
             let request_port = self.request_port;
             let request_timeout = self.request_timeout;
+            let socket_options = self.socket_options.clone();
+            let events = self.events.clone();
 
             tokio::spawn(async move {
                 let check_result = CloudflareChecker::check_cloudflare_routes(
@@ -68,9 +95,12 @@ impl CloudflareChecker {
                     tries_per_ip,
                     request_port,
                     request_timeout,
+                    &socket_options,
+                    &events,
                 )
                 .await;
-                tx.send(check_result).await.unwrap();
+                // 检查提前结束时没有接收方
+                let _ = tx.send(check_result).await;
             });
         }
 
@@ -78,10 +108,26 @@ impl CloudflareChecker {
         let mut diff: usize = 0;
         // Handle the check results
         for _ in 0..total {
-            if let Some(ip_status) = rx.recv().await {
+            // 到时停止,还在检查的 IP 结果不再接收
+            let received = match self.deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline.into(), rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => break,
+                },
+                None => rx.recv().await,
+            };
+            if let Some(ip_status) = received {
+                self.events.result(
+                    "route",
+                    ip_status.ip,
+                    ip_status.route_status != RouteStatus::NoLocation,
+                    json!({
+                        "status": format!("{:?}", ip_status.route_status),
+                        "location": ip_status.location_code,
+                    }),
+                );
                 match ip_status.route_status {
                     RouteStatus::Normal => {
-                        pb.set_message(format!("Addr: {}", ip_status.ip));
                         valid_result.push(ip_status);
                     }
                     RouteStatus::DiffLocation => {
@@ -94,13 +140,14 @@ impl CloudflareChecker {
                     }
                 }
             }
-            pb.inc(1);
 
             if let Some(ip_address) = ips_iter.next() {
                 let tx = tx.clone();
                 let tries_per_ip = self.tries_per_ip;
                 let request_port = self.request_port;
                 let request_timeout = self.request_timeout;
+                let socket_options = self.socket_options.clone();
+                let events = self.events.clone();
 
                 tokio::spawn(async move {
                     let check_result = CloudflareChecker::check_cloudflare_routes(
@@ -108,20 +155,21 @@ impl CloudflareChecker {
                         tries_per_ip,
                         request_port,
                         request_timeout,
+                        &socket_options,
+                        &events,
                     )
                     .await;
-                    tx.send(check_result).await.unwrap();
+                    // 检查提前结束时没有接收方
+                    let _ = tx.send(check_result).await;
                 });
             } 
         }
-        pb.finish_with_message("finshed");
+        self.events.stage_end("route", valid_result.len());
 
         // summary all ip routes status
         println!(
-            "vaild: {} \t empty: {} \t diff: {}",
-            valid_result.len(),
-            empty,
-            diff
+            "{}",
+            trf(Msg::RoutesSummary, &[&valid_result.len(), &empty, &diff])
         );
 
         valid_result
@@ -133,6 +181,8 @@ impl CloudflareChecker {
         tries_per_ip: u64,
         request_port: u16,
         request_timeout: Duration,
+        socket_options: &SocketOptions,
+        events: &ProgressEvents,
     ) -> CFCDNCheckResult {
         let mut result = CFCDNCheckResult {
             ip: ip_address,
@@ -145,9 +195,14 @@ impl CloudflareChecker {
         // Check the route information of the IP address multiple times to get a stable result
         for _ in 0..tries_per_ip {
             count += 1;
-            if let Some(code) =
-                CloudflareChecker::get_location_code(&ip_address, request_port, request_timeout)
-                    .await
+            if let Some(code) = CloudflareChecker::get_location_code(
+                &ip_address,
+                request_port,
+                request_timeout,
+                socket_options,
+                events,
+            )
+            .await
             {
                 location_code = code;
                 break;
@@ -164,11 +219,19 @@ impl CloudflareChecker {
 
         // Check the route information of the IP address again to ensure the accuracy of the result
         for _ in count..tries_per_ip {
-            if let Some(code) =
-                CloudflareChecker::get_location_code(&ip_address, request_port, request_timeout)
-                    .await
+            if let Some(code) = CloudflareChecker::get_location_code(
+                &ip_address,
+                request_port,
+                request_timeout,
+                socket_options,
+                events,
+            )
+            .await
             {
                 if code != location_code {
+                    events.trace("route", ip_address, || {
+                        format!("location changed from {} to {}", location_code, code)
+                    });
                     // println!(
                     //     "{} has different location code by {} and {}",
                     //     ip_address, location_code, code
@@ -182,14 +245,12 @@ impl CloudflareChecker {
     }
 
     #[inline]
-    async fn tcp_connect(address: String, request_timeout: Duration) -> io::Result<TcpStream> {
-        let stream =
-            tokio::time::timeout(
-                request_timeout,
-                async move { TcpStream::connect(address).await },
-            )
-            .await??;
-        Ok(stream)
+    async fn tcp_connect(
+        address: SocketAddr,
+        request_timeout: Duration,
+        socket_options: &SocketOptions,
+    ) -> io::Result<TcpStream> {
+        socket_options.connect(address, request_timeout).await
     }
 
     #[inline]
@@ -216,13 +277,20 @@ impl CloudflareChecker {
         ip_address: &IpAddr,
         request_port: u16,
         request_timeout: Duration,
+        socket_options: &SocketOptions,
+        events: &ProgressEvents,
     ) -> Option<String> {
-        let address = format!("{}:{}", ip_address, request_port);
+        let address = SocketAddr::new(*ip_address, request_port);
 
         // Connect to host:80
-        let mut stream = match CloudflareChecker::tcp_connect(address, request_timeout).await {
+        let start = Instant::now();
+        let stream = CloudflareChecker::tcp_connect(address, request_timeout, socket_options).await;
+        let mut stream = match stream {
             Ok(stream) => stream,
-            Err(_) => {
+            Err(e) => {
+                events.trace("route", *ip_address, || {
+                    format!("connect to port {} failed: {}", address.port(), e)
+                });
                 return None;
             }
         };
@@ -232,39 +300,42 @@ impl CloudflareChecker {
             &mut stream,
             format!(
                 "GET /cdn-cgi/trace HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-                ip_address
+                utils::host_header(ip_address)
             )
             .as_bytes(),
             request_timeout,
         )
         .await).is_err()
         {
+            events.trace("route", *ip_address, || "write failed".to_string());
             return None;
         }
 
 
         // Read the response from the stream into a buffer
         let mut buffer = [0; 1024];
-        if (CloudflareChecker::read_with_timeout(&mut stream, &mut buffer, request_timeout).await).is_err()
-        {
+        let read = CloudflareChecker::read_with_timeout(&mut stream, &mut buffer, request_timeout).await;
+        events.trace("route", *ip_address, || match read {
+            Ok(n) => format!(
+                "response after {:?}: {}",
+                start.elapsed(),
+                printable(&buffer[..n], 512)
+            ),
+            Err(ref e) => format!("read failed after {:?}: {}", start.elapsed(), e),
+        });
+        if read.is_err() {
             return None;
         }
         // shutdown tcpStream
         tokio::spawn(async move {
-            match stream.shutdown().await {
-                _ => {}
-            }
+            let _ = stream.shutdown().await;
         });
         // Convert the buffer into a string
         let response = String::from_utf8_lossy(&buffer);
         // Split the response into lines
         let lines: Vec<&str> = response.split("\r\n").collect();
         // Find the line that starts with CF-ray header
-        let cf_ray_line = if let Some(line) = lines.iter().find(|line| line.to_uppercase().starts_with("CF-RAY")) {
-            line
-        } else {
-            return None;
-        };
+        let cf_ray_line = lines.iter().find(|line| line.to_uppercase().starts_with("CF-RAY"))?;
 
         // Get the last three letters of the CF-ray value as the location code
         let location_code = &cf_ray_line[cf_ray_line.len() - 3..];
@@ -273,25 +344,29 @@ impl CloudflareChecker {
     }
 }
 
+impl Prober for CloudflareChecker {
+    fn stage(&self) -> &'static str {
+        "route"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            let mut routes = self.check_routes().await;
+            routes.sort();
+            ScanResult::Routes(routes)
+        })
+    }
+}
+
 /// CloudflareCheckResult struct, used to represent the check result of an IP address routeed
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CFCDNCheckResult {
     pub ip: IpAddr,             // IP address
     pub route_status: RouteStatus, // Whether the route is consistent
     pub location_code: String,
 }
 
-impl CFCDNCheckResult {
-    pub fn to_map(routes: Vec<CFCDNCheckResult>) -> HashMap<IpAddr, CFCDNCheckResult> {
-        let mut map = HashMap::new();
-        for route in routes {
-            map.insert(route.ip, route);
-        }
-        map
-    }
-}
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum RouteStatus {
     /// normal
     Normal,
@@ -304,19 +379,11 @@ pub enum RouteStatus {
 
 impl Ord for CFCDNCheckResult {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.route_status == RouteStatus::Normal {
-            return Ordering::Less;
-        }
-
-        if other.route_status == RouteStatus::Normal{
-            return  Ordering::Greater;
-        }
-
-        if self.route_status == RouteStatus::DiffLocation || self.route_status == RouteStatus::NoLocation{
-            return Ordering::Less;
-        }
-
-        return Ordering::Greater;
+        // 路由正常的在前,其余按 IP,结果的顺序与检查完成的先后无关
+        let abnormal = |result: &Self| result.route_status != RouteStatus::Normal;
+        abnormal(self)
+            .cmp(&abnormal(other))
+            .then(self.ip.cmp(&other.ip))
     }
 }
 
@@ -346,7 +413,15 @@ mod tests {
         let ip_v4 = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
 
         let check_result_v4 =
-            CloudflareChecker::check_cloudflare_routes(ip_v4, 2, 80, Duration::from_secs(5)).await;
+            CloudflareChecker::check_cloudflare_routes(
+                ip_v4,
+                2,
+                80,
+                Duration::from_secs(5),
+                &SocketOptions::default(),
+                &ProgressEvents::default(),
+            )
+            .await;
         assert_eq!(check_result_v4.ip, ip_v4);
         assert_eq!(check_result_v4.route_status, RouteStatus::Normal);
     }
@@ -356,7 +431,14 @@ mod tests {
         let ip_v4 = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
 
         let location_code_v4 =
-            CloudflareChecker::get_location_code(&ip_v4, 80, Duration::from_secs(5)).await;
+            CloudflareChecker::get_location_code(
+                &ip_v4,
+                80,
+                Duration::from_secs(5),
+                &SocketOptions::default(),
+                &ProgressEvents::default(),
+            )
+            .await;
         assert!(location_code_v4.is_some());
     }
 
@@ -0,0 +1,142 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use hdrhistogram::Histogram;
+
+/// Successful round trips needed before the adaptive timeout replaces the fixed one
+pub const ADAPTIVE_MIN_SAMPLES: u64 = 100;
+/// The adaptive timeout never drops below this
+pub const ADAPTIVE_FLOOR: Duration = Duration::from_millis(50);
+// 每记录这么多样本重新计算一次 p99
+const RECOMPUTE_EVERY: u64 = 32;
+// 可记录的最大往返时间(微秒)
+const HIGHEST_MICROS: u64 = 60_000_000;
+
+/// Shortens the timeout of a scan to `factor` times the p99 of the
+/// successful round trips seen so far, so that dead IPs are given up on
+/// sooner. Shared by all probes of a scan, whichever engine runs it.
+///
+/// The default value is disabled and keeps the fixed timeout.
+#[derive(Clone, Default)]
+pub struct AdaptiveTimeout {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    factor: f64,
+    histogram: Mutex<Histogram<u64>>,
+    // 当前的超时(微秒),样本不足时为 0
+    current: AtomicU64,
+}
+
+impl std::fmt::Debug for AdaptiveTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdaptiveTimeout")
+            .field("factor", &self.inner.as_ref().map(|inner| inner.factor))
+            .field("current", &self.current())
+            .finish()
+    }
+}
+
+impl AdaptiveTimeout {
+    pub fn new(factor: f64) -> Self {
+        let histogram =
+            Histogram::new_with_bounds(1, HIGHEST_MICROS, 2).expect("valid histogram bounds");
+        AdaptiveTimeout {
+            inner: Some(Arc::new(Inner {
+                factor,
+                histogram: Mutex::new(histogram),
+                current: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    /// Record the round trip of a successful probe
+    pub fn record(&self, rtt: Duration) {
+        let Some(ref inner) = self.inner else {
+            return;
+        };
+        let mut histogram = inner.histogram.lock().expect("rtt lock");
+        histogram.saturating_record(rtt.as_micros() as u64);
+        let count = histogram.len();
+        if count >= ADAPTIVE_MIN_SAMPLES && count % RECOMPUTE_EVERY == 0 {
+            let p99 = Duration::from_micros(histogram.value_at_quantile(0.99));
+            let timeout = p99.mul_f64(inner.factor).max(ADAPTIVE_FLOOR);
+            inner
+                .current
+                .store(timeout.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// The adaptive timeout, `None` while disabled or before enough samples
+    pub fn current(&self) -> Option<Duration> {
+        let micros = self.inner.as_ref()?.current.load(Ordering::Relaxed);
+        (micros > 0).then(|| Duration::from_micros(micros))
+    }
+
+    /// The timeout for the next probe, never longer than `fixed`
+    pub fn timeout(&self, fixed: Duration) -> Duration {
+        self.current().map_or(fixed, |current| current.min(fixed))
+    }
+}
+
+/// Parse the '--adaptive-timeout' factor, which must be at least 1
+pub fn parse_factor(s: &str) -> Result<f64, String> {
+    let factor: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid factor: {}", s))?;
+    if !(1.0..=100.0).contains(&factor) {
+        return Err(format!("the factor must be between 1 and 100: {}", s));
+    }
+    Ok(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_timeout() {
+        let fixed = Duration::from_secs(1);
+        let disabled = AdaptiveTimeout::default();
+        disabled.record(Duration::from_millis(10));
+        assert_eq!(disabled.timeout(fixed), fixed);
+
+        let adaptive = AdaptiveTimeout::new(3.0);
+        for _ in 0..ADAPTIVE_MIN_SAMPLES - 1 {
+            adaptive.clone().record(Duration::from_millis(40));
+        }
+        // 样本不足时保持固定超时
+        assert_eq!(adaptive.timeout(fixed), fixed);
+        for _ in 0..RECOMPUTE_EVERY {
+            adaptive.record(Duration::from_millis(40));
+        }
+        let timeout = adaptive.timeout(fixed);
+        assert!(timeout > Duration::from_millis(115) && timeout < Duration::from_millis(125));
+        // 不超过固定超时
+        assert_eq!(
+            adaptive.timeout(Duration::from_millis(80)),
+            Duration::from_millis(80)
+        );
+
+        let fast = AdaptiveTimeout::new(2.0);
+        for _ in 0..ADAPTIVE_MIN_SAMPLES + RECOMPUTE_EVERY {
+            fast.record(Duration::from_millis(1));
+        }
+        assert_eq!(fast.current(), Some(ADAPTIVE_FLOOR));
+    }
+
+    #[test]
+    fn test_parse_factor() {
+        assert_eq!(parse_factor("3"), Ok(3.0));
+        assert_eq!(parse_factor("1.5"), Ok(1.5));
+        assert!(parse_factor("0.5").is_err());
+        assert!(parse_factor("abc").is_err());
+    }
+}
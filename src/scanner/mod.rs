@@ -1,20 +1,257 @@
 use std::{
-    cmp::{self, Ordering},
-    collections::HashMap,
-    fmt,
-    net::{IpAddr, SocketAddr},
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     num::NonZeroU8,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use indicatif::{ProgressBar, ProgressStyle};
-use tokio::{io::AsyncWriteExt, sync::mpsc};
+use futures::{future, future::LocalBoxFuture, stream, FutureExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Semaphore,
+};
+
+use crate::budget::ErrorBudget;
+use crate::checkpoint::Checkpoint;
+use crate::deadnet::DeadSubnets;
+use crate::histogram::LatencyHistogram;
+use crate::i18n::{tr, trf, Msg};
+use crate::memguard::MemoryGuard;
+use crate::probe::{expired, until_deadline, Prober, ScanResult, StopAfter};
+use crate::progress::ProgressEvents;
+use crate::retry::RetryPolicy;
+use crate::rtt::AdaptiveTimeout;
+use crate::sentinel::Sentinel;
+use crate::socket::{self, SocketOptions};
+use crate::spill::Spill;
+use crate::targets::Targets;
+use crate::utils::format_millis;
+use crate::tls::{self, TlsConnector};
+
+/// Local connects made to measure the overhead of this host
+pub const CALIBRATION_SAMPLES: u8 = 32;
+
+/// Which handshake depth a delay sample measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyMetric {
+    /// TCP connect (SYN/ACK)
+    Tcp,
+    /// TCP connect and TLS handshake
+    Tls,
+    /// TCP connect, TLS handshake and the first byte of an HTTP response
+    HttpTtfb,
+}
+
+impl FromStr for LatencyMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(LatencyMetric::Tcp),
+            "tls" => Ok(LatencyMetric::Tls),
+            "http-ttfb" => Ok(LatencyMetric::HttpTtfb),
+            _ => Err(format!(
+                "unknown latency metric: {} (expected tcp|tls|http-ttfb)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LatencyMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LatencyMetric::Tcp => write!(f, "tcp"),
+            LatencyMetric::Tls => write!(f, "tls"),
+            LatencyMetric::HttpTtfb => write!(f, "http-ttfb"),
+        }
+    }
+}
+
+/// How the tcping and udping results are ranked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DelayKey {
+    /// The most successful samples first, then the median delay, as [`Delay`] orders
+    #[default]
+    Loss,
+    /// The median delay, or the average where no median was taken, whatever the loss
+    Latency,
+    /// The best single sample, after the loss
+    Min,
+    /// The worst single sample after the loss, to keep IPs with congestion spikes out of the top
+    Max,
+    /// The longest run of successful samples after the loss, then the median
+    Streak,
+    /// The median delay in ms plus the loss rate times a weight, see [`sort_delays`]
+    Score,
+}
+
+impl FromStr for DelayKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // 'median' 是 'loss' 原来的名字
+            "loss" | "median" => Ok(DelayKey::Loss),
+            "latency" => Ok(DelayKey::Latency),
+            "min" => Ok(DelayKey::Min),
+            "max" => Ok(DelayKey::Max),
+            "streak" => Ok(DelayKey::Streak),
+            "score" => Ok(DelayKey::Score),
+            _ => Err(format!(
+                "unknown sort key: {} (expected loss|median|latency|min|max|streak|score)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DelayKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelayKey::Loss => write!(f, "loss"),
+            DelayKey::Latency => write!(f, "latency"),
+            DelayKey::Min => write!(f, "min"),
+            DelayKey::Max => write!(f, "max"),
+            DelayKey::Streak => write!(f, "streak"),
+            DelayKey::Score => write!(f, "score"),
+        }
+    }
+}
+
+/// Sort `delays` by `key`, the IPs without a successful sample last and ties
+/// in the order of [`Delay`]. [`DelayKey::Score`] adds `loss_weight` ms for
+/// an IP that lost all of its `time` samples, and a share of it for fewer.
+pub fn sort_delays(delays: &mut [Delay], key: DelayKey, time: u8, loss_weight: f64) {
+    let score = |d: &Delay| {
+        let loss = 1.0 - d.success as f64 / time.max(1) as f64;
+        d.typical().as_secs_f64() * 1000.0 + loss_weight * loss
+    };
+    delays.sort_by(|a, b| {
+        let more_success = b.success.cmp(&a.success);
+        let typical = a.typical().cmp(&b.typical());
+        let by_key = match key {
+            DelayKey::Loss => more_success.then(typical),
+            DelayKey::Latency => typical,
+            DelayKey::Min => more_success.then(a.min_delay.cmp(&b.min_delay)),
+            DelayKey::Max => more_success.then(a.max_delay.cmp(&b.max_delay)),
+            DelayKey::Streak => more_success.then(b.streak.cmp(&a.streak)).then(typical),
+            DelayKey::Score => score(a).total_cmp(&score(b)),
+        };
+        (a.success == 0)
+            .cmp(&(b.success == 0))
+            .then(by_key)
+            .then(a.cmp(b))
+    });
+}
+
+/// Everything needed to take one delay sample beyond the tcp connect
+#[derive(Clone)]
+struct Probe {
+    metric: LatencyMetric,
+    // TLS 握手时发送的 SNI,也是 HTTP 请求的 Host
+    server_name: String,
+    tls: Option<TlsConnector>,
+}
+
+impl fmt::Debug for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Probe")
+            .field("metric", &self.metric)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe {
+            metric: LatencyMetric::Tcp,
+            server_name: String::new(),
+            tls: None,
+        }
+    }
+}
+
+impl Probe {
+    /// Continue on a connected `stream` up to the configured depth
+    async fn finish(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let connector = match (self.metric, self.tls.as_ref()) {
+            (LatencyMetric::Tcp, _) | (_, None) => {
+                tokio::spawn(async move {
+                    let _ = stream.shutdown().await;
+                });
+                return Ok(());
+            }
+            (_, Some(connector)) => connector,
+        };
+
+        let mut stream = tls::handshake(connector, &self.server_name, stream).await?;
+        if self.metric == LatencyMetric::HttpTtfb {
+            let request = format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustspeedtest\r\nConnection: close\r\n\r\n",
+                self.server_name
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut first = [0; 1];
+            if stream.read(&mut first).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        tokio::spawn(async move {
+            let _ = stream.shutdown().await;
+        });
+        Ok(())
+    }
+}
+
+/// Outcome of one delay sample
+enum Sample {
+    /// Measured up to the configured depth, with the RTT of the connect as
+    /// measured by the kernel where available and the time the connect took
+    Done(Option<Duration>, Duration),
+    /// Connected, but the TLS or HTTP exchange was reset or cut off
+    Interfered,
+    Failed(std::io::Error),
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sample::Done(Some(rtt), connect) => {
+                write!(f, "done, connect {:?}, kernel rtt {:?}", connect, rtt)
+            }
+            Sample::Done(None, connect) => write!(f, "done, connect {:?}", connect),
+            Sample::Interfered => write!(f, "interfered"),
+            Sample::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Whether an error after a successful connect looks like a middlebox tearing down the connection
+fn is_interference(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::BrokenPipe
+    )
+}
 
 #[derive(Debug)]
 // 扫描基本设置
 pub struct Scanner {
     // 测试IP地址集合
-    ips: Vec<IpAddr>,
+    targets: Targets,
     // 同时测试的最大数量
     batch_size: usize,
     // 同个IP测试的次数
@@ -27,11 +264,50 @@ pub struct Scanner {
     max_average_delay: u128,
     // 平均延迟下限
     min_average_delay: u128,
+    // 抖动上限
+    max_jitter: Option<Duration>,
+    // 丢包比例上限,超过的 IP 不进内存也不换出
+    max_loss: Option<f64>,
+    // 本地 socket 设置
+    socket_options: SocketOptions,
+    // 进度事件
+    events: ProgressEvents,
+    // 延迟的测量深度
+    probe: Probe,
+    // 先测量本机开销并从延迟中扣除
+    calibrate: bool,
+    // 同个IP两次测量之间的间隔,为 0 时连续测量
+    probe_gap: Duration,
+    // 定期保存的进度,run 结束时删除
+    checkpoint: Mutex<Option<Checkpoint>>,
+    // 到时停止测试,保留已有的结果
+    deadline: Option<Instant>,
+    // 内存中最多保留的结果数,其余写到磁盘
+    memory_limit: Option<(PathBuf, usize)>,
+    // 内存占用接近 --max-memory 时改为写入磁盘
+    memory_guard: MemoryGuard,
+    // 所有成功测量的延迟分布
+    histogram: LatencyHistogram,
+    // 失败比例上限和用于确认本地网络的对照目标
+    error_budget: Option<(f64, SocketAddr)>,
+    // 测试期间定期测量的对照目标
+    sentinel: Option<Sentinel>,
+    // 根据已测延迟缩短的超时
+    adaptive_timeout: AdaptiveTimeout,
+    // 暂时性错误的重试
+    retry: RetryPolicy,
+    // 前几个 IP 都失败的子网跳过剩下的 IP
+    dead_subnets: DeadSubnets,
+    // 找到足够多的可用 IP 后停止测试
+    stop_after: StopAfter,
 }
 
+/// Connects tried to a control target when the error budget is exceeded
+const CONTROL_ATTEMPTS: u8 = 3;
+
 impl Scanner {
     pub fn new(
-        ips: Vec<IpAddr>,
+        ips: impl Into<Targets>,
         batch_size: usize,
         timeout: Duration,
         times: u8,
@@ -52,105 +328,483 @@ impl Scanner {
         };
 
         Self {
-            ips,
+            targets: ips.into(),
             batch_size,
             timeout,
             times: NonZeroU8::new(times).unwrap_or(NonZeroU8::new(1).unwrap()),
             target_port: port,
             max_average_delay: avg_delay_upper,
             min_average_delay: avg_delay_lower,
+            max_jitter: None,
+            max_loss: None,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            probe: Probe::default(),
+            calibrate: false,
+            probe_gap: Duration::ZERO,
+            checkpoint: Mutex::new(None),
+            deadline: None,
+            memory_limit: None,
+            memory_guard: MemoryGuard::default(),
+            histogram: LatencyHistogram::default(),
+            error_budget: None,
+            sentinel: None,
+            adaptive_timeout: AdaptiveTimeout::default(),
+            retry: RetryPolicy::default(),
+            dead_subnets: DeadSubnets::default(),
+            stop_after: StopAfter::default(),
         }
     }
 
-    pub async fn run(&self) -> Vec<Delay> {
-        // create a channel for sending tasks to the pool
-        let (tx, mut rx) = mpsc::channel(self.batch_size);
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
 
-        let mut res = Vec::new();
-        let total = self.ips.len();
-        let pb = ProgressBar::new(total as u64);
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-            )
-            .unwrap()
-            .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
-        );
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
 
-        let mut ips_iter = self.ips.clone().into_iter();
+    /// Measure the delay up to `metric`, using `server_name` as TLS SNI and HTTP host
+    pub fn with_latency_metric(mut self, metric: LatencyMetric, server_name: &str) -> Self {
+        self.probe = Probe {
+            metric,
+            server_name: server_name.to_string(),
+            tls: match metric {
+                LatencyMetric::Tcp => None,
+                _ => Some(tls::connector()),
+            },
+        };
+        self
+    }
 
-        for _ in 0..cmp::min(self.ips.len(), self.batch_size) {
-            let ip = ips_iter.next().unwrap();
-            let tx = tx.clone();
-            let socket = SocketAddr::new(ip, self.target_port);
-            let times = self.times;
-            let timeout = self.timeout;
+    /// Keep only the best `limit` results in memory and spill the others to
+    /// sorted segments in `dir`, see [`crate::spill`]
+    pub fn with_memory_limit(mut self, dir: &Path, limit: usize) -> Self {
+        self.memory_limit = Some((dir.to_path_buf(), limit));
+        self
+    }
 
-            tokio::spawn(async move {
-                let delay = Scanner::tcp_socket(times, timeout, socket);
-                tx.send(delay.await).await.unwrap();
-            });
+    /// Spill like [`Self::with_memory_limit`] once `guard` reports memory pressure
+    pub fn with_memory_guard(mut self, guard: MemoryGuard) -> Self {
+        self.memory_guard = guard;
+        self
+    }
+
+    /// Drop the IPs whose jitter is above `max_jitter`
+    pub fn with_max_jitter(mut self, max_jitter: Option<Duration>) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Drop the IPs that lost more than `max_loss` of their samples
+    pub fn with_max_loss(mut self, max_loss: Option<f64>) -> Self {
+        self.max_loss = max_loss;
+        self
+    }
+
+    /// Subtract the overhead measured by [`Scanner::overhead`] from every delay
+    pub fn with_calibration(mut self, calibrate: bool) -> Self {
+        self.calibrate = calibrate;
+        self
+    }
+
+    /// Wait `gap` after each sample before probing the same IP again, so that
+    /// the samples of an IP are spread out instead of taken in one burst
+    pub fn with_probe_gap(mut self, gap: Duration) -> Self {
+        self.probe_gap = gap;
+        self
+    }
+
+    /// Save the progress of [`Scanner::run`] to `path` now and then, and
+    /// continue the run saved there if it had the same targets and `config`
+    pub fn with_checkpoint(mut self, path: &Path, config: &str) -> Self {
+        let (checkpoint, seed) = Checkpoint::open(path, config, &self.targets);
+        self.targets = std::mem::take(&mut self.targets).with_seed(seed);
+        self.checkpoint = Mutex::new(Some(checkpoint));
+        self
+    }
+
+    /// Stop [`Scanner::run`] at `deadline`, returning the delays measured so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Record every successful sample into `histogram`
+    pub fn with_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    /// When more than `fraction` of the recent targets fail, pause and check
+    /// that `control` is reachable: resume if it is, abort the run if not
+    pub fn with_error_budget(mut self, fraction: Option<f64>, control: SocketAddr) -> Self {
+        self.error_budget = fraction.map(|fraction| (fraction, control));
+        self
+    }
+
+    /// Probe `sentinel` during [`Scanner::run`] and keep its latest delay with every result
+    pub fn with_sentinel(mut self, sentinel: Option<Sentinel>) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
+
+    /// Shorten the timeout of every sample to what `adaptive_timeout`
+    /// derives from the round trips so far, and record them into it
+    pub fn with_adaptive_timeout(mut self, adaptive_timeout: AdaptiveTimeout) -> Self {
+        self.adaptive_timeout = adaptive_timeout;
+        self
+    }
+
+    /// Take a sample again as `retry` says when it fails with a transient error
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Skip the IPs of the subnets `dead_subnets` gives up on
+    pub fn with_dead_subnets(mut self, dead_subnets: DeadSubnets) -> Self {
+        self.dead_subnets = dead_subnets;
+        self
+    }
+
+    /// End the scan once `stop_after` counts enough IPs that pass the filters
+    pub fn with_stop_after(mut self, stop_after: StopAfter) -> Self {
+        self.stop_after = stop_after;
+        self
+    }
+
+    /// The average delay of tcp connects to a local listener, i.e. what this
+    /// host and engine add to every sample
+    pub async fn overhead(&self) -> io::Result<Duration> {
+        // 连接数小于积压队列,不需要 accept
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let local = Scanner::new(
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST)],
+            1,
+            self.timeout,
+            CALIBRATION_SAMPLES,
+            port,
+            u128::MAX,
+            0,
+        );
+        let delays = local.stream();
+        tokio::pin!(delays);
+        match delays.next().await {
+            Some(delay) if delay.success > 0 => Ok(delay.average_delay),
+            _ => Err(io::Error::other("no local connect succeeded")),
         }
+    }
+
+    /// Yield the delay of every IP as soon as it is measured, unfiltered.
+    /// At most `batch_size` IPs are in flight, and new ones are only started
+    /// while the consumer keeps polling.
+    pub fn stream(&self) -> impl Stream<Item = Delay> + '_ {
+        self.indexed_stream(0, BTreeSet::new())
+            .filter_map(|(_, delay)| future::ready(delay))
+    }
+
+    /// Like [`Scanner::stream`], skipping the targets before `next` and those
+    /// in `done`. Yields the index of every target tested, with its delay
+    /// unless the probe failed.
+    fn indexed_stream(
+        &self,
+        next: u64,
+        done: BTreeSet<u64>,
+    ) -> impl Stream<Item = (u64, Option<Delay>)> + '_ {
+        // 有间隔时等待中的 IP 不占用连接,多放一些 IP 进来交错测量,
+        // 同时打开的连接仍不超过 batch_size
+        let in_flight = if self.probe_gap.is_zero() {
+            self.batch_size
+        } else {
+            self.batch_size.saturating_mul(self.times.get() as usize)
+        };
+        let sockets = Arc::new(Semaphore::new(self.batch_size));
+
+        let targets = self
+            .targets
+            .iter()
+            .zip(0u64..)
+            .skip(next as usize)
+            .filter(move |(_, index)| !done.contains(index))
+            .filter(move |(ip, _)| !self.skip_dead(*ip));
+        stream::iter(targets)
+            .map(move |(ip, index)| {
+                let probe = Scanner::tcp_socket(
+                    self.times,
+                    self.timeout,
+                    SocketAddr::new(ip, self.target_port),
+                    self.socket_options.clone(),
+                    self.probe.clone(),
+                    self.probe_gap,
+                    sockets.clone(),
+                    self.histogram.clone(),
+                    self.adaptive_timeout.clone(),
+                    self.retry,
+                    self.events.clone(),
+                );
+                tokio::spawn(probe).map(move |delay| (index, delay.ok().and_then(|d| d.ok())))
+            })
+            .buffer_unordered(in_flight)
+    }
+
+    pub async fn run(&self) -> Vec<Delay> {
+        let mut res = Vec::new();
+        let mut valid_count = 0;
+        let total = self.targets.len();
+
+        let overhead = if self.calibrate {
+            report_overhead(self.overhead().await)
+        } else {
+            Duration::ZERO
+        };
+        self.histogram.set_overhead(overhead);
 
-        for _ in 0..total {
-            if let Some(Ok(delay)) = rx.recv().await {
-                pb.set_message(format!("Addr: {}", delay.ip));
+        self.events.stage_start("tcping", total);
 
-                let delay_millis = delay.average_delay.as_millis();
-                if delay_millis < self.max_average_delay && delay_millis > self.min_average_delay {
-                    res.push(delay);
+        // 从保存的进度继续
+        let mut checkpoint = self.checkpoint.lock().expect("checkpoint lock").take();
+        let (next, done) = match checkpoint {
+            Some(ref mut checkpoint) => {
+                res.extend(checkpoint.take_results());
+                let (next, done) = checkpoint.position();
+                if next > 0 || !done.is_empty() {
+                    let tested = next as usize + done.len();
+                    println!("{}", trf(Msg::ResumingScan, &[&tested, &total]));
                 }
+                (next, done)
             }
+            None => (0, BTreeSet::new()),
+        };
 
-            pb.inc(1);
-            if let Some(ip) = ips_iter.next() {
-                let tx = tx.clone();
-                let socket = SocketAddr::new(ip, self.target_port);
-                let times = self.times;
-                let timeout = self.timeout;
+        valid_count += res.iter().filter(|d| d.success > 0).count();
+        let mut spill = self
+            .memory_limit
+            .as_ref()
+            .map(|(dir, limit)| Spill::new(dir, *limit));
 
-                tokio::spawn(async move {
-                    let delay = Scanner::tcp_socket(times, timeout, socket);
-                    tx.send(delay.await).await.unwrap();
+        let mut budget = self
+            .error_budget
+            .map(|(fraction, _)| ErrorBudget::new(fraction));
+        let _sentinel = self.sentinel.as_ref().map(Sentinel::start);
+
+        let delays = until_deadline(self.indexed_stream(next, done), self.deadline);
+        tokio::pin!(delays);
+        while let Some((index, delay)) = delays.next().await {
+            // 不再取新的目标即暂停测试,确认本地网络后继续或退出
+            let failed = delay.as_ref().is_none_or(|delay| delay.success == 0);
+            if let Some(ref mut budget) = budget {
+                if budget.record(failed) {
+                    if !self.control_reachable(budget).await {
+                        if let Some(checkpoint) = checkpoint.take() {
+                            checkpoint.suspend();
+                        }
+                        std::process::exit(1);
+                    }
+                    budget.reset();
+                }
+            }
+            let Some(mut delay) = delay else {
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.record(index, None);
+                }
+                continue;
+            };
+            self.dead_subnets.record(delay.ip, !failed);
+            delay.subtract(overhead);
+            delay.sentinel_delay = self.sentinel.as_ref().and_then(Sentinel::latest);
+            let loss = delay.loss(self.times.get());
+            let lossy = self.max_loss.is_some_and(|max| loss > max);
+            let valid = delay.within(self.max_average_delay, self.min_average_delay)
+                && self.max_jitter.is_none_or(|max| delay.jitter <= max)
+                && !lossy;
+            if !valid {
+                self.events.trace("tcping", delay.ip, || match lossy && delay.success > 0 {
+                    true => format!("dropped by --max-loss: loss {:.2}", loss),
+                    false => drop_reason(&delay, self.max_average_delay, self.min_average_delay),
                 });
             }
+            self.events.result(
+                "tcping",
+                delay.ip,
+                valid,
+                json!({
+                    "delay_ms": delay.average_delay.as_millis() as u64,
+                    "jitter_ms": delay.jitter.as_secs_f64() * 1000.0,
+                    "p50_ms": delay.percentiles.p50.as_secs_f64() * 1000.0,
+                    "p90_ms": delay.percentiles.p90.as_secs_f64() * 1000.0,
+                    "p99_ms": delay.percentiles.p99.as_secs_f64() * 1000.0,
+                    "success": delay.success,
+                    "streak": delay.streak,
+                    "metric": self.probe.metric.to_string(),
+                    "interference": delay.interference,
+                    "sentinel_ms": delay.sentinel_delay.map(|d| d.as_secs_f64() * 1000.0),
+                    "kernel_rtt_ms": delay.kernel_rtt.map(|d| d.as_secs_f64() * 1000.0),
+                    "connect_ms": delay.connect_delay.map(|d| d.as_secs_f64() * 1000.0),
+                    "handshake_ms": delay.handshake_delay().map(|d| d.as_secs_f64() * 1000.0),
+                }),
+            );
+            // 握手被重置的 IP 即使没有成功的测量也保留,以便单独报告
+            let keep = valid || (delay.success == 0 && delay.interference > 0 && !lossy);
+            if let Some(ref mut checkpoint) = checkpoint {
+                checkpoint.record(index, Some(&delay).filter(|_| keep));
+                checkpoint.save_if_due();
+            }
+            if keep {
+                if delay.success > 0 {
+                    valid_count += 1;
+                    self.stop_after.found();
+                }
+                if spill.is_none() && self.memory_guard.under_pressure() {
+                    spill = Some(self.memory_guard.spill(res.drain(..)));
+                }
+                match spill {
+                    Some(ref mut spill) => spill.push(delay),
+                    None => res.push(delay),
+                }
+            }
+            // 丢弃还在测试的 IP
+            if self.stop_after.reached() {
+                break;
+            }
+        }
+        if let Some(spill) = spill {
+            res.extend(spill.finish());
+        }
+        // 到时停止的测试留下进度,以便之后继续
+        match checkpoint {
+            Some(checkpoint) if expired(self.deadline) => checkpoint.suspend(),
+            Some(checkpoint) => checkpoint.finish(),
+            None => {}
         }
 
-        pb.finish_with_message("finshed");
+        self.events.stage_end("tcping", valid_count);
+        self.dead_subnets.report();
+        self.stop_after.report();
 
         res
     }
 
+    /// Whether `ip` is skipped as part of a dead subnet, reported as an invalid result
+    fn skip_dead(&self, ip: IpAddr) -> bool {
+        if !self.dead_subnets.skips(ip) {
+            return false;
+        }
+        self.events.trace("tcping", ip, || "skipped: dead subnet".to_string());
+        self.events
+            .result("tcping", ip, false, json!({"skipped": "dead subnet"}));
+        true
+    }
+
+    /// Report an exceeded error budget and whether the control target still
+    /// answers, i.e. whether the failures come from the targets or from here
+    async fn control_reachable(&self, budget: &ErrorBudget) -> bool {
+        let Some((_, control)) = self.error_budget else {
+            return true;
+        };
+        let percent = (budget.failed_share() * 100.0).round();
+        println!(
+            "{}",
+            trf(
+                Msg::ErrorBudgetExceeded,
+                &[&percent, &crate::budget::ERROR_BUDGET_WINDOW, &control]
+            )
+        );
+        for _ in 0..CONTROL_ATTEMPTS {
+            if self.socket_options.connect(control, self.timeout).await.is_ok() {
+                println!("{}", trf(Msg::ControlReachable, &[&control]));
+                return true;
+            }
+        }
+        println!("{}", trf(Msg::ControlUnreachable, &[&control]));
+        false
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn tcp_socket(
         times: NonZeroU8,
         timeout: Duration,
         socket: SocketAddr,
+        socket_options: SocketOptions,
+        probe: Probe,
+        gap: Duration,
+        sockets: Arc<Semaphore>,
+        histogram: LatencyHistogram,
+        adaptive_timeout: AdaptiveTimeout,
+        retry: RetryPolicy,
+        events: ProgressEvents,
     ) -> std::io::Result<Delay> {
         let mut total_elapsed_time = Duration::new(0, 0);
+        let mut total_connect_time = Duration::ZERO;
         let mut successful_calls = 0;
+        let mut interference = 0;
+        let mut jitter = Jitter::default();
+        let mut kernel_rtt = KernelRtt::default();
+        let mut streak = Streak::default();
+        let mut samples = Vec::with_capacity(times.get() as usize);
 
-        for _ in 1..=times.get() {
-            let start = Instant::now();
-            let result = Scanner::connect(timeout, socket).await;
-            let elapsed = start.elapsed();
+        for n in 1..=times.get() {
+            if n > 1 && !gap.is_zero() {
+                tokio::time::sleep(gap).await;
+            }
+            // 信号量不会被关闭
+            let _permit = sockets.acquire().await.expect("semaphore closed");
+            let timeout = adaptive_timeout.timeout(timeout);
+            let mut retried = 0;
+            let (result, elapsed) = loop {
+                let start = Instant::now();
+                let result = Scanner::sample(&socket_options, &probe, timeout, socket).await;
+                let elapsed = start.elapsed();
+                match result {
+                    Sample::Failed(ref e) if retry.retries(retried, e) => {
+                        let backoff = retry.backoff(retried);
+                        events.trace("tcping", socket.ip(), || {
+                            format!("sample {}/{}: {}, retrying in {:?}", n, times, e, backoff)
+                        });
+                        tokio::time::sleep(backoff).await;
+                        retried += 1;
+                    }
+                    result => break (result, elapsed),
+                }
+            };
+            events.trace("tcping", socket.ip(), || {
+                format!(
+                    "sample {}/{} to {} ({}, timeout {:?}): {} after {:.1}ms",
+                    n,
+                    times,
+                    socket,
+                    probe.metric,
+                    timeout,
+                    result,
+                    elapsed.as_secs_f64() * 1000.0
+                )
+            });
 
+            streak.add(matches!(result, Sample::Done(..)));
             match result {
-                Ok(mut tcp_stream) => {
-                    tokio::spawn(async move {
-                        match tcp_stream.shutdown().await {
-                            _ => {}
-                        }
-                    });
-
+                Sample::Done(rtt, connect) => {
                     successful_calls += 1;
                     total_elapsed_time += elapsed;
+                    total_connect_time += connect;
+                    jitter.add(elapsed);
+                    kernel_rtt.add(rtt);
+                    samples.push(elapsed);
+                    histogram.record(elapsed);
+                    adaptive_timeout.record(elapsed);
                 }
 
-                Err(e) => {
+                Sample::Interfered => interference += 1,
+
+                Sample::Failed(e) => {
                     let error_string = e.to_string();
 
                     if error_string.to_lowercase().contains("too many open files") {
-                        panic!("Too many open files. Please reduce batch size\nPlease try to reduce this value and then try to run again.");
+                        panic!("{}", tr(Msg::TooManyOpenFiles));
                     }
                 }
             }
@@ -164,24 +818,111 @@ impl Scanner {
                 Duration::from_secs(0)
             },
             success: successful_calls,
+            interference,
+            jitter: jitter.value(),
+            percentiles: Percentiles::of(&mut samples),
+            // 已按延迟排好序
+            min_delay: samples.first().copied().unwrap_or_default(),
+            max_delay: samples.last().copied().unwrap_or_default(),
+            sentinel_delay: None,
+            kernel_rtt: kernel_rtt.value(),
+            // 只测到 tcp 时连接时间就是延迟本身
+            connect_delay: (probe.metric != LatencyMetric::Tcp && successful_calls > 0)
+                .then(|| total_connect_time / successful_calls as u32),
+            streak: streak.value(),
         })
     }
 
 
+    /// Take one delay sample: connect and continue up to the depth of `probe`, all within `timeout`
+    async fn sample(
+        socket_options: &SocketOptions,
+        probe: &Probe,
+        timeout: Duration,
+        server_socket: SocketAddr,
+    ) -> Sample {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let start = Instant::now();
+        let stream = match Scanner::connect(socket_options, timeout, server_socket).await {
+            Ok(stream) => stream,
+            Err(e) => return Sample::Failed(e),
+        };
+        let connect = start.elapsed();
+        let kernel_rtt = socket::kernel_rtt(&stream).ok();
+        // 超时不算干扰,只有连接被重置或中断才算
+        match tokio::time::timeout_at(deadline, probe.finish(stream)).await {
+            Ok(Ok(())) => Sample::Done(kernel_rtt, connect),
+            Ok(Err(e)) if is_interference(&e) => Sample::Interfered,
+            Ok(Err(e)) => Sample::Failed(e),
+            Err(e) => Sample::Failed(e.into()),
+        }
+    }
+
     #[inline]
     async fn connect(
+        socket_options: &SocketOptions,
         connection_timeout: Duration,
         server_socket: SocketAddr,
     ) -> tokio::io::Result<tokio::net::TcpStream> {
-        let stream = tokio::time::timeout(connection_timeout, async move {
-            tokio::net::TcpStream::connect(server_socket).await
+        socket_options
+            .connect(server_socket, connection_timeout)
+            .await
+    }
+}
+
+/// The delay limit `millis` ('--au' or '--al') as a duration
+pub fn millis_limit(millis: u128) -> Duration {
+    Duration::from_millis(u64::try_from(millis).unwrap_or(u64::MAX))
+}
+
+/// Why `delay` does not pass the delay limits `au` and `al` or the jitter
+/// limit, for '--trace-ip'
+pub fn drop_reason(delay: &Delay, au: u128, al: u128) -> String {
+    let delay_millis = delay.average_delay.as_secs_f64() * 1000.0;
+    if delay.success == 0 {
+        "dropped: no successful sample".to_string()
+    } else if delay.average_delay >= millis_limit(au) {
+        format!("dropped: average delay {:.3}ms not below --au {}", delay_millis, au)
+    } else if delay.average_delay <= millis_limit(al) {
+        format!("dropped: average delay {:.3}ms not above --al {}", delay_millis, al)
+    } else {
+        format!(
+            "dropped: jitter {:.1}ms above --max-jitter",
+            delay.jitter.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Print the calibrated overhead and return it, or zero if it could not be measured
+pub fn report_overhead(overhead: io::Result<Duration>) -> Duration {
+    match overhead {
+        Ok(overhead) => {
+            let millis = format!("{:.3}", overhead.as_secs_f64() * 1000.0);
+            println!("{}", trf(Msg::CalibratedOverhead, &[&millis]));
+            overhead
+        }
+        Err(error) => {
+            println!("{}", trf(Msg::CalibrationFailed, &[&error]));
+            Duration::ZERO
+        }
+    }
+}
+
+impl Prober for Scanner {
+    fn stage(&self) -> &'static str {
+        "tcping"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            let mut delays = self.run().await;
+            delays.sort();
+            ScanResult::Delays(delays)
         })
-        .await??;
-        Ok(stream)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Delay {
     /// IP 地址
     pub ip: IpAddr,
@@ -189,30 +930,252 @@ pub struct Delay {
     pub average_delay: Duration,
     /// 成功次数
     pub success: u8,
+    /// tcp 已连接但 TLS/HTTP 握手被重置的次数
+    pub interference: u8,
+    /// 抖动,相邻两次成功测量的平均差值
+    #[serde(default)]
+    pub jitter: Duration,
+    /// 成功测量的延迟分位数
+    #[serde(default)]
+    pub percentiles: Percentiles,
+    /// 成功测量中最快的一次
+    #[serde(default)]
+    pub min_delay: Duration,
+    /// 成功测量中最慢的一次
+    #[serde(default)]
+    pub max_delay: Duration,
+    /// 测量完成时对照目标的延迟,见 --sentinel-interval
+    #[serde(default)]
+    pub sentinel_delay: Option<Duration>,
+    /// 内核测得的平均 RTT(TCP_INFO),不含本进程的调度延迟
+    #[serde(default)]
+    pub kernel_rtt: Option<Duration>,
+    /// 测量到 TLS/HTTP 时其中 tcp 连接的平均时间,其余是握手和请求的时间
+    #[serde(default)]
+    pub connect_delay: Option<Duration>,
+    /// 一轮测量中连续成功的最长次数,偶尔失败的 IP 在负载下往往更不稳定
+    #[serde(default)]
+    pub streak: u8,
 }
 
 impl Delay {
-    pub fn to_map(delays: Vec<Delay>) -> HashMap<IpAddr, Delay> {
-        let mut map = HashMap::new();
-        for delay in delays {
-            map.insert(delay.ip, delay);
+    /// An IP of which no sample succeeded
+    pub fn failed(ip: IpAddr) -> Delay {
+        Delay {
+            ip,
+            average_delay: Duration::ZERO,
+            success: 0,
+            interference: 0,
+            jitter: Duration::ZERO,
+            percentiles: Percentiles::default(),
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            sentinel_delay: None,
+            kernel_rtt: None,
+            connect_delay: None,
+            streak: 0,
+        }
+    }
+
+    /// This and another round of samples of the same IP as one: the delays
+    /// averaged over the successful samples of both, the counts per round
+    pub fn merge(&self, other: &Delay) -> Delay {
+        let success = self.success as u32 + other.success as u32;
+        let average = |a: Duration, b: Duration| match success {
+            0 => Duration::ZERO,
+            _ => (a * self.success as u32 + b * other.success as u32) / success,
+        };
+        let either = |a: Option<Duration>, b: Option<Duration>| match (a, b) {
+            (Some(a), Some(b)) => Some(average(a, b)),
+            (a, b) => a.or(b),
+        };
+        // 没有成功的一轮没有最小和最大值
+        let extreme = |a: Duration, b: Duration, pick: fn(Duration, Duration) -> Duration| {
+            match (self.success, other.success) {
+                (0, _) => b,
+                (_, 0) => a,
+                _ => pick(a, b),
+            }
+        };
+        Delay {
+            ip: self.ip,
+            average_delay: average(self.average_delay, other.average_delay),
+            success: success.div_ceil(2) as u8,
+            interference: (self.interference as u32 + other.interference as u32).div_ceil(2) as u8,
+            jitter: average(self.jitter, other.jitter),
+            percentiles: Percentiles {
+                p50: average(self.percentiles.p50, other.percentiles.p50),
+                p90: average(self.percentiles.p90, other.percentiles.p90),
+                p99: average(self.percentiles.p99, other.percentiles.p99),
+            },
+            min_delay: extreme(self.min_delay, other.min_delay, Duration::min),
+            max_delay: extreme(self.max_delay, other.max_delay, Duration::max),
+            sentinel_delay: other.sentinel_delay.or(self.sentinel_delay),
+            kernel_rtt: either(self.kernel_rtt, other.kernel_rtt),
+            connect_delay: either(self.connect_delay, other.connect_delay),
+            // 和成功次数一样按一轮计,不超过 --time,与没有再测的 IP 可比
+            streak: self.streak.max(other.streak),
+        }
+    }
+
+    /// The fraction of `times` samples that failed
+    pub fn loss(&self, times: u8) -> f64 {
+        1.0 - self.success as f64 / times.max(1) as f64
+    }
+
+    /// Whether the average delay is below `au` and above `al` milliseconds
+    pub fn within(&self, au: u128, al: u128) -> bool {
+        // 按 Duration 比较,扣除开销后不到 1ms 的延迟不会被当成 0
+        self.average_delay < millis_limit(au) && self.average_delay > millis_limit(al)
+    }
+
+    /// Whether some samples connected but were reset during the TLS or HTTP exchange
+    pub fn interference_suspected(&self) -> bool {
+        self.interference > 0
+    }
+
+    /// Remove the local `overhead` from a measured delay
+    pub fn subtract(&mut self, overhead: Duration) {
+        if self.success > 0 {
+            self.average_delay = self.average_delay.saturating_sub(overhead);
+            self.percentiles.subtract(overhead);
+            self.min_delay = self.min_delay.saturating_sub(overhead);
+            self.max_delay = self.max_delay.saturating_sub(overhead);
+            self.connect_delay = self.connect_delay.map(|d| d.saturating_sub(overhead));
+        }
+    }
+
+    /// The average time after the connect, i.e. of the TLS handshake and the
+    /// HTTP request, where they were measured
+    pub fn handshake_delay(&self) -> Option<Duration> {
+        self.connect_delay
+            .map(|connect| self.average_delay.saturating_sub(connect))
+    }
+
+    /// The delay IPs are ranked by: the median, so that one slow sample does
+    /// not outweigh the others, or the average where no median was taken
+    pub fn typical(&self) -> Duration {
+        if self.percentiles.p50.is_zero() {
+            self.average_delay
+        } else {
+            self.percentiles.p50
         }
-        map
     }
 }
 
-impl Ord for Delay {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.success == 0 {
-            return Ordering::Greater;
+/// The median and tail delays of the successful samples of an IP
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles of `samples`, all zero if there are none
+    pub fn of(samples: &mut [Duration]) -> Self {
+        samples.sort();
+        let rank = |percent: usize| {
+            if samples.is_empty() {
+                return Duration::ZERO;
+            }
+            let rank = (percent * samples.len()).div_ceil(100);
+            samples[rank.max(1) - 1]
+        };
+        Percentiles {
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+        }
+    }
+
+    fn subtract(&mut self, overhead: Duration) {
+        for value in [&mut self.p50, &mut self.p90, &mut self.p99] {
+            *value = value.saturating_sub(overhead);
         }
+    }
+}
+
+/// The mean of the smoothed RTTs the kernel reported (TCP_INFO) for the
+/// successful samples of an IP
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelRtt {
+    total: Duration,
+    count: u32,
+}
 
-        if self.success > other.success {
-            return Ordering::Less;
+impl KernelRtt {
+    pub fn add(&mut self, rtt: Option<Duration>) {
+        if let Some(rtt) = rtt {
+            self.total += rtt;
+            self.count += 1;
         }
+    }
+
+    /// None if the kernel reported no RTT, e.g. on other systems than Linux
+    pub fn value(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+}
+
+/// The jitter of a series of samples: the mean difference between one
+/// successful sample and the next, as ping and RFC 3550 report it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Jitter {
+    last: Option<Duration>,
+    total: Duration,
+    count: u32,
+}
+
+impl Jitter {
+    pub fn add(&mut self, sample: Duration) {
+        if let Some(last) = self.last {
+            self.total += sample.abs_diff(last);
+            self.count += 1;
+        }
+        self.last = Some(sample);
+    }
+
+    /// Zero until two samples succeeded
+    pub fn value(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.count
+        }
+    }
+}
+
+/// The longest run of consecutive successful samples of an IP
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Streak {
+    current: u8,
+    longest: u8,
+}
+
+impl Streak {
+    pub fn add(&mut self, success: bool) {
+        if success {
+            self.current += 1;
+            self.longest = self.longest.max(self.current);
+        } else {
+            self.current = 0;
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        self.longest
+    }
+}
 
-        (self.average_delay.as_nanos() / self.success as u128)
-            .cmp(&(other.average_delay.as_nanos() / other.success as u128))
+impl Ord for Delay {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // 没有成功的排在最后,成功次数多的在前,再按延迟;最后按 IP,结果的顺序与测量完成的先后无关
+        (self.success == 0)
+            .cmp(&(other.success == 0))
+            .then(other.success.cmp(&self.success))
+            .then(self.typical().cmp(&other.typical()))
+            .then(self.ip.cmp(&other.ip))
     }
 }
 
@@ -222,21 +1185,24 @@ impl PartialOrd for Delay {
     }
 }
 
+// 与排序用同一个键,排序相同的结果才相等
 impl PartialEq for Delay {
     fn eq(&self, other: &Self) -> bool {
-        self.average_delay == other.average_delay && self.success == other.success && self.ip == other.ip
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for Delay {}
 
+/// The average delay has as many decimals as the precision, e.g. `{:.3}`,
+/// and is in whole milliseconds without one
 impl std::fmt::Display for Delay {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "IP:{:>15} {:>10.6}ms {:>5} success",
+            "IP:{:>15} {:>10}ms {:>5} success",
             self.ip,
-            self.average_delay.as_millis(),
+            format_millis(self.average_delay, f.precision().unwrap_or(0)),
             self.success
         )
     }
@@ -244,10 +1210,18 @@ impl std::fmt::Display for Delay {
 
 #[cfg(test)]
 mod test {
-    use std::{net::IpAddr, num::NonZeroU8, str::FromStr, time::Duration};
+    use std::{
+        net::{IpAddr, SocketAddr},
+        num::NonZeroU8,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
     // use crate::scanner::sort_delays;
 
-    use super::{Delay, Scanner};
+    use super::{
+        sort_delays, Delay, DelayKey, Jitter, LatencyMetric, Percentiles, Scanner, Streak,
+    };
 
     #[test]
     fn test_config() {
@@ -280,37 +1254,406 @@ mod test {
         assert!(!result.is_empty());
     }
 
+    #[test]
+    fn test_stream_unfiltered() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            use futures::StreamExt;
+
+            let listener = tokio::net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+            // 延迟上限为 0,run() 会过滤掉所有结果,stream() 不会
+            let ips: Vec<IpAddr> = ["127.0.0.1", "127.0.0.2", "127.0.0.3"]
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect();
+            let scanner = Scanner::new(ips, 2, Duration::from_millis(500), 1, port, 0, 0);
+            let delays: Vec<Delay> = scanner.stream().collect().await;
+            assert_eq!(delays.len(), 3);
+            assert!(delays.iter().all(|d| d.success == 1));
+        });
+    }
+
+    #[test]
+    fn test_max_loss_before_spill() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let bind = |ip: &str, port| {
+                let addr = SocketAddr::new(ip.parse().unwrap(), port);
+                std::net::TcpListener::bind(addr).unwrap()
+            };
+            let accept = |listener: std::net::TcpListener| {
+                listener.set_nonblocking(true).unwrap();
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                tokio::spawn(async move { while listener.accept().await.is_ok() {} })
+            };
+            // 前三个地址一直可连,后三个在第二次测量之后关闭,丢包一半
+            let first = bind("127.0.0.1", 0);
+            let port = first.local_addr().unwrap().port();
+            accept(first);
+            accept(bind("127.0.0.2", port));
+            accept(bind("127.0.0.3", port));
+            let closing: Vec<_> = ["127.0.0.4", "127.0.0.5", "127.0.0.6"]
+                .iter()
+                .map(|ip| accept(bind(ip, port)))
+                .collect();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                closing.iter().for_each(|task| task.abort());
+            });
+
+            let ips: Vec<IpAddr> = (1..=6).map(|i| IpAddr::from([127, 0, 0, i])).collect();
+            let dir = crate::spill::scan_dir();
+            let scanner = Scanner::new(ips, 6, Duration::from_secs(1), 4, port, 9999, 0)
+                .with_probe_gap(Duration::from_millis(200))
+                .with_memory_limit(&dir, 1)
+                .with_max_loss(Some(0.25));
+            let mut delays = scanner.run().await;
+            delays.extend(crate::spill::merged(&dir).unwrap());
+            let _ = std::fs::remove_dir_all(&dir);
+
+            // 一个留在内存,两个换出到磁盘,丢包的一个也没有
+            let mut ips: Vec<IpAddr> = delays.iter().map(|delay| delay.ip).collect();
+            ips.sort();
+            let steady: Vec<IpAddr> = (1..=3).map(|i| IpAddr::from([127, 0, 0, i])).collect();
+            assert_eq!(ips, steady);
+            assert!(delays.iter().all(|delay| delay.loss(4) <= 0.25));
+        });
+    }
+
+    #[test]
+    fn test_probe_gap() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            use futures::StreamExt;
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+            let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap()];
+            let scanner = Scanner::new(ips, 1, Duration::from_secs(1), 3, port, 9999, 0)
+                .with_probe_gap(Duration::from_millis(100));
+            let start = Instant::now();
+            let delays: Vec<Delay> = scanner.stream().collect().await;
+            assert!(start.elapsed() >= Duration::from_millis(200));
+            assert_eq!(delays[0].success, 3);
+        });
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut jitter = Jitter::default();
+        jitter.add(Duration::from_millis(20));
+        assert_eq!(jitter.value(), Duration::ZERO);
+        // 相邻差值 10ms 和 4ms
+        jitter.add(Duration::from_millis(30));
+        jitter.add(Duration::from_millis(26));
+        assert_eq!(jitter.value(), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let ms = Duration::from_millis;
+        let mut samples = vec![ms(13), ms(200), ms(10), ms(12), ms(11)];
+        let percentiles = Percentiles::of(&mut samples);
+        assert_eq!(percentiles.p50, ms(12));
+        assert_eq!(percentiles.p90, ms(200));
+        assert_eq!(percentiles.p99, ms(200));
+        assert_eq!(Percentiles::of(&mut []), Percentiles::default());
+
+        // 一次很慢的测量拉高了平均值,但按中位数排序
+        let spiky = Delay {
+            average_delay: ms(49),
+            success: 5,
+            percentiles,
+            min_delay: ms(10),
+            max_delay: ms(200),
+            ..Delay::failed("127.0.0.1".parse().unwrap())
+        };
+        let steady = Delay {
+            average_delay: ms(30),
+            success: 5,
+            percentiles: Percentiles {
+                p50: ms(30),
+                p90: ms(31),
+                p99: ms(31),
+            },
+            min_delay: ms(29),
+            max_delay: ms(31),
+            ..Delay::failed("127.0.0.2".parse().unwrap())
+        };
+        assert!(spiky < steady);
+    }
+
+    #[test]
+    fn test_overhead() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let scanner = Scanner::new(Vec::<IpAddr>::new(), 1, Duration::from_secs(1), 1, 443, 9999, 0);
+        let overhead = rt.block_on(scanner.overhead()).unwrap();
+        assert!(overhead > Duration::ZERO && overhead < Duration::from_secs(1));
+
+        let mut delay = Delay {
+            average_delay: Duration::from_micros(300),
+            success: 1,
+            ..Delay::failed(IpAddr::from_str("1.1.1.1").unwrap())
+        };
+        delay.max_delay = Duration::from_micros(800);
+        delay.subtract(Duration::from_micros(500));
+        assert_eq!(delay.average_delay, Duration::ZERO);
+        assert_eq!(delay.max_delay, Duration::from_micros(300));
+        assert_eq!(delay.handshake_delay(), None);
+
+        // 握手时间是连接之后的部分
+        delay.average_delay = Duration::from_millis(30);
+        delay.connect_delay = Some(Duration::from_millis(12));
+        assert_eq!(delay.handshake_delay(), Some(Duration::from_millis(18)));
+
+        // 扣除开销后不到 1ms 的延迟仍在 --al 0 之上
+        delay.average_delay = Duration::from_micros(400);
+        assert!(delay.within(9999, 0));
+        delay.average_delay = Duration::from_micros(10_400);
+        assert!(delay.within(11, 10));
+        assert!(!delay.within(10, 0));
+    }
+
+    #[test]
+    fn test_latency_metric() {
+        assert_eq!("tls".parse::<LatencyMetric>(), Ok(LatencyMetric::Tls));
+        assert_eq!("http-ttfb".parse::<LatencyMetric>(), Ok(LatencyMetric::HttpTtfb));
+        assert!("udp".parse::<LatencyMetric>().is_err());
+        assert_eq!(LatencyMetric::HttpTtfb.to_string(), "http-ttfb");
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_tls_metric_local_server() {
+        use crate::histogram::LatencyHistogram;
+        use crate::progress::ProgressEvents;
+        use crate::retry::RetryPolicy;
+        use crate::rtt::AdaptiveTimeout;
+        use crate::socket::SocketOptions;
+        use std::{net::SocketAddr, sync::Arc};
+        use tokio::sync::Semaphore;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // 只接受 tcp 连接,不回应 TLS 握手
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+            // 本地延迟不足 1ms 会被下限过滤,直接测量单个地址
+            let addr = SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), port);
+            let times = NonZeroU8::new(2).unwrap();
+            let timeout = Duration::from_millis(1000);
+
+            let tcp = Scanner::new(vec![], 1, timeout, 2, port, 9999, 0);
+            let histogram = LatencyHistogram::new();
+            let delay = Scanner::tcp_socket(
+                times,
+                timeout,
+                addr,
+                SocketOptions::default(),
+                tcp.probe,
+                Duration::ZERO,
+                Arc::new(Semaphore::new(1)),
+                histogram.clone(),
+                AdaptiveTimeout::default(),
+                RetryPolicy::default(),
+                ProgressEvents::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(delay.success, 2);
+            assert_eq!(histogram.count(), 2);
+            // 只测到 tcp 时不单独记录连接时间
+            assert_eq!(delay.connect_delay, None);
+
+            let tls = Scanner::new(vec![], 1, timeout, 2, port, 9999, 0)
+                .with_latency_metric(LatencyMetric::Tls, "example.com");
+            let delay = Scanner::tcp_socket(
+                times,
+                timeout,
+                addr,
+                SocketOptions::default(),
+                tls.probe,
+                Duration::ZERO,
+                Arc::new(Semaphore::new(1)),
+                histogram.clone(),
+                AdaptiveTimeout::default(),
+                RetryPolicy::default(),
+                ProgressEvents::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(delay.success, 0);
+            // 服务端在握手中途关闭连接,视为干扰而不是超时
+            assert_eq!(delay.interference, 2);
+            assert!(delay.interference_suspected());
+        });
+    }
+
     #[test]
     fn test_delay_sort() {
         let delay1 = Delay {
-            ip: "127.0.0.1".parse().unwrap(),
             average_delay: Duration::from_secs(1),
-            success: 0,
+            ..Delay::failed("127.0.0.1".parse().unwrap())
         };
 
         let delay2 = Delay {
-            ip: "127.0.0.2".parse().unwrap(),
             average_delay: Duration::from_secs(2),
             success: 1,
+            ..Delay::failed("127.0.0.2".parse().unwrap())
         };
 
         let delay3 = Delay {
-            ip: "127.0.0.3".parse().unwrap(),
             average_delay: Duration::from_secs(3),
             success: 2,
+            ..Delay::failed("127.0.0.3".parse().unwrap())
         };
 
         let delay4 = Delay {
-            ip: "127.0.0.4".parse().unwrap(),
             average_delay: Duration::from_secs(5),
             success: 2,
+            ..Delay::failed("127.0.0.4".parse().unwrap())
         };
 
-        let mut delays = vec![&delay1, &delay2, &delay3, &delay4];
+        let mut delays = [&delay1, &delay2, &delay3, &delay4];
         delays.sort();
         assert!(delays[0].eq(&delay3));
         assert!(delays[1].eq(&delay4));
         assert!(delays[2].eq(&delay2));
         assert!(delays[3].eq(&delay1));
+
+        // 延迟相同时按 IP 排序,与输入顺序无关
+        let tie = Delay {
+            ip: "127.0.0.0".parse().unwrap(),
+            ..delay4.clone()
+        };
+        let mut shuffled = [&delay4, &delay1, &tie, &delay3, &delay2];
+        shuffled.sort();
+        let mut reversed = [&delay2, &delay3, &tie, &delay1, &delay4];
+        reversed.sort();
+        assert_eq!(shuffled, reversed);
+        assert_eq!(shuffled[1].ip, tie.ip);
+    }
+
+    #[test]
+    fn test_sort_delays() {
+        let ms = Duration::from_millis;
+        let delay = |ip: &str, success, min, max| Delay {
+            average_delay: ms((min + max) / 2),
+            success,
+            min_delay: ms(min),
+            max_delay: ms(max),
+            ..Delay::failed(ip.parse().unwrap())
+        };
+        // 最小延迟很好但偶尔拥塞的 IP 与稳定的 IP
+        let mut delays = vec![
+            delay("1.0.0.1", 4, 60, 80),
+            delay("1.0.0.2", 4, 10, 300),
+            delay("1.0.0.3", 0, 0, 0),
+            delay("1.0.0.4", 3, 5, 10),
+        ];
+        let ips = |delays: &[Delay]| -> Vec<String> {
+            delays.iter().map(|d| d.ip.to_string()).collect()
+        };
+
+        let sort = |delays: &mut Vec<Delay>, key| sort_delays(delays, key, 4, 1000.0);
+
+        sort(&mut delays, DelayKey::Loss);
+        assert_eq!(ips(&delays), ["1.0.0.1", "1.0.0.2", "1.0.0.4", "1.0.0.3"]);
+        sort(&mut delays, DelayKey::Min);
+        assert_eq!(ips(&delays), ["1.0.0.2", "1.0.0.1", "1.0.0.4", "1.0.0.3"]);
+        sort(&mut delays, DelayKey::Max);
+        assert_eq!(ips(&delays), ["1.0.0.1", "1.0.0.2", "1.0.0.4", "1.0.0.3"]);
+        // 丢了一次包但快得多的 IP 按延迟排在最前,按加权分数则排在后面
+        sort(&mut delays, DelayKey::Latency);
+        assert_eq!(ips(&delays), ["1.0.0.4", "1.0.0.1", "1.0.0.2", "1.0.0.3"]);
+        sort(&mut delays, DelayKey::Score);
+        assert_eq!(ips(&delays), ["1.0.0.1", "1.0.0.2", "1.0.0.4", "1.0.0.3"]);
+        sort_delays(&mut delays, DelayKey::Score, 4, 100.0);
+        assert_eq!(ips(&delays), ["1.0.0.4", "1.0.0.1", "1.0.0.2", "1.0.0.3"]);
+
+        assert_eq!("score".parse(), Ok(DelayKey::Score));
+        assert_eq!("median".parse(), Ok(DelayKey::Loss));
+        assert!("p99".parse::<DelayKey>().is_err());
+
+        // 成功次数相同时,连续成功更长的在前
+        delays[1].streak = 2;
+        delays[2].streak = 4;
+        sort(&mut delays, DelayKey::Streak);
+        assert_eq!(ips(&delays), ["1.0.0.2", "1.0.0.1", "1.0.0.4", "1.0.0.3"]);
+
+        // 相等与排序一致:中位数相同即相等,与平均延迟无关
+        let mut median = delay("1.0.0.1", 4, 60, 80);
+        median.percentiles.p50 = ms(70);
+        let mut other = median.clone();
+        other.average_delay = ms(75);
+        assert_eq!(median, other);
+        other.percentiles.p50 = ms(71);
+        assert!(median != other && median < other);
+    }
+
+    #[test]
+    fn test_merge() {
+        let ms = Duration::from_millis;
+        let ip = "1.1.1.1".parse().unwrap();
+        let first = Delay {
+            average_delay: ms(90),
+            success: 4,
+            min_delay: ms(80),
+            max_delay: ms(100),
+            streak: 4,
+            ..Delay::failed(ip)
+        };
+        let second = Delay {
+            average_delay: ms(120),
+            success: 2,
+            min_delay: ms(110),
+            max_delay: ms(130),
+            streak: 2,
+            ..Delay::failed(ip)
+        };
+        let merged = first.merge(&second);
+        assert_eq!(merged.average_delay, ms(100));
+        assert_eq!(merged.success, 3);
+        assert_eq!((merged.min_delay, merged.max_delay), (ms(80), ms(130)));
+        assert_eq!(merged.streak, 4);
+
+        // 再测失败只降低成功次数
+        let merged = first.merge(&Delay::failed(ip));
+        assert_eq!(merged.average_delay, ms(90));
+        assert_eq!(merged.success, 2);
+        assert_eq!((merged.min_delay, merged.max_delay), (ms(80), ms(100)));
+
+        // 再测全部成功的 IP 连续成功次数不超过一轮的测试次数
+        let round = |samples: &[bool]| {
+            let mut streak = Streak::default();
+            samples.iter().for_each(|&success| streak.add(success));
+            Delay {
+                success: samples.iter().filter(|&&success| success).count() as u8,
+                streak: streak.value(),
+                ..Delay::failed(ip)
+            }
+        };
+        let clean = round(&[true; 4]);
+        let merged = clean.merge(&clean);
+        assert_eq!((merged.success, merged.streak), (4, 4));
+        let merged = round(&[true, true, false, true]).merge(&round(&[true, true, true, false]));
+        assert_eq!((merged.success, merged.streak), (3, 3));
+    }
+
+    #[test]
+    fn test_streak() {
+        let mut streak = Streak::default();
+        assert_eq!(streak.value(), 0);
+        for success in [true, false, true, true, true, false, true] {
+            streak.add(success);
+        }
+        assert_eq!(streak.value(), 3);
     }
 }
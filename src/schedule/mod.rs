@@ -0,0 +1,324 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Timelike};
+
+// 最多向后查找的天数,足以覆盖 2 月 29 日这类罕见的表达式
+const MAX_LOOKAHEAD_DAYS: i64 = 366 * 5;
+// 查找落在时间窗口内的运行时间时最多跳过的次数,约为每分钟运行时的一周
+const MAX_WINDOW_SKIPS: usize = 7 * 24 * 60;
+
+/// Hours covered by one time-of-day bucket, see [`time_bucket`]
+pub const TIME_BUCKET_HOURS: u32 = 4;
+
+/// The time-of-day bucket `time` falls into, e.g. `04-08`, to compare
+/// peak and off-peak results
+pub fn time_bucket(time: NaiveTime) -> String {
+    let start = time.hour() / TIME_BUCKET_HOURS * TIME_BUCKET_HOURS;
+    format!("{:02}-{:02}", start, start + TIME_BUCKET_HOURS)
+}
+
+/// A daily window of local time such as `02:00-06:00`. The end is
+/// exclusive and a window may wrap past midnight (`22:00-06:00`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time window '{}', expected e.g. 02:00-06:00", s);
+        let (start, end) = s.trim().split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        let window = TimeWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            return Err(invalid());
+        }
+        Ok(window)
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// A cron expression with the five standard fields:
+/// `minute hour day-of-month month day-of-week`.
+///
+/// Every field accepts `*`, numbers, lists (`1,15`), ranges (`1-5`) and steps (`*/10`, `0-30/5`).
+/// As in cron, when both day fields are restricted a day matches if either of them does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// The first matching minute strictly after `after` that falls inside
+    /// `window`, if any
+    pub fn next_within(
+        &self,
+        after: NaiveDateTime,
+        window: Option<TimeWindow>,
+    ) -> Option<NaiveDateTime> {
+        let mut next = self.next_after(after)?;
+        let Some(window) = window else {
+            return Some(next);
+        };
+        for _ in 0..MAX_WINDOW_SKIPS {
+            if window.contains(next.time()) {
+                return Some(next);
+            }
+            next = self.next_after(next)?;
+        }
+        None
+    }
+
+    /// The first matching minute strictly after `after`
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let start_date = start.date();
+
+        for offset in 0..MAX_LOOKAHEAD_DAYS {
+            let date = start_date + Duration::days(offset);
+            if !self.matches_day(date.month(), date.day(), date.weekday().num_days_from_sunday()) {
+                continue;
+            }
+            // 第一天从起始时间开始匹配,之后每天从 00:00 开始
+            let (from_hour, from_minute) = if offset == 0 {
+                (start.hour(), start.minute())
+            } else {
+                (0, 0)
+            };
+            for hour in from_hour..24 {
+                if !has_bit(self.hours, hour) {
+                    continue;
+                }
+                let first_minute = if hour == from_hour { from_minute } else { 0 };
+                for minute in first_minute..60 {
+                    if has_bit(self.minutes, minute) {
+                        return date.and_hms_opt(hour, minute, 0);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, month: u32, day: u32, weekday: u32) -> bool {
+        if !has_bit(self.months, month) {
+            return false;
+        }
+        let day_match = has_bit(self.days, day);
+        let weekday_match = has_bit(self.weekdays, weekday);
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday_match,
+            (false, true) => day_match,
+            (false, false) => day_match || weekday_match,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "invalid schedule '{}': expected 5 fields (minute hour day month weekday)",
+                s
+            ));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // 7 和 0 都表示星期日
+        if has_bit(weekdays, 7) {
+            weekdays |= 1;
+        }
+
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+#[inline]
+fn has_bit(bits: u64, n: u32) -> bool {
+    bits & (1 << n) != 0
+}
+
+/// Parse one cron field into a bitset of allowed values
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .map_err(|_| format!("invalid step in '{}'", part))?,
+            ),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("invalid step in '{}'", part));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, part)?, parse_value(end, part)?)
+        } else {
+            let value = parse_value(range, part)?;
+            // `5/10` 表示从 5 开始每 10 个
+            (value, if part.contains('/') { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{}' is out of range {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_value(value: &str, part: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value in '{}'", part))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_time_window() {
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert!(night.contains(time(23, 30)));
+        assert!(night.contains(time(2, 0)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+        assert_eq!(night.to_string(), "22:00-06:00");
+        assert!("06:00-06:00".parse::<TimeWindow>().is_err());
+        assert!("2am-6am".parse::<TimeWindow>().is_err());
+
+        let hourly: Schedule = "0 * * * *".parse().unwrap();
+        let early: TimeWindow = "02:00-06:00".parse().unwrap();
+        assert_eq!(
+            hourly.next_within(at(2023, 1, 1, 12, 0), Some(early)),
+            Some(at(2023, 1, 2, 2, 0))
+        );
+        assert_eq!(
+            hourly.next_within(at(2023, 1, 1, 12, 0), None),
+            Some(at(2023, 1, 1, 13, 0))
+        );
+        // 每天 12 点运行的计划永远不会落在窗口内
+        let noon: Schedule = "0 12 * * *".parse().unwrap();
+        assert_eq!(noon.next_within(at(2023, 1, 1, 0, 0), Some(early)), None);
+
+        assert_eq!(time_bucket(time(0, 0)), "00-04");
+        assert_eq!(time_bucket(time(23, 59)), "20-24");
+    }
+
+    #[test]
+    fn test_daily() {
+        let schedule: Schedule = "0 3 * * *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 1, 2, 59)),
+            Some(at(2023, 1, 1, 3, 0))
+        );
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 1, 3, 0)),
+            Some(at(2023, 1, 2, 3, 0))
+        );
+    }
+
+    #[test]
+    fn test_steps_and_weekdays() {
+        let schedule: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // 2023-01-07 是星期六
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 6, 17, 50)),
+            Some(at(2023, 1, 9, 9, 0))
+        );
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 9, 9, 1)),
+            Some(at(2023, 1, 9, 9, 15))
+        );
+
+        let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+        assert_eq!(
+            sunday.next_after(at(2023, 1, 2, 0, 0)),
+            Some(at(2023, 1, 8, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_day_or_weekday() {
+        // 每月 1 日或每个星期一
+        let schedule: Schedule = "0 0 1 * 1".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 27, 0, 0)),
+            Some(at(2023, 1, 30, 0, 0))
+        );
+        assert_eq!(
+            schedule.next_after(at(2023, 1, 30, 0, 0)),
+            Some(at(2023, 2, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("a * * * *".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+
+/// Probes a known-good target now and then during a scan, so that every
+/// result can carry the delay of the local uplink at the time it was
+/// measured, and periods of local congestion can be discounted later
+#[derive(Debug, Clone)]
+pub struct Sentinel {
+    target: SocketAddr,
+    interval: Duration,
+    timeout: Duration,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+    // 最近一次测量的延迟,失败时为 None
+    latest: Arc<Mutex<Option<Duration>>>,
+}
+
+/// Stops the background probes of a [`Sentinel`] when dropped
+pub struct SentinelGuard(JoinHandle<()>);
+
+impl Drop for SentinelGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Sentinel {
+    pub fn new(target: SocketAddr, interval: Duration, timeout: Duration) -> Self {
+        Sentinel {
+            target,
+            interval: interval.max(Duration::from_millis(100)),
+            timeout,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream a result event for every probe to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Probe the target every `interval` until the returned guard is dropped.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start(&self) -> SentinelGuard {
+        let sentinel = self.clone();
+        SentinelGuard(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(sentinel.interval);
+            loop {
+                ticks.tick().await;
+                let delay = sentinel.probe().await;
+                *sentinel.latest.lock().expect("sentinel lock") = delay;
+                sentinel.events.result(
+                    "sentinel",
+                    sentinel.target.ip(),
+                    delay.is_some(),
+                    json!({"delay_ms": delay.map(|d| d.as_secs_f64() * 1000.0)}),
+                );
+            }
+        }))
+    }
+
+    /// The delay of the latest probe, `None` before the first one or if it failed
+    pub fn latest(&self) -> Option<Duration> {
+        *self.latest.lock().expect("sentinel lock")
+    }
+
+    async fn probe(&self) -> Option<Duration> {
+        let start = Instant::now();
+        self.socket_options
+            .connect(self.target, self.timeout)
+            .await
+            .ok()
+            .map(|_| start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sentinel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        let sentinel = Sentinel::new(target, Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(sentinel.latest(), None);
+        let guard = sentinel.start();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(sentinel.latest().is_some());
+
+        // 停止后不再更新
+        drop(guard);
+        *sentinel.latest.lock().unwrap() = None;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(sentinel.latest(), None);
+    }
+}
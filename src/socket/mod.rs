@@ -0,0 +1,386 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Local settings applied to every probe socket before it connects
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Outgoing network interface (SO_BINDTODEVICE)
+    pub interface: Option<String>,
+    /// Firewall mark for policy routing (SO_MARK)
+    pub fwmark: Option<u32>,
+    /// Local address bound before connecting to targets of the same family
+    pub source_ip: Option<IpAddr>,
+}
+
+impl SocketOptions {
+    /// Create a non-blocking tcp socket for `addr` with all local settings applied
+    pub fn tcp_socket(&self, addr: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = self.raw_tcp_socket(addr)?;
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    /// Like [`SocketOptions::tcp_socket`], for event loops other than tokio
+    pub fn raw_tcp_socket(&self, addr: &SocketAddr) -> io::Result<Socket> {
+        self.bound_tcp_socket(addr, None)
+    }
+
+    /// A blocking raw IPv4 socket that sends hand-built tcp segments and
+    /// receives a copy of every incoming one. Needs CAP_NET_RAW.
+    pub fn raw_ip_socket(&self) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+        if let Some(ref interface) = self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+        if let Some(ip @ IpAddr::V4(_)) = self.source_ip {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        Ok(socket)
+    }
+
+    /// A tcp socket for `addr`, bound to local `port` if given. Such a socket
+    /// resets on close instead of lingering in TIME_WAIT, so that the same
+    /// port can connect to the same address again right away.
+    fn bound_tcp_socket(&self, addr: &SocketAddr, port: Option<u16>) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+
+        if let Some(ref interface) = self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+        if port.is_some() {
+            // 同一源端口同时连接不同的 IP
+            socket.set_reuse_address(true)?;
+            socket.set_linger(Some(Duration::ZERO))?;
+        }
+        if self.source_for(addr).is_some() || port.is_some() {
+            let ip = self.source_for(addr).unwrap_or(if addr.is_ipv4() {
+                Ipv4Addr::UNSPECIFIED.into()
+            } else {
+                Ipv6Addr::UNSPECIFIED.into()
+            });
+            socket.bind(&SocketAddr::new(ip, port.unwrap_or(0)).into())?;
+        }
+
+        Ok(socket)
+    }
+
+    /// The source address to bind for `addr`, if one of its family is set
+    fn source_for(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.source_ip.filter(|ip| ip.is_ipv4() == addr.is_ipv4())
+    }
+
+    /// Create a udp socket connected to `addr` with all local settings applied.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn udp_socket(&self, addr: &SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_nonblocking(true)?;
+
+        if let Some(ref interface) = self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+
+        let local: IpAddr = match self.source_for(addr) {
+            Some(ip) => ip,
+            None if addr.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+            None => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        socket.bind(&SocketAddr::new(local, 0).into())?;
+        socket.connect(&(*addr).into())?;
+
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Apply every setting to a throwaway socket, so that a missing privilege
+    /// is reported before the run instead of failing every probe. The error
+    /// comes with the capability that grants the failed setting.
+    pub fn check(&self) -> Result<(), (io::Error, &'static str)> {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))
+            .map_err(|e| (e, "cap_net_raw"))?;
+        if let Some(ref interface) = self.interface {
+            // 5.7 之前的内核需要 CAP_NET_RAW
+            bind_device(&socket, interface).map_err(|e| (e, "cap_net_raw"))?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark).map_err(|e| (e, "cap_net_admin"))?;
+        }
+        if let Some(ip) = self.source_ip {
+            // 地址不属于本机时 bind 失败,与权限无关
+            let local = SocketAddr::new(ip, 0);
+            let socket = Socket::new(Domain::for_address(local), Type::STREAM, None)
+                .map_err(|e| (e, "cap_net_raw"))?;
+            socket.bind(&local.into()).map_err(|e| (e, "cap_net_raw"))?;
+        }
+        Ok(())
+    }
+
+    /// Connect to `addr` from local `port` within `timeout`, so that repeated
+    /// connects take the same path through per-flow load balancing
+    pub async fn connect_from(
+        &self,
+        addr: SocketAddr,
+        port: u16,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let socket = TcpSocket::from_std_stream(self.bound_tcp_socket(&addr, Some(port))?.into());
+        let stream = tokio::time::timeout(timeout, socket.connect(addr)).await??;
+        Ok(stream)
+    }
+
+    /// Connect to `addr` within `timeout`
+    pub async fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let socket = self.tcp_socket(&addr)?;
+        let stream = tokio::time::timeout(timeout, socket.connect(addr)).await??;
+        Ok(stream)
+    }
+}
+
+/// The capability that lets an unprivileged user enter a network namespace
+pub const NETNS_CAPABILITY: &str = "cap_sys_admin";
+
+/// Move the calling thread into the named network namespace.
+///
+/// Must be called before any worker thread is spawned, since threads inherit
+/// the namespace of their parent. `name` is looked up in `/var/run/netns`
+/// unless it already is a path.
+#[cfg(target_os = "linux")]
+pub fn enter_netns(name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let path = if name.contains('/') {
+        name.to_string()
+    } else {
+        format!("/var/run/netns/{}", name)
+    };
+    let file = std::fs::File::open(&path)?;
+
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enter_netns(name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot enter network namespace {}: not supported on this platform", name),
+    ))
+}
+
+/// The smoothed RTT the kernel measured on a connected tcp socket (TCP_INFO).
+/// Right after connecting this is the RTT of the handshake, without the
+/// scheduling delays of this process.
+#[cfg(target_os = "linux")]
+pub fn kernel_rtt(socket: &impl std::os::unix::io::AsRawFd) -> io::Result<Duration> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::from_micros(info.tcpi_rtt as u64))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kernel_rtt<T>(_socket: &T) -> io::Result<Duration> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_INFO is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot bind to interface {}: not supported on this platform", interface),
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn set_mark(_socket: &Socket, mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot set fwmark {:#x}: not supported on this platform", mark),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_local_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = SocketOptions::default()
+            .connect(addr, Duration::from_secs(1))
+            .await;
+        assert!(stream.is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_kernel_rtt() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = SocketOptions::default()
+            .connect(addr, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let rtt = kernel_rtt(&stream).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        // 未连接的 UDP 套接字没有 TCP_INFO
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(kernel_rtt(&udp).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_fwmark() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = SocketOptions {
+            fwmark: Some(0x100),
+            ..Default::default()
+        };
+        // SO_MARK 需要 CAP_NET_ADMIN,没有权限或不是 Linux 时跳过
+        let stream = options.connect(addr, Duration::from_secs(1)).await;
+        if let Err(ref e) = stream {
+            use io::ErrorKind::{PermissionDenied, Unsupported};
+            if matches!(e.kind(), PermissionDenied | Unsupported) {
+                return;
+            }
+        }
+        assert!(stream.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_source_ip() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = SocketOptions {
+            source_ip: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+        let stream = options.connect(addr, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip().to_string(), "127.0.0.2");
+
+        // 源地址与目标不是同一协议族时不绑定
+        let options = SocketOptions {
+            source_ip: Some("::1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(options.connect(addr, Duration::from_secs(1)).await.is_ok());
+
+        // 不属于本机的地址在测试前就报错
+        let options = SocketOptions {
+            source_ip: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(options.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let port = {
+            let free = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            free.local_addr().unwrap().port()
+        };
+
+        // 同一个源端口可以马上再次连接同一地址
+        for _ in 0..2 {
+            let stream = SocketOptions::default()
+                .connect_from(addr, port, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(stream.local_addr().unwrap().port(), port);
+            let (accepted, _) = listener.accept().await.unwrap();
+            drop(stream);
+            drop(accepted);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_socket() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+
+        let socket = SocketOptions::default().udp_socket(&addr).unwrap();
+        socket.send(b"ping").await.unwrap();
+
+        let mut buf = [0; 4];
+        let (n, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, socket.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(SocketOptions::default().check().is_ok());
+
+        let options = SocketOptions {
+            interface: Some("nonexistent0".to_string()),
+            ..Default::default()
+        };
+        let (_, capability) = options.check().unwrap_err();
+        assert_eq!(capability, "cap_net_raw");
+    }
+
+    #[test]
+    fn test_enter_missing_netns() {
+        assert!(enter_netns("rustspeedtest-missing-netns").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_interface() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = SocketOptions {
+            interface: Some("nonexistent0".to_string()),
+            ..Default::default()
+        };
+        let stream = options.connect(addr, Duration::from_secs(1)).await;
+        assert!(stream.is_err());
+    }
+}
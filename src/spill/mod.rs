@@ -0,0 +1,189 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Lines, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::i18n::{trf, Msg};
+use crate::scanner::Delay;
+
+// 本进程已经分配的换出目录数
+static SCANS: AtomicUsize = AtomicUsize::new(0);
+
+/// A new directory for the results one run spills, not shared with the
+/// runs going on at the same time, e.g. of other '--jobs'
+pub fn scan_dir() -> PathBuf {
+    let scan = SCANS.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "rustspeedtest-spill-{}-{}",
+        std::process::id(),
+        scan
+    ))
+}
+
+/// Keeps the best `limit` results of a scan in memory and writes the others
+/// to sorted segment files in `dir`, so that memory does not grow with the
+/// number of targets. [`merged`] reads them back in order.
+pub struct Spill {
+    dir: PathBuf,
+    limit: usize,
+    // 最差的结果在堆顶,超出上限时被换出
+    best: BinaryHeap<Delay>,
+    pending: Vec<Delay>,
+    segments: usize,
+    // 写入失败后不再换出,全部留在内存
+    failed: bool,
+}
+
+impl Spill {
+    /// Start with an empty `dir`, removing what an earlier run left there
+    pub fn new(dir: &Path, limit: usize) -> Self {
+        let _ = fs::remove_dir_all(dir);
+        Spill {
+            dir: dir.to_path_buf(),
+            limit: limit.max(1),
+            best: BinaryHeap::new(),
+            pending: Vec::new(),
+            segments: 0,
+            failed: false,
+        }
+    }
+
+    pub fn push(&mut self, delay: Delay) {
+        self.best.push(delay);
+        if self.best.len() > self.limit && !self.failed {
+            let worst = self.best.pop().expect("heap is not empty");
+            self.pending.push(worst);
+        }
+        if self.pending.len() >= self.limit {
+            self.flush();
+        }
+    }
+
+    // 排序后写成一个新的段文件
+    fn flush(&mut self) {
+        if self.pending.is_empty() || self.failed {
+            return;
+        }
+        self.pending.sort();
+        let path = self.dir.join(format!("{}.jsonl", self.segments));
+        let written = fs::create_dir_all(&self.dir).and_then(|_| {
+            let mut file = BufWriter::new(File::create(&path)?);
+            for delay in self.pending.iter() {
+                serde_json::to_writer(&mut file, delay)?;
+                file.write_all(b"\n")?;
+            }
+            file.flush()
+        });
+        match written {
+            Ok(_) => {
+                self.segments += 1;
+                self.pending.clear();
+            }
+            Err(error) => {
+                println!(
+                    "{}",
+                    trf(Msg::CannotSpillResults, &[&self.dir.display(), &error])
+                );
+                self.failed = true;
+                self.best.extend(self.pending.drain(..));
+            }
+        }
+    }
+
+    /// Write what is left and return the results kept in memory, best first
+    pub fn finish(mut self) -> Vec<Delay> {
+        self.flush();
+        self.best.into_sorted_vec()
+    }
+}
+
+/// All results spilled to `dir`, merged into one sorted sequence
+pub fn merged(dir: &Path) -> io::Result<Merged> {
+    let mut segments = Vec::new();
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            segments.push(BufReader::new(File::open(entry?.path())?).lines());
+        }
+    }
+    let mut merged = Merged {
+        segments,
+        heads: BinaryHeap::new(),
+    };
+    for index in 0..merged.segments.len() {
+        merged.advance(index);
+    }
+    Ok(merged)
+}
+
+/// The spilled results in order, see [`merged`]
+pub struct Merged {
+    segments: Vec<Lines<BufReader<File>>>,
+    // 每个段当前最好的结果
+    heads: BinaryHeap<Reverse<(Delay, usize)>>,
+}
+
+impl Merged {
+    fn advance(&mut self, index: usize) {
+        for line in self.segments[index].by_ref().map_while(Result::ok) {
+            if let Ok(delay) = serde_json::from_str(&line) {
+                self.heads.push(Reverse((delay, index)));
+                return;
+            }
+        }
+    }
+}
+
+impl Iterator for Merged {
+    type Item = Delay;
+
+    fn next(&mut self) -> Option<Delay> {
+        let Reverse((delay, index)) = self.heads.pop()?;
+        self.advance(index);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn delay(n: u8) -> Delay {
+        Delay {
+            average_delay: Duration::from_millis(n as u64),
+            success: 4,
+            ..Delay::failed(format!("10.0.0.{}", n).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_scan_dir() {
+        assert_ne!(scan_dir(), scan_dir());
+    }
+
+    #[test]
+    fn test_spill() {
+        let dir =
+            std::env::temp_dir().join(format!("rustspeedtest-spill-test-{}", std::process::id()));
+        let mut spill = Spill::new(&dir, 3);
+        for n in [9, 2, 7, 1, 5, 8, 3, 6, 4, 10] {
+            spill.push(delay(n));
+        }
+        let ms = |delays: &[Delay]| -> Vec<u128> {
+            delays.iter().map(|d| d.average_delay.as_millis()).collect()
+        };
+
+        assert_eq!(ms(&spill.finish()), vec![1, 2, 3]);
+        let spilled: Vec<Delay> = merged(&dir).unwrap().collect();
+        assert_eq!(ms(&spilled), vec![4, 5, 6, 7, 8, 9, 10]);
+        assert!(fs::read_dir(&dir).unwrap().count() > 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(merged(&dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU8,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::histogram::LatencyHistogram;
+use crate::progress::ProgressEvents;
+use crate::socket::SocketOptions;
+use crate::tls::{self, TlsConnector};
+use crate::udping::{UdpPayload, UdpPinger};
+
+/// How one payload of a sweep is sent
+#[derive(Clone)]
+pub enum SweepMode {
+    /// UDP packets of the payload size to an echo peer
+    UdpEcho { interval: Duration },
+    /// An HTTPS request padded to the payload size, timed up to the first response byte
+    Https {
+        server_name: String,
+        tls: TlsConnector,
+    },
+}
+
+/// Latency of one IP at every payload size of a sweep
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepResult {
+    pub ip: IpAddr,
+    /// Average delay per payload size, `None` if no sample succeeded
+    pub delays: Vec<Option<Duration>>,
+}
+
+impl SweepResult {
+    /// Least-squares growth of the delay with the payload size, in ms per KB
+    pub fn slope_ms_per_kb(&self, sizes: &[usize]) -> Option<f64> {
+        let points: Vec<(f64, f64)> = sizes
+            .iter()
+            .zip(self.delays.iter())
+            .filter_map(|(size, delay)| {
+                delay.map(|d| (*size as f64 / 1024.0, d.as_secs_f64() * 1000.0))
+            })
+            .collect();
+        if points.len() < 2 {
+            return None;
+        }
+
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance)
+    }
+}
+
+/// Measures how the latency of a few IPs scales with the payload size
+pub struct SizeSweep {
+    sizes: Vec<usize>,
+    times: NonZeroU8,
+    timeout: Duration,
+    port: u16,
+    mode: SweepMode,
+    socket_options: SocketOptions,
+    events: ProgressEvents,
+}
+
+impl SizeSweep {
+    pub fn new(
+        sizes: Vec<usize>,
+        times: u8,
+        timeout: Duration,
+        port: u16,
+        mode: SweepMode,
+    ) -> Self {
+        SizeSweep {
+            sizes,
+            times: NonZeroU8::new(times).unwrap_or(NonZeroU8::new(1).unwrap()),
+            timeout,
+            port,
+            mode,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Sweep all `ips` concurrently, keeping their order
+    pub async fn run(&self, ips: &[IpAddr]) -> Vec<SweepResult> {
+        self.events.stage_start("size_sweep", ips.len());
+
+        let results = join_all(ips.iter().map(|ip| self.sweep(*ip))).await;
+
+        for result in results.iter() {
+            let delays: Vec<Option<f64>> = result
+                .delays
+                .iter()
+                .map(|d| d.map(|d| d.as_secs_f64() * 1000.0))
+                .collect();
+            self.events.result(
+                "size_sweep",
+                result.ip,
+                delays.iter().any(|d| d.is_some()),
+                json!({"sizes": self.sizes, "delay_ms": delays}),
+            );
+        }
+        self.events.stage_end(
+            "size_sweep",
+            results
+                .iter()
+                .filter(|r| r.delays.iter().any(|d| d.is_some()))
+                .count(),
+        );
+
+        results
+    }
+
+    async fn sweep(&self, ip: IpAddr) -> SweepResult {
+        let addr = SocketAddr::new(ip, self.port);
+        let mut delays = Vec::with_capacity(self.sizes.len());
+
+        // 同一 IP 的不同大小依次测量,避免相互影响
+        for size in self.sizes.iter() {
+            let delay = match self.mode {
+                SweepMode::UdpEcho { interval } => {
+                    let delay = UdpPinger::ping(
+                        addr,
+                        self.times,
+                        self.timeout,
+                        *size,
+                        &UdpPayload::Echo,
+                        interval,
+                        &self.socket_options,
+                        &LatencyHistogram::default(),
+                        &self.events,
+                    )
+                    .await;
+                    if delay.success > 0 {
+                        Some(delay.average_delay)
+                    } else {
+                        None
+                    }
+                }
+                SweepMode::Https {
+                    ref server_name,
+                    ref tls,
+                } => self.https_delay(addr, *size, server_name, tls).await,
+            };
+            delays.push(delay);
+        }
+
+        SweepResult { ip, delays }
+    }
+
+    /// Average time from sending a request padded to `size` bytes to its first response byte
+    async fn https_delay(
+        &self,
+        addr: SocketAddr,
+        size: usize,
+        server_name: &str,
+        connector: &TlsConnector,
+    ) -> Option<Duration> {
+        let mut total = Duration::ZERO;
+        let mut success = 0;
+
+        for _ in 0..self.times.get() {
+            let sample = tokio::time::timeout(self.timeout, async {
+                let stream = self.socket_options.connect(addr, self.timeout).await?;
+                let mut stream = tls::handshake(connector, server_name, stream).await?;
+
+                // 握手不计入延迟,只测量请求本身
+                let request = padded_request(server_name, size);
+                let start = Instant::now();
+                stream.write_all(request.as_bytes()).await?;
+                let mut first = [0; 1];
+                if stream.read(&mut first).await? == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                let elapsed = start.elapsed();
+                let _ = stream.shutdown().await;
+                Ok::<_, std::io::Error>(elapsed)
+            })
+            .await;
+
+            if let Ok(Ok(elapsed)) = sample {
+                total += elapsed;
+                success += 1;
+            }
+        }
+
+        if success > 0 {
+            Some(total / success)
+        } else {
+            None
+        }
+    }
+}
+
+/// A GET request padded with a filler header to exactly `size` bytes where possible
+fn padded_request(server_name: &str, size: usize) -> String {
+    let head = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustspeedtest\r\nConnection: close\r\nX-Pad: ",
+        server_name
+    );
+    let tail = "\r\n\r\n";
+    let pad = size.saturating_sub(head.len() + tail.len());
+    format!("{}{}{}", head, "a".repeat(pad), tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_padded_request() {
+        assert_eq!(padded_request("example.com", 512).len(), 512);
+        // 太小时保持最短的合法请求
+        assert!(padded_request("example.com", 10).ends_with("X-Pad: \r\n\r\n"));
+    }
+
+    #[test]
+    fn test_slope() {
+        let ms = |v: u64| Some(Duration::from_millis(v));
+        let result = SweepResult {
+            ip: "1.1.1.1".parse().unwrap(),
+            delays: vec![ms(10), ms(12), None],
+        };
+        let slope = result.slope_ms_per_kb(&[0, 1024, 2048]).unwrap();
+        assert!((slope - 2.0).abs() < 1e-9);
+
+        let single = SweepResult {
+            ip: "1.1.1.1".parse().unwrap(),
+            delays: vec![ms(10), None],
+        };
+        assert_eq!(single.slope_ms_per_kb(&[64, 1400]), None);
+    }
+
+    #[tokio::test]
+    async fn test_udp_sweep() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let sweep = SizeSweep::new(
+            vec![64, 1400],
+            2,
+            Duration::from_millis(500),
+            port,
+            SweepMode::UdpEcho {
+                interval: Duration::ZERO,
+            },
+        );
+        let results = sweep.run(&["127.0.0.1".parse().unwrap()]).await;
+        assert_eq!(results.len(), 1);
+        assert!(results[0].delays.iter().all(|d| d.is_some()));
+    }
+}
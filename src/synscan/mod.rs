@@ -0,0 +1,612 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use futures::future::LocalBoxFuture;
+use serde_json::json;
+use socket2::{Domain, Socket, Type};
+
+use crate::histogram::LatencyHistogram;
+use crate::probe::{expired, Prober, ScanResult, StopAfter};
+use crate::progress::ProgressEvents;
+use crate::scanner::{drop_reason, Delay, Jitter, Percentiles, Streak};
+use crate::socket::SocketOptions;
+use crate::trace::hex;
+use crate::targets::Targets;
+
+/// The capability needed to send raw SYN probes
+pub const SYN_CAPABILITY: &str = "cap_net_raw";
+
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+// 20 字节的 tcp 头部加 4 字节的 MSS 选项,像普通的 SYN 一样不容易被丢弃
+const SYN_LEN: usize = 24;
+const SYN_MSS: u16 = 1460;
+const SYN_WINDOW: u16 = 64240;
+// 接收缓冲区,能放下一个不带数据的 IP 包
+const RECV_BUFFER: usize = 1500;
+
+/// Whether raw SYN probes can be sent, i.e. a raw socket can be opened.
+/// Only Linux delivers incoming tcp segments to raw sockets.
+pub fn available(socket_options: &SocketOptions) -> io::Result<()> {
+    if cfg!(target_os = "linux") {
+        socket_options.raw_ip_socket().map(|_| ())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "raw SYN probes are only supported on Linux",
+        ))
+    }
+}
+
+/// A half-open scanner: sends a raw SYN to every IP and measures the time
+/// until its SYN/ACK, without completing the handshake. The kernel answers
+/// the SYN/ACK with a RST since no socket owns the connection, so neither
+/// side keeps any state. Cheaper per IP than a full connect, for very large
+/// sweeps. IPv4 only; needs [`SYN_CAPABILITY`].
+#[derive(Debug, Clone)]
+pub struct SynScanner {
+    // 测试IP地址集合
+    targets: Targets,
+    // 同时测试的最大数量
+    batch_size: usize,
+    // 同个IP测试的次数
+    times: u8,
+    // 超时设置
+    timeout: Duration,
+    // 设定端口
+    target_port: u16,
+    // 平均延迟上限
+    max_average_delay: u128,
+    // 平均延迟下限
+    min_average_delay: u128,
+    // 抖动上限
+    max_jitter: Option<Duration>,
+    // 本地 socket 设置
+    socket_options: SocketOptions,
+    // 进度事件
+    events: ProgressEvents,
+    // 到时停止测试,保留已有的结果
+    deadline: Option<Instant>,
+    // 找到足够多的可用 IP 后停止测试
+    stop_after: StopAfter,
+    // 所有成功测量的延迟分布
+    histogram: LatencyHistogram,
+}
+
+/// One SYN in flight, keyed by its sequence number
+struct Attempt {
+    tally: Tally,
+    start: Instant,
+    deadline: Instant,
+}
+
+/// The samples of one IP so far
+struct Tally {
+    ip: Ipv4Addr,
+    // 发往该 IP 时使用的本地地址
+    source: Ipv4Addr,
+    done: u8,
+    success: u8,
+    total: Duration,
+    jitter: Jitter,
+    streak: Streak,
+    samples: Vec<Duration>,
+}
+
+/// The fields of an incoming tcp segment a reply is matched on
+#[derive(Debug, PartialEq, Eq)]
+struct Segment {
+    source: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    ack: u32,
+    flags: u8,
+}
+
+impl SynScanner {
+    pub fn new(
+        ips: impl Into<Targets>,
+        batch_size: usize,
+        timeout: Duration,
+        times: u8,
+        port: u16,
+        avg_delay_upper: u128,
+        avg_delay_lower: u128,
+    ) -> Self {
+        SynScanner {
+            targets: ips.into(),
+            batch_size: batch_size.max(1),
+            times: times.max(1),
+            timeout: if timeout.is_zero() {
+                Duration::from_millis(10)
+            } else {
+                timeout
+            },
+            target_port: if port == 0 { 80 } else { port },
+            max_average_delay: avg_delay_upper,
+            min_average_delay: avg_delay_lower,
+            max_jitter: None,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            deadline: None,
+            stop_after: StopAfter::default(),
+            histogram: LatencyHistogram::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Drop the IPs whose jitter is above `max_jitter`
+    pub fn with_max_jitter(mut self, max_jitter: Option<Duration>) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Stop the scan at `deadline`, keeping the delays measured so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// End the scan once `stop_after` counts enough IPs that pass the filters
+    pub fn with_stop_after(mut self, stop_after: StopAfter) -> Self {
+        self.stop_after = stop_after;
+        self
+    }
+
+    /// Record every successful sample into `histogram`
+    pub fn with_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    /// Measure every IP on the calling thread and pass its delay, unfiltered,
+    /// to `on_delay` as soon as all its samples are taken
+    pub fn scan(&self, mut on_delay: impl FnMut(Delay)) -> io::Result<()> {
+        let mut socket = self.socket_options.raw_ip_socket()?;
+        // 占用一个本地端口,内核不会把它分给别的连接;它不监听,回来的 SYN/ACK 由内核回 RST
+        let reserved = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+        let source_ip = match self.socket_options.source_ip {
+            Some(IpAddr::V4(ip)) => ip,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        reserved.bind(&SocketAddr::from((source_ip, 0)).into())?;
+        let local_port = reserved
+            .local_addr()?
+            .as_socket()
+            .map_or(0, |addr| addr.port());
+        // 连接 UDP 套接字不发送数据,只用来查路由得到本地地址
+        let router = UdpSocket::bind((source_ip, 0))?;
+
+        let mut attempts: HashMap<u32, Attempt> = HashMap::with_capacity(self.batch_size);
+        // 超时都相同,按发送顺序排队的截止时间天然有序
+        let mut deadlines: VecDeque<(u32, Instant)> = VecDeque::new();
+        let mut pending = self.targets.iter();
+        let mut again: VecDeque<Tally> = VecDeque::new();
+        let mut buf = [0u8; RECV_BUFFER];
+
+        loop {
+            if expired(self.deadline) || self.stop_after.reached() {
+                return Ok(());
+            }
+
+            while attempts.len() < self.batch_size {
+                let tally = match again.pop_front() {
+                    Some(tally) => tally,
+                    None => match pending.next() {
+                        Some(IpAddr::V4(ip)) => {
+                            let source = match source_ip {
+                                Ipv4Addr::UNSPECIFIED => route_source(&router, ip),
+                                source => Ok(source),
+                            };
+                            // 没有路由的目标直接算作失败
+                            let Ok(source) = source else {
+                                on_delay(delay_of(
+                                    ip.into(),
+                                    Duration::ZERO,
+                                    0,
+                                    Jitter::default(),
+                                    Streak::default(),
+                                    Vec::new(),
+                                ));
+                                continue;
+                            };
+                            Tally {
+                                ip,
+                                source,
+                                done: 0,
+                                success: 0,
+                                total: Duration::ZERO,
+                                jitter: Jitter::default(),
+                                streak: Streak::default(),
+                                samples: Vec::new(),
+                            }
+                        }
+                        // 只支持 IPv4,IPv6 目标直接算作失败
+                        Some(ip) => {
+                            on_delay(delay_of(
+                                ip,
+                                Duration::ZERO,
+                                0,
+                                Jitter::default(),
+                                Streak::default(),
+                                Vec::new(),
+                            ));
+                            continue;
+                        }
+                        None => break,
+                    },
+                };
+
+                let seq: u32 = rand::random();
+                let segment =
+                    syn_segment(tally.source, tally.ip, local_port, self.target_port, seq);
+                let addr = SocketAddr::from((tally.ip, 0));
+                let start = Instant::now();
+                if socket.send_to(&segment, &addr.into()).is_err() {
+                    self.tally(tally, None, &mut again, &mut on_delay);
+                    continue;
+                }
+                let deadline = start + self.timeout;
+                attempts.insert(
+                    seq,
+                    Attempt {
+                        tally,
+                        start,
+                        deadline,
+                    },
+                );
+                deadlines.push_back((seq, deadline));
+            }
+
+            if attempts.is_empty() && again.is_empty() {
+                return Ok(());
+            }
+
+            let wake = deadlines.front().map(|&(_, deadline)| deadline);
+            let wake = match (wake, self.deadline) {
+                (Some(wake), Some(deadline)) => wake.min(deadline),
+                (wake, deadline) => wake.or(deadline).unwrap_or_else(Instant::now),
+            };
+            // 超时为 0 表示一直阻塞,至少等 1 毫秒
+            let wait = wake
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            socket.set_read_timeout(Some(wait))?;
+            match socket.read(&mut buf) {
+                Ok(n) => {
+                    let now = Instant::now();
+                    if let Some(segment) = parse_segment(&buf[..n]) {
+                        let seq = segment.ack.wrapping_sub(1);
+                        let matches = segment.destination_port == local_port
+                            && segment.source_port == self.target_port
+                            && segment.flags & TCP_ACK != 0
+                            && matches!(attempts.get(&seq), Some(a) if a.tally.ip == segment.source);
+                        if matches {
+                            let attempt = attempts.remove(&seq).unwrap();
+                            self.events.trace("tcping", IpAddr::V4(segment.source), || {
+                                format!(
+                                    "syn reply after {:.1}ms, flags {:#04x}, segment {}",
+                                    (now - attempt.start).as_secs_f64() * 1000.0,
+                                    segment.flags,
+                                    hex(&buf[..n], 64)
+                                )
+                            });
+                            // SYN/ACK 表示端口开放,RST 表示关闭
+                            let elapsed =
+                                (segment.flags & TCP_SYN != 0).then(|| now - attempt.start);
+                            self.tally(attempt.tally, elapsed, &mut again, &mut on_delay);
+                        }
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = Instant::now();
+            while let Some(&(seq, deadline)) = deadlines.front() {
+                if deadline > now {
+                    break;
+                }
+                deadlines.pop_front();
+                // 已经收到回复的 SYN 不在 attempts 中,序号被重新使用时截止时间不同
+                if !matches!(attempts.get(&seq), Some(attempt) if attempt.deadline == deadline) {
+                    continue;
+                }
+                let attempt = attempts.remove(&seq).unwrap();
+                self.events.trace("tcping", IpAddr::V4(attempt.tally.ip), || {
+                    format!("syn timed out after {:?}", self.timeout)
+                });
+                self.tally(attempt.tally, None, &mut again, &mut on_delay);
+            }
+        }
+    }
+
+    /// Record one more sample; `elapsed` is None if the probe failed
+    fn tally(
+        &self,
+        mut tally: Tally,
+        elapsed: Option<Duration>,
+        again: &mut VecDeque<Tally>,
+        on_delay: &mut impl FnMut(Delay),
+    ) {
+        tally.done += 1;
+        tally.streak.add(elapsed.is_some());
+        if let Some(elapsed) = elapsed {
+            tally.success += 1;
+            tally.total += elapsed;
+            tally.jitter.add(elapsed);
+            tally.samples.push(elapsed);
+            self.histogram.record(elapsed);
+        }
+        if tally.done < self.times {
+            again.push_back(tally);
+            return;
+        }
+
+        on_delay(delay_of(
+            IpAddr::V4(tally.ip),
+            tally.total,
+            tally.success,
+            tally.jitter,
+            tally.streak,
+            tally.samples,
+        ));
+    }
+
+    pub async fn run(&self) -> Vec<Delay> {
+        let scanner = self.clone();
+        // 事件循环会阻塞,放到单独的线程里
+        let scan = tokio::task::spawn_blocking(move || {
+            let mut res = Vec::new();
+            scanner.events.stage_start("tcping", scanner.targets.len());
+
+            let scanned = scanner.scan(|delay| {
+                let valid = delay.within(scanner.max_average_delay, scanner.min_average_delay)
+                    && scanner.max_jitter.is_none_or(|max| delay.jitter <= max);
+                if !valid {
+                    scanner.events.trace("tcping", delay.ip, || {
+                        drop_reason(&delay, scanner.max_average_delay, scanner.min_average_delay)
+                    });
+                }
+                scanner.events.result(
+                    "tcping",
+                    delay.ip,
+                    valid,
+                    json!({
+                        "delay_ms": delay.average_delay.as_millis() as u64,
+                        "jitter_ms": delay.jitter.as_secs_f64() * 1000.0,
+                        "p50_ms": delay.percentiles.p50.as_secs_f64() * 1000.0,
+                        "p90_ms": delay.percentiles.p90.as_secs_f64() * 1000.0,
+                        "p99_ms": delay.percentiles.p99.as_secs_f64() * 1000.0,
+                        "success": delay.success,
+                        "streak": delay.streak,
+                        "metric": "syn",
+                        "interference": 0,
+                    }),
+                );
+                if valid {
+                    res.push(delay);
+                    scanner.stop_after.found();
+                }
+            });
+            if let Err(e) = scanned {
+                eprintln!("{}", e);
+            }
+
+            scanner.events.stage_end("tcping", res.len());
+            scanner.stop_after.report();
+            res
+        });
+        scan.await.unwrap_or_default()
+    }
+}
+
+impl Prober for SynScanner {
+    fn stage(&self) -> &'static str {
+        "tcping"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            let mut delays = self.run().await;
+            delays.sort();
+            ScanResult::Delays(delays)
+        })
+    }
+}
+
+/// The delay of `ip` from `success` samples adding up to `total`
+fn delay_of(
+    ip: IpAddr,
+    total: Duration,
+    success: u8,
+    jitter: Jitter,
+    streak: Streak,
+    mut samples: Vec<Duration>,
+) -> Delay {
+    Delay {
+        ip,
+        average_delay: if success != 0 {
+            total / success as u32
+        } else {
+            Duration::ZERO
+        },
+        success,
+        interference: 0,
+        jitter: jitter.value(),
+        percentiles: Percentiles::of(&mut samples),
+        // 已按延迟排好序
+        min_delay: samples.first().copied().unwrap_or_default(),
+        max_delay: samples.last().copied().unwrap_or_default(),
+        sentinel_delay: None,
+        kernel_rtt: None,
+        connect_delay: None,
+        streak: streak.value(),
+    }
+}
+
+/// The local address the kernel would send from to `ip`
+fn route_source(router: &UdpSocket, ip: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    router.connect((ip, 9))?;
+    match router.local_addr()?.ip() {
+        IpAddr::V4(source) => Ok(source),
+        IpAddr::V6(_) => Ok(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+/// A SYN from `source:source_port` to `destination:destination_port`,
+/// without the IP header the kernel adds
+fn syn_segment(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+) -> [u8; SYN_LEN] {
+    let mut segment = [0u8; SYN_LEN];
+    segment[0..2].copy_from_slice(&source_port.to_be_bytes());
+    segment[2..4].copy_from_slice(&destination_port.to_be_bytes());
+    segment[4..8].copy_from_slice(&seq.to_be_bytes());
+    // 头部长度 6 个 32 位字
+    segment[12] = ((SYN_LEN / 4) as u8) << 4;
+    segment[13] = TCP_SYN;
+    segment[14..16].copy_from_slice(&SYN_WINDOW.to_be_bytes());
+    // MSS 选项: 类型 2,长度 4
+    segment[20] = 2;
+    segment[21] = 4;
+    segment[22..24].copy_from_slice(&SYN_MSS.to_be_bytes());
+
+    let checksum = tcp_checksum(source, destination, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+/// The tcp checksum of `segment` over the IPv4 pseudo header (RFC 793)
+fn tcp_checksum(source: Ipv4Addr, destination: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |bytes: &[u8]| {
+        for chunk in bytes.chunks(2) {
+            let word = match *chunk {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+        }
+    };
+    add(&source.octets());
+    add(&destination.octets());
+    add(&[0, libc::IPPROTO_TCP as u8]);
+    add(&(segment.len() as u16).to_be_bytes());
+    add(segment);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The tcp segment in an IPv4 packet received on a raw socket
+fn parse_segment(packet: &[u8]) -> Option<Segment> {
+    let version = packet.first()? >> 4;
+    let header_len = (packet.first()? & 0x0f) as usize * 4;
+    if version != 4 || *packet.get(9)? != libc::IPPROTO_TCP as u8 {
+        return None;
+    }
+    let tcp = packet.get(header_len..header_len + 20)?;
+    let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    Some(Segment {
+        source: Ipv4Addr::from(source),
+        source_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+        destination_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+        ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        flags: tcp[13] & (TCP_SYN | TCP_RST | TCP_ACK),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_segment() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let destination = Ipv4Addr::new(10, 0, 0, 2);
+        let segment = syn_segment(source, destination, 40000, 443, 7);
+        // 校验和正确时,包含校验和重新计算的结果为 0
+        assert_eq!(tcp_checksum(source, destination, &segment), 0);
+        assert_eq!(segment[13], TCP_SYN);
+
+        // 对端的 SYN/ACK,确认号是我们的序号加 1
+        let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0];
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(&source.octets());
+        let mut reply = syn_segment(destination, source, 443, 40000, 99);
+        reply[8..12].copy_from_slice(&8u32.to_be_bytes());
+        reply[13] = TCP_SYN | TCP_ACK;
+        packet.extend_from_slice(&reply);
+        assert_eq!(
+            parse_segment(&packet),
+            Some(Segment {
+                source: destination,
+                source_port: 443,
+                destination_port: 40000,
+                ack: 8,
+                flags: TCP_SYN | TCP_ACK,
+            })
+        );
+        assert_eq!(parse_segment(&packet[..30]), None);
+    }
+
+    #[test]
+    fn test_scan_local() {
+        // 没有 CAP_NET_RAW 时跳过
+        if available(&SocketOptions::default()).is_err() {
+            return;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap()];
+        let scanner = SynScanner::new(ips.clone(), 4, Duration::from_secs(1), 3, port, 9999, 0);
+        let mut delays = Vec::new();
+        scanner.scan(|d| delays.push(d)).unwrap();
+        assert_eq!(delays.len(), 1);
+        assert_eq!(delays[0].success, 3);
+
+        // 关闭的端口回 RST,不用等到超时
+        let start = Instant::now();
+        let scanner = SynScanner::new(ips, 4, Duration::from_secs(5), 2, closed_port, 9999, 0);
+        let mut delays = Vec::new();
+        scanner.scan(|d| delays.push(d)).unwrap();
+        assert_eq!(delays[0].success, 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(listener);
+    }
+}
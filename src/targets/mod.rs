@@ -0,0 +1,714 @@
+use std::{
+    io::{self, BufRead},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use cidr_utils::cidr::IpCidr;
+use sha2::{Digest, Sha256};
+
+// 主机位超过这个数的 IPv6 网段不再逐个展开,改为随机抽样
+const MAX_IPV6_EXPAND_BITS: u8 = 16;
+
+/// A block of consecutive addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Range {
+    v6: bool,
+    // IPv4 地址放在低 32 位
+    start: u128,
+    len: u128,
+}
+
+/// `count` equal pieces of the ranges, `stride` apart and each inside one
+/// subnet, of which only `take` addresses each are tested
+#[derive(Debug, Clone, Copy)]
+struct Stratum {
+    v6: bool,
+    start: u128,
+    len: u128,
+    stride: u128,
+    count: u128,
+    take: u128,
+    // 与 len 互质,k -> k * mult + b (mod len) 是 [0, len) 上的排列
+    mult: u128,
+}
+
+/// The addresses left by [`Targets::with_per_subnet`]
+#[derive(Debug, Clone)]
+struct Strata {
+    per_subnet: usize,
+    prefix: u8,
+    pieces: Vec<Stratum>,
+    // offsets[i] 是 pieces[i] 之前抽取的地址总数
+    offsets: Vec<u128>,
+}
+
+/// The IPs to test, kept as merged address ranges so that a /8 costs no more
+/// memory than a single IP. Iterates in a random order without materializing
+/// the hosts.
+#[derive(Debug, Clone, Default)]
+pub struct Targets {
+    ranges: Vec<Range>,
+    // offsets[i] 是 ranges[i] 之前的地址总数
+    offsets: Vec<u128>,
+    total: u128,
+    // 只测试前 limit 个(随机顺序下即随机抽样)
+    limit: Option<u128>,
+    seed: u128,
+    // 按子网分层抽样时取代 ranges 决定测试哪些地址
+    strata: Option<Strata>,
+}
+
+impl Targets {
+    /// Parse one IP, CIDR, range (`1.0.0.1-1.0.0.255` or `1.0.0.1-255`) or
+    /// wildcard (`1.0.0.*`) per line, skipping anything else. Of an IPv6
+    /// network too large to expand (e.g. a /32) only `ipv6_samples` random
+    /// addresses are kept.
+    pub fn parse(text: &str, ipv6_samples: usize) -> Targets {
+        Targets::parse_lines(io::Cursor::new(text.as_bytes()), Some(ipv6_samples))
+    }
+
+    /// Parse like [`Targets::parse`] line by line from `reader`, e.g. stdin,
+    /// without holding all of its text
+    pub fn read(reader: impl BufRead, ipv6_samples: usize) -> Targets {
+        Targets::parse_lines(reader, Some(ipv6_samples))
+    }
+
+    /// Parse like [`Targets::parse`] but keep every network whole, e.g. for
+    /// the ranges to exclude
+    pub fn parse_exact(text: &str) -> Targets {
+        Targets::parse_lines(io::Cursor::new(text.as_bytes()), None)
+    }
+
+    fn parse_lines(reader: impl BufRead, ipv6_samples: Option<usize>) -> Targets {
+        let mut ranges = Vec::new();
+
+        reader.lines().map_while(Result::ok).for_each(|line| {
+            let line = line.trim();
+            if let Some(range) = parse_range(line).or_else(|| parse_wildcard(line)) {
+                if let Some(ipv6_samples) =
+                    ipv6_samples.filter(|_| range.v6 && range.len > 1 << MAX_IPV6_EXPAND_BITS)
+                {
+                    ranges.extend((0..ipv6_samples).map(|_| Range {
+                        v6: true,
+                        start: range.start + rand::random::<u128>() % range.len,
+                        len: 1,
+                    }));
+                } else {
+                    ranges.push(range);
+                }
+                return;
+            }
+            match IpCidr::from_str(line) {
+                Ok(IpCidr::V6(cidr))
+                    if ipv6_samples.is_some() && 128 - cidr.get_bits() > MAX_IPV6_EXPAND_BITS =>
+                {
+                    let ipv6_samples = ipv6_samples.unwrap_or_default();
+                    let host_mask = !cidr.get_mask();
+                    ranges.extend((0..ipv6_samples).map(|_| Range {
+                        v6: true,
+                        start: cidr.first() | (rand::random::<u128>() & host_mask),
+                        len: 1,
+                    }));
+                }
+                Ok(IpCidr::V6(cidr)) => ranges.push(Range {
+                    v6: true,
+                    start: cidr.first(),
+                    // ::/0 的长度放不进 u128,少算一个地址
+                    len: 1u128
+                        .checked_shl(128 - cidr.get_bits() as u32)
+                        .unwrap_or(u128::MAX),
+                }),
+                Ok(IpCidr::V4(cidr)) => ranges.push(Range {
+                    v6: false,
+                    start: cidr.first() as u128,
+                    len: cidr.size() as u128,
+                }),
+                Err(_) => {}
+            }
+        });
+        Targets::from_ranges(ranges)
+    }
+
+    /// The fewest CIDRs covering exactly all addresses, before any sampling
+    pub fn cidrs(&self) -> Vec<String> {
+        let mut cidrs = Vec::new();
+        for range in self.ranges.iter() {
+            let bits = if range.v6 { 128 } else { 32 };
+            let (mut start, mut remaining) = (range.start, range.len);
+            while remaining > 0 {
+                // 起点对齐且不超过剩余长度的最大块
+                let aligned = start.trailing_zeros().min(bits);
+                let host_bits = aligned.min(127 - remaining.leading_zeros());
+                let network = if range.v6 {
+                    IpAddr::V6(Ipv6Addr::from(start))
+                } else {
+                    IpAddr::V4(Ipv4Addr::from(start as u32))
+                };
+                cidrs.push(format!("{}/{}", network, bits - host_bits));
+                remaining -= 1 << host_bits;
+                start = start.wrapping_add(1 << host_bits);
+            }
+        }
+        cidrs
+    }
+
+    /// The 1-based number and text of every line of `text` that is neither an
+    /// IP, CIDR, range or wildcard, nor blank or a `#` comment
+    pub fn invalid_lines(text: &str) -> Vec<(usize, String)> {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .filter(|(_, line)| {
+                parse_range(line).is_none()
+                    && parse_wildcard(line).is_none()
+                    && IpCidr::from_str(line).is_err()
+            })
+            .map(|(number, line)| (number, line.to_string()))
+            .collect()
+    }
+
+    /// All addresses of `self` and `other`
+    pub fn union(self, other: Targets) -> Targets {
+        let mut ranges = self.ranges;
+        ranges.extend(other.ranges);
+        Targets::from_ranges(ranges)
+    }
+
+    /// The addresses of `self` that are not in `excluded`
+    pub fn exclude(self, excluded: &Targets) -> Targets {
+        let mut ranges = Vec::with_capacity(self.ranges.len());
+        for range in self.ranges {
+            // 用闭区间,地址空间末尾的区间也不会溢出
+            let (mut first, last) = (range.start, range.start + (range.len - 1));
+            let mut covered = false;
+            for cut in excluded.ranges.iter().filter(|cut| cut.v6 == range.v6) {
+                let (cut_first, cut_last) = (cut.start, cut.start + (cut.len - 1));
+                if cut_last < first || cut_first > last {
+                    continue;
+                }
+                if cut_first > first {
+                    ranges.push(Range {
+                        v6: range.v6,
+                        start: first,
+                        len: cut_first - first,
+                    });
+                }
+                if cut_last >= last {
+                    covered = true;
+                    break;
+                }
+                first = cut_last + 1;
+            }
+            if !covered {
+                ranges.push(Range {
+                    v6: range.v6,
+                    start: first,
+                    len: last - first + 1,
+                });
+            }
+        }
+        Targets::from_ranges(ranges)
+    }
+
+    fn from_ranges(mut ranges: Vec<Range>) -> Targets {
+        // 排序后合并重叠和相邻的区间,顺便去重
+        ranges.sort();
+        let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                // 用差值比较,避免地址空间末尾的区间溢出
+                Some(last) if last.v6 == range.v6 && range.start - last.start <= last.len => {
+                    last.len = last
+                        .len
+                        .max((range.start - last.start).saturating_add(range.len));
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        let mut offsets = Vec::with_capacity(merged.len());
+        let mut total: u128 = 0;
+        for range in merged.iter() {
+            offsets.push(total);
+            total = total.saturating_add(range.len);
+        }
+
+        Targets {
+            ranges: merged,
+            offsets,
+            total,
+            limit: None,
+            seed: rand::random(),
+            strata: None,
+        }
+    }
+
+    /// The seed of the random order
+    pub fn seed(&self) -> u128 {
+        self.seed
+    }
+
+    /// Test in the random order of `seed`, e.g. the one of an interrupted run
+    pub fn with_seed(mut self, seed: u128) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Only test `n` random addresses, or all if `n` is 0
+    pub fn with_limit(mut self, n: usize) -> Self {
+        self.limit = if n == 0 { None } else { Some(n as u128) };
+        self
+    }
+
+    /// Only test `n` random addresses of every IPv4 /`prefix` subnet, so that
+    /// a sample spreads over all subnets instead of clustering in a few. IPv6
+    /// addresses are grouped by the prefix 24 bits longer (/48 for /24). Off if
+    /// `n` is 0.
+    pub fn with_per_subnet(mut self, n: usize, prefix: u8) -> Self {
+        if n == 0 {
+            return self;
+        }
+        let n = n as u128;
+
+        let mut pieces = Vec::new();
+        // 前一个区间用掉的同一子网配额,合并后的区间之间仍可能共享子网
+        let mut last_subnet: Option<(bool, u128, u128)> = None;
+        for range in self.ranges.iter() {
+            let host_bits = if range.v6 {
+                104u32.saturating_sub(prefix as u32)
+            } else {
+                32u32.saturating_sub(prefix as u32)
+            };
+            let block = 1u128 << host_bits;
+            let last = range.start + (range.len - 1);
+            let mut cur = range.start;
+            loop {
+                let subnet = cur >> host_bits;
+                let subnet_start = subnet << host_bits;
+                let subnet_last = subnet_start + (block - 1);
+
+                if cur == subnet_start && subnet_last <= last {
+                    // 连续的整个子网合成一项
+                    let full = (last - subnet_start) / block
+                        + u128::from((last - subnet_start) % block == block - 1);
+                    pieces.push(Stratum {
+                        v6: range.v6,
+                        start: subnet_start,
+                        len: block,
+                        stride: block,
+                        count: full,
+                        take: n.min(block),
+                        mult: coprime_multiplier(block),
+                    });
+                    last_subnet = None;
+                    match subnet_start.checked_add(full * block) {
+                        Some(next) if next <= last => cur = next,
+                        _ => break,
+                    }
+                } else {
+                    let piece_last = subnet_last.min(last);
+                    let len = piece_last - cur + 1;
+                    let used = match last_subnet {
+                        Some((v6, id, used)) if v6 == range.v6 && id == subnet => used,
+                        _ => 0,
+                    };
+                    let take = (n - used).min(len);
+                    if take > 0 {
+                        pieces.push(Stratum {
+                            v6: range.v6,
+                            start: cur,
+                            len,
+                            stride: block,
+                            count: 1,
+                            take,
+                            mult: coprime_multiplier(len),
+                        });
+                    }
+                    last_subnet = Some((range.v6, subnet, used + take));
+                    if piece_last >= last {
+                        break;
+                    }
+                    cur = piece_last + 1;
+                }
+            }
+        }
+
+        let mut offsets = Vec::with_capacity(pieces.len());
+        let mut total: u128 = 0;
+        for piece in pieces.iter() {
+            offsets.push(total);
+            total = total.saturating_add(piece.count * piece.take);
+        }
+        self.total = total;
+        self.strata = Some(Strata {
+            per_subnet: n as usize,
+            prefix,
+            pieces,
+            offsets,
+        });
+        self
+    }
+
+    /// Whether `ip` is in the ranges, before any sampling
+    pub fn covers(&self, ip: IpAddr) -> bool {
+        let (v6, addr) = match ip {
+            IpAddr::V4(ip) => (false, u32::from(ip) as u128),
+            IpAddr::V6(ip) => (true, u128::from(ip)),
+        };
+        // 区间按 (v6, start) 排好序且互不重叠
+        let i = self
+            .ranges
+            .partition_point(|range| (range.v6, range.start) <= (v6, addr));
+        i > 0 && {
+            let range = self.ranges[i - 1];
+            range.v6 == v6 && addr - range.start < range.len
+        }
+    }
+
+    /// Whether only part of the ranges is tested, see [`Targets::with_limit`]
+    /// and [`Targets::with_per_subnet`]
+    pub fn is_sampled(&self) -> bool {
+        self.strata.is_some() || self.limit.is_some_and(|limit| limit < self.total)
+    }
+
+    pub fn len(&self) -> usize {
+        let len = match self.limit {
+            Some(limit) => limit.min(self.total),
+            None => self.total,
+        };
+        len.try_into().unwrap_or(usize::MAX)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `index`-th address in ascending order, or in ascending order of
+    /// subnets when sampled per subnet
+    fn get(&self, index: u128) -> IpAddr {
+        let (v6, addr) = match self.strata {
+            Some(ref strata) => {
+                let i = strata.offsets.partition_point(|&offset| offset <= index) - 1;
+                let piece = strata.pieces[i];
+                let j = index - strata.offsets[i];
+                let start = piece.start + (j / piece.take) * piece.stride;
+                // 每个子网的偏移不同,同一子网内取到的地址互不相同
+                let b = (self.seed ^ start).wrapping_mul(0x9e37_79b9_7f4a_7c15) % piece.len;
+                let k = j % piece.take;
+                let offset = (k * piece.mult % piece.len + b) % piece.len;
+                (piece.v6, start + offset)
+            }
+            None => {
+                let i = self.offsets.partition_point(|&offset| offset <= index) - 1;
+                let range = self.ranges[i];
+                (range.v6, range.start + (index - self.offsets[i]))
+            }
+        };
+        if v6 {
+            IpAddr::V6(Ipv6Addr::from(addr))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(addr as u32))
+        }
+    }
+
+    /// Every address once, in a random order that stays the same for this
+    /// value. Needs constant memory however many addresses there are.
+    pub fn iter(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        // 模 2 的幂的线性同余发生器在 c 为奇数、a ≡ 1 (mod 4) 时周期取满,
+        // 即 [0, m) 的一个排列;跳过 >= total 的值就得到 [0, total) 的排列
+        let mask = self.total.max(1).next_power_of_two() - 1;
+        let a = (self.seed >> 64) << 2 | 1;
+        let c = self.seed | 1;
+        let mut state = self.seed >> 32;
+
+        (0..=mask)
+            .map(move |_| {
+                state = a.wrapping_mul(state).wrapping_add(c) & mask;
+                state
+            })
+            .filter(move |&index| index < self.total)
+            .take(self.len())
+            .map(move |index| self.get(index))
+    }
+
+    /// Hash of the tested addresses, independent of their order
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for range in self.ranges.iter() {
+            hasher.update([range.v6 as u8]);
+            hasher.update(range.start.to_be_bytes());
+            hasher.update(range.len.to_be_bytes());
+        }
+        // 抽样时每次测试的地址不同
+        if let Some(ref strata) = self.strata {
+            hasher.update(strata.per_subnet.to_be_bytes());
+            hasher.update([strata.prefix]);
+            hasher.update(self.seed.to_be_bytes());
+        }
+        if let Some(limit) = self.limit.filter(|&limit| limit < self.total) {
+            hasher.update(limit.to_be_bytes());
+            hasher.update(self.seed.to_be_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// `first-last`, where `last` may be only the final IPv4 octet (`1.0.0.1-255`)
+fn parse_range(line: &str) -> Option<Range> {
+    let (first, last) = line.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let (v6, start, end) = match first.parse::<IpAddr>().ok()? {
+        IpAddr::V4(first) => {
+            let end = match last.parse::<u8>() {
+                Ok(octet) => {
+                    let [a, b, c, _] = first.octets();
+                    Ipv4Addr::new(a, b, c, octet)
+                }
+                Err(_) => last.parse::<Ipv4Addr>().ok()?,
+            };
+            (false, u32::from(first) as u128, u32::from(end) as u128)
+        }
+        IpAddr::V6(first) => (
+            true,
+            u128::from(first),
+            u128::from(last.parse::<Ipv6Addr>().ok()?),
+        ),
+    };
+    // 整个 IPv6 地址空间的长度放不进 u128
+    if end < start || end - start == u128::MAX {
+        return None;
+    }
+    Some(Range {
+        v6,
+        start,
+        len: end - start + 1,
+    })
+}
+
+/// An IPv4 address whose trailing octets are `*`, e.g. `1.0.*.*`
+fn parse_wildcard(line: &str) -> Option<Range> {
+    let octets: Vec<&str> = line.split('.').collect();
+    let fixed = octets.iter().position(|&octet| octet == "*")?;
+    if octets.len() != 4 || fixed == 0 || octets[fixed..].iter().any(|&octet| octet != "*") {
+        return None;
+    }
+
+    let mut start = 0u32;
+    for octet in octets[..fixed].iter() {
+        start = start << 8 | octet.parse::<u8>().ok()? as u32;
+    }
+    let host_bits = 8 * (4 - fixed) as u32;
+    Some(Range {
+        v6: false,
+        start: (start << host_bits) as u128,
+        len: 1 << host_bits,
+    })
+}
+
+/// A multiplier coprime with `len`
+fn coprime_multiplier(len: u128) -> u128 {
+    fn gcd(a: u128, b: u128) -> u128 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+    // 从一个大奇数开始找,子网内的排列看起来更随机
+    let mut mult = 0x5851_f42d % len.max(1);
+    while gcd(mult, len) != 1 && len > 1 {
+        mult += 1;
+    }
+    mult.max(1)
+}
+
+impl From<Vec<IpAddr>> for Targets {
+    fn from(ips: Vec<IpAddr>) -> Self {
+        let ranges = ips
+            .into_iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => Range {
+                    v6: false,
+                    start: u32::from(ip) as u128,
+                    len: 1,
+                },
+                IpAddr::V6(ip) => Range {
+                    v6: true,
+                    start: u128::from(ip),
+                    len: 1,
+                },
+            })
+            .collect();
+        Targets::from_ranges(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_ranges() {
+        let targets = Targets::parse("10.0.0.0/24\n10.0.0.128/25\n10.0.1.0/24\n::1\n", 0);
+        assert_eq!(targets.ranges.len(), 2);
+        assert_eq!(targets.len(), 513);
+
+        let ips: HashSet<IpAddr> = targets.iter().collect();
+        assert_eq!(ips.len(), 513);
+        assert!(ips.contains(&"10.0.1.255".parse().unwrap()));
+        assert!(ips.contains(&"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ranges_and_wildcards() {
+        let targets = Targets::parse("1.0.0.1-1.0.0.255\n1.0.1.10 - 20\n1.0.2.*\n1.1.*.*\n", 0);
+        assert_eq!(targets.len(), 255 + 11 + 256 + 65536);
+        let ips: HashSet<IpAddr> = targets.iter().collect();
+        assert!(ips.contains(&"1.0.0.1".parse().unwrap()));
+        assert!(!ips.contains(&"1.0.0.0".parse().unwrap()));
+        assert!(ips.contains(&"1.0.1.20".parse().unwrap()));
+        assert!(ips.contains(&"1.1.255.255".parse().unwrap()));
+
+        assert_eq!(Targets::parse("::1-::ff", 0).len(), 255);
+        assert_eq!(
+            Targets::parse("2606:4700::-2606:4700::ffff:ffff", 10).len(),
+            10
+        );
+
+        // 反向区间、中间的通配符和全通配都不是合法的目标
+        assert!(Targets::parse("1.0.0.9-1.0.0.1\n1.*.0.1\n*.*.*.*\n1.0.0.1-::1", 0).is_empty());
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let input = io::BufReader::new("1.1.1.1\n# comment\n1.0.0.0/24\n".as_bytes());
+        assert_eq!(Targets::read(input, 0).len(), 257);
+    }
+
+    #[test]
+    fn test_invalid_lines() {
+        let text = "1.1.1.1\n\n# comment\n1.1.1.300\n10.0.0.*\nexample.com\n2606:4700::/32";
+        assert_eq!(
+            Targets::invalid_lines(text),
+            vec![(4, "1.1.1.300".to_string()), (6, "example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_cidrs() {
+        let targets = Targets::parse_exact(
+            "10.0.0.0/24\n10.0.1.0/24\n10.0.2.5-10.0.2.8\n2606:4700::/48\n2606:4700:1::/48",
+        );
+        assert_eq!(
+            targets.cidrs(),
+            vec![
+                "10.0.0.0/23",
+                "10.0.2.5/32",
+                "10.0.2.6/31",
+                "10.0.2.8/32",
+                "2606:4700::/47"
+            ]
+        );
+        assert_eq!(Targets::parse_exact("0.0.0.0/0").cidrs(), vec!["0.0.0.0/0"]);
+    }
+
+    #[test]
+    fn test_exclude() {
+        let targets = Targets::parse("10.0.0.0/24\n10.0.2.0/24\n2606:4700::/120", 0);
+        let excluded = Targets::parse_exact("10.0.0.0/26\n10.0.0.100\n10.0.2.0/23\n2606:4700::/32");
+        let left = targets.exclude(&excluded);
+        assert_eq!(left.len(), 256 - 64 - 1);
+        let ips: HashSet<IpAddr> = left.iter().collect();
+        assert!(!ips.contains(&"10.0.0.100".parse().unwrap()));
+        assert!(ips.contains(&"10.0.0.101".parse().unwrap()));
+        assert!(ips.contains(&"10.0.0.64".parse().unwrap()));
+
+        // 精确解析不对大 IPv6 网段抽样
+        assert_eq!(Targets::parse_exact("2606:4700::/32").total, 1 << 96);
+        assert_eq!(Targets::parse_exact("::/0").total, u128::MAX);
+    }
+
+    #[test]
+    fn test_per_subnet() {
+        // 两个 /24,一个残缺的 /24 和一个跨子网边界的区间
+        let targets = Targets::parse(
+            "10.0.0.0/23\n10.0.5.10-10.0.5.12\n10.0.5.200\n10.0.7.250-10.0.8.5",
+            0,
+        )
+        .with_per_subnet(4, 24);
+        // 10.0.0/24 和 10.0.1/24 各 4 个,10.0.5/24 共 4 个,10.0.7/24 4 个,10.0.8/24 4 个
+        assert_eq!(targets.len(), 20);
+
+        let ips: Vec<IpAddr> = targets.iter().collect();
+        assert_eq!(ips.iter().collect::<HashSet<_>>().len(), 20);
+        let mut per_subnet = std::collections::HashMap::new();
+        for ip in ips.iter() {
+            let IpAddr::V4(ip) = ip else { unreachable!() };
+            *per_subnet.entry(ip.octets()[2]).or_insert(0) += 1;
+        }
+        assert!(per_subnet.values().all(|&n| n == 4));
+
+        // 再按 --random-number 抽样仍然在这些地址里
+        let sample: HashSet<IpAddr> = targets.clone().with_limit(5).iter().collect();
+        assert_eq!(sample.len(), 5);
+        assert!(sample.iter().all(|ip| ips.contains(ip)));
+
+        let all = Targets::parse("10.0.0.0/8", 0).with_per_subnet(1, 24);
+        assert_eq!(all.len(), 65536);
+        assert_ne!(
+            all.fingerprint(),
+            Targets::parse("10.0.0.0/8", 0).fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_covers() {
+        let targets = Targets::parse("10.0.0.0/24\n10.0.2.1-10.0.2.9\n::1\n", 0);
+        let covers = |ip: &str| targets.covers(ip.parse().unwrap());
+        assert!(covers("10.0.0.0") && covers("10.0.0.255") && covers("10.0.2.9") && covers("::1"));
+        assert!(!covers("9.255.255.255") && !covers("10.0.1.0") && !covers("10.0.2.10"));
+        // IPv4 地址和低 32 位相同的 IPv6 地址不混淆
+        assert!(!covers("::a00:1") && !covers("::2"));
+        assert!(!targets.is_sampled());
+        assert!(!targets.clone().with_limit(9999).is_sampled());
+        assert!(targets.clone().with_limit(10).is_sampled());
+        assert!(targets.with_per_subnet(1, 24).is_sampled());
+    }
+
+    #[test]
+    fn test_random_order() {
+        let targets = Targets::parse("192.168.0.0/16", 0);
+        let ips: Vec<IpAddr> = targets.iter().collect();
+        assert_eq!(ips, targets.iter().collect::<Vec<_>>());
+        assert_eq!(ips.iter().collect::<HashSet<_>>().len(), 65536);
+        assert!(ips.windows(2).any(|w| w[0] > w[1]));
+
+        let sample = targets.clone().with_limit(50);
+        assert_eq!(sample.iter().count(), 50);
+        assert_ne!(sample.fingerprint(), targets.fingerprint());
+    }
+
+    #[test]
+    fn test_huge_cidr() {
+        // 不展开 /8 也能知道数量和取到地址
+        let targets = Targets::parse("10.0.0.0/8", 0).with_limit(1000);
+        assert_eq!(targets.total, 1 << 24);
+        let cidr = IpCidr::from_str("10.0.0.0/8").unwrap();
+        assert!(targets.iter().all(|ip| cidr.contains(ip)));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let a = Targets::from(vec!["1.1.1.1".parse().unwrap(), "1.0.0.1".parse().unwrap()]);
+        let b = Targets::from(vec!["1.0.0.1".parse().unwrap(), "1.1.1.1".parse().unwrap()]);
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a.fingerprint(), Targets::parse("1.1.1.1", 0).fingerprint());
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+#[cfg(feature = "tls")]
+pub use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Stand-in for builds without the `tls` feature: every handshake fails
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub struct TlsConnector;
+
+#[cfg(not(feature = "tls"))]
+pub type TlsStream<S> = S;
+
+/// A TLS client that verifies servers against the bundled web PKI roots
+#[cfg(feature = "tls")]
+pub fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn connector() -> TlsConnector {
+    TlsConnector
+}
+
+/// Perform a TLS handshake over `stream`, sending `server_name` as SNI
+#[cfg(feature = "tls")]
+pub async fn handshake(
+    connector: &TlsConnector,
+    server_name: &str,
+    stream: TcpStream,
+) -> io::Result<TlsStream<TcpStream>> {
+    let name = ServerName::try_from(server_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    connector.connect(name, stream).await
+}
+
+#[cfg(not(feature = "tls"))]
+pub async fn handshake(
+    _connector: &TlsConnector,
+    _server_name: &str,
+    _stream: TcpStream,
+) -> io::Result<TlsStream<TcpStream>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "built without the tls feature",
+    ))
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        // 不说 TLS 的服务端直接关闭连接
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(handshake(&connector(), "example.com", stream)
+            .await
+            .is_err());
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let err = handshake(&connector(), "not a name", stream)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashSet, fmt, net::IpAddr, sync::Arc, time::Instant};
+
+use crate::output::Redaction;
+
+/// Logs every operation on a few chosen IPs (see '--trace-ip') to stderr,
+/// with its timing and the raw response, to find out why an IP was dropped.
+///
+/// The default value traces nothing.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    ips: HashSet<IpAddr>,
+    // 每行的时间相对于开始跟踪的时刻
+    started: Instant,
+    redaction: Redaction,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("ips", &self.inner.as_ref().map(|inner| &inner.ips))
+            .finish()
+    }
+}
+
+impl Tracer {
+    /// Trace `ips`, nothing when empty. The lines show them masked by `redaction`.
+    pub fn new(ips: impl IntoIterator<Item = IpAddr>, redaction: Redaction) -> Self {
+        let ips: HashSet<IpAddr> = ips.into_iter().collect();
+        if ips.is_empty() {
+            return Tracer::default();
+        }
+        Tracer {
+            inner: Some(Arc::new(Inner {
+                ips,
+                started: Instant::now(),
+                redaction,
+            })),
+        }
+    }
+
+    /// Log `message` about `ip` in `stage` if `ip` is traced. The message is
+    /// only built then, so tracing costs nothing for the other IPs.
+    pub fn log(&self, stage: &str, ip: IpAddr, message: impl FnOnce() -> String) {
+        let Some(ref inner) = self.inner else {
+            return;
+        };
+        if inner.ips.contains(&ip) {
+            let ip = inner.redaction.apply(&ip);
+            eprintln!("{}", Tracer::line(inner.started, stage, &ip, &message()));
+        }
+    }
+
+    fn line(started: Instant, stage: &str, ip: &str, message: &str) -> String {
+        format!(
+            "[trace +{:.3}s] {} {} {}",
+            started.elapsed().as_secs_f64(),
+            stage,
+            ip,
+            message
+        )
+    }
+}
+
+/// `bytes` as text for a trace line, with control characters escaped and
+/// cut after `limit` bytes
+pub fn printable(bytes: &[u8], limit: usize) -> String {
+    let mut text: String = String::from_utf8_lossy(&bytes[..bytes.len().min(limit)])
+        .escape_debug()
+        .collect();
+    if bytes.len() > limit {
+        text.push_str(&format!("...({} bytes)", bytes.len()));
+    }
+    text
+}
+
+/// `bytes` in hex for a trace line, cut after `limit` bytes
+pub fn hex(bytes: &[u8], limit: usize) -> String {
+    let mut text: String = bytes[..bytes.len().min(limit)]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > limit {
+        text.push_str(&format!("...({} bytes)", bytes.len()));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracer() {
+        let ip: IpAddr = "104.16.1.1".parse().unwrap();
+        let other: IpAddr = "104.16.1.2".parse().unwrap();
+
+        let disabled = Tracer::new(Vec::new(), Redaction::None);
+        assert!(disabled.inner.is_none());
+        disabled.log("tcping", ip, || unreachable!());
+
+        let tracer = Tracer::new(vec![ip], Redaction::LastOctet);
+        // 不跟踪的 IP 不生成消息
+        tracer.log("tcping", other, || unreachable!());
+
+        let line = Tracer::line(Instant::now(), "tcping", "104.16.1.1", "connected in 12.0ms");
+        assert!(line.starts_with("[trace +0.0"));
+        assert!(line.ends_with("s] tcping 104.16.1.1 connected in 12.0ms"));
+    }
+
+    #[test]
+    fn test_printable() {
+        assert_eq!(
+            printable(b"HTTP/1.1 200 OK\r\n", 100),
+            "HTTP/1.1 200 OK\\r\\n"
+        );
+        assert_eq!(printable(b"abcdef", 3), "abc...(6 bytes)");
+        assert_eq!(hex(&[0x12, 0xab], 8), "12ab");
+        assert_eq!(hex(&[1, 2, 3], 2), "0102...(3 bytes)");
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    cmp, fmt,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU8,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use futures::{future, future::LocalBoxFuture, stream, Stream, StreamExt};
+use serde_json::json;
+
+use crate::histogram::LatencyHistogram;
+use crate::i18n::{tr, Msg};
+use crate::probe::{until_deadline, Prober, ScanResult, StopAfter};
+use crate::progress::ProgressEvents;
+use crate::scanner::{millis_limit, Delay, Jitter, Percentiles, Streak};
+use crate::socket::SocketOptions;
+use crate::trace::hex;
+
+// 每个包开头用于匹配回包的标记长度
+const TOKEN_LEN: usize = 8;
+// QUIC 服务端会丢弃小于此大小的 Initial 包 (RFC 9000 14.1)
+const QUIC_MIN_INITIAL: usize = 1200;
+// 形如 0x?a?a?a?a 的版本号为保留版本,服务端必须回复版本协商包 (RFC 9000 15)
+const QUIC_RESERVED_VERSION: [u8; 4] = [0x1a, 0x2a, 0x3a, 0x4a];
+
+/// What every UDP probe sends, and which reply answers it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UdpPayload {
+    /// `--probe-size` bytes the peer echoes back verbatim
+    #[default]
+    Echo,
+    /// A DNS query for the root name servers, answered by any DNS server (port 53)
+    Dns,
+    /// A QUIC Initial with a reserved version, answered by any QUIC server
+    /// with a version negotiation packet (port 443)
+    Quic,
+    /// These bytes as they are. Replies are not matched to requests, so
+    /// any datagram received counts as the answer.
+    Raw(Vec<u8>),
+}
+
+impl UdpPayload {
+    /// The datagram to send, marked with `token` where the protocol allows
+    fn packet(&self, token: u64, probe_size: usize) -> Vec<u8> {
+        let token = token.to_be_bytes();
+        match self {
+            UdpPayload::Echo => {
+                let mut packet = vec![0u8; probe_size];
+                packet[..TOKEN_LEN].copy_from_slice(&token);
+                packet
+            }
+            UdpPayload::Dns => {
+                // 事务号由会话号和序号各取一字节,RD=1,查询根域的 NS 记录
+                let mut packet = vec![token[3], token[7], 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+                packet.extend_from_slice(&[0, 0, 2, 0, 1]);
+                packet
+            }
+            UdpPayload::Quic => {
+                // 长包头,目标与源连接 ID 都是标记,填充到最小 Initial 大小
+                let mut packet = vec![0xc0];
+                packet.extend_from_slice(&QUIC_RESERVED_VERSION);
+                packet.push(TOKEN_LEN as u8);
+                packet.extend_from_slice(&token);
+                packet.push(TOKEN_LEN as u8);
+                packet.extend_from_slice(&token);
+                packet.resize(QUIC_MIN_INITIAL.max(probe_size), 0);
+                packet
+            }
+            UdpPayload::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    /// Whether `reply` answers `packet`
+    fn answers(&self, packet: &[u8], reply: &[u8]) -> bool {
+        match self {
+            UdpPayload::Echo => {
+                reply.len() >= TOKEN_LEN && reply[..TOKEN_LEN] == packet[..TOKEN_LEN]
+            }
+            // 事务号相同且 QR=1
+            UdpPayload::Dns => {
+                reply.len() >= 12 && reply[..2] == packet[..2] && reply[2] & 0x80 != 0
+            }
+            // 版本协商包的版本为 0,目标连接 ID 是我们的源连接 ID
+            UdpPayload::Quic => {
+                reply.len() >= 6 + TOKEN_LEN
+                    && reply[0] & 0x80 != 0
+                    && reply[1..5] == [0; 4]
+                    && reply[5] as usize == TOKEN_LEN
+                    && reply[6..6 + TOKEN_LEN] == packet[6..6 + TOKEN_LEN]
+            }
+            UdpPayload::Raw(_) => true,
+        }
+    }
+}
+
+impl FromStr for UdpPayload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "echo" => Ok(UdpPayload::Echo),
+            "dns" => Ok(UdpPayload::Dns),
+            "quic" => Ok(UdpPayload::Quic),
+            other => {
+                let invalid = || {
+                    format!(
+                        "invalid udp payload '{}', expected echo, dns, quic or hex:<bytes>",
+                        s
+                    )
+                };
+                let hex = other.strip_prefix("hex:").ok_or_else(invalid)?;
+                if hex.is_empty() || hex.len() % 2 != 0 {
+                    return Err(invalid());
+                }
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+                    .collect::<Result<Vec<u8>, String>>()
+                    .map(UdpPayload::Raw)
+            }
+        }
+    }
+}
+
+impl fmt::Display for UdpPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpPayload::Echo => write!(f, "echo"),
+            UdpPayload::Dns => write!(f, "dns"),
+            UdpPayload::Quic => write!(f, "quic"),
+            UdpPayload::Raw(bytes) => {
+                write!(f, "hex:")?;
+                bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+/// Measures latency with small UDP packets sent to a peer that echoes them back,
+/// e.g. the far end of a GRE or WireGuard tunnel running a UDP echo service,
+/// or with requests any DNS or QUIC server answers, see [`UdpPayload`].
+#[derive(Debug)]
+pub struct UdpPinger {
+    // 测试IP地址集合
+    ips: Vec<IpAddr>,
+    // 同时测试的最大数量
+    batch_size: usize,
+    // 同个IP发送的包数
+    times: NonZeroU8,
+    // 等待回包的超时
+    timeout: Duration,
+    // 目标端口
+    target_port: u16,
+    // 每个包的大小
+    probe_size: usize,
+    // 发送的内容
+    payload: UdpPayload,
+    // 同个IP两个包之间的间隔
+    interval: Duration,
+    // 平均延迟上限
+    max_average_delay: u128,
+    // 平均延迟下限
+    min_average_delay: u128,
+    // 抖动上限
+    max_jitter: Option<Duration>,
+    // 本地 socket 设置
+    socket_options: SocketOptions,
+    // 进度事件
+    events: ProgressEvents,
+    // 到时停止测试,保留已有的结果
+    deadline: Option<Instant>,
+    // 找到足够多的可用 IP 后停止测试
+    stop_after: StopAfter,
+    // 所有成功测量的延迟分布
+    histogram: LatencyHistogram,
+}
+
+impl UdpPinger {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ips: Vec<IpAddr>,
+        batch_size: usize,
+        timeout: Duration,
+        times: u8,
+        port: u16,
+        probe_size: usize,
+        interval: Duration,
+        avg_delay_upper: u128,
+        avg_delay_lower: u128,
+    ) -> Self {
+        Self {
+            ips,
+            batch_size: cmp::max(batch_size, 1),
+            timeout,
+            times: NonZeroU8::new(times).unwrap_or(NonZeroU8::new(1).unwrap()),
+            target_port: port,
+            probe_size: cmp::max(probe_size, TOKEN_LEN),
+            payload: UdpPayload::Echo,
+            interval,
+            max_average_delay: avg_delay_upper,
+            min_average_delay: avg_delay_lower,
+            max_jitter: None,
+            socket_options: SocketOptions::default(),
+            events: ProgressEvents::default(),
+            deadline: None,
+            stop_after: StopAfter::default(),
+            histogram: LatencyHistogram::default(),
+        }
+    }
+
+    /// Apply local socket settings (e.g. outgoing interface) to every probe
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stream progress and result events to `events`
+    pub fn with_events(mut self, events: ProgressEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Send `payload` instead of echo packets
+    pub fn with_payload(mut self, payload: UdpPayload) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Drop the IPs whose jitter is above `max_jitter`
+    pub fn with_max_jitter(mut self, max_jitter: Option<Duration>) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Stop [`UdpPinger::run`] at `deadline`, returning the delays measured so far
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// End the scan once `stop_after` counts enough IPs that pass the filters
+    pub fn with_stop_after(mut self, stop_after: StopAfter) -> Self {
+        self.stop_after = stop_after;
+        self
+    }
+
+    /// Record every successful sample into `histogram`
+    pub fn with_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    /// Yield the delay of every IP as soon as it is measured, unfiltered.
+    /// At most `batch_size` IPs are in flight at a time.
+    pub fn stream(&self) -> impl Stream<Item = Delay> + '_ {
+        stream::iter(self.ips.iter().copied())
+            .map(move |ip| {
+                let addr = SocketAddr::new(ip, self.target_port);
+                let times = self.times;
+                let timeout = self.timeout;
+                let probe_size = self.probe_size;
+                let payload = self.payload.clone();
+                let interval = self.interval;
+                let socket_options = self.socket_options.clone();
+                let histogram = self.histogram.clone();
+                let events = self.events.clone();
+                tokio::spawn(async move {
+                    UdpPinger::ping(
+                        addr,
+                        times,
+                        timeout,
+                        probe_size,
+                        &payload,
+                        interval,
+                        &socket_options,
+                        &histogram,
+                        &events,
+                    )
+                    .await
+                })
+            })
+            .buffer_unordered(self.batch_size)
+            .filter_map(|delay| future::ready(delay.ok()))
+    }
+
+    pub async fn run(&self) -> Vec<Delay> {
+        let mut res = Vec::new();
+        let total = self.ips.len();
+
+        self.events.stage_start("udping", total);
+
+        let delays = until_deadline(self.stream(), self.deadline);
+        tokio::pin!(delays);
+        while let Some(delay) = delays.next().await {
+            let valid = delay.success > 0
+                && delay.average_delay < millis_limit(self.max_average_delay)
+                && delay.average_delay >= millis_limit(self.min_average_delay)
+                && self.max_jitter.is_none_or(|max| delay.jitter <= max);
+            self.events.result(
+                "udping",
+                delay.ip,
+                valid,
+                json!({
+                    "delay_ms": delay.average_delay.as_millis() as u64,
+                    "jitter_ms": delay.jitter.as_secs_f64() * 1000.0,
+                    "p50_ms": delay.percentiles.p50.as_secs_f64() * 1000.0,
+                    "p90_ms": delay.percentiles.p90.as_secs_f64() * 1000.0,
+                    "p99_ms": delay.percentiles.p99.as_secs_f64() * 1000.0,
+                    "success": delay.success,
+                    "streak": delay.streak,
+                    "probe_size": self.probe_size,
+                    "payload": self.payload.to_string(),
+                }),
+            );
+            if valid {
+                res.push(delay);
+                self.stop_after.found();
+                if self.stop_after.reached() {
+                    break;
+                }
+            }
+        }
+
+        self.events.stage_end("udping", res.len());
+        self.stop_after.report();
+
+        res
+    }
+
+    /// Send `times` packets of `payload` (echo packets of `probe_size` bytes)
+    /// to `addr` and average the round trips
+    #[allow(clippy::too_many_arguments)]
+    pub async fn ping(
+        addr: SocketAddr,
+        times: NonZeroU8,
+        timeout: Duration,
+        probe_size: usize,
+        payload: &UdpPayload,
+        interval: Duration,
+        socket_options: &SocketOptions,
+        histogram: &LatencyHistogram,
+        events: &ProgressEvents,
+    ) -> Delay {
+        let mut delay = Delay {
+            ip: addr.ip(),
+            average_delay: Duration::ZERO,
+            success: 0,
+            interference: 0,
+            jitter: Duration::ZERO,
+            percentiles: Percentiles::default(),
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            sentinel_delay: None,
+            kernel_rtt: None,
+            connect_delay: None,
+            streak: 0,
+        };
+
+        let socket = match socket_options.udp_socket(&addr) {
+            Ok(socket) => socket,
+            Err(e) => {
+                if e.to_string().to_lowercase().contains("too many open files") {
+                    panic!("{}", tr(Msg::TooManyOpenFiles));
+                }
+                return delay;
+            }
+        };
+
+        let session: u32 = rand::random();
+        let mut buf = vec![0u8; probe_size.max(1500)];
+        let mut total_elapsed_time = Duration::ZERO;
+        let mut jitter = Jitter::default();
+        let mut streak = Streak::default();
+        let mut samples = Vec::with_capacity(times.get() as usize);
+
+        for seq in 0..times.get() {
+            if seq > 0 {
+                tokio::time::sleep(interval).await;
+            }
+
+            // 标记 = 会话号 + 序号,用于丢弃迟到的旧回包
+            let token = ((session as u64) << 32) | seq as u64;
+            let packet = payload.packet(token, probe_size);
+
+            let start = Instant::now();
+            if let Err(e) = socket.send(&packet).await {
+                events.trace("udping", addr.ip(), || format!("send failed: {}", e));
+                streak.add(false);
+                continue;
+            }
+            let reply = tokio::time::timeout(timeout, async {
+                loop {
+                    match socket.recv(&mut buf).await {
+                        Ok(n) if payload.answers(&packet, &buf[..n]) => return Ok(n),
+                        Ok(_) => continue,
+                        // 如 ICMP 端口不可达
+                        Err(e) => return Err(e),
+                    }
+                }
+            })
+            .await;
+            events.trace("udping", addr.ip(), || {
+                let outcome = match reply {
+                    Ok(Ok(n)) => format!(
+                        "reply after {:.1}ms: {}",
+                        start.elapsed().as_secs_f64() * 1000.0,
+                        hex(&buf[..n], 64)
+                    ),
+                    Ok(Err(ref e)) => format!("failed: {}", e),
+                    Err(_) => format!("no reply within {:?}", timeout),
+                };
+                format!(
+                    "probe {}/{} to port {} ({}, {} bytes): {}",
+                    seq + 1,
+                    times,
+                    addr.port(),
+                    payload,
+                    packet.len(),
+                    outcome
+                )
+            });
+
+            streak.add(matches!(reply, Ok(Ok(_))));
+            if let Ok(Ok(_)) = reply {
+                let elapsed = start.elapsed();
+                total_elapsed_time += elapsed;
+                jitter.add(elapsed);
+                samples.push(elapsed);
+                histogram.record(elapsed);
+                delay.success += 1;
+            }
+        }
+        delay.jitter = jitter.value();
+        delay.streak = streak.value();
+        delay.percentiles = Percentiles::of(&mut samples);
+        // 已按延迟排好序
+        delay.min_delay = samples.first().copied().unwrap_or_default();
+        delay.max_delay = samples.last().copied().unwrap_or_default();
+
+        if delay.success > 0 {
+            delay.average_delay = total_elapsed_time / delay.success as u32;
+        }
+        delay
+    }
+}
+
+impl Prober for UdpPinger {
+    fn stage(&self) -> &'static str {
+        "udping"
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, ScanResult> {
+        Box::pin(async move {
+            let mut delays = self.run().await;
+            delays.sort();
+            ScanResult::Delays(delays)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_local_echo() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let pinger = UdpPinger::new(
+            vec!["127.0.0.1".parse().unwrap()],
+            1,
+            Duration::from_millis(500),
+            3,
+            port,
+            148,
+            Duration::from_millis(10),
+            9999,
+            0,
+        );
+        let result = pinger.run().await;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].success, 3);
+    }
+
+    #[test]
+    fn test_payload() {
+        for text in ["echo", "dns", "quic", "hex:0a0bff"] {
+            assert_eq!(text.parse::<UdpPayload>().unwrap().to_string(), text);
+        }
+        assert!("hex:abc".parse::<UdpPayload>().is_err());
+        assert!("ntp".parse::<UdpPayload>().is_err());
+
+        let quic = UdpPayload::Quic.packet(7, 64);
+        assert_eq!(quic.len(), QUIC_MIN_INITIAL);
+        // 版本协商包: 版本 0,连接 ID 互换
+        let mut negotiation = vec![0x80, 0, 0, 0, 0, TOKEN_LEN as u8];
+        negotiation.extend_from_slice(&7u64.to_be_bytes());
+        assert!(UdpPayload::Quic.answers(&quic, &negotiation));
+        negotiation[13] = 8;
+        assert!(!UdpPayload::Quic.answers(&quic, &negotiation));
+    }
+
+    #[tokio::test]
+    async fn test_dns_payload() {
+        // 把查询原样返回并置 QR 位,模拟 DNS 服务器
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((n, from)) = server.recv_from(&mut buf).await {
+                buf[2] |= 0x80;
+                let _ = server.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let pinger = UdpPinger::new(
+            vec!["127.0.0.1".parse().unwrap()],
+            1,
+            Duration::from_millis(500),
+            2,
+            port,
+            148,
+            Duration::ZERO,
+            9999,
+            0,
+        )
+        .with_payload(UdpPayload::Dns);
+        let result = pinger.run().await;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].success, 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_reply() {
+        // 绑定后不回包
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+
+        let delay = UdpPinger::ping(
+            addr,
+            NonZeroU8::new(2).unwrap(),
+            Duration::from_millis(50),
+            64,
+            &UdpPayload::Echo,
+            Duration::ZERO,
+            &SocketOptions::default(),
+            &LatencyHistogram::default(),
+            &ProgressEvents::default(),
+        )
+        .await;
+        assert_eq!(delay.success, 0);
+    }
+}
@@ -1,49 +1,82 @@
-use cidr_utils::cidr::IpCidr;
-
+use std::collections::HashMap;
 use std::error::Error;
 
-use std::fs;
-use std::io::BufRead;
-use std::{io, net::IpAddr};
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
 
+use crate::compare::{settings_path, RunConfig};
 use crate::download::Speed;
+use crate::history::Measurement;
 use crate::httping::HttpingResult;
 use crate::input::Opts;
+use crate::probe::ScanResult;
 use crate::routes::{CFCDNCheckResult, self};
-use crate::scanner::Delay;
+use crate::scanner::{Delay, LatencyMetric};
+use crate::spill;
+#[cfg(test)]
+use crate::targets::Targets;
+
+/// 每个大 IPv6 网段默认抽样的地址数
+pub const DEFAULT_IPV6_SAMPLES: usize = 1024;
 
 /// 根据字符串解析成ip 地址
+#[cfg(test)]
 pub fn parse_addresses(ips_str: &str) -> Vec<IpAddr> {
-    let mut ips: Vec<IpAddr> = Vec::new();
-    let reader = io::Cursor::new(ips_str.as_bytes());
-
-    reader.lines().map(|r| r.unwrap()).for_each(|line| {
-        IpCidr::from_str(line)
-            .map(|cidr| ips.extend(cidr.iter_as_ip_addr()))
-            .ok();
-    });
-    ips
+    parse_addresses_sampled(ips_str, DEFAULT_IPV6_SAMPLES)
+}
+
+/// 根据字符串解析成ip 地址,过大的 IPv6 网段(如 /32)只随机抽取 `ipv6_samples` 个地址
+#[cfg(test)]
+pub fn parse_addresses_sampled(ips_str: &str, ipv6_samples: usize) -> Vec<IpAddr> {
+    Targets::parse(ips_str, ipv6_samples).iter().collect()
+}
+
+/// The value of an HTTP `Host` header addressing `ip` directly
+pub fn host_header(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
 }
 
+/// Write the results to '--output', in the order of `valis_ips`, which every
+/// stage sorts by its score and then by IP, so the same results always give
+/// the same file. The tcping results spilled to `spill_dir` follow them. The
+/// settings of the run go to the file of [`settings_path`].
 pub fn write_to_csv(
     valis_ips: &[IpAddr],
-    tcping_result: Option<Vec<Delay>>,
-    // httping_result:Option<Vec<HttpingResult>>,
-    cfcdn_result: Option<Vec<CFCDNCheckResult>>,
+    latency: &ScanResult,
     speedtest_result: Option<Vec<Speed>>,
     opts: &Opts,
+    spill_dir: &Path,
 ) -> Result<(), Box<dyn Error>> {
     let mut csv = String::new();
     let mut titel = String::with_capacity(200);
     titel.push_str("IP");
 
+    // 只有测量到 TLS/HTTP 时才能判断握手是否被干扰
+    let handshake = latency.delays().is_some() && opts.latency_metric != LatencyMetric::Tcp;
+
     // tcp 测速标题
-    if tcping_result.is_some() {
-        titel.push_str(",Loss,Delay(ms)");
+    if latency.delays().is_some() {
+        titel.push_str(",Loss,Delay(ms),Min(ms),Max(ms),Jitter(ms),P50(ms),P90(ms),P99(ms),KernelRTT(ms),Streak");
+    }
+    if handshake {
+        titel.push_str(",Handshake,Connect(ms),TLS/HTTP(ms)");
+    }
+    // 只有 tcping 测量对照目标
+    let sentinel = latency.delays().is_some() && opts.sentinel_interval.is_some();
+    if sentinel {
+        titel.push_str(",Sentinel(ms)");
     }
-    if cfcdn_result.is_some() {
+    if latency.routes().is_some() {
         titel.push_str(",Status,Area");
     }
+    if latency.http().is_some() {
+        titel.push_str(",TTFB(ms),Connect(ms),Send(ms),Recv(ms)");
+    }
 
     if speedtest_result.is_some() {
         titel.push_str(",Speed(MB/s)");
@@ -53,17 +86,17 @@ pub fn write_to_csv(
     // add title
     csv.push_str(&titel);
 
-    let tcping_map = if tcping_result.is_some(){
-        Some(Delay::to_map(tcping_result.unwrap_or(vec![])))
-    } else {
-        None
-    };
+    let tcping_map: Option<HashMap<IpAddr, &Delay>> = latency
+        .delays()
+        .map(|delays| delays.iter().map(|d| (d.ip, d)).collect());
 
-    let httping_map = if cfcdn_result.is_some(){
-        Some(CFCDNCheckResult::to_map(cfcdn_result.unwrap_or(vec![])))
-    } else {
-        None
-    };
+    let httping_map: Option<HashMap<IpAddr, &CFCDNCheckResult>> = latency
+        .routes()
+        .map(|routes| routes.iter().map(|r| (r.ip, r)).collect());
+
+    let http_map: Option<HashMap<IpAddr, &HttpingResult>> = latency
+        .http()
+        .map(|results| results.iter().map(|r| (r.ip, r)).collect());
 
     let speed_map = if speedtest_result.is_some(){
         Some(Speed::to_map(speedtest_result.unwrap_or(vec![])))
@@ -71,17 +104,28 @@ pub fn write_to_csv(
         None
     };
 
+    // 握手被重置且没有成功测量的 IP 追加在最后
+    let mut rows: Vec<IpAddr> = valis_ips.to_vec();
+    if let Some(ref record) = tcping_map {
+        let mut interfered: Vec<&Delay> = record
+            .values()
+            .copied()
+            .filter(|d| d.success == 0 && d.interference_suspected() && !valis_ips.contains(&d.ip))
+            .collect();
+        interfered.sort_by_key(|d| d.ip);
+        rows.extend(interfered.iter().map(|d| d.ip));
+    }
+
     // push data to csv
-    for ip in valis_ips.iter(){
+    for ip in rows.iter(){
         let mut line = String::with_capacity(1024);
-        line.push_str(&ip.to_string());
+        line.push_str(&opts.redact.apply(ip));
 
         // push tcp result
         if let Some(ref record) = tcping_map{
             if record.contains_key(ip){
                 let value = record.get(ip).unwrap();
-                let loss_rate = 1.0 - (value.success as f64 / opts.time as f64);
-                line.push_str(&format!(",{:.1},{:.2}", loss_rate,value.average_delay.as_millis()));
+                line.push_str(&delay_columns(value, opts, handshake, sentinel));
             }
         }
 
@@ -98,6 +142,22 @@ pub fn write_to_csv(
             }
         }
 
+        if let Some(ref record) = http_map {
+            if let Some(value) = record.get(ip) {
+                let ms = |d: Option<std::time::Duration>| {
+                    d.map(|d| format!("{:.1}", d.as_secs_f64() * 1000.0))
+                        .unwrap_or_default()
+                };
+                line.push_str(&format!(
+                    ",{},{},{},{}",
+                    ms(value.ttfb),
+                    ms(value.steps.map(|steps| steps.connect)),
+                    ms(value.steps.map(|steps| steps.send)),
+                    ms(value.steps.map(|steps| steps.recv))
+                ));
+            }
+        }
+
         if let Some(ref record) = speed_map{
             if record.contains_key(ip){
                 let value = record.get(ip).unwrap();
@@ -115,9 +175,88 @@ pub fn write_to_csv(
     }
 
     fs::write(&opts.output, csv)?;
+    // 测试设置写在旁边的文件里,compare 据此判断两次结果是否可比
+    fs::write(
+        settings_path(Path::new(&opts.output)),
+        RunConfig::from_opts(opts).to_comment() + "\n",
+    )?;
+
+    // 超出 --memory-limit 或因 --max-memory 换出的结果在磁盘上,按顺序合并后追加
+    if (opts.memory_limit > 0 || opts.max_memory.is_some()) && latency.delays().is_some() {
+        // --top 只保留每组最好的几个,都在内存里
+        if opts.top == 0 {
+            let mut file = BufWriter::new(OpenOptions::new().append(true).open(&opts.output)?);
+            for delay in spill::merged(spill_dir)? {
+                let measurement = Measurement {
+                    ip: delay.ip,
+                    colo: None,
+                    delay_ms: Some(delay.average_delay.as_secs_f64() * 1000.0),
+                    loss: Some(1.0 - delay.success as f64 / opts.time as f64),
+                    speed_mbps: None,
+                };
+                if opts.filter.as_ref().is_some_and(|filter| !filter.matches(&measurement)) {
+                    continue;
+                }
+                writeln!(
+                    file,
+                    "{}{}",
+                    opts.redact.apply(&delay.ip),
+                    delay_columns(&delay, opts, handshake, sentinel)
+                )?;
+            }
+            file.flush()?;
+        }
+        let _ = fs::remove_dir_all(spill_dir);
+    }
     Ok(())
 }
 
+/// The Loss, Delay, Min, Max, Jitter, percentile, kernel RTT, streak and (if measured) Handshake, Connect,
+/// TLS/HTTP and Sentinel columns of one IP, the delays with '--latency-precision' decimals
+fn delay_columns(value: &Delay, opts: &Opts, handshake: bool, sentinel: bool) -> String {
+    let ms = |duration| format_millis(duration, opts.latency_precision);
+    let loss_rate = 1.0 - (value.success as f64 / opts.time as f64);
+    let mut columns = format!(",{:.1},{}", loss_rate, ms(value.average_delay));
+    columns.push_str(&format!(",{},{}", ms(value.min_delay), ms(value.max_delay)));
+    columns.push_str(&format!(",{:.1}", value.jitter.as_secs_f64() * 1000.0));
+    let percentiles = value.percentiles;
+    columns.push_str(&format!(
+        ",{},{},{}",
+        ms(percentiles.p50),
+        ms(percentiles.p90),
+        ms(percentiles.p99)
+    ));
+    // 没有内核 RTT 时留空,如 udping 或 Linux 以外的系统
+    columns.push(',');
+    if let Some(rtt) = value.kernel_rtt {
+        columns.push_str(&format!("{:.3}", rtt.as_secs_f64() * 1000.0));
+    }
+    columns.push_str(&format!(",{}", value.streak));
+    if handshake {
+        columns.push_str(if value.interference_suspected() {
+            ",Interference"
+        } else {
+            ",OK"
+        });
+        // 连接时间和之后的握手与请求时间分开列出
+        columns.push(',');
+        if let Some(connect) = value.connect_delay {
+            columns.push_str(&format!("{:.1}", connect.as_secs_f64() * 1000.0));
+        }
+        columns.push(',');
+        if let Some(handshake) = value.handshake_delay() {
+            columns.push_str(&format!("{:.1}", handshake.as_secs_f64() * 1000.0));
+        }
+    }
+    if sentinel {
+        columns.push(',');
+        if let Some(delay) = value.sentinel_delay {
+            columns.push_str(&format!("{:.1}", delay.as_secs_f64() * 1000.0));
+        }
+    }
+    columns
+}
+
 pub fn human_readable_size(size: f64) -> String {
     let units = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
     let mut size = size;
@@ -129,6 +268,65 @@ pub fn human_readable_size(size: f64) -> String {
     format!("{:.2} {}", size, units[idx])
 }
 
+/// `duration` in milliseconds with `precision` decimals (at most 6, i.e.
+/// nanoseconds), see '--latency-precision'. 0 gives whole milliseconds,
+/// truncated like [`std::time::Duration::as_millis`].
+pub fn format_millis(duration: std::time::Duration, precision: usize) -> String {
+    if precision == 0 {
+        duration.as_millis().to_string()
+    } else {
+        format!("{:.*}", precision.min(6), duration.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Format a duration in the units of [`parse_duration`], e.g. `1h05m` or `42s`
+pub fn human_readable_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{:02}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m{:02}s", mins, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Parse a share given as a percentage (`20%`) or a fraction (`0.2`), between 0 and 1
+pub fn parse_fraction(src: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid fraction '{}', expected e.g. 20% or 0.2", src);
+    let fraction = match src.trim().strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map_err(|_| invalid())? / 100.0,
+        None => src.trim().parse::<f64>().map_err(|_| invalid())?,
+    };
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Parse a duration such as `45m`, `12h`, `30d` or `2w`. A bare number is taken as seconds.
+pub fn parse_duration(src: &str) -> Result<std::time::Duration, String> {
+    let src = src.trim();
+    let split = src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len());
+    let (value, unit) = src.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", src))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return Err(format!("invalid duration unit in '{}' (expected s|m|h|d|w)", src)),
+    };
+    Ok(std::time::Duration::from_secs(value * seconds))
+}
+
 pub fn get_domain_from_url(input: &str) -> Result<String, url::ParseError> {
     match url::Url::parse(input) {
         Ok(url) => {
@@ -147,7 +345,10 @@ mod test {
     use crate::{
         input::Opts,
         parse_addresses_from_opt,
-        utils::{human_readable_size, parse_addresses},
+        utils::{
+            format_millis, host_header, human_readable_duration, human_readable_size,
+            parse_addresses, parse_addresses_sampled, parse_duration, parse_fraction,
+        },
     };
 
     use super::get_domain_from_url;
@@ -156,15 +357,73 @@ mod test {
     /// Makes sure the network is available
     pub fn parse_cidr() {
         let cidr_str = "192.168.1.1/24";
-        let ips = parse_addresses(&cidr_str);
+        let ips = parse_addresses(cidr_str);
         assert!(ips.len() == 256);
     }
 
+    #[test]
+    pub fn parse_ipv6_cidr() {
+        // 小网段完整展开
+        assert_eq!(parse_addresses("2606:4700::/120").len(), 256);
+
+        // 大网段随机抽样
+        let ips = parse_addresses_sampled("2606:4700::/32", 100);
+        assert_eq!(ips.len(), 100);
+        let cidr = cidr_utils::cidr::IpCidr::from_str("2606:4700::/32").unwrap();
+        assert!(ips.iter().all(|ip| ip.is_ipv6() && cidr.contains(*ip)));
+    }
+
+    #[test]
+    pub fn test_host_header() {
+        assert_eq!(host_header(&"1.1.1.1".parse().unwrap()), "1.1.1.1");
+        assert_eq!(host_header(&"2606:4700::1".parse().unwrap()), "[2606:4700::1]");
+    }
+
     #[test]
     pub fn parse_nothing_string() {
         let cidr_str = "# nothing";
         let ips = parse_addresses(cidr_str);
-        assert!(ips.len() == 0);
+        assert!(ips.is_empty());
+    }
+
+    #[test]
+    pub fn test_parse_fraction() {
+        assert_eq!(parse_fraction("20%"), Ok(0.2));
+        assert_eq!(parse_fraction(" 0.25 "), Ok(0.25));
+        assert_eq!(parse_fraction("0"), Ok(0.0));
+        assert!(parse_fraction("1.5").is_err());
+        assert!(parse_fraction("-10%").is_err());
+        assert!(parse_fraction("some").is_err());
+    }
+
+    #[test]
+    pub fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap().as_secs(), 90);
+        assert_eq!(parse_duration("45m").unwrap().as_secs(), 45 * 60);
+        assert_eq!(parse_duration("12h").unwrap().as_secs(), 12 * 3600);
+        assert_eq!(parse_duration("30d").unwrap().as_secs(), 30 * 24 * 3600);
+        assert_eq!(parse_duration("2w").unwrap().as_secs(), 14 * 24 * 3600);
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+    }
+
+    #[test]
+    pub fn test_format_millis() {
+        let delay = std::time::Duration::from_micros(12_345);
+        assert_eq!(format_millis(delay, 0), "12");
+        assert_eq!(format_millis(std::time::Duration::from_micros(12_999), 0), "12");
+        assert_eq!(format_millis(delay, 1), "12.3");
+        assert_eq!(format_millis(delay, 3), "12.345");
+        assert_eq!(format_millis(std::time::Duration::from_nanos(1), 9), "0.000001");
+    }
+
+    #[test]
+    pub fn test_human_readable_duration() {
+        let secs = std::time::Duration::from_secs;
+        assert_eq!(human_readable_duration(secs(42)), "42s");
+        assert_eq!(human_readable_duration(secs(200)), "3m20s");
+        assert_eq!(human_readable_duration(secs(3900)), "1h05m");
+        assert_eq!(human_readable_duration(secs(2 * 86400 + 7200)), "2d02h");
     }
 
     #[test]
@@ -199,7 +458,7 @@ mod test {
             random_number: 0,
             ..Default::default() // 初始化其他参数为默认值
         };
-        let result = parse_addresses_from_opt(&opts);
+        let result = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(result.len(), 16777472);
 
         let opts = Opts {
@@ -207,7 +466,7 @@ mod test {
             random_number: 50,
             ..Default::default() // 初始化其他参数为默认值
         };
-        let result = parse_addresses_from_opt(&opts);
+        let result = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(result.len(), 50);
 
         let opts = Opts {
@@ -215,8 +474,18 @@ mod test {
             random_number: 50,
             ..Default::default() // 初始化其他参数为默认值
         };
-        let result = parse_addresses_from_opt(&opts);
+        let result = parse_addresses_from_opt(&opts, &[]);
         assert_eq!(result.len(), 50);
+
+        // 先排除再抽样
+        let opts = Opts {
+            args: vec!["192.168.1.0/24".to_string()],
+            exclude: vec!["192.168.1.0/25".to_string(), "192.168.1.255".to_string()],
+            random_number: 500,
+            ..Default::default()
+        };
+        let result = parse_addresses_from_opt(&opts, &[]);
+        assert_eq!(result.len(), 127);
     }
 
     
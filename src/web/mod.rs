@@ -0,0 +1,190 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast::error::RecvError,
+};
+
+use crate::progress::ProgressEvents;
+
+// 内嵌的单页面
+const INDEX_HTML: &str = include_str!("index.html");
+
+/// Progress of one stage as shown by the web ui
+#[derive(Debug, Default)]
+struct StageState {
+    stage: String,
+    total: usize,
+    done: usize,
+    valid: Option<usize>,
+}
+
+/// Everything the web ui knows about the current run
+#[derive(Debug, Default)]
+struct WebState {
+    stages: Vec<StageState>,
+    // 只保留有效的结果,避免大范围扫描时占用过多内存
+    results: Vec<Value>,
+}
+
+impl WebState {
+    fn apply(&mut self, event: &Value) {
+        let stage = event["stage"].as_str().unwrap_or_default();
+        match event["event"].as_str() {
+            Some("stage_start") => self.stages.push(StageState {
+                stage: stage.to_string(),
+                total: event["total"].as_u64().unwrap_or(0) as usize,
+                ..Default::default()
+            }),
+            Some("result") => {
+                if let Some(state) = self.stages.iter_mut().rev().find(|s| s.stage == stage) {
+                    state.done += 1;
+                }
+                if event["valid"].as_bool().unwrap_or(false) {
+                    self.results.push(event.clone());
+                }
+            }
+            Some("stage_end") => {
+                if let Some(state) = self.stages.iter_mut().rev().find(|s| s.stage == stage) {
+                    state.valid = event["valid"].as_u64().map(|v| v as usize);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let stages: Vec<Value> = self
+            .stages
+            .iter()
+            .map(|s| {
+                json!({
+                    "stage": s.stage,
+                    "total": s.total,
+                    "done": s.done,
+                    "valid": s.valid,
+                    "finished": s.valid.is_some(),
+                })
+            })
+            .collect();
+        json!({"stages": stages, "results": self.results})
+    }
+}
+
+/// Serve the web ui on `addr`, fed by `events`. Returns the bound address.
+///
+/// Must be called from within a tokio runtime.
+pub fn serve(addr: SocketAddr, events: &ProgressEvents) -> io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let local_addr = listener.local_addr()?;
+
+    let state = Arc::new(Mutex::new(WebState::default()));
+
+    if let Some(mut rx) = events.subscribe() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => state.lock().unwrap().apply(&event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let _ = handle_connection(stream, state).await;
+            });
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<WebState>>) -> io::Result<()> {
+    let mut buf = [0; 2048];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        "/" | "/index.html" => ("200 OK", "text/html; charset=utf-8", INDEX_HTML.to_string()),
+        "/state" => (
+            "200 OK",
+            "application/json",
+            state.lock().unwrap().to_json().to_string(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_state_from_events() {
+        let mut state = WebState::default();
+        state.apply(&json!({"event": "stage_start", "stage": "tcping", "total": 2}));
+        state.apply(&json!({"event": "result", "stage": "tcping", "ip": "1.1.1.1", "valid": true}));
+        state.apply(&json!({"event": "result", "stage": "tcping", "ip": "1.0.0.1", "valid": false}));
+        state.apply(&json!({"event": "stage_end", "stage": "tcping", "valid": 1}));
+
+        let value = state.to_json();
+        assert_eq!(value["stages"][0]["done"], 2);
+        assert_eq!(value["stages"][0]["finished"], true);
+        assert_eq!(value["results"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let events = ProgressEvents::new();
+        let addr = serve("127.0.0.1:0".parse().unwrap(), &events).unwrap();
+
+        assert!(get(addr, "/").await.contains("<html"));
+        assert!(get(addr, "/missing").await.starts_with("HTTP/1.1 404"));
+
+        events.stage_start("tcping", 10);
+        // 等待后台任务处理事件
+        let mut response = String::new();
+        for _ in 0..100 {
+            response = get(addr, "/state").await;
+            if response.contains("tcping") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(response.contains("\"total\":10"));
+    }
+}